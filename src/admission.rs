@@ -0,0 +1,224 @@
+//! Admission policy for submitted events
+//!
+//! Events from clients and from upstream relays pass the same checks
+//! before they reach the database writer.  The `cid` given to each
+//! check only identifies the source in logs.
+use crate::config::Settings;
+use crate::event::Event;
+use crate::notice::Notice;
+use crate::utils::unix_time;
+use tracing::info;
+
+/// Check a client event before its signature is verified, so that
+/// malformed keys and oversized events are refused with a specific
+/// reason, without the cost of verification.
+pub fn reject_unverified_event(e: &Event, settings: &Settings, cid: &str) -> Option<Notice> {
+    if settings.options.require_valid_pubkeys && !e.is_valid_pubkey() {
+        info!("client: {} sent an event with an invalid pubkey", cid);
+        Some(Notice::invalid(
+            e.id.clone(),
+            "pubkey is not a valid BIP-340 x-only public key",
+        ))
+    // check if the event is too large.
+    } else if !e.is_valid_size(settings.limits.max_event_bytes) {
+        info!("client: {} sent an oversized event", cid);
+        let max = settings.limits.max_event_bytes.unwrap_or_default();
+        let msg = format!("Events may not exceed {max} bytes on this relay.");
+        Some(Notice::invalid(e.id.clone(), &msg))
+    // check if the event has too many tags.
+    } else if !e.is_valid_tag_count(settings.limits.max_tag_count) {
+        info!("client: {} sent an event with too many tags", cid);
+        let max = settings.limits.max_tag_count.unwrap_or_default();
+        let msg = format!("Events may not have more than {max} tags on this relay.");
+        Some(Notice::invalid(e.id.clone(), &msg))
+    // check if any tag values are too long.
+    } else if !e.is_valid_tag_lengths(settings.limits.max_tag_value_length) {
+        info!("client: {} sent an event with an oversized tag value", cid);
+        let max_len = settings.limits.max_tag_value_length.unwrap_or_default();
+        let msg = format!("Tag values may not exceed {max_len} bytes on this relay.");
+        Some(Notice::invalid(e.id.clone(), &msg))
+    } else {
+        None
+    }
+}
+
+/// Check a validated event against the relay's admission policy,
+/// returning the notice to send if it is rejected.
+pub fn reject_client_event(e: &Event, settings: &Settings, cid: &str) -> Option<Notice> {
+    let now = unix_time();
+    let (past_seconds, future_seconds) = settings.options.created_at_bounds(e.kind);
+    // check if the relay is under maintenance
+    if let Some(w) = settings.maintenance.active_window(now) {
+        info!("rejecting event during maintenance (cid: {})", cid);
+        Some(Notice::error(e.id.clone(), &w.message(now)))
+    // check if the relay is accepting events at this time of day
+    } else if !settings.posting_hours.is_open() {
+        info!("rejecting event outside of posting hours (cid: {})", cid);
+        Some(Notice::blocked(
+            e.id.clone(),
+            &settings.posting_hours.message(),
+        ))
+    // check if event is expired
+    } else if e.is_expired() {
+        info!("client: {} sent an expired event", cid);
+        Some(Notice::invalid(
+            e.id.clone(),
+            "The event has already expired",
+        ))
+    // check that the kind is in range.
+    } else if !e.is_valid_kind(settings.limits.max_kind) {
+        info!("client: {} sent an event with kind {}", cid, e.kind);
+        let max = settings.limits.max_kind.unwrap_or_default();
+        let msg = format!("Event kinds may not exceed {max} on this relay.");
+        Some(Notice::invalid(e.id.clone(), &msg))
+    // check that the event id carries enough proof-of-work.
+    } else if let Some(required) = settings
+        .limits
+        .min_pow_difficulty
+        .filter(|r| e.pow_difficulty() < *r)
+    {
+        info!(
+            "client: {} sent an event with insufficient proof-of-work",
+            cid
+        );
+        let msg = format!(
+            "difficulty {} required; got {}",
+            required,
+            e.pow_difficulty()
+        );
+        Some(Notice::pow(e.id.clone(), &msg))
+    // check that the author committed to the required proof-of-work,
+    // rather than getting lucky.
+    } else if let Some(required) = settings
+        .limits
+        .min_pow_difficulty
+        .filter(|r| *r > 0 && e.pow_target().map_or(true, |t| t < *r))
+    {
+        info!(
+            "client: {} sent an event with an insufficient proof-of-work target",
+            cid
+        );
+        let msg = match e.pow_target() {
+            Some(t) => format!("committed target {t} is below required difficulty {required}"),
+            None => format!("nonce tag must commit to a target difficulty of at least {required}"),
+        };
+        Some(Notice::pow(e.id.clone(), &msg))
+    // check if a contact list is too large.
+    } else if !e.is_valid_contact_list_size(settings.limits.max_contact_list_entries) {
+        info!("client: {} sent an oversized contact list", cid);
+        let max = settings.limits.max_contact_list_entries.unwrap_or_default();
+        let msg = format!("Contact lists may not exceed {max} entries on this relay.");
+        Some(Notice::invalid(e.id.clone(), &msg))
+    // check if the event mentions too many pubkeys.
+    } else if !e.is_valid_distinct_p_tags(settings.limits.max_distinct_p_tags) {
+        info!("client: {} sent an event mentioning too many pubkeys", cid);
+        let max = settings.limits.max_distinct_p_tags.unwrap_or_default();
+        let msg = format!("Events may not mention more than {max} distinct pubkeys on this relay.");
+        Some(Notice::invalid(e.id.clone(), &msg))
+    // check if the event would create too many tag index entries.
+    } else if !e.is_valid_indexed_tag_count(settings.limits.max_indexed_tags) {
+        info!("client: {} sent an event with too many indexed tags", cid);
+        let max = settings.limits.max_indexed_tags.unwrap_or_default();
+        let msg = format!("Events may not have more than {max} single-letter tags on this relay.");
+        Some(Notice::invalid(e.id.clone(), &msg))
+    // check that relay hints look like relay URLs.
+    } else if settings.options.validate_relay_hints && !e.is_valid_relay_hints() {
+        info!("client: {} sent an event with an invalid relay hint", cid);
+        Some(Notice::invalid(
+            e.id.clone(),
+            "relay hints in e and p tags must be relay URLs",
+        ))
+    // check that e tag markers are well-formed.
+    } else if settings.options.validate_etag_markers && !e.is_valid_etag_markers() {
+        info!("client: {} sent an event with an invalid e tag marker", cid);
+        Some(Notice::invalid(
+            e.id.clone(),
+            "e tag markers must be one of root, reply or mention",
+        ))
+    // check that plaintext kinds do not carry JSON content.
+    } else if !e.is_valid_plaintext_content(&settings.options.reject_json_content_kinds) {
+        info!("client: {} sent JSON content for a plaintext kind", cid);
+        Some(Notice::invalid(
+            e.id.clone(),
+            "content must be plaintext, not JSON, for this kind",
+        ))
+    // check that events have the tags required for their kind.
+    } else if let Some(tag) = e.missing_required_tag(settings.options.required_tags(e.kind)) {
+        info!("client: {} sent an event missing a required tag", cid);
+        Some(Notice::invalid(
+            e.id.clone(),
+            &format!("events of this kind must have a \"{tag}\" tag"),
+        ))
+    // check that parameterized replaceable events name their parameter.
+    } else if !e.is_valid_param_tag(settings.options.require_d_tag_for_parameterized) {
+        info!(
+            "client: {} sent a parameterized replaceable event without a d tag",
+            cid
+        );
+        Some(Notice::invalid(
+            e.id.clone(),
+            "parameterized replaceable events must include a d tag on this relay",
+        ))
+    // check that parameterized replaceable events have a single parameter.
+    } else if !e.is_valid_unique_d_tag(settings.options.reject_duplicate_d_tags) {
+        info!(
+            "client: {} sent a parameterized replaceable event with several d tags",
+            cid
+        );
+        Some(Notice::invalid(
+            e.id.clone(),
+            "parameterized replaceable events may not have more than one d tag",
+        ))
+    // check that every tag has a name.
+    } else if !e.is_valid_nonempty_tags(settings.options.reject_empty_tags) {
+        info!("client: {} sent an event with an empty tag", cid);
+        Some(Notice::invalid(
+            e.id.clone(),
+            "tags must have at least one element",
+        ))
+    // check that delegated events are allowed by their delegation.
+    } else if !e.is_valid_delegation() {
+        info!("client: {} sent an event with an invalid delegation", cid);
+        Some(Notice::invalid(
+            e.id.clone(),
+            "delegation tag is invalid, or does not allow this event",
+        ))
+    // check that the delegation is limited, if it must be.
+    } else if !e.is_constrained_delegation(settings.options.require_constrained_delegation) {
+        info!(
+            "client: {} sent an event with an unconstrained delegation",
+            cid
+        );
+        Some(Notice::invalid(
+            e.id.clone(),
+            "delegation conditions must restrict the kind or created_at of events",
+        ))
+    // check that the timestamp is not a placeholder.
+    } else if !e.is_valid_created_at(&settings.options.reject_created_at) {
+        info!("client: {} sent an event with a placeholder timestamp", cid);
+        Some(Notice::invalid(
+            e.id.clone(),
+            "created_at is a placeholder, not a real timestamp",
+        ))
+    // check if the event is too far in the future.
+    } else if !e.is_valid_timestamp(past_seconds, future_seconds) {
+        info!(
+            "client: {} sent an event with an out of range timestamp",
+            cid
+        );
+        let range: Vec<String> = [
+            past_seconds.map(|s| format!("-{s}sec")),
+            future_seconds.map(|s| format!("+{s}sec")),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+        let msg = format!(
+            "The event created_at field is out of the acceptable range ({}) for this relay.",
+            range.join(", ")
+        );
+        Some(Notice::invalid(e.id.clone(), &msg))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,143 @@
+//! Periodic self-announcement of the relay
+//!
+//! When the operator provides a private key for the relay, a task
+//! signs a kind-10002 (relay list) event naming the relay's own URL,
+//! and submits it to the database writer on a fixed interval.  The
+//! writer stores and broadcasts it exactly as it would an event from
+//! a client.
+use crate::config::Settings;
+use crate::db::SubmittedEvent;
+use crate::error::Result;
+use crate::event::Event;
+use crate::notice::Notice;
+use nostr::event::{Kind, Tag};
+use nostr::key::{FromSkStr, Keys};
+use nostr::EventBuilder;
+use std::time::Duration;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::mpsc;
+use tracing::{debug, info, warn};
+
+/// Kind of the announcement event (NIP-65 relay list metadata)
+pub const ANNOUNCEMENT_KIND: u64 = 10002;
+
+/// Start the announcement task, if a relay key is configured.
+pub fn start_announcer(
+    settings: &Settings,
+    event_tx: &mpsc::Sender<SubmittedEvent>,
+    shutdown_tx: &Sender<()>,
+) {
+    let keys = match settings
+        .announcement
+        .secret_key
+        .as_deref()
+        .map(Keys::from_sk_str)
+    {
+        None => return,
+        Some(Ok(k)) => k,
+        Some(Err(e)) => {
+            warn!("invalid announcement secret_key: {}", e);
+            return;
+        }
+    };
+    let relay_url = match &settings.info.relay_url {
+        Some(u) => u.clone(),
+        None => {
+            warn!("announcement requires info.relay_url to be set");
+            return;
+        }
+    };
+    let interval = Duration::from_secs(settings.announcement.interval_seconds.max(1));
+    info!(
+        "announcing relay as {} every {:?}",
+        keys.public_key(),
+        interval
+    );
+    tokio::task::spawn(announce(
+        keys,
+        relay_url,
+        interval,
+        event_tx.clone(),
+        shutdown_tx.subscribe(),
+    ));
+}
+
+/// Build a signed announcement event for the relay.
+pub fn announcement_event(keys: &Keys, relay_url: &str) -> Result<Event> {
+    let event = EventBuilder::new(
+        Kind::Custom(ANNOUNCEMENT_KIND),
+        "",
+        &[Tag::Reference(relay_url.to_owned())],
+    )
+    .to_event(keys)?;
+    let mut event: Event = event.into();
+    event.build_index();
+    Ok(event)
+}
+
+/// Publish the announcement every `interval`, until shutdown is
+/// requested.
+pub async fn announce(
+    keys: Keys,
+    relay_url: String,
+    interval: Duration,
+    event_tx: mpsc::Sender<SubmittedEvent>,
+    mut shutdown: Receiver<()>,
+) {
+    // results of each write are reported here
+    let (notice_tx, mut notice_rx) = mpsc::channel::<Notice>(8);
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            Some(notice) = notice_rx.recv() => {
+                if let Notice::EventResult(r) = notice {
+                    debug!("relay announcement {}: {}", r.id, r.msg);
+                }
+            },
+            _ = ticker.tick() => {
+                let event = match announcement_event(&keys, &relay_url) {
+                    Ok(e) => e,
+                    Err(e) => {
+                        warn!("could not sign relay announcement: {}", e);
+                        continue;
+                    }
+                };
+                let submit_event = SubmittedEvent {
+                    event,
+                    notice_tx: notice_tx.clone(),
+                    source_ip: "127.0.0.1".to_owned(),
+                    origin: None,
+                    user_agent: None,
+                    auth_pubkey: None,
+                    reviewed: false,
+                    imported: false,
+                };
+                if event_tx.send(submit_event).await.is_err() {
+                    return;
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SECRET: &str = "6b911fd37cdf5c81d4c0adb1ab7fa822ed253ab0ad9aa18d77257c88b29b718e";
+
+    #[test]
+    fn announcement_is_valid() {
+        let keys = Keys::from_sk_str(SECRET).unwrap();
+        let event = announcement_event(&keys, "wss://relay.example.com").unwrap();
+        assert!(event.validate().is_ok());
+        assert_eq!(event.kind, ANNOUNCEMENT_KIND);
+        assert_eq!(event.pubkey, keys.public_key().to_string());
+        assert_eq!(
+            event.tags,
+            vec![vec!["r".to_owned(), "wss://relay.example.com".to_owned()]]
+        );
+        assert!(event.is_replaceable());
+    }
+}
@@ -0,0 +1,195 @@
+//! Audit log of event acceptance decisions
+//!
+//! When `logging.audit_folder_path` is set, the result of every event
+//! a client submits is appended to a file of its own, one JSON record
+//! per line: the event id, author and kind, the client's address,
+//! whether the event was accepted, and the status and reason sent in
+//! the OK message.  The file rolls over daily, like the main log.
+use crate::config::Logging;
+use crate::notice::EventResult;
+use crate::utils::unix_time;
+use serde_json::json;
+use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+/// Most submitted events remembered per connection while awaiting a
+/// result.  Results for events beyond this are still recorded, just
+/// without the author and kind.
+const MAX_PENDING: usize = 1000;
+
+/// Append-only destination for audit records, shared by all
+/// connections.
+#[derive(Clone)]
+pub struct AuditLog {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl std::fmt::Debug for AuditLog {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AuditLog").finish_non_exhaustive()
+    }
+}
+
+impl AuditLog {
+    /// Write audit records to `writer`.
+    #[must_use]
+    pub fn new(writer: Box<dyn Write + Send>) -> AuditLog {
+        AuditLog {
+            writer: Arc::new(Mutex::new(writer)),
+        }
+    }
+
+    /// The configured audit log, writing to a daily rolling file, if
+    /// one is configured.
+    #[must_use]
+    pub fn from_settings(logging: &Logging) -> Option<AuditLog> {
+        let path = logging.audit_folder_path.as_ref()?;
+        let prefix = logging
+            .audit_file_prefix
+            .as_deref()
+            .unwrap_or("relay-audit");
+        let appender = tracing_appender::rolling::daily(path, prefix);
+        Some(AuditLog::new(Box::new(appender)))
+    }
+
+    /// Record the result sent for an event.
+    pub fn record(&self, result: &EventResult, submission: Option<&Submission>) {
+        let record = json!({
+            "time": unix_time(),
+            "id": result.id,
+            "pubkey": submission.map(|s| &s.pubkey),
+            "kind": submission.map(|s| s.kind),
+            "ip": submission.map(|s| &s.ip),
+            "decision": if result.status.to_bool() { "accepted" } else { "rejected" },
+            "status": result.status.prefix(),
+            "reason": result.msg,
+        });
+        let written = self
+            .writer
+            .lock()
+            .map(|mut w| writeln!(w, "{record}").and_then(|_| w.flush()));
+        if !matches!(written, Ok(Ok(()))) {
+            warn!("could not write audit record for event {:?}", result.id);
+        }
+    }
+}
+
+/// What the audit log records about a submitted event, before it is
+/// validated.
+#[derive(Debug, Clone)]
+pub struct Submission {
+    pub pubkey: String,
+    pub kind: u64,
+    pub ip: String,
+}
+
+/// Events submitted on one connection that are awaiting a result.
+#[derive(Debug)]
+pub struct ConnectionAudit {
+    log: AuditLog,
+    pending: Mutex<HashMap<String, Submission>>,
+}
+
+impl ConnectionAudit {
+    #[must_use]
+    pub fn new(log: AuditLog) -> ConnectionAudit {
+        ConnectionAudit {
+            log,
+            pending: Mutex::default(),
+        }
+    }
+
+    /// Remember an event submitted by the client.
+    pub fn submitted(&self, id: &str, submission: Submission) {
+        if let Ok(mut pending) = self.pending.lock() {
+            if pending.len() < MAX_PENDING || pending.contains_key(id) {
+                pending.insert(id.to_owned(), submission);
+            }
+        }
+    }
+
+    /// Record the result sent for a submitted event.
+    pub fn decided(&self, result: &EventResult) {
+        let submission = self
+            .pending
+            .lock()
+            .ok()
+            .and_then(|mut pending| pending.remove(&result.id));
+        self.log.record(result, submission.as_ref());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notice::Notice;
+    use serde_json::Value;
+
+    /// Collects everything written, for inspection.
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Buffer {
+        fn records(&self) -> Vec<Value> {
+            let bytes = self.0.lock().unwrap().clone();
+            String::from_utf8(bytes)
+                .unwrap()
+                .lines()
+                .map(|l| serde_json::from_str(l).unwrap())
+                .collect()
+        }
+    }
+
+    fn result(notice: Notice) -> EventResult {
+        match notice {
+            Notice::EventResult(r) => r,
+            _ => panic!("not an event result"),
+        }
+    }
+
+    #[test]
+    fn decisions_recorded() {
+        let buffer = Buffer::default();
+        let audit = ConnectionAudit::new(AuditLog::new(Box::new(buffer.clone())));
+        for (id, kind) in [("aa", 1), ("bb", 7)] {
+            audit.submitted(
+                id,
+                Submission {
+                    pubkey: "cafe".into(),
+                    kind,
+                    ip: "127.0.0.1".into(),
+                },
+            );
+        }
+        audit.decided(&result(Notice::saved("aa".into())));
+        audit.decided(&result(Notice::blocked("bb".into(), "kind not allowed")));
+        // results for events never seen are recorded all the same
+        audit.decided(&result(Notice::invalid("cc".into(), "bad id")));
+        let records = buffer.records();
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0]["id"], "aa");
+        assert_eq!(records[0]["pubkey"], "cafe");
+        assert_eq!(records[0]["kind"], 1);
+        assert_eq!(records[0]["ip"], "127.0.0.1");
+        assert_eq!(records[0]["decision"], "accepted");
+        assert_eq!(records[0]["status"], "saved");
+        assert_eq!(records[1]["kind"], 7);
+        assert_eq!(records[1]["decision"], "rejected");
+        assert_eq!(records[1]["status"], "blocked");
+        assert_eq!(records[1]["reason"], "blocked: kind not allowed");
+        assert_eq!(records[2]["pubkey"], Value::Null);
+        assert_eq!(records[2]["status"], "invalid");
+    }
+}
@@ -89,7 +89,7 @@ pub fn main() -> Result<()> {
                 Ok(Some(e)) => {
                     events_read += 1;
                     // ignore ephemeral events
-                    if !(e.kind >= 20000 && e.kind < 30000) {
+                    if !e.is_ephemeral() {
                         match write_event(&tx, e) {
                             Ok(c) => {
                                 new_events += c;
@@ -131,9 +131,11 @@ fn write_event(tx: &Transaction, e: Event) -> Result<usize> {
     let pubkey_blob: Option<Vec<u8>> = hex::decode(&e.pubkey).ok();
     let delegator_blob: Option<Vec<u8>> = e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok());
     let event_str = serde_json::to_string(&e).ok();
+    // assign the next sequence number
+    tx.execute("UPDATE event_sequence SET last=last+1;", [])?;
     // ignore if the event hash is a duplicate.
     let ins_count = tx.execute(
-	"INSERT OR IGNORE INTO event (event_hash, created_at, kind, author, delegated_by, content, first_seen, hidden) VALUES (?1, ?2, ?3, ?4, ?5, ?6, strftime('%s','now'), FALSE);",
+	"INSERT OR IGNORE INTO event (event_hash, created_at, kind, author, delegated_by, content, first_seen, hidden, seq) VALUES (?1, ?2, ?3, ?4, ?5, ?6, strftime('%s','now'), FALSE, (SELECT last FROM event_sequence));",
 	params![id_blob, e.created_at, e.kind, pubkey_blob, delegator_blob, event_str]
     )?;
     if ins_count == 0 {
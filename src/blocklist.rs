@@ -0,0 +1,64 @@
+//! In-memory pubkey blocklist
+//!
+//! Banned pubkeys are persisted by the repository, and loaded into
+//! this set at startup so that event admission never needs to hit
+//! the database.
+use std::collections::HashSet;
+use std::sync::{Arc, RwLock};
+
+/// Shared set of pubkeys that may not publish to this relay.
+#[derive(Debug, Clone, Default)]
+pub struct Blocklist {
+    pubkeys: Arc<RwLock<HashSet<String>>>,
+}
+
+impl Blocklist {
+    /// Add pubkeys to the blocklist.
+    pub fn extend(&self, pubkeys: impl IntoIterator<Item = String>) {
+        if let Ok(mut set) = self.pubkeys.write() {
+            set.extend(pubkeys);
+        }
+    }
+
+    /// Add a single pubkey, returning true if it was not already present.
+    pub fn insert(&self, pubkey: &str) -> bool {
+        self.pubkeys
+            .write()
+            .map(|mut set| set.insert(pubkey.to_owned()))
+            .unwrap_or(false)
+    }
+
+    /// Check if a pubkey is blocked.
+    #[must_use]
+    pub fn contains(&self, pubkey: &str) -> bool {
+        self.pubkeys
+            .read()
+            .map(|set| set.contains(pubkey))
+            .unwrap_or(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn insert_and_check() {
+        let blocklist = Blocklist::default();
+        assert!(!blocklist.contains("abcd"));
+        assert!(blocklist.insert("abcd"));
+        assert!(!blocklist.insert("abcd"));
+        assert!(blocklist.contains("abcd"));
+    }
+
+    #[test]
+    fn clones_share_state() {
+        let blocklist = Blocklist::default();
+        let other = blocklist.clone();
+        std::thread::spawn(move || other.extend(vec!["abcd".to_owned(), "ef01".to_owned()]))
+            .join()
+            .unwrap();
+        assert!(blocklist.contains("abcd"));
+        assert!(blocklist.contains("ef01"));
+    }
+}
@@ -2,8 +2,12 @@
 use crate::payment::Processor;
 use config::{Config, ConfigError, File};
 use serde::{Deserialize, Serialize};
+use std::fmt;
 use std::time::Duration;
 
+/// Shown in place of secrets when settings are logged
+const REDACTED: &str = "<redacted>";
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 #[allow(unused)]
 pub struct Info {
@@ -14,6 +18,7 @@ pub struct Info {
     pub contact: Option<String>,
     pub favicon: Option<String>,
     pub relay_icon: Option<String>,
+    pub welcome_message: Option<String>, // NOTICE sent to every client when it connects
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -26,6 +31,10 @@ pub struct Database {
     pub max_conn: u32,
     pub connection: String,
     pub connection_write: Option<String>,
+    pub slow_query_threshold_ms: Option<u64>, // Log stored-event queries slower than this at WARN
+    pub min_free_disk_mb: Option<u64>, // refuse events while the database volume has less free space than this
+    pub disk_check_interval_seconds: u64, // how often to check free space on the database volume
+    pub query_chunk_seconds: Option<u64>, // read unlimited SQLite queries in created_at windows of this many seconds
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -40,13 +49,81 @@ pub struct Network {
     pub port: u16,
     pub address: String,
     pub remote_ip_header: Option<String>, // retrieve client IP from this HTTP header if present
+    pub forwarded_proto_header: Option<String>, // learn whether the client connected over TLS from this HTTP header
     pub ping_interval_seconds: u32,
+    pub metrics_address: Option<String>, // if set, also serve metrics on this address (host:port)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Options {
     pub reject_future_seconds: Option<usize>, // if defined, reject any events with a timestamp more than X seconds in the future
+    pub reject_past_seconds: Option<usize>, // if defined, reject any events with a timestamp more than X seconds in the past
+    #[serde(default)]
+    pub kind_created_at_bounds: Vec<KindCreatedAtBounds>, // per-kind replacements for reject_past_seconds/reject_future_seconds
+    #[serde(default)]
+    pub reject_created_at: Vec<u64>, // reject events whose timestamp is exactly one of these placeholder values
+    pub require_increasing_created_at: bool, // if true, reject events not newer than their author's latest stored event
+    pub require_d_tag_for_parameterized: bool, // if true, reject parameterized replaceable events without a "d" tag
+    pub reject_duplicate_d_tags: bool, // if true, reject parameterized replaceable events with more than one "d" tag
+    pub reject_empty_tags: bool,       // if true, reject events with a tag that has no elements
+    pub require_constrained_delegation: bool, // if true, reject delegated events whose conditions allow any event
+    pub validate_etag_markers: bool, // if true, reject events whose "e" tag markers are not root, reply or mention
+    pub validate_relay_hints: bool, // if true, reject events whose "e"/"p" tag relay hints are not relay URLs
+    #[serde(default)]
+    pub reject_json_content_kinds: Vec<u64>, // reject events of these kinds whose content is a JSON object or array
+    #[serde(default)]
+    pub kind_required_tags: Vec<KindRequiredTags>, // reject events of these kinds that are missing any of the listed tags
+    pub require_valid_pubkeys: bool, // if true, reject events whose pubkey is not a valid BIP-340 x-only public key
+    pub batch_events: bool,          // if true, accept several events in one EVENT message
+    pub serve_tombstones: bool, // if true, tell clients requesting deleted events by id that they were deleted
+    pub deleted_references: DeletedReferences, // whether events referencing a deleted event are accepted, rejected, or held for review
+    #[serde(default)]
+    pub unindexed_kinds: Vec<u64>, // store events of these kinds without indexing their tags
+    pub tag_and_filters: bool, // if true, allow "&t" filters, matching events with every listed tag value
+    pub resume_subscriptions: bool, // if true, authenticated clients resume subscriptions from the last delivered sequence number
+    pub sequence_cursors: bool, // if true, allow "after_seq" filters, paging stored events in insertion order
+    pub resolve_address_filters: bool, // if true, "#a" filters also return the parameterized replaceable events they address
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct KindCreatedAtBounds {
+    pub kind: u64,                     // Event kind these bounds apply to
+    pub past_seconds: Option<usize>, // Reject events more than X seconds in the past; unbounded if unset
+    pub future_seconds: Option<usize>, // Reject events more than X seconds in the future; unbounded if unset
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct KindRequiredTags {
+    pub kind: u64,         // Event kind these requirements apply to
+    pub tags: Vec<String>, // Names of tags that events of this kind must have, with a value
+}
+
+impl Options {
+    /// Allowed (past, future) distance of `created_at` from the
+    /// current time, in seconds, for events of this kind.  Kinds with
+    /// their own bounds ignore the global limits entirely.
+    #[must_use]
+    pub fn created_at_bounds(&self, kind: u64) -> (Option<usize>, Option<usize>) {
+        self.kind_created_at_bounds
+            .iter()
+            .find(|b| b.kind == kind)
+            .map_or(
+                (self.reject_past_seconds, self.reject_future_seconds),
+                |b| (b.past_seconds, b.future_seconds),
+            )
+    }
+
+    /// Names of the tags that events of this kind must have.
+    #[must_use]
+    pub fn required_tags(&self, kind: u64) -> &[String] {
+        self.kind_required_tags
+            .iter()
+            .find(|r| r.kind == kind)
+            .map_or(&[], |r| r.tags.as_slice())
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -63,7 +140,15 @@ pub struct Retention {
 #[allow(unused)]
 pub struct Limits {
     pub messages_per_sec: Option<u32>, // Artificially slow down event writing to limit disk consumption (averaged over 1 minute)
+    pub event_rate_limit_per_min: Option<u32>, // Maximum EVENT submissions per minute per connection; faster events are refused as rate-limited
+    pub event_burst: Option<u32>, // Number of events a connection may submit at once before event_rate_limit_per_min applies
     pub subscriptions_per_min: Option<u32>, // Artificially slow down request (db query) creation to prevent abuse (averaged over 1 minute)
+    pub req_rate_per_second: Option<u32>, // Maximum REQ commands per second per connection; faster REQs are closed as rate-limited
+    pub req_burst: Option<u32>, // Number of REQ commands a connection may send at once before req_rate_per_second applies
+    pub close_rate_per_second: Option<u32>, // Maximum CLOSE commands per second per connection; faster CLOSEs are ignored with a NOTICE
+    pub close_burst: Option<u32>, // Number of CLOSE commands a connection may send at once before close_rate_per_second applies
+    pub subscription_creation_rate: Option<u32>, // Maximum new subscriptions per second across all connections; excess are closed as rate-limited
+    pub subscription_creation_burst: Option<u32>, // Number of subscriptions that may be created at once before subscription_creation_rate applies
     pub db_conns_per_client: Option<u32>, // How many concurrent database queries (not subscriptions) may a client have?
     pub max_blocking_threads: usize,
     pub max_event_bytes: Option<usize>, // Maximum size of an EVENT message
@@ -73,14 +158,234 @@ pub struct Limits {
     pub event_persist_buffer: usize, // events to buffer for database commits (block senders if database writes are too slow)
     pub event_kind_blacklist: Option<Vec<u64>>,
     pub event_kind_allowlist: Option<Vec<u64>>,
+    pub max_kind: Option<u64>, // Reject events with a kind number greater than this
+    pub max_tag_value_length: Option<usize>, // Maximum length of any tag element after the tag name
+    pub max_tag_count: Option<usize>, // Maximum number of tags in an event
+    pub max_contact_list_entries: Option<usize>, // Maximum number of "p" tags in a contact list (kind 3)
+    pub max_distinct_p_tags: Option<usize>, // Maximum number of distinct pubkeys mentioned in "p" tags (except contact lists)
+    pub max_authors_per_connection: Option<usize>, // Maximum number of distinct event authors a single connection may publish for
+    pub max_indexed_tags: Option<usize>, // Maximum number of indexed (single-letter) tags in an event
+    pub max_limit: Option<u64>, // Maximum number of stored events returned for a single filter
+    pub limit_boundary: LimitBoundary, // How events sharing the timestamp of the last event within a filter's limit are returned
+    pub hard_max_results_per_subscription: Option<usize>, // Maximum number of stored events sent for a subscription, across all its filters
+    pub notify_truncated_results: bool, // Send a NOTICE when results were capped by max_limit or hard_max_results_per_subscription
+    pub notify_dropped_events: bool, // Send a NOTICE when realtime events were dropped because a client could not keep up
+    pub max_connections: Option<usize>, // Maximum number of concurrent websocket connections
+    pub max_connections_per_ip: Option<usize>, // Maximum number of concurrent websocket connections from one client address
+    pub max_subscription_id_length: usize,     // Maximum length of a subscription identifier
+    pub max_subscriptions_per_connection: usize, // Maximum concurrent subscriptions on one connection
+    pub min_pow_difficulty: Option<u8>, // Minimum NIP-13 PoW difficulty (leading zero bits of the id) for accepted events
+    pub pow_rate_limit_bypass: Option<u8>, // Recent events with at least this committed PoW difficulty skip the event rate limit
+    pub pow_rate_limit_bypass_max_age: u64, // How recent (seconds) an event must be to bypass the rate limit with PoW
+    pub max_projected_results: Option<u64>, // Refuse REQs whose filters are projected to return more stored events than this
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct Authorization {
     pub pubkey_whitelist: Option<Vec<String>>, // If present, only allow these pubkeys to publish events
+    pub pubkey_blacklist: Option<Vec<String>>, // If present, never allow these pubkeys to publish events
     pub nip42_auth: bool,                      // if true enables NIP-42 authentication
     pub nip42_dms: bool, // if true send DMs only to their authenticated recipients
+    pub nip42_require_secure: bool, // if true refuse NIP-42 AUTH on connections not made over TLS (wss://)
+    pub revoked_pubkeys: Option<Vec<String>>, // Compromised keys; events signed by these are always rejected
+    pub purge_revoked: bool, // if true delete stored events from revoked keys at startup
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Admin {
+    pub api_token: Option<String>, // Bearer token required for admin HTTP endpoints; if unset they are disabled
+}
+
+// secrets are redacted, since the configuration is logged at startup
+impl fmt::Debug for Admin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Admin")
+            .field("api_token", &self.api_token.as_ref().map(|_| REDACTED))
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Quarantine {
+    #[serde(default)]
+    pub content_patterns: Vec<String>, // Regular expressions; events whose content matches are held for admin review
+    pub new_authors: bool, // Hold events from authors with no stored events for admin review
+    pub max_held: usize, // Most events held at once; further events are refused until some are released
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Federation {
+    #[serde(default)]
+    pub forward_to_relays: Vec<String>, // Websocket URLs of relays that accepted events are published to
+    #[serde(default)]
+    pub import_from_relays: Vec<ImportRelay>, // Relays (and filters) that events are imported from
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct ImportRelay {
+    pub url: String,    // Websocket URL of the upstream relay
+    pub filter: String, // Subscription filter, as JSON
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Announcement {
+    pub secret_key: Option<String>, // Relay's own private key; if set, the relay periodically publishes a signed kind-10002 event advertising itself
+    pub interval_seconds: u64,      // How often the announcement is published
+}
+
+impl fmt::Debug for Announcement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Announcement")
+            .field("secret_key", &self.secret_key.as_ref().map(|_| REDACTED))
+            .field("interval_seconds", &self.interval_seconds)
+            .finish()
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Maintenance {
+    #[serde(default)]
+    pub windows: Vec<MaintenanceWindow>, // Scheduled periods during which the relay refuses traffic
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct MaintenanceWindow {
+    pub start: String, // Beginning of the window (RFC 3339)
+    pub end: String,   // End of the window (RFC 3339), exclusive
+    #[serde(default)]
+    pub reject_reads: bool, // if true refuse subscriptions as well as events
+}
+
+impl Maintenance {
+    /// The window in effect at `now` (unix seconds), if any.
+    #[must_use]
+    pub fn active_window(&self, now: u64) -> Option<&MaintenanceWindow> {
+        self.windows.iter().find(|w| w.contains(now))
+    }
+}
+
+impl MaintenanceWindow {
+    fn parse_time(t: &str) -> Option<u64> {
+        chrono::DateTime::parse_from_rfc3339(t)
+            .ok()
+            .and_then(|d| u64::try_from(d.timestamp()).ok())
+    }
+
+    #[must_use]
+    pub fn start_time(&self) -> Option<u64> {
+        Self::parse_time(&self.start)
+    }
+
+    #[must_use]
+    pub fn end_time(&self) -> Option<u64> {
+        Self::parse_time(&self.end)
+    }
+
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        self.start_time().is_some() && self.end_time().is_some()
+    }
+
+    /// Is `now` (unix seconds) within this window?
+    #[must_use]
+    pub fn contains(&self, now: u64) -> bool {
+        match (self.start_time(), self.end_time()) {
+            (Some(start), Some(end)) => start <= now && now < end,
+            _ => false,
+        }
+    }
+
+    /// Seconds from `now` until the window ends.
+    #[must_use]
+    pub fn retry_after(&self, now: u64) -> u64 {
+        self.end_time().map_or(0, |end| end.saturating_sub(now))
+    }
+
+    /// Message sent to clients whose requests are refused.
+    #[must_use]
+    pub fn message(&self, now: u64) -> String {
+        format!(
+            "relay is down for scheduled maintenance; retry after {} seconds",
+            self.retry_after(now)
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Localization {
+    #[serde(default)]
+    pub translations: Vec<Translation>, // Translations of messages sent to clients
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct Translation {
+    pub lang: String,    // Language tag the translation is for (e.g. "es", "pt-BR")
+    pub message: String, // The English message, as sent (without any "prefix:")
+    pub text: String,    // The translated message
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[allow(unused)]
+pub struct PostingHours {
+    pub start: Option<String>, // Time of day (HH:MM, UTC) from which events are accepted
+    pub end: Option<String>,   // Time of day (HH:MM, UTC) after which events are refused
+}
+
+impl PostingHours {
+    fn parse_time(t: &str) -> Option<chrono::NaiveTime> {
+        chrono::NaiveTime::parse_from_str(t, "%H:%M").ok()
+    }
+
+    /// Both or neither times are set, and they parse.
+    #[must_use]
+    pub fn is_valid(&self) -> bool {
+        match (&self.start, &self.end) {
+            (Some(start), Some(end)) => {
+                Self::parse_time(start).is_some() && Self::parse_time(end).is_some()
+            }
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    /// Are events accepted at this time of day?  A window whose end is
+    /// before its start spans midnight.
+    #[must_use]
+    pub fn is_open_at(&self, now: chrono::NaiveTime) -> bool {
+        let start = self.start.as_deref().and_then(Self::parse_time);
+        let end = self.end.as_deref().and_then(Self::parse_time);
+        match (start, end) {
+            (Some(start), Some(end)) if start <= end => start <= now && now < end,
+            (Some(start), Some(end)) => start <= now || now < end,
+            _ => true,
+        }
+    }
+
+    /// Are events accepted right now?
+    #[must_use]
+    pub fn is_open(&self) -> bool {
+        self.is_open_at(chrono::Utc::now().time())
+    }
+
+    /// Message sent to clients whose events are refused.
+    #[must_use]
+    pub fn message(&self) -> String {
+        format!(
+            "relay only accepts events between {} and {} UTC",
+            self.start.as_deref().unwrap_or_default(),
+            self.end.as_deref().unwrap_or_default()
+        )
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -112,6 +417,30 @@ pub enum VerifiedUsersMode {
     Disabled,
 }
 
+/// Which events a limited filter returns when its limit falls within
+/// a group of events with the same `created_at`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum LimitBoundary {
+    /// Stop at the limit; the group is cut by event id, lowest first.
+    Cut,
+    /// Return every event of the group, even past the limit.
+    Ties,
+}
+
+/// What happens to events that reference (by "e" tag) an event
+/// removed by a NIP-09 deletion.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletedReferences {
+    /// Store them like any other event.
+    Accept,
+    /// Refuse them.
+    Reject,
+    /// Hold them in the quarantine for an administrator to review.
+    Flag,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[allow(unused)]
 pub struct VerifiedUsers {
@@ -171,6 +500,8 @@ impl VerifiedUsers {
 pub struct Logging {
     pub folder_path: Option<String>,
     pub file_prefix: Option<String>,
+    pub audit_folder_path: Option<String>, // Directory for the audit log of event acceptance decisions; disabled if unset
+    pub audit_file_prefix: Option<String>, // File name prefix of the audit log
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -183,11 +514,23 @@ pub struct Settings {
     pub network: Network,
     pub limits: Limits,
     pub authorization: Authorization,
+    pub admin: Admin,
+    pub quarantine: Quarantine,
+    #[serde(default)]
+    pub federation: Federation,
+    #[serde(default)]
+    pub maintenance: Maintenance,
+    pub posting_hours: PostingHours,
+    #[serde(default)]
+    pub localization: Localization,
+    pub announcement: Announcement,
     pub pay_to_relay: PayToRelay,
     pub verified_users: VerifiedUsers,
     pub retention: Retention,
     pub options: Options,
     pub logging: Logging,
+    #[serde(skip)]
+    pub config_file: Option<String>, // the file these settings were read from, for reloads
 }
 
 impl Settings {
@@ -228,6 +571,7 @@ impl Settings {
             .add_source(File::with_name(config))
             .build()?;
         let mut settings: Settings = config.try_deserialize()?;
+        settings.config_file = Some(config_file_name.clone().unwrap_or(default_config_file_name));
         // ensure connection pool size is logical
         assert!(
             settings.database.min_conn <= settings.database.max_conn,
@@ -242,6 +586,19 @@ impl Settings {
         );
         // initialize durations for verified users
         settings.verified_users.init();
+        // ensure maintenance windows parse
+        for w in &settings.maintenance.windows {
+            assert!(
+                w.is_valid(),
+                "Maintenance window ({} - {}) could not be parsed",
+                w.start,
+                w.end
+            );
+        }
+        assert!(
+            settings.posting_hours.is_valid(),
+            "Posting hours must both be set, as HH:MM"
+        );
 
         // Validate pay to relay settings
         if settings.pay_to_relay.enabled {
@@ -274,6 +631,7 @@ impl Default for Settings {
                 contact: None,
                 favicon: None,
                 relay_icon: None,
+                welcome_message: None,
             },
             diagnostics: Diagnostics { tracing: false },
             database: Database {
@@ -284,6 +642,10 @@ impl Default for Settings {
                 max_conn: 8,
                 connection: "".to_owned(),
                 connection_write: None,
+                slow_query_threshold_ms: None,
+                min_free_disk_mb: None,
+                disk_check_interval_seconds: 30,
+                query_chunk_seconds: None,
             },
             grpc: Grpc {
                 event_admission_server: None,
@@ -293,10 +655,20 @@ impl Default for Settings {
                 ping_interval_seconds: 300,
                 address: "0.0.0.0".to_owned(),
                 remote_ip_header: None,
+                forwarded_proto_header: None,
+                metrics_address: None,
             },
             limits: Limits {
                 messages_per_sec: None,
                 subscriptions_per_min: None,
+                event_rate_limit_per_min: None,
+                event_burst: None,
+                req_rate_per_second: None,
+                req_burst: None,
+                close_rate_per_second: None,
+                close_burst: None,
+                subscription_creation_rate: None,
+                subscription_creation_burst: None,
                 db_conns_per_client: None,
                 max_blocking_threads: 16,
                 max_event_bytes: Some(2 << 17),      // 128K
@@ -306,11 +678,57 @@ impl Default for Settings {
                 event_persist_buffer: 4096,
                 event_kind_blacklist: None,
                 event_kind_allowlist: None,
+                max_kind: None,
+                max_tag_value_length: None,
+                max_tag_count: None,
+                max_contact_list_entries: None,
+                max_indexed_tags: None,
+                max_authors_per_connection: None,
+                max_distinct_p_tags: None,
+                max_limit: None,
+                limit_boundary: LimitBoundary::Cut,
+                hard_max_results_per_subscription: None,
+                notify_truncated_results: false,
+                notify_dropped_events: false,
+                max_connections: None,
+                max_connections_per_ip: None,
+                max_subscription_id_length: 256,
+                max_subscriptions_per_connection: 32,
+                min_pow_difficulty: None,
+                pow_rate_limit_bypass: None,
+                pow_rate_limit_bypass_max_age: 300,
+                max_projected_results: None,
             },
             authorization: Authorization {
                 pubkey_whitelist: None, // Allow any address to publish
-                nip42_auth: false,      // Disable NIP-42 authentication
-                nip42_dms: false,       // Send DMs to everybody
+                pubkey_blacklist: None,
+                nip42_auth: false, // Disable NIP-42 authentication
+                nip42_dms: false,
+                nip42_require_secure: false, // Send DMs to everybody
+                revoked_pubkeys: None,
+                purge_revoked: false,
+            },
+            admin: Admin { api_token: None },
+            quarantine: Quarantine {
+                content_patterns: vec![],
+                new_authors: false,
+                max_held: 1000,
+            },
+            federation: Federation {
+                forward_to_relays: vec![],
+                import_from_relays: vec![],
+            },
+            maintenance: Maintenance { windows: vec![] },
+            posting_hours: PostingHours {
+                start: None,
+                end: None,
+            },
+            localization: Localization {
+                translations: vec![],
+            },
+            announcement: Announcement {
+                secret_key: None,
+                interval_seconds: 3600,
             },
             pay_to_relay: PayToRelay {
                 enabled: false,
@@ -342,11 +760,35 @@ impl Default for Settings {
             },
             options: Options {
                 reject_future_seconds: None, // Reject events in the future if defined
+                reject_past_seconds: None,   // Reject events in the past if defined
+                kind_created_at_bounds: vec![],
+                reject_created_at: vec![],
+                require_increasing_created_at: false,
+                require_d_tag_for_parameterized: false,
+                reject_duplicate_d_tags: false,
+                reject_empty_tags: false,
+                require_constrained_delegation: false,
+                validate_etag_markers: false,
+                validate_relay_hints: false,
+                reject_json_content_kinds: vec![],
+                kind_required_tags: vec![],
+                require_valid_pubkeys: false,
+                batch_events: true,
+                serve_tombstones: false,
+                deleted_references: DeletedReferences::Accept,
+                unindexed_kinds: vec![],
+                tag_and_filters: false,
+                sequence_cursors: false,
+                resolve_address_filters: false,
+                resume_subscriptions: false,
             },
             logging: Logging {
                 folder_path: None,
                 file_prefix: None,
+                audit_folder_path: None,
+                audit_file_prefix: None,
             },
+            config_file: None,
         }
     }
 }
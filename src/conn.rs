@@ -1,5 +1,6 @@
 //! Client connection state
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
 
 use tracing::{debug, trace};
 use uuid::Uuid;
@@ -12,7 +13,7 @@ use crate::event::Event;
 use crate::subscription::Subscription;
 use crate::utils::{host_str, unix_time};
 
-/// A subscription identifier has a maximum length
+/// A subscription identifier has a maximum length (unless configured)
 const MAX_SUBSCRIPTION_ID_LEN: usize = 256;
 
 /// NIP-42 authentication state
@@ -35,8 +36,14 @@ pub struct ClientConn {
     subscriptions: HashMap<String, Subscription>,
     /// Per-connection maximum concurrent subscriptions
     max_subs: usize,
+    /// Maximum length of a subscription identifier
+    max_sub_id_len: usize,
     /// NIP-42 AUTH
     auth: Nip42AuthState,
+    /// Distinct authors of events published on this connection
+    authors: HashSet<String>,
+    /// Maximum number of distinct authors (unlimited if `None`)
+    max_authors: Option<usize>,
 }
 
 impl Default for ClientConn {
@@ -55,10 +62,49 @@ impl ClientConn {
             client_id,
             subscriptions: HashMap::new(),
             max_subs: 32,
+            max_sub_id_len: MAX_SUBSCRIPTION_ID_LEN,
             auth: NoAuth,
+            authors: HashSet::new(),
+            max_authors: None,
         }
     }
 
+    /// Set the maximum length of subscription identifiers.
+    pub fn set_max_subscription_id_len(&mut self, len: usize) {
+        self.max_sub_id_len = len;
+    }
+
+    /// Set the maximum number of concurrent subscriptions.
+    pub fn set_max_subscriptions(&mut self, max: usize) {
+        self.max_subs = max;
+    }
+
+    /// Set the maximum number of distinct event authors.
+    pub fn set_max_authors(&mut self, max: Option<usize>) {
+        self.max_authors = max;
+    }
+
+    /// Record an author publishing on this connection.  Returns false
+    /// (without recording) if the author is new and the connection
+    /// has already seen the maximum number of distinct authors.
+    pub fn admit_author(&mut self, pubkey: &str) -> bool {
+        if self.authors.contains(pubkey) {
+            return true;
+        }
+        if let Some(max) = self.max_authors {
+            if self.authors.len() >= max {
+                debug!(
+                    "connection reached distinct author limit (cid: {}, max: {})",
+                    self.get_client_prefix(),
+                    max
+                );
+                return false;
+            }
+        }
+        self.authors.insert(pubkey.to_owned());
+        true
+    }
+
     #[must_use]
     pub fn subscriptions(&self) -> &HashMap<String, Subscription> {
         &self.subscriptions
@@ -102,19 +148,25 @@ impl ClientConn {
     /// # Errors
     ///
     /// Will return `Err` if the client has too many subscriptions, or
-    /// if the provided name is excessively long.
+    /// if the provided name is excessively long or contains control
+    /// characters.
     pub fn subscribe(&mut self, s: Subscription) -> Result<()> {
         let k = s.get_id();
         let sub_id_len = k.len();
         // prevent arbitrarily long subscription identifiers from
         // being used.
-        if sub_id_len > MAX_SUBSCRIPTION_ID_LEN {
+        if sub_id_len > self.max_sub_id_len {
             debug!(
                 "ignoring sub request with excessive length: ({})",
                 sub_id_len
             );
             return Err(Error::SubIdMaxLengthError);
         }
+        // identifiers are echoed back to clients, and logged.
+        if k.chars().any(char::is_control) {
+            debug!("ignoring sub request with control characters in id");
+            return Err(Error::SubIdInvalidError);
+        }
         // check if an existing subscription exists, and replace if so
         if self.subscriptions.contains_key(&k) {
             self.subscriptions.remove(&k);
@@ -227,3 +279,67 @@ impl ClientConn {
         }
     }
 }
+
+/// Open connections from each client address, shared by all
+/// connections, for limiting how many one address may have.
+#[derive(Debug, Clone)]
+pub struct IpConnections {
+    max_per_ip: usize,
+    open: Arc<Mutex<HashMap<String, usize>>>,
+}
+
+impl IpConnections {
+    #[must_use]
+    pub fn new(max_per_ip: usize) -> Self {
+        IpConnections {
+            max_per_ip,
+            open: Arc::default(),
+        }
+    }
+
+    /// Count a new connection from `ip`, unless it already has the
+    /// maximum open.  The connection is counted until the returned
+    /// slot is dropped, however the connection ends.
+    #[must_use]
+    pub fn try_acquire(&self, ip: &str) -> Option<IpSlot> {
+        let mut open = self.open.lock().ok()?;
+        let count = open.get(ip).copied().unwrap_or_default();
+        if count >= self.max_per_ip {
+            return None;
+        }
+        open.insert(ip.to_owned(), count + 1);
+        Some(IpSlot {
+            connections: self.clone(),
+            ip: ip.to_owned(),
+        })
+    }
+
+    /// Number of connections open from `ip`.
+    #[must_use]
+    pub fn open_connections(&self, ip: &str) -> usize {
+        self.open
+            .lock()
+            .map(|open| open.get(ip).copied().unwrap_or_default())
+            .unwrap_or_default()
+    }
+}
+
+/// A connection counted against its client address
+#[derive(Debug)]
+pub struct IpSlot {
+    connections: IpConnections,
+    ip: String,
+}
+
+impl Drop for IpSlot {
+    fn drop(&mut self) {
+        if let Ok(mut open) = self.connections.open.lock() {
+            if let Some(count) = open.get_mut(&self.ip) {
+                *count = count.saturating_sub(1);
+                if *count == 0 {
+                    open.remove(&self.ip);
+                }
+            }
+        }
+    }
+}
@@ -1,15 +1,20 @@
 //! Event persistence and querying
-use crate::config::Settings;
+use crate::blocklist::Blocklist;
+use crate::config::{DeletedReferences, Limits, Settings};
+use crate::diskspace::{self, DiskGuard};
 use crate::error::{Error, Result};
 use crate::event::Event;
 use crate::nauthz;
 use crate::notice::Notice;
 use crate::payment::PaymentMessage;
+use crate::quarantine::Quarantine;
 use crate::repo::postgres::{PostgresPool, PostgresRepo};
 use crate::repo::sqlite::SqliteRepo;
 use crate::repo::NostrRepo;
 use crate::server::NostrMetrics;
-use governor::clock::Clock;
+use crate::utils::unix_time;
+use governor::clock::{Clock, DefaultClock};
+use governor::state::{InMemoryState, NotKeyed};
 use governor::{Quota, RateLimiter};
 use nostr::key::FromPkStr;
 use nostr::key::Keys;
@@ -20,6 +25,7 @@ use sqlx::ConnectOptions;
 use std::sync::Arc;
 use std::thread;
 use std::time::{Duration, Instant};
+use tokio::sync::watch;
 use tracing::log::LevelFilter;
 use tracing::{debug, info, trace, warn};
 
@@ -34,6 +40,10 @@ pub struct SubmittedEvent {
     pub origin: Option<String>,
     pub user_agent: Option<String>,
     pub auth_pubkey: Option<Vec<u8>>,
+    /// Approved by an administrator, so never quarantined
+    pub reviewed: bool,
+    /// Fetched from an upstream relay, so never forwarded
+    pub imported: bool,
 }
 
 /// Database file
@@ -88,7 +98,15 @@ async fn build_postgres_pool(settings: &Settings, metrics: NostrMetrics) -> Post
         None => pool.clone(),
     };
 
-    let repo = PostgresRepo::new(pool, write_pool, metrics);
+    let repo = PostgresRepo::new(
+        pool,
+        write_pool,
+        metrics,
+        settings.limits.max_limit,
+        settings.limits.limit_boundary,
+        settings.database.slow_query_threshold_ms,
+        settings.options.unindexed_kinds.clone(),
+    );
 
     // Panic on migration failure
     let version = repo.migrate_up().await.unwrap();
@@ -99,13 +117,19 @@ async fn build_postgres_pool(settings: &Settings, metrics: NostrMetrics) -> Post
 }
 
 /// Spawn a database writer that persists events to the `SQLite` store.
+#[allow(clippy::too_many_arguments)]
 pub async fn db_writer(
     repo: Arc<dyn NostrRepo>,
-    settings: Settings,
+    mut settings: Settings,
+    mut reloads: watch::Receiver<Arc<Settings>>,
     mut event_rx: tokio::sync::mpsc::Receiver<SubmittedEvent>,
     bcast_tx: tokio::sync::broadcast::Sender<Event>,
+    forward_tx: tokio::sync::broadcast::Sender<Event>,
     metadata_tx: tokio::sync::broadcast::Sender<Event>,
     payment_tx: tokio::sync::broadcast::Sender<PaymentMessage>,
+    blocklist: Blocklist,
+    quarantine: Quarantine,
+    disk_guard: DiskGuard,
     mut shutdown: tokio::sync::broadcast::Receiver<()>,
 ) -> Result<()> {
     // are we performing NIP-05 checking?
@@ -120,20 +144,19 @@ pub async fn db_writer(
     //upgrade_db(&mut pool.get()?)?;
 
     // Make a copy of the whitelist
-    let whitelist = &settings.authorization.pubkey_whitelist.clone();
+    let mut whitelist = settings.authorization.pubkey_whitelist.clone();
+
+    // Keys known to be compromised
+    let mut revoked = key_list(&settings.authorization.revoked_pubkeys);
+
+    // Keys the operator refuses events from
+    let mut denied = key_list(&settings.authorization.pubkey_blacklist);
 
     // get rate limit settings
-    let rps_setting = settings.limits.messages_per_sec;
+    let mut rps_setting = settings.limits.messages_per_sec;
     let mut most_recent_rate_limit = Instant::now();
-    let mut lim_opt = None;
+    let mut lim_opt = write_limiter(rps_setting);
     let clock = governor::clock::QuantaClock::default();
-    if let Some(rps) = rps_setting {
-        if rps > 0 {
-            info!("Enabling rate limits for event creation ({}/sec)", rps);
-            let quota = core::num::NonZeroU32::new(rps * 60).unwrap();
-            lim_opt = Some(RateLimiter::direct(Quota::per_minute(quota)));
-        }
-    }
     // create a client if GRPC is enabled.
     // Check with externalized event admitter service, if one is defined.
     let mut grpc_client = if let Some(svr) = settings.grpc.event_admission_server {
@@ -157,12 +180,36 @@ pub async fn db_writer(
         if next_event.is_none() {
             break;
         }
+        // apply any settings reloaded since the last event
+        if reloads.has_changed().unwrap_or(false) {
+            settings = reloads.borrow_and_update().as_ref().clone();
+            whitelist = settings.authorization.pubkey_whitelist.clone();
+            revoked = key_list(&settings.authorization.revoked_pubkeys);
+            denied = key_list(&settings.authorization.pubkey_blacklist);
+            if settings.limits.messages_per_sec != rps_setting {
+                rps_setting = settings.limits.messages_per_sec;
+                lim_opt = write_limiter(rps_setting);
+            }
+        }
         // track if an event write occurred; this is used to
         // update the rate limiter
         let mut event_write = false;
         let subm_event = next_event.unwrap();
         let event = subm_event.event;
         let notice_tx = subm_event.notice_tx;
+        let rate_limited = is_rate_limited(&event, &settings.limits);
+
+        // Check that the database volume has room for the event
+        if disk_guard.is_read_only() {
+            debug!(
+                "rejecting event: {}, database disk is low on space",
+                event.get_event_id_prefix()
+            );
+            notice_tx
+                .try_send(Notice::error(event.id, diskspace::READ_ONLY_MESSAGE))
+                .ok();
+            continue;
+        }
 
         // Check that event kind isn't blacklisted
         let kinds_blacklist = &settings.limits.event_kind_blacklist.clone();
@@ -196,6 +243,66 @@ pub async fn db_writer(
             }
         }
 
+        // Check that the author (or delegator) key has not been revoked
+        if revoked.contains(&event.pubkey)
+            || event
+                .delegated_by
+                .as_ref()
+                .map_or(false, |d| revoked.contains(d))
+        {
+            debug!(
+                "rejecting event: {}, revoked key",
+                event.get_event_id_prefix()
+            );
+            notice_tx
+                .try_send(Notice::blocked(
+                    event.id,
+                    "pubkey has been revoked as compromised",
+                ))
+                .ok();
+            continue;
+        }
+
+        // Check that the author (or delegator) has not been banned
+        if blocklist.contains(&event.pubkey)
+            || event
+                .delegated_by
+                .as_ref()
+                .map_or(false, |d| blocklist.contains(d))
+        {
+            debug!(
+                "rejecting event: {}, banned author",
+                event.get_event_id_prefix()
+            );
+            notice_tx
+                .try_send(Notice::blocked(
+                    event.id,
+                    "pubkey is banned from this relay",
+                ))
+                .ok();
+            continue;
+        }
+
+        // Check that the author (or delegator) is not blacklisted
+        if denied.contains(&event.pubkey)
+            || event
+                .delegated_by
+                .as_ref()
+                .map_or(false, |d| denied.contains(d))
+        {
+            debug!(
+                "rejecting event: {}, blacklisted author",
+                event.get_event_id_prefix()
+            );
+            notice_tx
+                .try_send(Notice::blocked(
+                    event.id,
+                    "pubkey is not allowed to publish to this relay",
+                ))
+                .ok();
+            continue;
+        }
+
         // Set to none until balance is got from db
         // Will stay none if user in whitelisted and does not have to pay to post
         // When pay to relay is enabled the whitelist is not a list of who can post
@@ -203,10 +310,15 @@ pub async fn db_writer(
         let mut user_balance: Option<u64> = None;
         if !pay_to_relay_enabled {
             // check if this event is authorized.
-            if let Some(allowed_addrs) = whitelist {
-                // TODO: incorporate delegated pubkeys
-                // if the event address is not in allowed_addrs.
-                if !allowed_addrs.contains(&event.pubkey) {
+            if let Some(allowed_addrs) = &whitelist {
+                // if neither the event address nor its delegator is
+                // in allowed_addrs.
+                if !allowed_addrs.contains(&event.pubkey)
+                    && !event
+                        .delegated_by
+                        .as_ref()
+                        .map_or(false, |d| allowed_addrs.contains(d))
+                {
                     debug!(
                         "rejecting event: {}, unauthorized author",
                         event.get_event_id_prefix()
@@ -390,10 +502,115 @@ pub async fn db_writer(
             }
         }
 
+        // Refuse events older than their author's latest, if required
+        if settings.options.require_increasing_created_at
+            && !event.is_ephemeral()
+            && !subm_event.imported
+            && !subm_event.reviewed
+        {
+            match repo.latest_created_at_for(&[event.pubkey.clone()]).await {
+                Ok(latest) => {
+                    if let Some(&newest) = latest.get(&event.pubkey) {
+                        if event.created_at <= newest {
+                            info!(
+                                "rejecting event not newer than its author's latest: {:?} (created_at: {}, latest: {}) from: {:?}",
+                                event.get_event_id_prefix(),
+                                event.created_at,
+                                newest,
+                                event.get_author_prefix(),
+                            );
+                            let msg = format!(
+                                "created_at must be later than the author's latest event ({newest})"
+                            );
+                            notice_tx.try_send(Notice::invalid(event.id, &msg)).ok();
+                            continue;
+                        }
+                    }
+                }
+                Err(e) => warn!("could not check author's latest event: {:?}", e),
+            }
+        }
+
+        // Refuse, or hold for review, engagement with deleted events
+        let deleted_references = settings.options.deleted_references;
+        if deleted_references != DeletedReferences::Accept
+            && !event.is_ephemeral()
+            && event.kind != 5
+            && !subm_event.reviewed
+        {
+            let referenced = event.tag_values_by_name("e");
+            let deleted = if referenced.is_empty() {
+                false
+            } else {
+                match repo.tombstones_for(&referenced).await {
+                    Ok(tombstones) => !tombstones.is_empty(),
+                    Err(e) => {
+                        warn!("could not check for deleted references: {:?}", e);
+                        false
+                    }
+                }
+            };
+            if deleted {
+                info!(
+                    "event references a deleted event: {:?} (kind: {}) from: {:?} (IP: {:?})",
+                    event.get_event_id_prefix(),
+                    event.kind,
+                    event.get_author_prefix(),
+                    subm_event.source_ip,
+                );
+                let id = event.id.clone();
+                let notice = if deleted_references == DeletedReferences::Reject {
+                    Notice::blocked(id, "event references a deleted event")
+                } else if quarantine.hold(event) {
+                    Notice::quarantined(
+                        id,
+                        "event references a deleted event, and is held for review by the relay operator",
+                    )
+                } else {
+                    info!("quarantine is full, refusing event: {:?}", id);
+                    Notice::rate_limited(id, "too many events are awaiting review; try again later")
+                };
+                notice_tx.try_send(notice).ok();
+                continue;
+            }
+        }
+
+        // Hold borderline events for admin review
+        if !event.is_ephemeral() && quarantine.is_active() && !subm_event.reviewed {
+            let mut held = quarantine.matches_content(&event);
+            if !held && quarantine.holds_new_authors() {
+                match repo.latest_created_at_for(&[event.pubkey.clone()]).await {
+                    Ok(seen) => held = seen.is_empty(),
+                    Err(e) => warn!("could not check for new author: {:?}", e),
+                }
+            }
+            if held {
+                info!(
+                    "quarantined event: {:?} (kind: {}) from: {:?} (IP: {:?})",
+                    event.get_event_id_prefix(),
+                    event.kind,
+                    event.get_author_prefix(),
+                    subm_event.source_ip,
+                );
+                let id = event.id.clone();
+                let notice = if quarantine.hold(event) {
+                    Notice::quarantined(id, "event is held for review by the relay operator")
+                } else {
+                    info!("quarantine is full, refusing event: {:?}", id);
+                    Notice::rate_limited(id, "too many events are awaiting review; try again later")
+                };
+                notice_tx.try_send(notice).ok();
+                continue;
+            }
+        }
+
         // TODO: cache recent list of authors to remove a DB call.
         let start = Instant::now();
         if event.is_ephemeral() {
             bcast_tx.send(event.clone()).ok();
+            if !subm_event.imported {
+                forward_tx.send(event.clone()).ok();
+            }
             debug!(
                 "published ephemeral event: {:?} from: {:?} in: {:?}",
                 event.get_event_id_prefix(),
@@ -401,6 +618,7 @@ pub async fn db_writer(
                 start.elapsed()
             );
             event_write = true;
+            notice_tx.try_send(Notice::saved(event.id.clone())).ok();
         } else {
             match repo.write_event(&event).await {
                 Ok(updated) => {
@@ -419,6 +637,10 @@ pub async fn db_writer(
                         event_write = true;
                         // send this out to all clients
                         bcast_tx.send(event.clone()).ok();
+                        // and to upstream relays, unless it came from one
+                        if !subm_event.imported {
+                            forward_tx.send(event.clone()).ok();
+                        }
                         notice_tx.try_send(Notice::saved(event.id)).ok();
                     }
                 }
@@ -443,7 +665,9 @@ pub async fn db_writer(
                         .await?;
                 }
             }
-            if let Some(ref lim) = lim_opt {
+            // events with enough recent proof-of-work skip the limiter
+            let limiter = lim_opt.as_ref().filter(|_| rate_limited);
+            if let Some(lim) = limiter {
                 if let Err(n) = lim.check() {
                     let wait_for = n.wait_time_from(clock.now());
                     // check if we have recently logged rate
@@ -468,6 +692,40 @@ pub async fn db_writer(
     Ok(())
 }
 
+/// A set of the configured keys.
+fn key_list(keys: &Option<Vec<String>>) -> Blocklist {
+    let list = Blocklist::default();
+    if let Some(keys) = keys {
+        list.extend(keys.iter().cloned());
+    }
+    list
+}
+
+/// Limiter of event writes to the relay-wide rate, if one is set.
+fn write_limiter(rps: Option<u32>) -> Option<RateLimiter<NotKeyed, InMemoryState, DefaultClock>> {
+    let rps = rps.filter(|rps| *rps > 0)?;
+    info!("Enabling rate limits for event creation ({}/sec)", rps);
+    let quota = core::num::NonZeroU32::new(rps * 60).unwrap();
+    Some(RateLimiter::direct(Quota::per_minute(quota)))
+}
+
+/// Does this event count against the event rate limit?
+///
+/// Recent events with enough committed proof-of-work are exempt, so
+/// that high-effort publishers can get through during a flood.
+/// Future-dated events are never recent.
+fn is_rate_limited(event: &Event, limits: &Limits) -> bool {
+    match limits.pow_rate_limit_bypass {
+        Some(min_pow) => {
+            let now = unix_time();
+            let recent = event.created_at <= now
+                && now.saturating_sub(limits.pow_rate_limit_bypass_max_age) <= event.created_at;
+            !(recent && event.committed_pow_difficulty() >= min_pow)
+        }
+        None => true,
+    }
+}
+
 /// Serialized event associated with a specific subscription request.
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct QueryResult {
@@ -476,3 +734,68 @@ pub struct QueryResult {
     /// Serialized event
     pub event: String,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event_with_pow(difficulty: u8, created_at: u64) -> Event {
+        let mut event = Event::simple_event();
+        let zeros = usize::from(difficulty / 4);
+        event.id = "0".repeat(zeros) + &"f".repeat(64 - zeros);
+        event.created_at = created_at;
+        event.tags = vec![vec![
+            "nonce".to_owned(),
+            "1".to_owned(),
+            difficulty.to_string(),
+        ]];
+        event
+    }
+
+    #[test]
+    fn recent_high_pow_skips_rate_limit() {
+        let mut settings = Settings::default();
+        settings.limits.pow_rate_limit_bypass = Some(16);
+        let now = unix_time();
+        // below the required difficulty
+        assert!(is_rate_limited(&event_with_pow(4, now), &settings.limits));
+        assert!(is_rate_limited(&event_with_pow(12, now), &settings.limits));
+        // at or above it
+        assert!(!is_rate_limited(&event_with_pow(16, now), &settings.limits));
+        assert!(!is_rate_limited(&event_with_pow(24, now), &settings.limits));
+        // committing to less than was mined only counts the commitment
+        let mut event = event_with_pow(24, now);
+        event.tags[0][2] = "8".to_owned();
+        assert!(is_rate_limited(&event, &settings.limits));
+    }
+
+    #[test]
+    fn stale_pow_is_rate_limited() {
+        let mut settings = Settings::default();
+        settings.limits.pow_rate_limit_bypass = Some(16);
+        let old = unix_time() - settings.limits.pow_rate_limit_bypass_max_age - 60;
+        assert!(is_rate_limited(&event_with_pow(24, old), &settings.limits));
+    }
+
+    #[test]
+    fn future_pow_is_rate_limited() {
+        let mut settings = Settings::default();
+        settings.limits.pow_rate_limit_bypass = Some(16);
+        settings.limits.pow_rate_limit_bypass_max_age = u64::MAX;
+        for created_at in [unix_time() + 3600, u64::MAX] {
+            assert!(is_rate_limited(
+                &event_with_pow(24, created_at),
+                &settings.limits
+            ));
+        }
+    }
+
+    #[test]
+    fn pow_bypass_disabled() {
+        let settings = Settings::default();
+        assert!(is_rate_limited(
+            &event_with_pow(32, unix_time()),
+            &settings.limits
+        ));
+    }
+}
@@ -1,11 +1,11 @@
 //! Event parsing and validation
 use crate::error::Error;
 use crate::error::Result;
-use crate::event::Event;
+use crate::event::{Event, SECP};
 use bitcoin_hashes::{sha256, Hash};
 use lazy_static::lazy_static;
 use regex::Regex;
-use secp256k1::{schnorr, Secp256k1, VerifyOnly, XOnlyPublicKey};
+use secp256k1::{schnorr, XOnlyPublicKey};
 use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 use tracing::{debug, info};
@@ -30,11 +30,6 @@ use tracing::{debug, info};
 // different condition strings, since we do not support grouping or
 // "OR" logic.
 
-lazy_static! {
-    /// Secp256k1 verification instance.
-    pub static ref SECP: Secp256k1<VerifyOnly> = Secp256k1::verification_only();
-}
-
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
 pub enum Field {
     Kind,
@@ -99,6 +94,16 @@ impl ConditionQuery {
         // were true
         true
     }
+
+    /// Does the query limit the delegation at all?  A not-equals
+    /// condition without values excludes nothing, so it does not
+    /// count.
+    #[must_use]
+    pub fn is_constrained(&self) -> bool {
+        self.conditions
+            .iter()
+            .any(|c| !(c.operator == Operator::NotEquals && c.values.is_empty()))
+    }
 }
 
 // Verify that the delegator approved the delegation; return a ConditionQuery if so.
@@ -113,7 +118,13 @@ pub fn validate_delegation(
     let tok = format!("nostr:delegation:{delegatee}:{cond_query}");
     // form SHA256 hash
     let digest: sha256::Hash = sha256::Hash::hash(tok.as_bytes());
-    let sig = schnorr::Signature::from_str(sigstr).unwrap();
+    let sig = match schnorr::Signature::from_str(sigstr) {
+        Ok(sig) => sig,
+        Err(_) => {
+            debug!("client sent malformed delegation signature");
+            return None;
+        }
+    };
     if let Ok(msg) = secp256k1::Message::from_slice(digest.as_ref()) {
         if let Ok(pubkey) = XOnlyPublicKey::from_str(delegator) {
             let verify = SECP.verify_schnorr(&sig, &msg, &pubkey);
@@ -227,6 +238,28 @@ mod tests {
     use super::*;
 
     // parse condition strings
+    #[test]
+    fn malformed_delegation_rejected() {
+        let delegator = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d";
+        let delegatee = "0".repeat(64);
+        for sig in ["zzzz", "", "abcd"] {
+            assert!(validate_delegation(delegator, &delegatee, "kind=1", sig).is_none());
+        }
+        let sig = "0".repeat(128);
+        assert!(validate_delegation("abcd", &delegatee, "kind=1", &sig).is_none());
+    }
+
+    #[test]
+    fn unconstrained_queries() -> Result<()> {
+        for q in ["", "kind!", "kind!&created_at!"] {
+            assert!(!q.parse::<ConditionQuery>()?.is_constrained(), "{q}");
+        }
+        for q in ["kind=1", "created_at<1665265999", "kind!&kind!7"] {
+            assert!(q.parse::<ConditionQuery>()?.is_constrained(), "{q}");
+        }
+        Ok(())
+    }
+
     #[test]
     fn parse_empty() -> Result<()> {
         // given an empty condition query, produce an empty vector
@@ -0,0 +1,229 @@
+//! Read-only mode while the database volume is low on space
+//!
+//! When `database.min_free_disk_mb` is set, a task periodically
+//! checks the free space on the volume holding the database.  Below
+//! the threshold the relay stops accepting events (reads are still
+//! served), and connected clients get a NOTICE; once space frees up,
+//! events are accepted again.
+use crate::config::Settings;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Reason given to clients for refusing events while read-only.
+pub const READ_ONLY_MESSAGE: &str = "relay is read-only: the database disk is low on space";
+
+/// Source of free-space figures for a volume.
+pub trait DiskSpaceProbe: Send + Sync {
+    /// Bytes available to the relay on the volume containing `path`,
+    /// or `None` if it could not be determined.
+    fn available_bytes(&self, path: &Path) -> Option<u64>;
+}
+
+/// Probe using the operating system's filesystem statistics.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StatvfsProbe;
+
+impl DiskSpaceProbe for StatvfsProbe {
+    #[cfg(unix)]
+    fn available_bytes(&self, path: &Path) -> Option<u64> {
+        use std::os::unix::ffi::OsStrExt;
+        let c_path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+        // SAFETY: statvfs only writes into the zeroed struct we own,
+        // and the path is a valid NUL-terminated string.
+        let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+            return None;
+        }
+        // the field types differ between platforms
+        #[allow(clippy::useless_conversion)]
+        let (blocks, block_size) = (u64::from(stat.f_bavail), u64::from(stat.f_frsize));
+        blocks.checked_mul(block_size)
+    }
+
+    #[cfg(not(unix))]
+    fn available_bytes(&self, _path: &Path) -> Option<u64> {
+        None
+    }
+}
+
+/// Shared read-only state, set while the database volume is low on
+/// space.
+#[derive(Debug, Clone)]
+pub struct DiskGuard {
+    read_only: Arc<watch::Sender<bool>>,
+}
+
+impl Default for DiskGuard {
+    fn default() -> Self {
+        DiskGuard {
+            read_only: Arc::new(watch::channel(false).0),
+        }
+    }
+}
+
+impl DiskGuard {
+    /// Is the relay refusing events for lack of disk space?
+    #[must_use]
+    pub fn is_read_only(&self) -> bool {
+        *self.read_only.borrow()
+    }
+
+    /// Receive changes to the read-only state.
+    #[must_use]
+    pub fn subscribe(&self) -> watch::Receiver<bool> {
+        self.read_only.subscribe()
+    }
+
+    /// Update the state from an available-space reading, returning
+    /// true if it changed.  Unknown readings leave it unchanged.
+    pub fn update(&self, available: Option<u64>, min_free_bytes: u64) -> bool {
+        let read_only = match available {
+            Some(bytes) => bytes < min_free_bytes,
+            None => return false,
+        };
+        self.read_only.send_if_modified(|current| {
+            let changed = *current != read_only;
+            *current = read_only;
+            changed
+        })
+    }
+}
+
+/// Start the disk space monitor, if a threshold is configured.  The
+/// first check happens before this returns.
+pub fn start_disk_monitor(
+    settings: &Settings,
+    guard: &DiskGuard,
+    probe: Arc<dyn DiskSpaceProbe>,
+    shutdown_tx: &Sender<()>,
+) {
+    let min_free_mb = match settings.database.min_free_disk_mb {
+        Some(mb) => mb,
+        None => return,
+    };
+    let min_free_bytes = min_free_mb.saturating_mul(1024 * 1024);
+    let path = PathBuf::from(&settings.database.data_directory);
+    let interval = Duration::from_secs(settings.database.disk_check_interval_seconds.max(1));
+    info!(
+        "refusing events while {:?} has less than {} MB free",
+        path, min_free_mb
+    );
+    check(guard, probe.as_ref(), &path, min_free_bytes);
+    tokio::task::spawn(monitor(
+        guard.clone(),
+        probe,
+        path,
+        min_free_bytes,
+        interval,
+        shutdown_tx.subscribe(),
+    ));
+}
+
+/// Check the volume once, logging any change of state.
+fn check(guard: &DiskGuard, probe: &dyn DiskSpaceProbe, path: &Path, min_free_bytes: u64) {
+    let available = probe.available_bytes(path);
+    if guard.update(available, min_free_bytes) {
+        if guard.is_read_only() {
+            warn!(
+                "database volume is low on space ({:?} bytes free); refusing events",
+                available
+            );
+        } else {
+            info!(
+                "database volume has space again ({:?} bytes free); accepting events",
+                available
+            );
+        }
+    }
+}
+
+/// Check the volume every `interval`, until shutdown is requested.
+pub async fn monitor(
+    guard: DiskGuard,
+    probe: Arc<dyn DiskSpaceProbe>,
+    path: PathBuf,
+    min_free_bytes: u64,
+    interval: Duration,
+    mut shutdown: Receiver<()>,
+) {
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            _ = ticker.tick() => check(&guard, probe.as_ref(), &path, min_free_bytes),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    /// Reports whatever free space the test sets.
+    #[derive(Default)]
+    struct FixedProbe(AtomicU64);
+
+    impl DiskSpaceProbe for FixedProbe {
+        fn available_bytes(&self, _path: &Path) -> Option<u64> {
+            Some(self.0.load(Ordering::SeqCst))
+        }
+    }
+
+    async fn wait_for_change(changes: &mut watch::Receiver<bool>) {
+        tokio::time::timeout(Duration::from_secs(5), changes.changed())
+            .await
+            .expect("read-only state did not change")
+            .unwrap();
+    }
+
+    #[test]
+    fn unknown_space_keeps_state() {
+        let guard = DiskGuard::default();
+        assert!(guard.update(Some(10), 100));
+        assert!(!guard.update(None, 100));
+        assert!(guard.is_read_only());
+    }
+
+    #[tokio::test]
+    async fn threshold_flips_write_acceptance() {
+        let guard = DiskGuard::default();
+        let probe = Arc::new(FixedProbe::default());
+        probe.0.store(500, Ordering::SeqCst);
+        let (shutdown_tx, _) = tokio::sync::broadcast::channel(1);
+        let mut changes = guard.subscribe();
+        tokio::task::spawn(monitor(
+            guard.clone(),
+            probe.clone(),
+            PathBuf::from("."),
+            100,
+            Duration::from_millis(10),
+            shutdown_tx.subscribe(),
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!guard.is_read_only());
+        // crossing the threshold stops writes
+        probe.0.store(99, Ordering::SeqCst);
+        wait_for_change(&mut changes).await;
+        assert!(guard.is_read_only());
+        // and freeing space allows them again
+        probe.0.store(100, Ordering::SeqCst);
+        wait_for_change(&mut changes).await;
+        assert!(!guard.is_read_only());
+        shutdown_tx.send(()).ok();
+    }
+
+    #[test]
+    fn statvfs_reports_space() {
+        if cfg!(unix) {
+            assert!(StatvfsProbe.available_bytes(Path::new(".")).is_some());
+            assert!(StatvfsProbe
+                .available_bytes(Path::new("/no/such/dir"))
+                .is_none());
+        }
+    }
+}
@@ -31,6 +31,8 @@ pub enum Error {
     EventMaxLengthError(usize),
     #[error("Subscription identifier max length exceeded")]
     SubIdMaxLengthError,
+    #[error("Subscription identifier contains control characters")]
+    SubIdInvalidError,
     #[error("Maximum concurrent subscription count reached")]
     SubMaxExceededError,
     // this should be used if the JSON is invalid
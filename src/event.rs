@@ -1,5 +1,5 @@
 //! Event parsing and validation
-use crate::delegation::validate_delegation;
+use crate::delegation::{validate_delegation, ConditionQuery};
 use crate::error::Error::{
     CommandUnknownError, EventCouldNotCanonicalize, EventInvalidId, EventInvalidSignature,
     EventMalformedPubkey,
@@ -8,20 +8,23 @@ use crate::error::Result;
 use crate::event::EventWrapper::WrappedAuth;
 use crate::event::EventWrapper::WrappedEvent;
 use crate::nip05;
-use crate::utils::unix_time;
+use crate::utils::{is_lower_hex_bytes, normalize_relay_url, unix_time};
+use crate::verify::{Secp256k1Verifier, SignatureCheck, SignatureScheme, Verifier};
 use bitcoin_hashes::{sha256, Hash};
 use lazy_static::lazy_static;
-use secp256k1::{schnorr, Secp256k1, VerifyOnly, XOnlyPublicKey};
+use secp256k1::{Secp256k1, VerifyOnly, XOnlyPublicKey};
 use serde::{Deserialize, Deserializer, Serialize};
 use serde_json::value::Value;
 use serde_json::Number;
 use std::collections::HashMap;
 use std::collections::HashSet;
 use std::str::FromStr;
-use tracing::{debug, info};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use tracing::{debug, error};
 
 lazy_static! {
-    /// Secp256k1 verification instance.
+    /// Secp256k1 verification instance, shared by every signature
+    /// and delegation check.
     pub static ref SECP: Secp256k1<VerifyOnly> = Secp256k1::verification_only();
 }
 
@@ -37,6 +40,74 @@ impl EventCmd {
     pub fn event_id(&self) -> &str {
         &self.event.id
     }
+
+    /// The event carried by this command, not yet validated.
+    #[must_use]
+    pub fn event(&self) -> &Event {
+        &self.event
+    }
+
+    /// Parse and validate the event, checking its signature with the
+    /// given verifier.
+    pub fn into_wrapper(self, verifier: &dyn Verifier) -> Result<EventWrapper> {
+        // ensure command is correct
+        if self.cmd == "EVENT" {
+            self.event.validate_with(verifier).map(|_| {
+                let mut e = self.event;
+                e.build_index();
+                e.update_delegation();
+                WrappedEvent(e)
+            })
+        } else if self.cmd == "AUTH" {
+            // we don't want to validate the event here, because NIP-42 can be disabled
+            // it will be validated later during the authentication process
+            Ok(WrappedAuth(self.event))
+        } else {
+            Err(CommandUnknownError)
+        }
+    }
+}
+
+/// Batch of events in network format: `["EVENT", [event, ...]]`.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct EventBatchCmd {
+    cmd: String, // expecting static "EVENT"
+    events: Vec<Value>,
+}
+
+impl EventBatchCmd {
+    /// Split the batch into individual event commands.  Elements that
+    /// could not be parsed as events are returned as errors, holding
+    /// the element's id (if it had one).
+    pub fn into_cmds(self) -> Result<Vec<std::result::Result<EventCmd, Option<String>>>> {
+        if self.cmd != "EVENT" {
+            return Err(CommandUnknownError);
+        }
+        Ok(self
+            .events
+            .into_iter()
+            .map(|v| {
+                let id = v.get("id").and_then(Value::as_str).map(str::to_owned);
+                serde_json::from_value::<Event>(v)
+                    .map(|event| EventCmd {
+                        cmd: "EVENT".to_owned(),
+                        event,
+                    })
+                    .map_err(|_| id)
+            })
+            .collect())
+    }
+
+    /// Number of events in the batch.
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
 }
 
 /// Parsed nostr event.
@@ -98,24 +169,11 @@ pub enum EventWrapper {
 /// Convert network event to parsed/validated event.
 impl From<EventCmd> for Result<EventWrapper> {
     fn from(ec: EventCmd) -> Result<EventWrapper> {
-        // ensure command is correct
-        if ec.cmd == "EVENT" {
-            ec.event.validate().map(|_| {
-                let mut e = ec.event;
-                e.build_index();
-                e.update_delegation();
-                WrappedEvent(e)
-            })
-        } else if ec.cmd == "AUTH" {
-            // we don't want to validate the event here, because NIP-42 can be disabled
-            // it will be validated later during the authentication process
-            Ok(WrappedAuth(ec.event))
-        } else {
-            Err(CommandUnknownError)
-        }
+        ec.into_wrapper(&Secp256k1Verifier)
     }
 }
 
+
 impl Event {
     #[cfg(test)]
     #[must_use]
@@ -153,7 +211,9 @@ impl Event {
         }
     }
 
-    /// Determine the time at which this event should expire
+    /// Determine the time at which this event should expire (NIP-40),
+    /// from the first `expiration` tag.  A value that is not a
+    /// non-negative integer means the event has no expiration.
     pub fn expiration(&self) -> Option<u64> {
         let default = "".to_string();
         let dvals: Vec<&String> = self
@@ -186,24 +246,25 @@ impl Event {
     /// Should this event be replaced with newer timestamps from same author, for distinct `d` tag values?
     #[must_use]
     pub fn distinct_param(&self) -> Option<String> {
-        if self.is_param_replaceable() {
-            let default = "".to_string();
-            let dvals: Vec<&String> = self
-                .tags
-                .iter()
-                .filter(|x| !x.is_empty())
-                .filter(|x| x.get(0).unwrap() == "d")
-                .map(|x| x.get(1).unwrap_or(&default))
-                .take(1)
-                .collect();
-            let dval_first = dvals.get(0);
-            match dval_first {
-                Some(_) => dval_first.map(|x| x.to_string()),
-                None => Some(default),
-            }
-        } else {
-            None
+        self.replaceable_identifier().map(|(_, _, d)| d.to_owned())
+    }
+
+    /// The (kind, pubkey, `d` tag value) that a parameterized
+    /// replaceable event replaces older events with.  The first `d` tag
+    /// is used; a missing tag, or one without a value, is the empty
+    /// string.
+    #[must_use]
+    pub fn replaceable_identifier(&self) -> Option<(u64, &str, &str)> {
+        if !self.is_param_replaceable() {
+            return None;
         }
+        let d = self
+            .tags
+            .iter()
+            .find(|t| t.first().map_or(false, |name| name == "d"))
+            .and_then(|t| t.get(1))
+            .map_or("", String::as_str);
+        Some((self.kind, &self.pubkey, d))
     }
 
     /// Pull a NIP-05 Name out of the event, if one exists
@@ -294,6 +355,43 @@ impl Event {
         self.tagidx = Some(idx);
     }
 
+    /// Proof-of-work difficulty (NIP-13): the number of leading zero
+    /// bits in the event id.
+    #[must_use]
+    pub fn pow_difficulty(&self) -> u8 {
+        let mut bits: u32 = 0;
+        for c in self.id.chars() {
+            match c.to_digit(16) {
+                Some(0) => bits += 4,
+                Some(d) => {
+                    bits += d.leading_zeros() - 28;
+                    break;
+                }
+                None => break,
+            }
+        }
+        bits.min(255) as u8
+    }
+
+    /// Proof-of-work difficulty the author committed to in a `nonce`
+    /// tag, limited to the difficulty actually achieved.  Events
+    /// without a committed target have no committed difficulty.
+    #[must_use]
+    pub fn committed_pow_difficulty(&self) -> u8 {
+        self.pow_target()
+            .map_or(0, |t| t.min(self.pow_difficulty()))
+    }
+
+    /// Target difficulty committed to by the first `nonce` tag that
+    /// carries one (NIP-13), whether or not it was achieved.
+    #[must_use]
+    pub fn pow_target(&self) -> Option<u8> {
+        self.tags
+            .iter()
+            .filter(|t| t.len() >= 3 && t[0] == "nonce")
+            .find_map(|t| t[2].parse::<u8>().ok())
+    }
+
     /// Create a short event identifier, suitable for logging.
     #[must_use]
     pub fn get_event_id_prefix(&self) -> String {
@@ -304,23 +402,225 @@ impl Event {
         self.pubkey.chars().take(8).collect()
     }
 
+    /// Retrieve the value (second element) of every tag with the
+    /// given name, such as `"t"` for hashtags.  Tags without a value
+    /// are skipped.
+    #[must_use]
+    pub fn get_tag_values(&self, tag_name: &str) -> Vec<&str> {
+        self.tags
+            .iter()
+            .filter(|x| x.len() >= 2)
+            .filter(|x| x[0] == tag_name)
+            .map(|x| x[1].as_str())
+            .collect()
+    }
+
     /// Retrieve tag initial values across all tags matching the name
     #[must_use]
     pub fn tag_values_by_name(&self, tag_name: &str) -> Vec<String> {
+        self.get_tag_values(tag_name)
+            .into_iter()
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// Retrieve the pubkeys referenced by `p` tags
+    #[must_use]
+    pub fn get_pubkey_tags(&self) -> Vec<String> {
+        self.tag_values_by_name("p")
+    }
+
+    /// Check that every `e` tag marker (NIP-10) is one of `root`,
+    /// `reply` or `mention`.  Tags without a marker, or with an empty
+    /// one, are accepted.
+    #[must_use]
+    pub fn is_valid_etag_markers(&self) -> bool {
         self.tags
             .iter()
-            .filter(|x| x.len() > 1)
-            .filter(|x| x.get(0).unwrap() == tag_name)
-            .map(|x| x.get(1).unwrap().clone())
+            .filter(|t| t.get(0).map_or(false, |n| n == "e"))
+            .filter_map(|t| t.get(3))
+            .all(|m| m.is_empty() || m == "root" || m == "reply" || m == "mention")
+    }
+
+    /// Relay hints (the third element) of `e` and `p` tags.  Empty
+    /// hints are skipped.
+    pub fn relay_hints(&self) -> impl Iterator<Item = &String> {
+        self.tags
+            .iter()
+            .filter(|t| t.get(0).map_or(false, |n| n == "e" || n == "p"))
+            .filter_map(|t| t.get(2))
+            .filter(|h| !h.is_empty())
+    }
+
+    /// Relay hints of `e` and `p` tags, normalized for indexing or
+    /// crawling.  Invalid hints are omitted; the event itself is never
+    /// modified.
+    #[must_use]
+    pub fn normalized_relay_hints(&self) -> Vec<String> {
+        self.relay_hints()
+            .filter_map(|h| normalize_relay_url(h))
             .collect()
     }
 
+    /// Check that every relay hint in `e` and `p` tags can be
+    /// understood as a relay URL (see `utils::normalize_relay_url`).
+    #[must_use]
+    pub fn is_valid_relay_hints(&self) -> bool {
+        self.relay_hints().all(|h| normalize_relay_url(h).is_some())
+    }
+
+    /// Check that the pubkey is a BIP-340 x-only public key: 64
+    /// lowercase hex characters, naming an x coordinate below the
+    /// field size that lies on the curve.
+    #[must_use]
+    pub fn is_valid_pubkey(&self) -> bool {
+        self.pubkey.len() == 64
+            && self
+                .pubkey
+                .chars()
+                .all(|c| matches!(c, '0'..='9' | 'a'..='f'))
+            && XOnlyPublicKey::from_str(&self.pubkey).is_ok()
+    }
+
+    /// Check that events of the given kinds do not carry a JSON
+    /// object or array as their content, where plaintext is expected.
+    /// Other kinds always pass.
+    #[must_use]
+    pub fn is_valid_plaintext_content(&self, plaintext_kinds: &[u64]) -> bool {
+        if !plaintext_kinds.contains(&self.kind) {
+            return true;
+        }
+        !matches!(
+            serde_json::from_str::<Value>(&self.content),
+            Ok(Value::Object(_) | Value::Array(_))
+        )
+    }
+
+    /// Find the first of the required tag names that this event has
+    /// no tag (with a value) for.
     #[must_use]
-    pub fn is_valid_timestamp(&self, reject_future_seconds: Option<usize>) -> bool {
+    pub fn missing_required_tag<'a>(&self, required: &'a [String]) -> Option<&'a str> {
+        let missing = required
+            .iter()
+            .find(|name| self.get_tag_values(name).is_empty())
+            .map(String::as_str);
+        if let Some(name) = missing {
+            debug!("event is missing required tag {:?}, rejecting", name);
+        }
+        missing
+    }
+
+    /// Check that a contact list (kind 3) does not follow more than
+    /// the allowed number of pubkeys.  Other kinds always pass.
+    #[must_use]
+    pub fn is_valid_contact_list_size(&self, max_entries: Option<usize>) -> bool {
+        if let Some(max) = max_entries {
+            if self.kind == 3 {
+                let entries = self.get_pubkey_tags().len();
+                if entries > max {
+                    debug!(
+                        "contact list has {} entries (max {}), rejecting",
+                        entries, max
+                    );
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Check that the event kind is no greater than the allowed
+    /// maximum.
+    #[must_use]
+    pub fn is_valid_kind(&self, max_kind: Option<u64>) -> bool {
+        if let Some(max) = max_kind {
+            if self.kind > max {
+                debug!("event kind {} exceeds max {}, rejecting", self.kind, max);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Check that the event does not mention more than the allowed
+    /// number of distinct pubkeys in `p` tags.  Repeated pubkeys count
+    /// once.  Contact lists (kind 3) are limited separately, by
+    /// `is_valid_contact_list_size`.
+    #[must_use]
+    pub fn is_valid_distinct_p_tags(&self, max_distinct: Option<usize>) -> bool {
+        if let Some(max) = max_distinct {
+            if self.kind != 3 {
+                let pubkeys = self.get_pubkey_tags();
+                let distinct = pubkeys.iter().collect::<HashSet<_>>().len();
+                if distinct > max {
+                    debug!(
+                        "event mentions {} distinct pubkeys (max {}), rejecting",
+                        distinct, max
+                    );
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Number of tags that are written to the tag index: those with a
+    /// single-letter name and a value.
+    #[must_use]
+    pub fn indexed_tag_count(&self) -> usize {
+        self.tags
+            .iter()
+            .filter(|t| t.len() >= 2 && single_char_tagname(&t[0]).is_some())
+            .count()
+    }
+
+    /// Check that the event does not carry more indexed tags than
+    /// allowed.
+    #[must_use]
+    pub fn is_valid_indexed_tag_count(&self, max_indexed: Option<usize>) -> bool {
+        if let Some(max) = max_indexed {
+            let count = self.indexed_tag_count();
+            if count > max {
+                debug!("event has {} indexed tags (max {}), rejecting", count, max);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Check that the event timestamp is not one of the `rejected`
+    /// placeholder values.
+    #[must_use]
+    pub fn is_valid_created_at(&self, rejected: &[u64]) -> bool {
+        if rejected.contains(&self.created_at) {
+            debug!(
+                "event has a placeholder timestamp ({}), rejecting",
+                self.created_at
+            );
+            return false;
+        }
+        true
+    }
+
+    /// Check that the event was created no more than the allowed
+    /// number of seconds in the past, or in the future.
+    #[must_use]
+    pub fn is_valid_timestamp(
+        &self,
+        reject_past_seconds: Option<usize>,
+        reject_future_seconds: Option<usize>,
+    ) -> bool {
+        let curr_time = unix_time();
+        if let Some(allowable_past) = reject_past_seconds {
+            if curr_time.saturating_sub(allowable_past as u64) > self.created_at {
+                let delta = curr_time - self.created_at;
+                debug!("event is too far in the past ({} seconds), rejecting", delta);
+                return false;
+            }
+        }
         if let Some(allowable_future) = reject_future_seconds {
-            let curr_time = unix_time();
             // calculate difference, plus how far future we allow
-            if curr_time + (allowable_future as u64) < self.created_at {
+            if curr_time.saturating_add(allowable_future as u64) < self.created_at {
                 let delta = self.created_at - curr_time;
                 debug!(
                     "event is too far in the future ({} seconds), rejecting",
@@ -332,14 +632,169 @@ impl Event {
         true
     }
 
+    /// Check that the event does not have more than the maximum
+    /// number of tags.
+    #[must_use]
+    pub fn is_valid_tag_count(&self, max_tag_count: Option<usize>) -> bool {
+        if let Some(max) = max_tag_count {
+            if self.tags.len() > max {
+                debug!("event has {} tags (max {}), rejecting", self.tags.len(), max);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Check that the serialized event is no larger than the maximum
+    /// size (in bytes).  A maximum of zero is unlimited.
+    #[must_use]
+    pub fn is_valid_size(&self, max_event_bytes: Option<usize>) -> bool {
+        if let Some(max) = max_event_bytes.filter(|m| *m > 0) {
+            let size = serde_json::to_vec(self).map_or(0, |v| v.len());
+            if size > max {
+                debug!("event is {} bytes (max {}), rejecting", size, max);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Check that no tag element after the tag name exceeds the
+    /// maximum allowed length (in bytes).
+    #[must_use]
+    pub fn is_valid_tag_lengths(&self, max_tag_value_length: Option<usize>) -> bool {
+        if let Some(max_len) = max_tag_value_length {
+            for t in &self.tags {
+                if t.iter().skip(1).any(|v| v.len() > max_len) {
+                    debug!(
+                        "event has a tag value longer than {} bytes, rejecting",
+                        max_len
+                    );
+                    return false;
+                }
+            }
+        }
+        true
+    }
+
+    /// Check that a parameterized replaceable event has at most one
+    /// `d` tag, if duplicates are rejected.  Otherwise only the first
+    /// `d` tag is used.
+    #[must_use]
+    pub fn is_valid_unique_d_tag(&self, reject_duplicates: bool) -> bool {
+        if reject_duplicates && self.is_param_replaceable() {
+            let count = self.tag_values_by_name("d").len();
+            if count > 1 {
+                debug!("parameterized replaceable event has {} d tags, rejecting", count);
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Check that every tag has at least a name (NIP-01), if empty
+    /// tag arrays are rejected.
+    #[must_use]
+    pub fn is_valid_nonempty_tags(&self, reject_empty: bool) -> bool {
+        if reject_empty && self.tags.iter().any(Vec::is_empty) {
+            debug!("event has an empty tag, rejecting");
+            return false;
+        }
+        true
+    }
+
+    /// Check that a delegation tag (NIP-26), if the event has one, has
+    /// a valid token signature, and conditions that allow this event.
+    /// Delegation must already be resolved with `update_delegation`.
+    #[must_use]
+    pub fn is_valid_delegation(&self) -> bool {
+        let has_delegation_tag = self
+            .tags
+            .iter()
+            .any(|t| t.first().map_or(false, |n| n == "delegation"));
+        if has_delegation_tag && self.delegated_by.is_none() {
+            debug!("event has an invalid delegation, rejecting");
+            return false;
+        }
+        true
+    }
+
+    /// Check that a delegated event's conditions restrict the kind or
+    /// time of events, if unconstrained delegations are rejected.
+    #[must_use]
+    pub fn is_constrained_delegation(&self, require_constrained: bool) -> bool {
+        if !require_constrained || self.delegated_by.is_none() {
+            return true;
+        }
+        let constrained = self
+            .tags
+            .iter()
+            .find(|t| t.first().map_or(false, |n| n == "delegation"))
+            .and_then(|t| t.get(2))
+            .and_then(|q| q.parse::<ConditionQuery>().ok())
+            .map_or(false, |q| q.is_constrained());
+        if !constrained {
+            debug!("event has an unconstrained delegation, rejecting");
+        }
+        constrained
+    }
+
+    /// Check that a parameterized replaceable event carries an
+    /// explicit `d` tag, if one is required.  Without the requirement,
+    /// a missing `d` tag is treated as an empty value.
+    #[must_use]
+    pub fn is_valid_param_tag(&self, require_d_tag: bool) -> bool {
+        if require_d_tag && self.is_param_replaceable() {
+            let has_d_tag = self
+                .tags
+                .iter()
+                .any(|t| t.get(0).map_or(false, |n| n == "d"));
+            if !has_d_tag {
+                debug!("parameterized replaceable event has no d tag, rejecting");
+                return false;
+            }
+        }
+        true
+    }
+
     /// Check if this event has a valid signature.
     pub fn validate(&self) -> Result<()> {
-        // TODO: return a Result with a reason for invalid events
+        self.validate_with(&Secp256k1Verifier)
+    }
+
+    /// The scheme this event is signed with.  Every event currently
+    /// uses BIP-340 schnorr; a future scheme would be selected here,
+    /// from the kind or a new event field.
+    #[must_use]
+    pub fn signature_scheme(&self) -> SignatureScheme {
+        SignatureScheme::Schnorr
+    }
+
+    /// Check if this event has a valid id, and a signature that
+    /// passes the given verifier.  The error names the reason:
+    /// `EventCouldNotCanonicalize`, `EventInvalidId`,
+    /// `EventMalformedPubkey` or `EventInvalidSignature`.
+    pub fn validate_with(&self, verifier: &dyn Verifier) -> Result<()> {
         // validation is performed by:
         // * parsing JSON string into event fields
+        // * checking the id, pubkey and sig are lowercase hex of the
+        //   right length, before any hashing
         // * create an array:
         // ** [0, pubkey-hex-string, created-at-num, kind-num, tags-array-of-arrays, content-string]
         // * serialize with no spaces/newlines
+        if !is_lower_hex_bytes(&self.id, 32) {
+            debug!("event id is not 32 bytes of lowercase hex");
+            return Err(EventInvalidId);
+        }
+        let scheme = self.signature_scheme();
+        if !is_lower_hex_bytes(&self.pubkey, scheme.pubkey_bytes()) {
+            debug!("event pubkey is not {} bytes of lowercase hex", scheme.pubkey_bytes());
+            return Err(EventMalformedPubkey);
+        }
+        if !is_lower_hex_bytes(&self.sig, scheme.signature_bytes()) {
+            debug!("event sig is not {} bytes of lowercase hex", scheme.signature_bytes());
+            return Err(EventInvalidSignature);
+        }
         let c_opt = self.to_canonical();
         if c_opt.is_none() {
             debug!("could not canonicalize");
@@ -355,19 +810,21 @@ impl Event {
             return Err(EventInvalidId);
         }
         // * validate the message digest (sig) using the pubkey & computed sha256 message hash.
-        let sig = schnorr::Signature::from_str(&self.sig).unwrap();
-        if let Ok(msg) = secp256k1::Message::from_slice(digest.as_ref()) {
-            if let Ok(pubkey) = XOnlyPublicKey::from_str(&self.pubkey) {
-                SECP.verify_schnorr(&sig, &msg, &pubkey)
-                    .map_err(|_| EventInvalidSignature)
-            } else {
-                debug!("client sent malformed pubkey");
-                Err(EventMalformedPubkey)
-            }
-        } else {
-            info!("error converting digest to secp256k1 message");
+        let check = SignatureCheck {
+            scheme,
+            digest: digest.as_ref(),
+            sig: &self.sig,
+            pubkey: &self.pubkey,
+        };
+        // a panic in the crypto backend rejects the event, rather
+        // than taking down the connection task.
+        catch_unwind(AssertUnwindSafe(|| verifier.verify(&check))).unwrap_or_else(|_| {
+            error!(
+                "signature verification panicked (event: {:?})",
+                self.get_event_id_prefix()
+            );
             Err(EventInvalidSignature)
-        }
+        })
     }
 
     /// Convert event to canonical representation for signing.
@@ -422,6 +879,18 @@ impl Event {
             None => false,
         }
     }
+
+    /// Determine if the given tag contains every one of the provided values.
+    #[must_use]
+    pub fn generic_tag_val_superset(&self, tagname: char, check: &HashSet<String>) -> bool {
+        match &self.tagidx {
+            Some(idx) => match idx.get(&tagname) {
+                Some(valset) => valset.is_superset(check),
+                None => check.is_empty(),
+            },
+            None => check.is_empty(),
+        }
+    }
 }
 
 impl From<nostr::Event> for Event {
@@ -443,6 +912,7 @@ impl From<nostr::Event> for Event {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::error::Error;
 
     #[test]
     fn event_creation() {
@@ -451,6 +921,115 @@ mod tests {
         assert_eq!(event.id, "0");
     }
 
+    #[test]
+    fn validation_reasons() {
+        let with_id = |mut e: Event| {
+            e.id = format!("{:x}", sha256::Hash::hash(e.to_canonical().unwrap().as_bytes()));
+            e
+        };
+        let mut event = Event::simple_event();
+        event.kind = 1;
+        assert!(matches!(event.validate(), Err(Error::EventInvalidId)));
+        event.pubkey = "not a pubkey".to_owned();
+        let event = with_id(event);
+        assert!(matches!(event.validate(), Err(Error::EventMalformedPubkey)));
+        let mut event = event;
+        event.pubkey = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d".to_owned();
+        let event = with_id(event);
+        assert!(matches!(event.validate(), Err(Error::EventInvalidSignature)));
+        // the reason is kept when parsing an EVENT command
+        let cmd = EventCmd {
+            cmd: "EVENT".to_owned(),
+            event,
+        };
+        assert!(matches!(
+            cmd.into_wrapper(&Secp256k1Verifier),
+            Err(Error::EventInvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn malformed_sig_and_pubkey_rejected() {
+        let mut event = Event::simple_event();
+        event.kind = 1;
+        event.sig = "zzzz".to_owned();
+        event.pubkey = "abcd".to_owned();
+        event.id = format!("{:x}", sha256::Hash::hash(event.to_canonical().unwrap().as_bytes()));
+        assert!(event.validate().is_err());
+        // a well-formed pubkey with a malformed signature
+        event.pubkey = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d".to_owned();
+        event.id = format!("{:x}", sha256::Hash::hash(event.to_canonical().unwrap().as_bytes()));
+        assert!(matches!(event.validate(), Err(Error::EventInvalidSignature)));
+        // and a malformed delegation signature, after validation
+        event.tags = vec![vec![
+            "delegation".to_owned(),
+            "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d".to_owned(),
+            "kind=1".to_owned(),
+            "zzzz".to_owned(),
+        ]];
+        event.update_delegation();
+        assert_eq!(event.delegated_by, None);
+    }
+
+    #[test]
+    fn invalid_delegation_rejected() {
+        let mut event = Event::simple_event();
+        event.update_delegation();
+        assert!(event.is_valid_delegation());
+        event.tags = vec![vec![
+            "delegation".to_owned(),
+            "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d".to_owned(),
+            "kind=1".to_owned(),
+            "0".repeat(128),
+        ]];
+        event.update_delegation();
+        assert!(!event.is_valid_delegation());
+        // a delegation tag without a token is invalid too
+        event.tags[0].truncate(2);
+        event.update_delegation();
+        assert!(!event.is_valid_delegation());
+    }
+
+    #[test]
+    fn pow_difficulty() {
+        let mut event = Event::simple_event();
+        event.id = "000f".to_owned() + &"f".repeat(60);
+        assert_eq!(event.pow_difficulty(), 12);
+        event.id = "0".repeat(5) + "2" + &"f".repeat(58);
+        assert_eq!(event.pow_difficulty(), 22);
+        event.id = "f".repeat(64);
+        assert_eq!(event.pow_difficulty(), 0);
+    }
+
+    #[test]
+    fn pow_target() {
+        let mut event = Event::simple_event();
+        event.id = "00".to_owned() + &"f".repeat(62);
+        assert_eq!(event.pow_target(), None);
+        // a nonce without a target
+        event.tags = vec![vec!["nonce".to_owned(), "42".to_owned()]];
+        assert_eq!(event.pow_target(), None);
+        // the target is reported even when it was not achieved
+        event.tags = vec![vec!["nonce".to_owned(), "42".to_owned(), "20".to_owned()]];
+        assert_eq!(event.pow_target(), Some(20));
+        event.tags = vec![vec!["nonce".to_owned(), "42".to_owned(), "lots".to_owned()]];
+        assert_eq!(event.pow_target(), None);
+    }
+
+    #[test]
+    fn committed_pow_difficulty() {
+        let mut event = Event::simple_event();
+        event.id = "0000".to_owned() + &"f".repeat(60);
+        // no commitment
+        assert_eq!(event.committed_pow_difficulty(), 0);
+        // committed below what was achieved
+        event.tags = vec![vec!["nonce".to_owned(), "42".to_owned(), "12".to_owned()]];
+        assert_eq!(event.committed_pow_difficulty(), 12);
+        // claimed more than was achieved
+        event.tags = vec![vec!["nonce".to_owned(), "42".to_owned(), "20".to_owned()]];
+        assert_eq!(event.committed_pow_difficulty(), 16);
+    }
+
     #[test]
     fn event_serialize() -> Result<()> {
         // serialize an event to JSON string
@@ -555,6 +1134,22 @@ mod tests {
         assert_eq!(v, vec!["foo", "bar", "baz"]);
     }
 
+    #[test]
+    fn tag_values_by_letter() {
+        let mut e = Event::simple_event();
+        e.tags = vec![
+            vec!["t".to_owned(), "bitcoin".to_owned()],
+            vec!["t".to_owned()],
+            vec!["e".to_owned(), "foo".to_owned()],
+            vec![],
+            vec!["t".to_owned(), "nostr".to_owned(), "extra".to_owned()],
+        ];
+        assert_eq!(e.get_tag_values("t"), vec!["bitcoin", "nostr"]);
+        assert_eq!(e.get_tag_values("e"), vec!["foo"]);
+        assert!(e.get_tag_values("p").is_empty());
+        assert!(e.get_pubkey_tags().is_empty());
+    }
+
     #[test]
     fn event_no_tag_select() {
         let e = Event {
@@ -687,6 +1282,284 @@ mod tests {
         assert_eq!(event.distinct_param(), Some("".to_string()));
     }
 
+    #[test]
+    fn batch_cmd_split() {
+        let mut event = Event::simple_event();
+        event.id = "abc".to_owned();
+        let batch: EventBatchCmd = serde_json::from_value(serde_json::json!([
+            "EVENT",
+            [event, {"id": "def", "kind": "not a number"}, 42]
+        ]))
+        .unwrap();
+        assert_eq!(batch.len(), 3);
+        let cmds = batch.into_cmds().unwrap();
+        assert_eq!(cmds[0].as_ref().map(EventCmd::event_id), Ok("abc"));
+        assert_eq!(cmds[1].as_ref().err(), Some(&Some("def".to_owned())));
+        assert_eq!(cmds[2].as_ref().err(), Some(&None));
+    }
+
+    #[test]
+    fn batch_cmd_requires_event() {
+        let batch: EventBatchCmd =
+            serde_json::from_value(serde_json::json!(["AUTH", []])).unwrap();
+        assert!(batch.into_cmds().is_err());
+    }
+
+    #[test]
+    fn relay_hints() {
+        let tag = |name: &str, hint: &str| vec![name.to_owned(), "0".repeat(64), hint.to_owned()];
+        let mut event = Event::simple_event();
+        event.tags = vec![
+            tag("e", "wss://r.example.com/"),
+            tag("p", "r2.example.com"),
+            tag("e", ""),
+            vec!["e".to_owned(), "0".repeat(64)],
+        ];
+        assert!(event.is_valid_relay_hints());
+        assert_eq!(
+            event.normalized_relay_hints(),
+            vec!["wss://r.example.com", "wss://r2.example.com"]
+        );
+        // hints in the tags themselves are untouched
+        assert_eq!(event.tags[1][2], "r2.example.com");
+        event.tags.push(tag("p", "garbage!"));
+        assert!(!event.is_valid_relay_hints());
+        assert_eq!(event.normalized_relay_hints().len(), 2);
+        // only e and p tags are checked
+        event.tags = vec![tag("a", "garbage!")];
+        assert!(event.is_valid_relay_hints());
+    }
+
+    #[test]
+    fn etag_markers() {
+        let etag = |marker: Option<&str>| {
+            let mut t = vec!["e".to_owned(), "0".repeat(64), "wss://r.example.com".to_owned()];
+            if let Some(m) = marker {
+                t.push(m.to_owned());
+            }
+            t
+        };
+        let mut event = Event::simple_event();
+        event.tags = vec![etag(Some("root")), etag(Some("reply")), etag(Some("mention"))];
+        assert!(event.is_valid_etag_markers());
+        // markers are optional
+        event.tags = vec![etag(None), vec!["e".to_owned(), "0".repeat(64)], etag(Some(""))];
+        assert!(event.is_valid_etag_markers());
+        // unknown markers are not allowed
+        event.tags = vec![etag(Some("root")), etag(Some("parent"))];
+        assert!(!event.is_valid_etag_markers());
+        // only e tags are checked
+        event.tags = vec![vec!["p".to_owned(), "0".repeat(64), "".to_owned(), "parent".to_owned()]];
+        assert!(event.is_valid_etag_markers());
+    }
+
+    #[test]
+    fn json_content_for_plaintext_kinds() {
+        let mut event = Event::simple_event();
+        event.kind = 1;
+        for content in ["hello", "42", "\"quoted\"", "{not json", ""] {
+            event.content = content.to_owned();
+            assert!(event.is_valid_plaintext_content(&[1]));
+        }
+        for content in [r#"{"name": "x"}"#, " [1, 2] "] {
+            event.content = content.to_owned();
+            assert!(!event.is_valid_plaintext_content(&[1]));
+            // only listed kinds are checked
+            assert!(event.is_valid_plaintext_content(&[0, 7]));
+        }
+    }
+
+    #[test]
+    fn replaceable_identifier_uses_first_d_tag() {
+        let mut event = Event::simple_event();
+        event.pubkey = "a".repeat(64);
+        event.kind = 30_000;
+        let d = |v: &str| vec!["d".to_owned(), v.to_owned()];
+        assert_eq!(
+            event.replaceable_identifier(),
+            Some((30_000, event.pubkey.as_str(), ""))
+        );
+        event.tags = vec![vec!["d".to_owned()], d("x")];
+        assert_eq!(event.replaceable_identifier().map(|(_, _, d)| d), Some(""));
+        event.tags = vec![d("x"), d("y")];
+        assert_eq!(event.replaceable_identifier().map(|(_, _, d)| d), Some("x"));
+        event.kind = 10_000;
+        assert_eq!(event.replaceable_identifier(), None);
+    }
+
+    #[test]
+    fn required_tags() {
+        let mut event = Event::simple_event();
+        let required = vec!["title".to_owned(), "d".to_owned()];
+        event.tags = vec![vec!["d".to_owned(), "x".to_owned()]];
+        assert_eq!(event.missing_required_tag(&required), Some("title"));
+        // a tag without a value does not count
+        event.tags.push(vec!["title".to_owned()]);
+        assert_eq!(event.missing_required_tag(&required), Some("title"));
+        event.tags.push(vec!["title".to_owned(), "Hello".to_owned()]);
+        assert_eq!(event.missing_required_tag(&required), None);
+        assert_eq!(event.missing_required_tag(&[]), None);
+    }
+
+    #[test]
+    fn timestamp_bounds() {
+        let mut event = Event::simple_event();
+        let now = unix_time();
+        event.created_at = now - 600;
+        assert!(event.is_valid_timestamp(None, Some(0)));
+        assert!(event.is_valid_timestamp(Some(3600), None));
+        assert!(!event.is_valid_timestamp(Some(60), None));
+        event.created_at = now + 600;
+        assert!(event.is_valid_timestamp(Some(0), None));
+        assert!(event.is_valid_timestamp(None, Some(3600)));
+        assert!(!event.is_valid_timestamp(None, Some(60)));
+        // far future timestamps are not in the past, and do not overflow
+        event.created_at = u64::MAX;
+        assert!(event.is_valid_timestamp(Some(60), None));
+        assert!(event.is_valid_timestamp(Some(60), Some(usize::MAX)));
+        assert!(!event.is_valid_timestamp(None, Some(60)));
+    }
+
+    #[test]
+    fn placeholder_timestamps() {
+        let mut event = Event::simple_event();
+        event.created_at = 0;
+        assert!(event.is_valid_created_at(&[]));
+        assert!(!event.is_valid_created_at(&[0, 1]));
+        event.created_at = 1;
+        assert!(!event.is_valid_created_at(&[0, 1]));
+        event.created_at = unix_time();
+        assert!(event.is_valid_created_at(&[0, 1]));
+    }
+
+    #[test]
+    fn pubkey_must_be_curve_point() {
+        let mut event = Event::simple_event();
+        // x = 1 is on the curve
+        event.pubkey = format!("{:064x}", 1);
+        assert!(event.is_valid_pubkey());
+        // x = 5 has no matching y coordinate
+        event.pubkey = format!("{:064x}", 5);
+        assert!(!event.is_valid_pubkey());
+        // above the field size
+        event.pubkey = "f".repeat(64);
+        assert!(!event.is_valid_pubkey());
+        // upper case, and short keys
+        event.pubkey = format!("{:064X}", 0xab);
+        assert!(!event.is_valid_pubkey());
+        event.pubkey = "01".to_owned();
+        assert!(!event.is_valid_pubkey());
+    }
+
+    #[test]
+    fn contact_list_size_cap() {
+        let mut event = Event::simple_event();
+        event.kind = 3;
+        event.tags = (0..3)
+            .map(|i| vec!["p".to_owned(), format!("{i:064}")])
+            .collect();
+        event.tags.push(vec!["e".to_owned(), "0".repeat(64)]);
+        assert_eq!(event.get_pubkey_tags().len(), 3);
+        // at the cap, and unlimited
+        assert!(event.is_valid_contact_list_size(Some(3)));
+        assert!(event.is_valid_contact_list_size(None));
+        // over the cap
+        assert!(!event.is_valid_contact_list_size(Some(2)));
+        // other kinds are unaffected
+        event.kind = 1;
+        assert!(event.is_valid_contact_list_size(Some(2)));
+    }
+
+    #[test]
+    fn kind_cap() {
+        let mut event = Event::simple_event();
+        event.kind = 30_023;
+        assert!(event.is_valid_kind(Some(u64::from(u16::MAX))));
+        event.kind = u64::MAX;
+        assert!(!event.is_valid_kind(Some(u64::from(u16::MAX))));
+        assert!(event.is_valid_kind(None));
+    }
+
+    #[test]
+    fn distinct_p_tag_cap() {
+        let mut event = Event::simple_event();
+        event.kind = 1;
+        let ptag = |i: usize| vec!["p".to_owned(), format!("{i:064}")];
+        event.tags = (0..3).map(ptag).collect();
+        assert!(event.is_valid_distinct_p_tags(Some(3)));
+        assert!(event.is_valid_distinct_p_tags(None));
+        assert!(!event.is_valid_distinct_p_tags(Some(2)));
+        // repeated pubkeys count once
+        event.tags = (0..50).map(|i| ptag(i % 2)).collect();
+        assert!(event.is_valid_distinct_p_tags(Some(2)));
+        // contact lists are limited separately
+        event.kind = 3;
+        event.tags = (0..3).map(ptag).collect();
+        assert!(event.is_valid_distinct_p_tags(Some(2)));
+    }
+
+    #[test]
+    fn indexed_tag_cap() {
+        let mut event = Event::simple_event();
+        event.tags = (0..3)
+            .map(|i| vec!["t".to_owned(), format!("topic{i}")])
+            .collect();
+        // multi-letter names and bare names are not indexed
+        for _ in 0..10 {
+            event.tags.push(vec!["alt".to_owned(), "x".to_owned()]);
+            event.tags.push(vec!["e".to_owned()]);
+        }
+        assert_eq!(event.indexed_tag_count(), 3);
+        assert!(event.is_valid_indexed_tag_count(Some(3)));
+        assert!(event.is_valid_indexed_tag_count(None));
+        assert!(!event.is_valid_indexed_tag_count(Some(2)));
+    }
+
+    #[test]
+    fn param_tag_required() {
+        let mut event = Event::simple_event();
+        event.kind = 30000;
+        // a missing d tag is only rejected when required
+        assert!(event.is_valid_param_tag(false));
+        assert!(!event.is_valid_param_tag(true));
+        // an explicit d tag, even an empty one, satisfies the requirement
+        event.tags = vec![vec!["d".to_owned()]];
+        assert!(event.is_valid_param_tag(false));
+        assert!(event.is_valid_param_tag(true));
+        event.tags = vec![vec!["d".to_owned(), "name".to_owned()]];
+        assert!(event.is_valid_param_tag(true));
+        // other kinds are unaffected
+        event.kind = 1;
+        event.tags = vec![];
+        assert!(event.is_valid_param_tag(true));
+    }
+
+    #[test]
+    fn duplicate_d_tags() {
+        let mut event = Event::simple_event();
+        event.kind = 30000;
+        event.tags = vec![vec!["d".to_owned(), "name".to_owned()]];
+        assert!(event.is_valid_unique_d_tag(true));
+        event.tags.push(vec!["d".to_owned(), "other".to_owned()]);
+        assert!(!event.is_valid_unique_d_tag(true));
+        // only rejected when configured
+        assert!(event.is_valid_unique_d_tag(false));
+        // other kinds are unaffected
+        event.kind = 1;
+        assert!(event.is_valid_unique_d_tag(true));
+    }
+
+    #[test]
+    fn empty_tags() {
+        let mut event = Event::simple_event();
+        event.tags = vec![vec!["t".to_owned(), "nostr".to_owned()], vec!["x".to_owned()]];
+        assert!(event.is_valid_nonempty_tags(true));
+        event.tags.push(vec![]);
+        assert!(!event.is_valid_nonempty_tags(true));
+        // only rejected when configured
+        assert!(event.is_valid_nonempty_tags(false));
+    }
+
     #[test]
     fn param_replaceable_value_case_4b() {
         // Variation of #4 with
@@ -791,4 +1664,93 @@ mod tests {
         ];
         assert_eq!(event.expiration(), Some(10));
     }
+
+    #[test]
+    fn expired_events() {
+        let now = unix_time();
+        let mut event = Event::simple_event();
+        event.kind = 1;
+        assert!(!event.is_expired());
+        event.tags = vec![vec!["expiration".to_owned(), (now - 10).to_string()]];
+        assert!(event.is_expired());
+        event.tags = vec![vec!["expiration".to_owned(), (now + 60).to_string()]];
+        assert!(!event.is_expired());
+        // malformed values are no expiration at all
+        event.tags = vec![vec!["expiration".to_owned(), "soon".to_owned()]];
+        assert!(!event.is_expired());
+        // only the first tag counts
+        event.tags = vec![
+            vec!["expiration".to_owned(), (now + 60).to_string()],
+            vec!["expiration".to_owned(), (now - 10).to_string()],
+        ];
+        assert!(!event.is_expired());
+    }
+
+    #[test]
+    fn tag_count_limit() {
+        let mut event = Event::simple_event();
+        event.tags = (0..100).map(|i| vec!["t".to_owned(), format!("{i}")]).collect();
+        assert!(event.is_valid_tag_count(None));
+        assert!(event.is_valid_tag_count(Some(100)));
+        assert!(!event.is_valid_tag_count(Some(1)));
+    }
+
+    #[test]
+    fn serialized_size_limit() {
+        let mut event = Event::simple_event();
+        event.content = "x".repeat(1000);
+        assert!(event.is_valid_size(None));
+        assert!(event.is_valid_size(Some(0)));
+        assert!(event.is_valid_size(Some(2000)));
+        assert!(!event.is_valid_size(Some(1000)));
+    }
+
+    #[test]
+    fn tag_length_unlimited() {
+        let mut event = Event::simple_event();
+        event.tags = vec![vec!["d".to_owned(), "x".repeat(1 << 20)]];
+        assert!(event.is_valid_tag_lengths(None));
+    }
+
+    #[test]
+    fn tag_length_at_limit() {
+        let mut event = Event::simple_event();
+        event.tags = vec![vec!["d".to_owned(), "x".repeat(64)]];
+        assert!(event.is_valid_tag_lengths(Some(64)));
+    }
+
+    #[test]
+    fn tag_length_over_limit() {
+        let mut event = Event::simple_event();
+        event.tags = vec![vec!["d".to_owned(), "x".repeat(65)]];
+        assert!(!event.is_valid_tag_lengths(Some(64)));
+    }
+
+    #[test]
+    fn tag_length_checks_all_elements() {
+        // a long relay hint (third element) is also checked
+        let mut event = Event::simple_event();
+        event.tags = vec![vec![
+            "e".to_owned(),
+            "a".repeat(64),
+            format!("wss://{}.example.com", "r".repeat(100)),
+        ]];
+        assert!(!event.is_valid_tag_lengths(Some(64)));
+    }
+
+    #[test]
+    fn tag_length_relay_hint() {
+        // typical event/pubkey references with relay hints pass
+        let mut event = Event::simple_event();
+        event.tags = vec![
+            vec![
+                "e".to_owned(),
+                "a".repeat(64),
+                "wss://relay.example.com".to_owned(),
+                "reply".to_owned(),
+            ],
+            vec!["p".to_owned(), "b".repeat(64), "wss://nos.lol".to_owned()],
+        ];
+        assert!(event.is_valid_tag_lengths(Some(128)));
+    }
 }
@@ -0,0 +1,101 @@
+//! Negotiation of non-standard protocol extensions
+//!
+//! Clients can ask which relay-specific extensions are active by
+//! sending `["EXTENSIONS", [name, ...]]`.  The relay answers with
+//! `["EXTENSIONS", [name, ...]]`, listing those requested extensions
+//! that it supports with the current configuration.  The full list is
+//! also published in the NIP-11 document.
+use crate::config::Settings;
+use serde::{Deserialize, Serialize};
+
+/// Multiple events may be sent in one message, `["EVENT", [...]]`.
+pub const BATCH_EVENTS: &str = "batch-events";
+/// Deleted events requested by id are reported with `DELETED`.
+pub const TOMBSTONES: &str = "tombstones";
+/// Filters may require every value of a tag, `{"&t": [...]}`.
+pub const TAG_AND: &str = "tag-and";
+/// Filters may select delegated events, `{"delegated": true}`.
+pub const DELEGATED_FILTER: &str = "delegated-filter";
+/// Filters may page by sequence number, `{"after_seq": N}`, and the
+/// last sequence delivered is reported with `CURSOR`.
+pub const SEQ_CURSOR: &str = "seq-cursor";
+/// Filters on `#a` addresses also return the addressed events.
+pub const ADDRESS_FILTER: &str = "address-filter";
+
+/// Protocol command name; only matches the literal "EXTENSIONS".
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+enum ExtensionsCmdName {
+    #[serde(rename = "EXTENSIONS")]
+    Extensions,
+}
+
+/// Extension request in network format
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct ExtensionsCmd {
+    /// Protocol command, always "EXTENSIONS".
+    cmd: ExtensionsCmdName,
+    /// Extensions the client would like to use.
+    pub requested: Vec<String>,
+}
+
+/// Extensions enabled by the given settings.
+#[must_use]
+pub fn supported(settings: &Settings) -> Vec<String> {
+    let mut exts = vec![];
+    if settings.options.batch_events {
+        exts.push(BATCH_EVENTS.to_owned());
+    }
+    if settings.options.serve_tombstones {
+        exts.push(TOMBSTONES.to_owned());
+    }
+    if settings.options.tag_and_filters {
+        exts.push(TAG_AND.to_owned());
+    }
+    if settings.options.sequence_cursors {
+        exts.push(SEQ_CURSOR.to_owned());
+    }
+    if settings.options.resolve_address_filters {
+        exts.push(ADDRESS_FILTER.to_owned());
+    }
+    exts.push(DELEGATED_FILTER.to_owned());
+    exts
+}
+
+/// The requested extensions that are supported, in request order.
+#[must_use]
+pub fn negotiate(requested: &[String], settings: &Settings) -> Vec<String> {
+    let available = supported(settings);
+    let mut accepted: Vec<String> = vec![];
+    for r in requested {
+        if available.contains(r) && !accepted.contains(r) {
+            accepted.push(r.clone());
+        }
+    }
+    accepted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_only_extensions_command() {
+        let cmd: ExtensionsCmd =
+            serde_json::from_str(r#"["EXTENSIONS", ["batch-events"]]"#).unwrap();
+        assert_eq!(cmd.requested, vec![BATCH_EVENTS]);
+        assert!(serde_json::from_str::<ExtensionsCmd>(r#"["EVENT", ["batch-events"]]"#).is_err());
+    }
+
+    #[test]
+    fn negotiation_follows_settings() {
+        let requested = vec![
+            "relative-since".to_owned(),
+            BATCH_EVENTS.to_owned(),
+            BATCH_EVENTS.to_owned(),
+        ];
+        let mut settings = Settings::default();
+        assert_eq!(negotiate(&requested, &settings), vec![BATCH_EVENTS]);
+        settings.options.batch_events = false;
+        assert!(negotiate(&requested, &settings).is_empty());
+    }
+}
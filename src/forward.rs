@@ -0,0 +1,108 @@
+//! Outbound federation: forward accepted events to upstream relays
+//!
+//! Every event the database writer accepts is published on a
+//! forwarding channel, except for events imported from upstream
+//! relays, which would otherwise be sent back where they came from.
+//! For each configured upstream relay, a task listens on that channel
+//! and re-publishes the event over a websocket client connection,
+//! reconnecting with exponential backoff when the upstream is
+//! unreachable.
+use crate::event::Event;
+use futures::{SinkExt, StreamExt};
+use serde_json::json;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio_tungstenite::connect_async;
+use tracing::{debug, info, warn};
+use tungstenite::protocol::Message;
+
+/// Wait before the first reconnection attempt.
+pub(crate) const MIN_BACKOFF: Duration = Duration::from_secs(1);
+/// Longest wait between reconnection attempts.
+pub(crate) const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// Start a forwarding task for each upstream relay.
+pub fn start_forwarders(relays: &[String], forward_tx: &Sender<Event>, shutdown_tx: &Sender<()>) {
+    for url in relays {
+        info!("forwarding accepted events to {}", url);
+        tokio::task::spawn(forward_events(
+            url.clone(),
+            forward_tx.subscribe(),
+            shutdown_tx.subscribe(),
+        ));
+    }
+}
+
+/// Publish every event received on `forward_rx` to a single upstream
+/// relay, until shutdown is requested.
+pub async fn forward_events(
+    url: String,
+    mut forward_rx: Receiver<Event>,
+    mut shutdown: Receiver<()>,
+) {
+    let mut backoff = MIN_BACKOFF;
+    // an event that could not be sent before the connection dropped
+    let mut pending: Option<Event> = None;
+    loop {
+        match connect_async(url.as_str()).await {
+            Ok((mut ws, _)) => {
+                info!("connected to upstream relay {}", url);
+                backoff = MIN_BACKOFF;
+                loop {
+                    let event = if let Some(e) = pending.take() {
+                        e
+                    } else {
+                        tokio::select! {
+                            _ = shutdown.recv() => {
+                                ws.close(None).await.ok();
+                                return;
+                            },
+                            ev = forward_rx.recv() => match ev {
+                                Ok(e) => e,
+                                Err(RecvError::Lagged(n)) => {
+                                    warn!("forwarder for {} fell behind, skipped {} events", url, n);
+                                    continue;
+                                }
+                                Err(RecvError::Closed) => return,
+                            },
+                            // read replies, so pings are answered and
+                            // disconnects are noticed.
+                            msg = ws.next() => match msg {
+                                Some(Ok(Message::Text(m))) => {
+                                    debug!("upstream relay {} replied: {}", url, m);
+                                    continue;
+                                }
+                                Some(Ok(_)) => continue,
+                                Some(Err(e)) => {
+                                    warn!("connection to upstream relay {} failed: {}", url, e);
+                                    break;
+                                }
+                                None => {
+                                    info!("upstream relay {} closed the connection", url);
+                                    break;
+                                }
+                            },
+                        }
+                    };
+                    let msg = json!(["EVENT", event]).to_string();
+                    if let Err(e) = ws.send(Message::Text(msg)).await {
+                        warn!("could not forward event to {}: {}", url, e);
+                        pending = Some(event);
+                        break;
+                    }
+                    debug!("forwarded event {} to {}", event.get_event_id_prefix(), url);
+                }
+            }
+            Err(e) => {
+                warn!("could not connect to upstream relay {}: {}", url, e);
+            }
+        }
+        debug!("reconnecting to {} in {:?}", url, backoff);
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {},
+            _ = shutdown.recv() => return,
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,161 @@
+//! Inbound federation: import events from upstream relays
+//!
+//! For each configured upstream relay, a task subscribes with the
+//! configured filter and submits every event it receives to the
+//! database writer, exactly as if a client had published it.  Events
+//! that are already stored are recognized as duplicates by the writer
+//! and skipped.
+use crate::admission::{reject_client_event, reject_unverified_event};
+use crate::config::{ImportRelay, Settings};
+use crate::db::SubmittedEvent;
+use crate::event::Event;
+use crate::forward::{MAX_BACKOFF, MIN_BACKOFF};
+use crate::notice::Notice;
+use crate::subscription::ReqFilter;
+use crate::verify::Verifier;
+use futures::{SinkExt, StreamExt};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::mpsc;
+use tokio_tungstenite::connect_async;
+use tracing::{debug, info, warn};
+use tungstenite::protocol::Message;
+
+/// Subscription id used for upstream requests
+const IMPORT_SUB_ID: &str = "import";
+
+/// Start an import task for each upstream relay.
+pub fn start_importers(
+    settings: &Settings,
+    event_tx: &mpsc::Sender<SubmittedEvent>,
+    verifier: &Arc<dyn Verifier>,
+    shutdown_tx: &Sender<()>,
+) {
+    for upstream in &settings.federation.import_from_relays {
+        let filter: ReqFilter = match serde_json::from_str(&upstream.filter) {
+            Ok(f) => f,
+            Err(e) => {
+                warn!("invalid import filter for {}: {}", upstream.url, e);
+                continue;
+            }
+        };
+        info!("importing events from {}", upstream.url);
+        tokio::task::spawn(import_events(
+            upstream.clone(),
+            filter,
+            settings.clone(),
+            event_tx.clone(),
+            verifier.clone(),
+            shutdown_tx.subscribe(),
+        ));
+    }
+}
+
+/// Apply the checks that client-submitted events receive before
+/// being sent to the database writer.
+fn admit(mut e: Event, settings: &Settings, verifier: &dyn Verifier, url: &str) -> Option<Event> {
+    if reject_unverified_event(&e, settings, url).is_some() || e.validate_with(verifier).is_err() {
+        return None;
+    }
+    e.build_index();
+    e.update_delegation();
+    if let Some(notice) = reject_client_event(&e, settings, url) {
+        if let Notice::EventResult(r) = notice {
+            debug!("import of {} from {} refused: {}", r.id, url, r.msg);
+        }
+        return None;
+    }
+    Some(e)
+}
+
+/// Subscribe to a single upstream relay, and submit matching events
+/// until shutdown is requested.
+pub async fn import_events(
+    upstream: ImportRelay,
+    filter: ReqFilter,
+    settings: Settings,
+    event_tx: mpsc::Sender<SubmittedEvent>,
+    verifier: Arc<dyn Verifier>,
+    mut shutdown: Receiver<()>,
+) {
+    let url = upstream.url;
+    // results of each write are reported here
+    let (notice_tx, mut notice_rx) = mpsc::channel::<Notice>(128);
+    let mut backoff = MIN_BACKOFF;
+    loop {
+        match connect_async(url.as_str()).await {
+            Ok((mut ws, _)) => {
+                info!("connected to upstream relay {}", url);
+                backoff = MIN_BACKOFF;
+                let req = json!(["REQ", IMPORT_SUB_ID, filter]).to_string();
+                if let Err(e) = ws.send(Message::Text(req)).await {
+                    warn!("could not subscribe to {}: {}", url, e);
+                }
+                loop {
+                    tokio::select! {
+                        _ = shutdown.recv() => {
+                            ws.close(None).await.ok();
+                            return;
+                        },
+                        Some(notice) = notice_rx.recv() => {
+                            if let Notice::EventResult(r) = notice {
+                                debug!("imported event {} from {}: {}", r.id, url, r.msg);
+                            }
+                        },
+                        msg = ws.next() => match msg {
+                            Some(Ok(Message::Text(m))) => {
+                                let msg: Value = match serde_json::from_str(&m) {
+                                    Ok(v) => v,
+                                    Err(_) => continue,
+                                };
+                                if msg[0] != "EVENT" || msg[1] != IMPORT_SUB_ID {
+                                    debug!("upstream relay {} sent: {}", url, m);
+                                    continue;
+                                }
+                                let event = serde_json::from_value::<Event>(msg[2].clone())
+                                    .ok()
+                                    .and_then(|e| admit(e, &settings, verifier.as_ref(), &url));
+                                if let Some(e) = event {
+                                    let submit_event = SubmittedEvent {
+                                        event: e,
+                                        notice_tx: notice_tx.clone(),
+                                        source_ip: url.clone(),
+                                        origin: None,
+                                        user_agent: None,
+                                        auth_pubkey: None,
+                                        reviewed: false,
+                                        imported: true,
+                                    };
+                                    if event_tx.send(submit_event).await.is_err() {
+                                        return;
+                                    }
+                                } else {
+                                    info!("rejected invalid event imported from {}", url);
+                                }
+                            }
+                            Some(Ok(_)) => {}
+                            Some(Err(e)) => {
+                                warn!("connection to upstream relay {} failed: {}", url, e);
+                                break;
+                            }
+                            None => {
+                                info!("upstream relay {} closed the connection", url);
+                                break;
+                            }
+                        },
+                    }
+                }
+            }
+            Err(e) => {
+                warn!("could not connect to upstream relay {}: {}", url, e);
+            }
+        }
+        debug!("reconnecting to {} in {:?}", url, backoff);
+        tokio::select! {
+            _ = tokio::time::sleep(backoff) => {},
+            _ = shutdown.recv() => return,
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
@@ -1,6 +1,8 @@
 //! Relay metadata using NIP-11
 /// Relay Info
 use crate::config::Settings;
+use crate::extensions;
+use crate::utils::unix_time;
 use serde::{Deserialize, Serialize};
 
 pub const CARGO_PKG_VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
@@ -13,6 +15,8 @@ pub const UNIT: &str = "sats";
 pub struct Limitation {
     #[serde(skip_serializing_if = "Option::is_none")]
     payment_required: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    compression: Option<bool>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -58,23 +62,40 @@ pub struct RelayInfo {
     pub payment_url: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub fees: Option<Fees>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub extensions: Option<Vec<String>>,
+    /// The relay's clock (unix seconds) when this document was built,
+    /// so clients can correct their `created_at` timestamps.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_time: Option<u64>,
 }
 
 /// Convert an Info configuration into public Relay Info
 impl From<Settings> for RelayInfo {
     fn from(c: Settings) -> Self {
-        let mut supported_nips = vec![1, 2, 9, 11, 12, 15, 16, 20, 22, 33, 40, 42];
+        let mut supported_nips = vec![1, 2, 9, 11, 12, 15, 16, 20, 22, 33, 40];
 
         if c.authorization.nip42_auth {
             supported_nips.push(42);
             supported_nips.sort();
         }
 
+        // content search relies on the SQLite full-text index
+        if c.database.engine == "sqlite" {
+            supported_nips.push(50);
+            supported_nips.sort();
+        }
+
+        let extensions = extensions::supported(&c);
         let i = c.info;
         let p = c.pay_to_relay;
 
         let limitations = Limitation {
             payment_required: Some(p.enabled),
+            // websocket permessage-deflate is not negotiated by this
+            // relay (tungstenite does not implement it), so clients
+            // should not request it.
+            compression: Some(false),
         };
 
         let (payment_url, fees) = if p.enabled {
@@ -127,6 +148,74 @@ impl From<Settings> for RelayInfo {
             payment_url,
             fees,
             icon: i.relay_icon,
+            extensions: Some(extensions),
+            current_time: Some(unix_time()),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compression_advertised() {
+        let info = RelayInfo::from(Settings::default());
+        let doc = serde_json::to_value(info).unwrap();
+        assert_eq!(doc["limitation"]["compression"], false);
+    }
+
+    #[test]
+    fn document_from_config() {
+        let mut settings = Settings::default();
+        settings.info.name = Some("Example relay".to_owned());
+        settings.info.description = Some("A relay for testing".to_owned());
+        settings.info.pubkey = Some("ab".repeat(32));
+        settings.info.contact = Some("mailto:admin@example.com".to_owned());
+        let doc = serde_json::to_value(RelayInfo::from(settings.clone())).unwrap();
+        assert_eq!(doc["name"], "Example relay");
+        assert_eq!(doc["description"], "A relay for testing");
+        assert_eq!(doc["pubkey"], "ab".repeat(32));
+        assert_eq!(doc["contact"], "mailto:admin@example.com");
+        assert_eq!(doc["software"], "https://git.sr.ht/~gheartsfield/nostr-rs-relay");
+        assert_eq!(doc["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(
+            doc["supported_nips"],
+            serde_json::json!([1, 2, 9, 11, 12, 15, 16, 20, 22, 33, 40, 50])
+        );
+        // NIP-42 is only advertised when enabled
+        settings.authorization.nip42_auth = true;
+        let doc = serde_json::to_value(RelayInfo::from(settings.clone())).unwrap();
+        assert_eq!(
+            doc["supported_nips"],
+            serde_json::json!([1, 2, 9, 11, 12, 15, 16, 20, 22, 33, 40, 42, 50])
+        );
+        // and search (NIP-50) only with the SQLite full-text index
+        settings.database.engine = "postgres".to_owned();
+        let doc = serde_json::to_value(RelayInfo::from(settings)).unwrap();
+        assert_eq!(
+            doc["supported_nips"],
+            serde_json::json!([1, 2, 9, 11, 12, 15, 16, 20, 22, 33, 40, 42])
+        );
+    }
+
+    #[test]
+    fn current_time_advertised() {
+        let doc = serde_json::to_value(RelayInfo::from(Settings::default())).unwrap();
+        let relay_time = doc["current_time"].as_u64().unwrap();
+        assert!(relay_time.abs_diff(unix_time()) <= 2);
+    }
+
+    #[test]
+    fn extensions_advertised() {
+        let mut settings = Settings::default();
+        let doc = serde_json::to_value(RelayInfo::from(settings.clone())).unwrap();
+        assert_eq!(
+            doc["extensions"],
+            serde_json::json!(["batch-events", "delegated-filter"])
+        );
+        settings.options.batch_events = false;
+        let doc = serde_json::to_value(RelayInfo::from(settings)).unwrap();
+        assert_eq!(doc["extensions"], serde_json::json!(["delegated-filter"]));
+    }
+}
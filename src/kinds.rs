@@ -0,0 +1,164 @@
+//! Per-kind publishing policies, for clients to discover
+//!
+//! Several settings treat event kinds differently: kind allow and
+//! block lists, timestamp bounds, required tags, and so on.  The
+//! relay serves a summary of them as JSON at `/kinds`, so a client can
+//! learn what an event of some kind needs before publishing it.
+use crate::config::Settings;
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// Policies of the relay for each event kind
+#[derive(Debug, Serialize)]
+pub struct KindPolicies {
+    /// Only these kinds are accepted, if set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub allowed: Option<Vec<u64>>,
+    /// Kinds that are never accepted
+    pub blocked: Vec<u64>,
+    /// Largest kind number accepted, if limited
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_kind: Option<u64>,
+    /// Policy for kinds without settings of their own
+    pub defaults: KindPolicy,
+    /// Policy for each kind with settings of its own
+    pub kinds: BTreeMap<u64, KindPolicy>,
+}
+
+/// The limits that apply to events of one kind.  Unset limits are
+/// omitted.
+#[derive(Debug, Default, PartialEq, Eq, Serialize)]
+pub struct KindPolicy {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_event_bytes: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tag_count: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_tag_value_length: Option<usize>,
+    /// Most pubkeys an event may mention in "p" tags
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_p_tags: Option<usize>,
+    /// Oldest accepted `created_at`, in seconds before now
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_past_seconds: Option<usize>,
+    /// Newest accepted `created_at`, in seconds after now
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_future_seconds: Option<usize>,
+    /// Relay-wide rate of accepted events
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub messages_per_sec: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_pow_difficulty: Option<u8>,
+    /// Tags events must have, with a value
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub required_tags: Vec<String>,
+    /// JSON objects and arrays are refused as content
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub rejects_json_content: bool,
+    /// Tags are not indexed, so tag filters don't find these events
+    #[serde(skip_serializing_if = "std::ops::Not::not")]
+    pub unindexed_tags: bool,
+}
+
+impl KindPolicy {
+    /// The policy for events of `kind`; with no kind, the defaults.
+    #[must_use]
+    pub fn new(settings: &Settings, kind: Option<u64>) -> KindPolicy {
+        let options = &settings.options;
+        let limits = &settings.limits;
+        let (max_past_seconds, max_future_seconds) = match kind {
+            Some(k) => options.created_at_bounds(k),
+            None => (options.reject_past_seconds, options.reject_future_seconds),
+        };
+        // contact lists are limited by entries, not distinct mentions
+        let max_p_tags = if kind == Some(3) {
+            limits.max_contact_list_entries
+        } else {
+            limits.max_distinct_p_tags
+        };
+        let has = |kinds: &[u64]| kind.map_or(false, |k| kinds.contains(&k));
+        KindPolicy {
+            max_event_bytes: limits.max_event_bytes,
+            max_tag_count: limits.max_tag_count,
+            max_tag_value_length: limits.max_tag_value_length,
+            max_p_tags,
+            max_past_seconds,
+            max_future_seconds,
+            messages_per_sec: limits.messages_per_sec,
+            min_pow_difficulty: limits.min_pow_difficulty,
+            required_tags: kind.map_or(vec![], |k| options.required_tags(k).to_vec()),
+            rejects_json_content: has(&options.reject_json_content_kinds),
+            unindexed_tags: has(&options.unindexed_kinds),
+        }
+    }
+}
+
+impl From<&Settings> for KindPolicies {
+    fn from(settings: &Settings) -> Self {
+        let options = &settings.options;
+        let mut special: Vec<u64> = options
+            .kind_created_at_bounds
+            .iter()
+            .map(|b| b.kind)
+            .chain(options.kind_required_tags.iter().map(|r| r.kind))
+            .chain(options.reject_json_content_kinds.iter().copied())
+            .chain(options.unindexed_kinds.iter().copied())
+            .collect();
+        if settings.limits.max_contact_list_entries.is_some() {
+            special.push(3);
+        }
+        KindPolicies {
+            allowed: settings.limits.event_kind_allowlist.clone(),
+            blocked: settings
+                .limits
+                .event_kind_blacklist
+                .clone()
+                .unwrap_or_default(),
+            max_kind: settings.limits.max_kind,
+            defaults: KindPolicy::new(settings, None),
+            kinds: special
+                .into_iter()
+                .map(|k| (k, KindPolicy::new(settings, Some(k))))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{KindCreatedAtBounds, KindRequiredTags};
+
+    #[test]
+    fn per_kind_overrides() {
+        let mut settings = Settings::default();
+        settings.limits.max_event_bytes = Some(4096);
+        settings.limits.max_contact_list_entries = Some(500);
+        settings.limits.max_distinct_p_tags = Some(20);
+        settings.options.reject_past_seconds = Some(3600);
+        settings.options.kind_created_at_bounds = vec![KindCreatedAtBounds {
+            kind: 30023,
+            past_seconds: None,
+            future_seconds: Some(60),
+        }];
+        settings.options.kind_required_tags = vec![KindRequiredTags {
+            kind: 30023,
+            tags: vec!["d".into(), "title".into()],
+        }];
+        settings.options.unindexed_kinds = vec![7];
+        let policies = KindPolicies::from(&settings);
+        assert_eq!(policies.defaults, KindPolicy::new(&settings, Some(1)));
+        assert_eq!(policies.defaults.max_p_tags, Some(20));
+        assert_eq!(policies.defaults.max_past_seconds, Some(3600));
+        let keys: Vec<&u64> = policies.kinds.keys().collect();
+        assert_eq!(keys, vec![&3, &7, &30023]);
+        assert_eq!(policies.kinds[&3].max_p_tags, Some(500));
+        assert!(policies.kinds[&7].unindexed_tags);
+        let article = &policies.kinds[&30023];
+        assert_eq!(article.required_tags, vec!["d", "title"]);
+        // the kind's own bounds replace the defaults entirely
+        assert_eq!(article.max_past_seconds, None);
+        assert_eq!(article.max_future_seconds, Some(60));
+        assert_eq!(article.max_event_bytes, Some(4096));
+    }
+}
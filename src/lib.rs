@@ -1,19 +1,33 @@
+pub mod admission;
+pub mod announce;
+pub mod audit;
+pub mod blocklist;
 pub mod cli;
 pub mod close;
 pub mod config;
 pub mod conn;
 pub mod db;
 pub mod delegation;
+pub mod diskspace;
 pub mod error;
 pub mod event;
+pub mod extensions;
+pub mod forward;
 pub mod hexrange;
+pub mod import;
 pub mod info;
+pub mod kinds;
+pub mod localization;
 pub mod nauthz;
 pub mod nip05;
 pub mod notice;
+pub mod quarantine;
+pub mod reload;
 pub mod repo;
+pub mod resume;
 pub mod subscription;
 pub mod utils;
+pub mod verify;
 // Public API for creating relays programmatically
 pub mod payment;
 pub mod server;
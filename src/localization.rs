@@ -0,0 +1,165 @@
+//! Translations of messages sent to clients
+//!
+//! Operators configure translations of English rejection and NOTICE
+//! messages per language.  Each connection picks a language from a
+//! `lang` query parameter or its `Accept-Language` header; messages
+//! without a translation in that language are sent in English.
+use crate::config::Localization;
+
+/// Choose the configured language that best matches a client's
+/// request.  An explicit `lang` query parameter wins over the
+/// `Accept-Language` header, whose entries are tried in order of
+/// preference.
+#[must_use]
+pub fn select_language(
+    localization: &Localization,
+    requested: Option<&str>,
+    accept_language: Option<&str>,
+) -> Option<String> {
+    if localization.translations.is_empty() {
+        return None;
+    }
+    if let Some(lang) = requested.and_then(|r| best_match(localization, r)) {
+        return Some(lang);
+    }
+    accept_language
+        .map(preferred_languages)
+        .unwrap_or_default()
+        .iter()
+        .find_map(|r| best_match(localization, r))
+}
+
+/// Translate a message into `lang`.  A machine-readable prefix
+/// ("invalid: ...") stays in English so clients can still parse it.
+#[must_use]
+pub fn localize(localization: &Localization, lang: Option<&str>, msg: &str) -> String {
+    let lang = match lang {
+        Some(lang) => lang,
+        None => return msg.to_owned(),
+    };
+    if let Some(text) = translation(localization, lang, msg) {
+        return text.to_owned();
+    }
+    if let Some((prefix, rest)) = msg.split_once(": ") {
+        if let Some(text) = translation(localization, lang, rest) {
+            return format!("{prefix}: {text}");
+        }
+    }
+    msg.to_owned()
+}
+
+fn translation<'a>(localization: &'a Localization, lang: &str, msg: &str) -> Option<&'a str> {
+    localization
+        .translations
+        .iter()
+        .find(|t| t.lang.eq_ignore_ascii_case(lang) && t.message == msg)
+        .map(|t| t.text.as_str())
+}
+
+/// The configured language for a requested tag, matching the full
+/// tag first ("pt-BR"), and then its primary language ("pt").
+fn best_match(localization: &Localization, requested: &str) -> Option<String> {
+    let requested = requested.trim();
+    let primary = requested.split('-').next().unwrap_or(requested);
+    let configured = |tag: &str| {
+        localization
+            .translations
+            .iter()
+            .find(|t| t.lang.eq_ignore_ascii_case(tag))
+            .map(|t| t.lang.clone())
+    };
+    if requested.is_empty() || requested == "*" {
+        return None;
+    }
+    configured(requested).or_else(|| configured(primary))
+}
+
+/// Language tags from an `Accept-Language` header, most preferred
+/// first.  Tags with a quality of zero are refused by the client, and
+/// are dropped.
+fn preferred_languages(header: &str) -> Vec<String> {
+    let mut langs: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|entry| {
+            let mut parts = entry.split(';');
+            let tag = parts.next()?.trim();
+            let quality = parts
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+            if tag.is_empty() || quality <= 0.0 {
+                None
+            } else {
+                Some((tag.to_owned(), quality))
+            }
+        })
+        .collect();
+    // stable, so equally preferred tags keep their order
+    langs.sort_by(|a, b| b.1.total_cmp(&a.1));
+    langs.into_iter().map(|(tag, _)| tag).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Translation;
+
+    fn spanish() -> Localization {
+        Localization {
+            translations: vec![Translation {
+                lang: "es".into(),
+                message: "could not parse command".into(),
+                text: "no se pudo interpretar el comando".into(),
+            }],
+        }
+    }
+
+    #[test]
+    fn accept_language_preference() {
+        let l = spanish();
+        assert_eq!(
+            select_language(&l, None, Some("fr;q=0.9, es-MX;q=0.8")),
+            Some("es".to_owned())
+        );
+        assert_eq!(select_language(&l, None, Some("fr, *;q=0.5")), None);
+        assert_eq!(select_language(&l, None, Some("es;q=0, fr")), None);
+        assert_eq!(select_language(&l, None, None), None);
+    }
+
+    #[test]
+    fn query_parameter_wins() {
+        let mut l = spanish();
+        l.translations.push(Translation {
+            lang: "de".into(),
+            message: "could not parse command".into(),
+            text: "Befehl konnte nicht gelesen werden".into(),
+        });
+        assert_eq!(
+            select_language(&l, Some("DE"), Some("es")),
+            Some("de".to_owned())
+        );
+        // an unknown requested language falls back to the header
+        assert_eq!(
+            select_language(&l, Some("ja"), Some("es")),
+            Some("es".to_owned())
+        );
+    }
+
+    #[test]
+    fn localize_keeps_prefix() {
+        let l = spanish();
+        assert_eq!(
+            localize(&l, Some("es"), "invalid: could not parse command"),
+            "invalid: no se pudo interpretar el comando"
+        );
+        assert_eq!(
+            localize(&l, Some("es"), "could not parse command"),
+            "no se pudo interpretar el comando"
+        );
+        // untranslated messages, and clients without a language, get English
+        assert_eq!(localize(&l, Some("es"), "blocked: no"), "blocked: no");
+        assert_eq!(
+            localize(&l, None, "could not parse command"),
+            "could not parse command"
+        );
+    }
+}
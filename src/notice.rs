@@ -1,3 +1,7 @@
+use crate::config::Localization;
+use crate::localization;
+
+#[derive(Debug, Clone, Copy)]
 pub enum EventResultStatus {
     Saved,
     Duplicate,
@@ -6,6 +10,8 @@ pub enum EventResultStatus {
     RateLimited,
     Error,
     Restricted,
+    Quarantined,
+    Pow,
 }
 
 pub struct EventResult {
@@ -24,8 +30,13 @@ impl EventResultStatus {
     #[must_use]
     pub fn to_bool(&self) -> bool {
         match self {
-            Self::Duplicate | Self::Saved => true,
-            Self::Invalid | Self::Blocked | Self::RateLimited | Self::Error | Self::Restricted => false,
+            Self::Duplicate | Self::Saved | Self::Quarantined => true,
+            Self::Invalid
+            | Self::Blocked
+            | Self::RateLimited
+            | Self::Error
+            | Self::Restricted
+            | Self::Pow => false,
         }
     }
 
@@ -39,6 +50,8 @@ impl EventResultStatus {
             Self::RateLimited => "rate-limited",
             Self::Error => "error",
             Self::Restricted => "restricted",
+            Self::Quarantined => "quarantined",
+            Self::Pow => "pow",
         }
     }
 }
@@ -75,7 +88,7 @@ impl Notice {
 
     #[must_use]
     pub fn duplicate(id: String) -> Notice {
-        Notice::prefixed(id, "", EventResultStatus::Duplicate)
+        Notice::prefixed(id, "already have this event", EventResultStatus::Duplicate)
     }
 
     #[must_use]
@@ -88,6 +101,30 @@ impl Notice {
         Notice::prefixed(id, msg, EventResultStatus::Restricted)
     }
 
+    #[must_use]
+    pub fn quarantined(id: String, msg: &str) -> Notice {
+        Notice::prefixed(id, msg, EventResultStatus::Quarantined)
+    }
+
+    #[must_use]
+    pub fn pow(id: String, msg: &str) -> Notice {
+        Notice::prefixed(id, msg, EventResultStatus::Pow)
+    }
+
+    /// This notice with its message translated into `lang`.
+    #[must_use]
+    pub fn localized(&self, l10n: &Localization, lang: Option<&str>) -> Notice {
+        match self {
+            Notice::Message(msg) => Notice::Message(localization::localize(l10n, lang, msg)),
+            Notice::EventResult(res) => Notice::EventResult(EventResult {
+                id: res.id.clone(),
+                msg: localization::localize(l10n, lang, &res.msg),
+                status: res.status,
+            }),
+            Notice::AuthChallenge(challenge) => Notice::AuthChallenge(challenge.clone()),
+        }
+    }
+
     #[must_use]
     pub fn saved(id: String) -> Notice {
         Notice::EventResult(EventResult {
@@ -0,0 +1,158 @@
+//! Holding area for borderline events
+//!
+//! Events that match a soft-block rule are accepted from the client,
+//! but instead of being stored and broadcast, they are held here until
+//! an administrator approves or discards them.  Held events live only
+//! in memory, and are lost if the relay restarts.  The number of held
+//! events is capped, so that a flood of new keys cannot exhaust memory.
+use crate::config::Quarantine as QuarantineSettings;
+use crate::event::Event;
+use regex::Regex;
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tracing::warn;
+
+/// Shared store of quarantined events, keyed by event id.
+#[derive(Debug, Clone, Default)]
+pub struct Quarantine {
+    /// Content patterns that send an event to quarantine
+    patterns: Arc<Vec<Regex>>,
+    /// Quarantine events from authors with nothing stored yet
+    new_authors: bool,
+    /// Most events held at once
+    max_held: usize,
+    /// Events awaiting review
+    held: Arc<RwLock<HashMap<String, Event>>>,
+}
+
+impl Quarantine {
+    /// Build an empty quarantine with the configured rules.  Invalid
+    /// patterns are logged and ignored.
+    #[must_use]
+    pub fn new(settings: &QuarantineSettings) -> Quarantine {
+        let patterns = settings
+            .content_patterns
+            .iter()
+            .filter_map(|p| match Regex::new(p) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    warn!("ignoring invalid quarantine pattern {:?}: {}", p, e);
+                    None
+                }
+            })
+            .collect();
+        Quarantine {
+            patterns: Arc::new(patterns),
+            new_authors: settings.new_authors,
+            max_held: settings.max_held,
+            held: Arc::default(),
+        }
+    }
+
+    /// Are any rules configured?
+    #[must_use]
+    pub fn is_active(&self) -> bool {
+        self.new_authors || !self.patterns.is_empty()
+    }
+
+    /// Are events from authors with no stored events quarantined?
+    #[must_use]
+    pub fn holds_new_authors(&self) -> bool {
+        self.new_authors
+    }
+
+    /// Check if the event content matches a quarantine pattern.
+    #[must_use]
+    pub fn matches_content(&self, event: &Event) -> bool {
+        self.patterns.iter().any(|r| r.is_match(&event.content))
+    }
+
+    /// Hold an event, returning false if the quarantine is full.
+    /// Holding an event that is already held always succeeds.
+    pub fn hold(&self, event: Event) -> bool {
+        self.held
+            .write()
+            .map(|mut m| {
+                if m.len() >= self.max_held && !m.contains_key(&event.id) {
+                    return false;
+                }
+                m.insert(event.id.clone(), event);
+                true
+            })
+            .unwrap_or(false)
+    }
+
+    /// Remove an event from quarantine, returning it if it was held.
+    pub fn release(&self, id: &str) -> Option<Event> {
+        self.held.write().ok().and_then(|mut m| m.remove(id))
+    }
+
+    /// All held events, oldest first.
+    #[must_use]
+    pub fn list(&self) -> Vec<Event> {
+        let mut events: Vec<Event> = self
+            .held
+            .read()
+            .map(|m| m.values().cloned().collect())
+            .unwrap_or_default();
+        events.sort_by(|a, b| a.created_at.cmp(&b.created_at).then(a.id.cmp(&b.id)));
+        events
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings(patterns: &[&str]) -> QuarantineSettings {
+        QuarantineSettings {
+            content_patterns: patterns.iter().map(|p| (*p).to_owned()).collect(),
+            new_authors: false,
+            max_held: 2,
+        }
+    }
+
+    #[test]
+    fn content_rules() {
+        let q = Quarantine::new(&settings(&["(?i)free money", "("]));
+        assert!(q.is_active());
+        let mut event = Event::simple_event();
+        event.content = "Get FREE MONEY now".to_owned();
+        assert!(q.matches_content(&event));
+        event.content = "hello".to_owned();
+        assert!(!q.matches_content(&event));
+        assert!(!Quarantine::new(&settings(&[])).is_active());
+    }
+
+    #[test]
+    fn hold_and_release() {
+        let q = Quarantine::new(&settings(&[]));
+        let mut event = Event::simple_event();
+        event.id = "abcd".to_owned();
+        assert!(q.hold(event.clone()));
+        assert!(q.hold(event));
+        assert_eq!(q.list().len(), 1);
+        assert_eq!(q.release("abcd").map(|e| e.id), Some("abcd".to_owned()));
+        assert!(q.release("abcd").is_none());
+        assert!(q.list().is_empty());
+    }
+
+    #[test]
+    fn hold_refused_when_full() {
+        let q = Quarantine::new(&settings(&[]));
+        let mut event = Event::simple_event();
+        for id in ["a", "b"] {
+            event.id = id.to_owned();
+            assert!(q.hold(event.clone()));
+        }
+        event.id = "c".to_owned();
+        assert!(!q.hold(event.clone()));
+        // held events can still be re-held, and room is made by release
+        event.id = "a".to_owned();
+        assert!(q.hold(event.clone()));
+        q.release("b");
+        event.id = "c".to_owned();
+        assert!(q.hold(event));
+        assert_eq!(q.list().len(), 2);
+    }
+}
@@ -0,0 +1,242 @@
+//! Reloading the configuration while running
+//!
+//! On SIGHUP the relay re-reads its config file, without dropping
+//! client connections.  New connections use the new settings, and
+//! open connections and the database writer pick them up with their
+//! next message.  Settings used to start listeners, database pools
+//! and background tasks keep their startup values; changes to them
+//! are logged as ignored, and need a restart.
+use crate::config::Settings;
+use serde::Serialize;
+use std::sync::Arc;
+use tokio::sync::broadcast::Receiver;
+use tokio::sync::watch;
+use tracing::{error, info, warn};
+
+/// The relay's current settings, shared by everything that reads
+/// them while running.
+#[derive(Debug, Clone)]
+pub struct LiveSettings {
+    current: Arc<watch::Sender<Arc<Settings>>>,
+}
+
+impl LiveSettings {
+    #[must_use]
+    pub fn new(settings: Settings) -> Self {
+        LiveSettings {
+            current: Arc::new(watch::channel(Arc::new(settings)).0),
+        }
+    }
+
+    /// The settings in effect now.
+    #[must_use]
+    pub fn current(&self) -> Arc<Settings> {
+        self.current.borrow().clone()
+    }
+
+    /// Receive the settings each time they are reloaded.
+    #[must_use]
+    pub fn subscribe(&self) -> watch::Receiver<Arc<Settings>> {
+        self.current.subscribe()
+    }
+
+    /// Re-read the config file the current settings came from, and
+    /// apply whatever can change at runtime.  Returns the names of
+    /// the settings whose changes were ignored.  If the file cannot be
+    /// read, or is invalid, the current settings stay in effect.
+    pub fn reload(&self) -> Result<Vec<&'static str>, String> {
+        let current = self.current();
+        let path = current.config_file.clone();
+        // invalid values are caught by assertions, at startup
+        let read = std::panic::catch_unwind(|| Settings::new(&path))
+            .map_err(|_| "invalid settings".to_owned())?;
+        let new = read.map_err(|e| e.to_string())?;
+        let (new, ignored) = keep_startup_settings(&current, new);
+        self.current.send_replace(Arc::new(new));
+        Ok(ignored)
+    }
+}
+
+/// Restore, in newly read settings, those that only take effect at
+/// startup, returning the names of any that had changed.
+#[must_use]
+pub fn keep_startup_settings(
+    current: &Settings,
+    mut new: Settings,
+) -> (Settings, Vec<&'static str>) {
+    let mut ignored = vec![];
+    let c = current;
+    let i = &mut ignored;
+    // listeners, pools and logging
+    keep("network", &c.network, &mut new.network, i);
+    keep("database", &c.database, &mut new.database, i);
+    keep("grpc", &c.grpc, &mut new.grpc, i);
+    keep("logging", &c.logging, &mut new.logging, i);
+    keep("diagnostics", &c.diagnostics, &mut new.diagnostics, i);
+    // background tasks
+    keep("federation", &c.federation, &mut new.federation, i);
+    keep("maintenance", &c.maintenance, &mut new.maintenance, i);
+    keep("retention", &c.retention, &mut new.retention, i);
+    keep("announcement", &c.announcement, &mut new.announcement, i);
+    keep(
+        "verified_users",
+        &c.verified_users,
+        &mut new.verified_users,
+        i,
+    );
+    keep("pay_to_relay", &c.pay_to_relay, &mut new.pay_to_relay, i);
+    keep("quarantine", &c.quarantine, &mut new.quarantine, i);
+    // limits shared by all connections, and by the database
+    let (cl, nl) = (&c.limits, &mut new.limits);
+    keep(
+        "limits.max_connections",
+        &cl.max_connections,
+        &mut nl.max_connections,
+        i,
+    );
+    keep(
+        "limits.max_connections_per_ip",
+        &cl.max_connections_per_ip,
+        &mut nl.max_connections_per_ip,
+        i,
+    );
+    keep(
+        "limits.subscription_creation_rate",
+        &cl.subscription_creation_rate,
+        &mut nl.subscription_creation_rate,
+        i,
+    );
+    keep(
+        "limits.subscription_creation_burst",
+        &cl.subscription_creation_burst,
+        &mut nl.subscription_creation_burst,
+        i,
+    );
+    keep(
+        "limits.broadcast_buffer",
+        &cl.broadcast_buffer,
+        &mut nl.broadcast_buffer,
+        i,
+    );
+    keep(
+        "limits.event_persist_buffer",
+        &cl.event_persist_buffer,
+        &mut nl.event_persist_buffer,
+        i,
+    );
+    keep("limits.max_limit", &cl.max_limit, &mut nl.max_limit, i);
+    keep(
+        "limits.limit_boundary",
+        &cl.limit_boundary,
+        &mut nl.limit_boundary,
+        i,
+    );
+    keep(
+        "options.unindexed_kinds",
+        &c.options.unindexed_kinds,
+        &mut new.options.unindexed_kinds,
+        i,
+    );
+    new.config_file = current.config_file.clone();
+    (new, ignored)
+}
+
+/// Put back the current value of a setting, if it changed.
+fn keep<T: Serialize + Clone>(
+    name: &'static str,
+    current: &T,
+    new: &mut T,
+    ignored: &mut Vec<&'static str>,
+) {
+    if serde_json::to_value(current).ok() != serde_json::to_value(&*new).ok() {
+        ignored.push(name);
+        *new = current.clone();
+    }
+}
+
+/// Reload the settings each time the process receives SIGHUP, until
+/// shutdown.
+pub async fn reload_on_hangup(live: LiveSettings, mut shutdown: Receiver<()>) {
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(s) => s,
+        Err(e) => {
+            warn!(
+                "could not listen for SIGHUP; configuration reloads are disabled: {}",
+                e
+            );
+            return;
+        }
+    };
+    loop {
+        tokio::select! {
+            _ = shutdown.recv() => return,
+            _ = hangup.recv() => {
+                info!("reloading configuration due to SIGHUP");
+                match live.reload() {
+                    Ok(ignored) => {
+                        for name in ignored {
+                            warn!("ignoring change to {} until restart", name);
+                        }
+                        info!("configuration reloaded");
+                    }
+                    Err(e) => error!("could not reload configuration, keeping the current one: {}", e),
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::admission::reject_client_event;
+    use crate::event::Event;
+    use crate::utils::unix_time;
+
+    #[test]
+    fn reload_applies_new_limits() {
+        let dir = std::env::temp_dir().join(format!("relay-reload-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        let write = |future: u64, port: u16| {
+            let toml = format!(
+                "[network]\nport = {port}\n\n[options]\nreject_future_seconds = {future}\n"
+            );
+            std::fs::write(&path, toml).unwrap();
+        };
+        write(3600, 8080);
+        let live =
+            LiveSettings::new(Settings::new(&Some(path.to_string_lossy().into_owned())).unwrap());
+        let mut changes = live.subscribe();
+        let mut event = Event::simple_event();
+        event.kind = 1;
+        event.created_at = unix_time() + 600;
+        assert!(reject_client_event(&event, &live.current(), "cid").is_none());
+        // tighten the limit, and try to move the listener
+        write(60, 9090);
+        let ignored = live.reload().unwrap();
+        assert_eq!(ignored, vec!["network"]);
+        assert!(changes.has_changed().unwrap());
+        let settings = changes.borrow_and_update().clone();
+        assert_eq!(settings.options.reject_future_seconds, Some(60));
+        assert_eq!(settings.network.port, 8080);
+        assert!(reject_client_event(&event, &settings, "cid").is_some());
+        // a broken file leaves the settings as they are
+        std::fs::write(&path, "[options\n").unwrap();
+        assert!(live.reload().is_err());
+        assert_eq!(live.current().options.reject_future_seconds, Some(60));
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn limits_fields_kept() {
+        let current = Settings::default();
+        let mut new = Settings::default();
+        new.limits.max_connections = Some(10);
+        new.limits.messages_per_sec = Some(5);
+        let (kept, ignored) = keep_startup_settings(&current, new);
+        assert_eq!(ignored, vec!["limits.max_connections"]);
+        assert_eq!(kept.limits.max_connections, None);
+        assert_eq!(kept.limits.messages_per_sec, Some(5));
+    }
+}
@@ -1,13 +1,20 @@
+use crate::config::LimitBoundary;
 use crate::db::QueryResult;
 use crate::error::Result;
-use crate::event::Event;
+use crate::event::{single_char_tagname, Event};
 use crate::nip05::VerificationRecord;
 use crate::payment::{InvoiceInfo, InvoiceStatus};
-use crate::subscription::Subscription;
+use crate::subscription::{ReqFilter, Subscription};
 use crate::utils::unix_time;
 use async_trait::async_trait;
+use futures::Stream;
 use nostr::Keys;
 use rand::Rng;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, oneshot};
 
 pub mod postgres;
 pub mod postgres_migration;
@@ -86,6 +93,284 @@ pub trait NostrRepo: Send + Sync {
     /// Get the most recent invoice for a given pubkey
     /// invoice must be unpaid and not expired
     async fn get_unpaid_invoice(&self, pubkey: &Keys) -> Result<Option<InvoiceInfo>>;
+
+    /// Add a pubkey to the persistent ban list
+    async fn ban_pubkey(&self, pubkey: &str) -> Result<()>;
+
+    /// Get all pubkeys on the persistent ban list
+    async fn get_banned_pubkeys(&self) -> Result<Vec<String>>;
+
+    /// Delete all events authored by a pubkey, returning the number removed
+    async fn delete_author_events(&self, pubkey: &str) -> Result<u64>;
+
+    /// Get the most recent events referencing a value in any indexed
+    /// tag, regardless of the tag name.
+    async fn events_with_tag_value(&self, value: &str, limit: u64) -> Result<Vec<Event>>;
+
+    /// Get the newest `created_at` of stored events for each of the
+    /// given authors.  Authors with no stored events are omitted.
+    async fn latest_created_at_for(&self, pubkeys: &[String]) -> Result<HashMap<String, u64>>;
+
+    /// Estimate how many stored events a subscription would return.
+    ///
+    /// Counting may stop early once the projection exceeds `budget`.
+    async fn count_projected_results(&self, sub: &Subscription, budget: u64) -> Result<u64>;
+
+    /// Find which of the given event ids were removed by a NIP-09
+    /// deletion, mapping each to the id of the deletion event.
+    async fn tombstones_for(&self, ids: &[String]) -> Result<HashMap<String, String>>;
+
+    /// Summarize the space used by stored events (optionally, only
+    /// those of one author), including the `top` largest events.
+    async fn storage_stats(&self, author: Option<&str>, top: u64) -> Result<StorageStats>;
+
+    /// Count tag index rows whose event no longer exists, deleting
+    /// them if `remove` is set.
+    async fn orphaned_tags(&self, remove: bool) -> Result<u64>;
+
+    /// Get the current version of each replaceable and parameterized
+    /// replaceable event of an author, ordered by kind and `d` tag.
+    async fn current_replaceable_for(&self, pubkey: &str) -> Result<Vec<Event>>;
+}
+
+/// Space used by stored events.  Sizes are of the serialized event
+/// JSON, the same measure that `max_event_bytes` limits on input.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct StorageStats {
+    pub events: u64,
+    pub total_bytes: u64,
+    pub average_bytes: u64,
+    pub largest: Vec<EventSize>,
+}
+
+/// Serialized size of a single stored event
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct EventSize {
+    pub id: String,
+    pub bytes: u64,
+}
+
+impl StorageStats {
+    #[must_use]
+    pub fn new(events: u64, total_bytes: u64, largest: Vec<EventSize>) -> StorageStats {
+        StorageStats {
+            events,
+            total_bytes,
+            average_bytes: total_bytes.checked_div(events).unwrap_or(0),
+            largest,
+        }
+    }
+}
+
+/// Query result sentinel indicating a filter's results were capped
+/// by the relay's `max_limit`.
+pub const TRUNCATED_SENTINEL: &str = "TRUNCATED";
+
+/// Prefix of the query result sentinel reporting the last sequence
+/// number delivered to filters using `after_seq`.
+const CURSOR_SENTINEL_PREFIX: &str = "CURSOR:";
+
+/// Query result sentinel for a cursor at sequence `seq`.
+#[must_use]
+pub fn cursor_sentinel(seq: u64) -> String {
+    format!("{CURSOR_SENTINEL_PREFIX}{seq}")
+}
+
+/// The sequence number carried by a cursor sentinel, if this is one.
+#[must_use]
+pub fn parse_cursor_sentinel(result: &str) -> Option<u64> {
+    result
+        .strip_prefix(CURSOR_SENTINEL_PREFIX)
+        .and_then(|s| s.parse().ok())
+}
+
+/// Subscription and client id used for programmatic queries
+const LIBRARY_QUERY_ID: &str = "library";
+
+/// Query stored events matching any of the filters, without a client
+/// connection.
+///
+/// Results are exactly those a `REQ` with the same filters would
+/// receive before `EOSE`, in the same order: each filter is capped by
+/// its `limit` and the relay's `max_limit`, and hidden or expired
+/// events are never returned.  The stream ends after the last stored
+/// match.  Must be called from within a tokio runtime.
+pub fn query(repo: Arc<dyn NostrRepo>, filters: &[ReqFilter]) -> impl Stream<Item = Event> {
+    let sub = Subscription {
+        id: LIBRARY_QUERY_ID.to_owned(),
+        filters: filters.to_vec(),
+    };
+    let (query_tx, query_rx) = mpsc::channel::<QueryResult>(1000);
+    let (_abandon_query_tx, abandon_query_rx) = oneshot::channel::<()>();
+    tokio::spawn(async move {
+        repo.query_subscription(sub, LIBRARY_QUERY_ID.to_owned(), query_tx, abandon_query_rx)
+            .await
+            .ok();
+    });
+    futures::stream::unfold(query_rx, |mut query_rx| async move {
+        // results end with EOSE, or the channel closing if the query was shed
+        while let Some(r) = query_rx.recv().await {
+            if r.event == "EOSE" {
+                return None;
+            }
+            if r.event == TRUNCATED_SENTINEL || parse_cursor_sentinel(&r.event).is_some() {
+                continue;
+            }
+            if let Ok(e) = serde_json::from_str::<Event>(&r.event) {
+                return Some((e, query_rx));
+            }
+        }
+        None
+    })
+}
+
+/// SQL condition selecting replaceable and parameterized replaceable
+/// kinds, matching [`Event::is_replaceable`] and
+/// [`Event::is_param_replaceable`].
+const REPLACEABLE_KINDS_SQL: &str =
+    "(kind IN (0,3,41) OR (kind>=10000 AND kind<20000) OR (kind>=30000 AND kind<40000))";
+
+/// Keep only the current version of each replaceable event: the newest
+/// for its kind (and `d` tag), with ties going to the lowest id.
+/// Anything that is not replaceable is dropped.
+#[must_use]
+pub fn current_versions(events: Vec<Event>) -> Vec<Event> {
+    let mut current: BTreeMap<(u64, String), Event> = BTreeMap::new();
+    for e in events {
+        if !e.is_replaceable() && !e.is_param_replaceable() {
+            continue;
+        }
+        let key = (e.kind, e.distinct_param().unwrap_or_default());
+        match current.get(&key) {
+            Some(c) if (c.created_at, &e.id) >= (e.created_at, &c.id) => {}
+            _ => {
+                current.insert(key, e);
+            }
+        }
+    }
+    current.into_values().collect()
+}
+
+/// The (name, value) pairs of an event's tags to store in the tag
+/// table, in order.  The first `d` tag of a parameterized replaceable
+/// event always has a row holding its identifier, even when the tag is
+/// missing or has no value, so replacement can find it.
+#[must_use]
+pub fn stored_tags(e: &Event) -> Vec<(&str, &str)> {
+    let identifier = e.replaceable_identifier().map(|(_, _, d)| d);
+    let mut seen_d = false;
+    let mut tags = vec![];
+    for tag in &e.tags {
+        let first_d = tag.first().map_or(false, |name| name == "d") && !seen_d;
+        if first_d {
+            seen_d = true;
+            if let Some(d) = identifier {
+                tags.push(("d", d));
+                continue;
+            }
+        }
+        if tag.len() >= 2 {
+            tags.push((tag[0].as_str(), tag[1].as_str()));
+        }
+    }
+    if let (Some(d), false) = (identifier, seen_d) {
+        tags.push(("d", d));
+    }
+    tags
+}
+
+/// Whether a tag of an event is written to the tag index.  Only
+/// single-letter tags are indexed, and then only for kinds not listed
+/// in `unindexed_kinds` -- except the `d` tag of parameterized
+/// replaceable events, which replacement relies on.
+pub(crate) fn index_tag(e: &Event, tag_name: &str, unindexed_kinds: &[u64]) -> bool {
+    single_char_tagname(tag_name).is_some()
+        && (!unindexed_kinds.contains(&e.kind) || (tag_name == "d" && e.is_param_replaceable()))
+}
+
+/// Apply the relay-wide result cap to a filter.
+///
+/// If the cap applies, the returned filter requests one extra row
+/// (so truncation can be detected), and the cap is returned.
+/// Capped filters without a limit are served most-recent first, like
+/// any other limited filter.
+pub(crate) fn cap_filter(f: &ReqFilter, max_limit: Option<u64>) -> (ReqFilter, Option<u64>) {
+    match max_limit {
+        Some(cap) if f.limit.map_or(true, |l| l > cap) => {
+            let mut capped = f.clone();
+            capped.limit = Some(cap + 1);
+            (capped, Some(cap))
+        }
+        _ => (f.clone(), None),
+    }
+}
+
+/// Should a (capped) filter return every event sharing the timestamp
+/// of the last event within its limit?  Only a client's own limits
+/// are extended; limits set by the relay's cap, and sequence pages,
+/// are always cut.
+pub(crate) fn includes_ties(boundary: LimitBoundary, f: &ReqFilter, cap: Option<u64>) -> bool {
+    boundary == LimitBoundary::Ties && cap.is_none() && f.limit.is_some() && !f.uses_sequence()
+}
+
+/// Windows of `created_at` a filter is read in, oldest first.
+///
+/// An unlimited filter is split into consecutive windows of `window`
+/// seconds, spanning `bounds` (the oldest and newest stored
+/// timestamps) within the filter's own `since`/`until`.  The windows
+/// do not overlap, and leave no gaps; the first and last keep the
+/// filter's own bounds, so events stored during the read are not
+/// missed.  Any
+/// other filter is read whole.
+pub(crate) fn chunk_filter(
+    f: &ReqFilter,
+    window: Option<u64>,
+    bounds: Option<(u64, u64)>,
+) -> Box<dyn Iterator<Item = ReqFilter> + Send + '_> {
+    let (window, (oldest, newest)) = match (window, bounds) {
+        (Some(w), Some(b)) if w > 0 && is_chunkable(f) => (w, b),
+        _ => return Box::new(std::iter::once(f.clone())),
+    };
+    let start = f.since.map_or(oldest, |s| s.max(oldest));
+    let end = f.until.map_or(newest, |u| u.min(newest));
+    if start >= end {
+        return Box::new(std::iter::once(f.clone()));
+    }
+    let starts = (start..=end).step_by(usize::try_from(window).unwrap_or(usize::MAX));
+    Box::new(starts.map(move |since| {
+        let last = since.saturating_add(window - 1);
+        ReqFilter {
+            since: if since == start { f.since } else { Some(since) },
+            until: if last >= end { f.until } else { Some(last) },
+            ..f.clone()
+        }
+    }))
+}
+
+/// Can a filter be read in `created_at` windows?  Limited filters are
+/// read newest first, sequence pages in insertion order, and id
+/// lookups are cheap already.
+pub(crate) fn is_chunkable(f: &ReqFilter) -> bool {
+    f.limit.is_none() && !f.uses_sequence() && f.ids.is_none() && !f.force_no_match
+}
+
+/// Describe a stored-event query that exceeded the slow-query
+/// threshold, or `None` if it was fast enough (or logging is disabled).
+pub(crate) fn slow_query_message(
+    f: &ReqFilter,
+    rows: u64,
+    elapsed: Duration,
+    threshold_ms: Option<u64>,
+) -> Option<String> {
+    let threshold = Duration::from_millis(threshold_ms?);
+    if elapsed <= threshold {
+        return None;
+    }
+    let filter = serde_json::to_string(f).unwrap_or_else(|_| format!("{:?}", f));
+    Some(format!(
+        "slow query: {} (rows: {}, duration: {:?}, threshold: {:?})",
+        filter, rows, elapsed, threshold
+    ))
 }
 
 // Current time, with a slight forward jitter in seconds
@@ -96,3 +381,188 @@ pub(crate) fn now_jitter(sec: u64) -> u64 {
     let now = unix_time();
     now.saturating_add(jitter_amount)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unindexed_kinds_keep_d_tag() {
+        let mut e = Event::simple_event();
+        e.kind = 7;
+        assert!(index_tag(&e, "e", &[]));
+        assert!(!index_tag(&e, "e", &[7]));
+        assert!(!index_tag(&e, "alt", &[]));
+        e.kind = 30_007;
+        assert!(!index_tag(&e, "e", &[30_007]));
+        assert!(index_tag(&e, "d", &[30_007]));
+    }
+
+    #[test]
+    fn stored_tags_include_identifier() {
+        let mut e = Event::simple_event();
+        let tag = |t: &[&str]| t.iter().map(|s| (*s).to_owned()).collect::<Vec<String>>();
+        e.kind = 30_000;
+        e.tags = vec![tag(&["t", "a"]), tag(&["p"])];
+        assert_eq!(stored_tags(&e), vec![("t", "a"), ("d", "")]);
+        e.tags = vec![tag(&["d"]), tag(&["d", "x"])];
+        assert_eq!(stored_tags(&e), vec![("d", ""), ("d", "x")]);
+        // other kinds store tags with values, as given
+        e.kind = 1;
+        assert_eq!(stored_tags(&e), vec![("d", "x")]);
+    }
+
+    #[test]
+    fn current_versions_per_kind_and_d_tag() {
+        let event = |id: &str, kind: u64, created_at: u64, d: Option<&str>| {
+            let mut e = Event::simple_event();
+            e.id = id.repeat(64);
+            e.kind = kind;
+            e.created_at = created_at;
+            e.tags = d.map_or(vec![], |d| vec![vec!["d".to_owned(), d.to_owned()]]);
+            e
+        };
+        let current = current_versions(vec![
+            event("2", 0, 10, None),
+            event("1", 0, 10, None),
+            event("3", 30_000, 5, None),
+            event("4", 30_000, 6, Some("")),
+            event("5", 30_000, 1, Some("x")),
+            event("6", 1, 50, None),
+        ]);
+        let ids: Vec<&str> = current.iter().map(|e| &e.id[..1]).collect();
+        // ties go to the lowest id, and a missing d tag is the same as an empty one
+        assert_eq!(ids, vec!["1", "4", "5"]);
+    }
+
+    #[test]
+    fn chunks_cover_range_once() {
+        let f = ReqFilter {
+            kinds: Some(vec![1]),
+            since: Some(105),
+            ..ReqFilter::default()
+        };
+        let chunks: Vec<(Option<u64>, Option<u64>)> = chunk_filter(&f, Some(10), Some((100, 130)))
+            .map(|c| {
+                assert_eq!(c.kinds, f.kinds);
+                (c.since, c.until)
+            })
+            .collect();
+        // each window starts right after the previous one, and the
+        // last is left open
+        assert_eq!(
+            chunks,
+            vec![
+                (Some(105), Some(114)),
+                (Some(115), Some(124)),
+                (Some(125), None)
+            ]
+        );
+        // the filter's own until is kept
+        let f = ReqFilter {
+            until: Some(112),
+            ..ReqFilter::default()
+        };
+        let chunks: Vec<(Option<u64>, Option<u64>)> = chunk_filter(&f, Some(10), Some((100, 130)))
+            .map(|c| (c.since, c.until))
+            .collect();
+        assert_eq!(chunks, vec![(None, Some(109)), (Some(110), Some(112))]);
+    }
+
+    #[test]
+    fn filters_read_whole() {
+        let limited = ReqFilter {
+            limit: Some(10),
+            ..ReqFilter::default()
+        };
+        let unlimited = ReqFilter::default();
+        for (f, window, bounds) in [
+            (&limited, Some(10), Some((0, 1000))),
+            (&unlimited, None, Some((0, 1000))),
+            (&unlimited, Some(0), Some((0, 1000))),
+            // nothing stored, or a single timestamp
+            (&unlimited, Some(10), None),
+            (&unlimited, Some(10), Some((50, 50))),
+        ] {
+            let chunks: Vec<ReqFilter> = chunk_filter(f, window, bounds).collect();
+            assert_eq!(chunks, vec![f.clone()]);
+        }
+    }
+
+    #[test]
+    fn cap_filter_none() {
+        let f = ReqFilter {
+            limit: Some(5000),
+            ..ReqFilter::default()
+        };
+        let (capped, cap) = cap_filter(&f, None);
+        assert_eq!(capped, f);
+        assert_eq!(cap, None);
+    }
+
+    #[test]
+    fn cap_filter_under_cap() {
+        let f = ReqFilter {
+            limit: Some(10),
+            ..ReqFilter::default()
+        };
+        let (capped, cap) = cap_filter(&f, Some(100));
+        assert_eq!(capped.limit, Some(10));
+        assert_eq!(cap, None);
+    }
+
+    #[test]
+    fn cap_filter_over_cap() {
+        let f = ReqFilter {
+            limit: Some(500),
+            ..ReqFilter::default()
+        };
+        let (capped, cap) = cap_filter(&f, Some(100));
+        assert_eq!(capped.limit, Some(101));
+        assert_eq!(cap, Some(100));
+    }
+
+    #[test]
+    fn cap_filter_unlimited() {
+        let f = ReqFilter::default();
+        let (capped, cap) = cap_filter(&f, Some(100));
+        assert_eq!(capped.limit, Some(101));
+        assert_eq!(cap, Some(100));
+    }
+
+    #[test]
+    fn slow_query_under_threshold() {
+        let f = ReqFilter::default();
+        assert_eq!(
+            slow_query_message(&f, 3, Duration::from_millis(10), Some(100)),
+            None
+        );
+        // disabled entirely
+        assert_eq!(
+            slow_query_message(&f, 3, Duration::from_secs(60), None),
+            None
+        );
+    }
+
+    #[test]
+    fn slow_query_over_threshold() {
+        let f = ReqFilter {
+            kinds: Some(vec![1]),
+            limit: Some(20),
+            ..ReqFilter::default()
+        };
+        let msg = slow_query_message(&f, 17, Duration::from_millis(250), Some(100)).unwrap();
+        assert!(msg.contains(r#""kinds":[1]"#));
+        assert!(msg.contains(r#""limit":20"#));
+        assert!(msg.contains("rows: 17"));
+        assert!(msg.contains("duration: 250ms"));
+    }
+
+    #[test]
+    fn cursor_sentinel_round_trip() {
+        assert_eq!(parse_cursor_sentinel(&cursor_sentinel(17)), Some(17));
+        assert_eq!(parse_cursor_sentinel(TRUNCATED_SENTINEL), None);
+        assert_eq!(parse_cursor_sentinel("EOSE"), None);
+        assert_eq!(parse_cursor_sentinel(r#"{"id":"CURSOR:1"}"#), None);
+    }
+}
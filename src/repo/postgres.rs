@@ -1,9 +1,14 @@
+use crate::config::LimitBoundary;
 use crate::db::QueryResult;
 use crate::error::Result;
-use crate::event::{single_char_tagname, Event};
+use crate::event::Event;
 use crate::nip05::{Nip05Name, VerificationRecord};
 use crate::payment::{InvoiceInfo, InvoiceStatus};
-use crate::repo::{now_jitter, NostrRepo};
+use crate::repo::{
+    cap_filter, current_versions, cursor_sentinel, includes_ties, index_tag, now_jitter,
+    slow_query_message, stored_tags, EventSize, NostrRepo, StorageStats, REPLACEABLE_KINDS_SQL,
+    TRUNCATED_SENTINEL,
+};
 use crate::subscription::{ReqFilter, Subscription};
 use async_std::stream::StreamExt;
 use async_trait::async_trait;
@@ -11,6 +16,7 @@ use chrono::{DateTime, TimeZone, Utc};
 use sqlx::postgres::PgRow;
 use sqlx::Error::RowNotFound;
 use sqlx::{Error, Execute, FromRow, Postgres, QueryBuilder, Row};
+use std::collections::HashMap;
 use std::time::{Duration, Instant};
 
 use crate::error;
@@ -26,18 +32,37 @@ use tracing::{debug, error, info, warn};
 
 pub type PostgresPool = sqlx::pool::Pool<Postgres>;
 
+/// Results returned for a single filter, if `max_limit` is not configured
+const DEFAULT_MAX_LIMIT: u64 = 1000;
+
 pub struct PostgresRepo {
     conn: PostgresPool,
     conn_write: PostgresPool,
     metrics: NostrMetrics,
+    max_limit: u64,
+    limit_boundary: LimitBoundary,
+    slow_query_threshold_ms: Option<u64>,
+    unindexed_kinds: Vec<u64>,
 }
 
 impl PostgresRepo {
-    pub fn new(c: PostgresPool, cw: PostgresPool, m: NostrMetrics) -> PostgresRepo {
+    pub fn new(
+        c: PostgresPool,
+        cw: PostgresPool,
+        m: NostrMetrics,
+        max_limit: Option<u64>,
+        limit_boundary: LimitBoundary,
+        slow_query_threshold_ms: Option<u64>,
+        unindexed_kinds: Vec<u64>,
+    ) -> PostgresRepo {
         PostgresRepo {
             conn: c,
             conn_write: cw,
             metrics: m,
+            max_limit: max_limit.unwrap_or(DEFAULT_MAX_LIMIT),
+            limit_boundary,
+            slow_query_threshold_ms,
+            unindexed_kinds,
         }
     }
 }
@@ -104,13 +129,15 @@ impl NostrRepo for PostgresRepo {
         let event_str = serde_json::to_string(&e).unwrap();
 
         // determine if this event would be shadowed by an existing
-        // replaceable event or parameterized replaceable event.
+        // replaceable event or parameterized replaceable event.  Of two
+        // events with the same timestamp, the lowest id wins.
         if e.is_replaceable() {
             let repl_count = sqlx::query(
-                "SELECT e.id FROM event e WHERE e.pub_key=$1 AND e.kind=$2 AND e.created_at >= $3 LIMIT 1;")
+                "SELECT e.id FROM event e WHERE e.pub_key=$1 AND e.kind=$2 AND (e.created_at > $3 OR (e.created_at = $3 AND e.id <= $4)) LIMIT 1;")
                 .bind(&pubkey_blob)
                 .bind(e.kind as i64)
                 .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+                .bind(&id_blob)
                 .fetch_optional(&mut tx)
                 .await?;
             if repl_count.is_some() {
@@ -120,20 +147,22 @@ impl NostrRepo for PostgresRepo {
         if let Some(d_tag) = e.distinct_param() {
             let repl_count: i64 = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
                 sqlx::query_scalar(
-                    "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value_hex=$3 AND e.created_at >= $4 LIMIT 1;")
+                    "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value_hex=$3 AND t.id=(SELECT MIN(t2.id) FROM tag t2 WHERE t2.event_id=e.id AND t2.name='d') AND (e.created_at > $4 OR (e.created_at = $4 AND e.id <= $5)) LIMIT 1;")
                     .bind(hex::decode(&e.pubkey).ok())
                     .bind(e.kind as i64)
                     .bind(hex::decode(d_tag).ok())
                     .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+                    .bind(&id_blob)
                     .fetch_one(&mut tx)
                     .await?
             } else {
                 sqlx::query_scalar(
-                    "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value=$3 AND e.created_at >= $4 LIMIT 1;")
+                    "SELECT count(*) AS count FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.pub_key=$1 AND e.kind=$2 AND t.name='d' AND t.value=$3 AND t.id=(SELECT MIN(t2.id) FROM tag t2 WHERE t2.event_id=e.id AND t2.name='d') AND (e.created_at > $4 OR (e.created_at = $4 AND e.id <= $5)) LIMIT 1;")
                     .bind(hex::decode(&e.pubkey).ok())
                     .bind(e.kind as i64)
                     .bind(d_tag.as_bytes())
                     .bind(Utc.timestamp_opt(e.created_at as i64, 0).unwrap())
+                    .bind(&id_blob)
                     .fetch_one(&mut tx)
                     .await?
             };
@@ -146,9 +175,10 @@ impl NostrRepo for PostgresRepo {
         }
         // ignore if the event hash is a duplicate.
         let mut ins_count = sqlx::query(
-            r#"INSERT INTO "event"
-(id, pub_key, created_at, expires_at, kind, "content", delegated_by)
-VALUES($1, $2, $3, $4, $5, $6, $7)
+            r#"WITH s AS (UPDATE event_sequence SET last = last + 1 RETURNING last)
+INSERT INTO "event"
+(id, pub_key, created_at, expires_at, kind, "content", delegated_by, seq)
+SELECT $1, $2, $3, $4, $5, $6, $7, s.last FROM s
 ON CONFLICT (id) DO NOTHING"#,
         )
         .bind(&id_blob)
@@ -172,42 +202,33 @@ ON CONFLICT (id) DO NOTHING"#,
         }
 
         // add all tags to the tag table
-        for tag in e.tags.iter() {
-            // ensure we have 2 values.
-            if tag.len() >= 2 {
-                let tag_name = &tag[0];
-                let tag_val = &tag[1];
-                // only single-char tags are searchable
-                let tag_char_opt = single_char_tagname(tag_name);
-                match &tag_char_opt {
-                    Some(_) => {
-                        // if tag value is lowercase hex;
-                        if is_lower_hex(tag_val) && (tag_val.len() % 2 == 0) {
-                            sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, NULL, $3) \
-                    ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
-                                .bind(&id_blob)
-                                .bind(tag_name)
-                                .bind(hex::decode(tag_val).ok())
-                                .execute(&mut tx)
-                                .await
-                                .unwrap();
-                        } else {
-                            sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, $3, NULL) \
-                    ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
-                                .bind(&id_blob)
-                                .bind(tag_name)
-                                .bind(tag_val.as_bytes())
-                                .execute(&mut tx)
-                                .await
-                                .unwrap();
-                        }
-                    }
-                    None => {}
+        for (tag_name, tag_val) in stored_tags(e) {
+            // only single-char tags are searchable
+            if index_tag(e, tag_name, &self.unindexed_kinds) {
+                // if tag value is lowercase hex;
+                if is_lower_hex(tag_val) && (tag_val.len() % 2 == 0) {
+                    sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, NULL, $3) \
+            ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
+                        .bind(&id_blob)
+                        .bind(tag_name)
+                        .bind(hex::decode(tag_val).ok())
+                        .execute(&mut tx)
+                        .await
+                        .unwrap();
+                } else {
+                    sqlx::query("INSERT INTO tag (event_id, \"name\", value, value_hex) VALUES($1, $2, $3, NULL) \
+            ON CONFLICT (event_id, \"name\", value, value_hex) DO NOTHING")
+                        .bind(&id_blob)
+                        .bind(tag_name)
+                        .bind(tag_val.as_bytes())
+                        .execute(&mut tx)
+                        .await
+                        .unwrap();
                 }
             }
         }
         if e.is_replaceable() {
-            let update_count = sqlx::query("DELETE FROM \"event\" WHERE kind=$1 and pub_key = $2 and id not in (select id from \"event\" where kind=$1 and pub_key=$2 order by created_at desc limit 1);")
+            let update_count = sqlx::query("DELETE FROM \"event\" WHERE kind=$1 and pub_key = $2 and id not in (select id from \"event\" where kind=$1 and pub_key=$2 order by created_at desc, id asc limit 1);")
                 .bind(e.kind as i64)
                 .bind(hex::decode(&e.pubkey).ok())
                 .execute(&mut tx)
@@ -225,14 +246,14 @@ ON CONFLICT (id) DO NOTHING"#,
         // check for parameterized replaceable events that would be hidden; don't insert these either.
         if let Some(d_tag) = e.distinct_param() {
             let update_count = if is_lower_hex(&d_tag) && (d_tag.len() % 2 == 0) {
-                sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value_hex=$3 ORDER BY created_at DESC OFFSET 1);")
+                sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value_hex=$3 AND t.id=(SELECT MIN(t2.id) FROM tag t2 WHERE t2.event_id=e.id AND t2.name='d') ORDER BY e.created_at DESC, e.id ASC OFFSET 1);")
                     .bind(e.kind as i64)
                     .bind(hex::decode(&e.pubkey).ok())
                     .bind(hex::decode(d_tag).ok())
                     .execute(&mut tx)
                     .await?.rows_affected()
             } else {
-                sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value=$3 ORDER BY created_at DESC OFFSET 1);")
+                sqlx::query("DELETE FROM event WHERE kind=$1 AND pub_key=$2 AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=$1 AND e.pub_key=$2 AND t.name='d' AND t.value=$3 AND t.id=(SELECT MIN(t2.id) FROM tag t2 WHERE t2.event_id=e.id AND t2.name='d') ORDER BY e.created_at DESC, e.id ASC OFFSET 1);")
                     .bind(e.kind as i64)
                     .bind(hex::decode(&e.pubkey).ok())
                     .bind(d_tag.as_bytes())
@@ -324,11 +345,20 @@ ON CONFLICT (id) DO NOTHING"#,
         let start = Instant::now();
         let mut row_count: usize = 0;
         let metrics = &self.metrics;
+        // highest sequence number delivered, for filters using after_seq
+        let mut cursor: Option<u64> = None;
 
         for filter in sub.filters.iter() {
+            if let Some(after_seq) = filter.after_seq {
+                cursor = Some(cursor.map_or(after_seq, |c: u64| c.max(after_seq)));
+            }
             let start = Instant::now();
             // generate SQL query
-            let q_filter = query_from_filter(filter);
+            let (filter, cap) = cap_filter(filter, Some(self.max_limit));
+            let mut filter_rows: u64 = 0;
+            let mut truncated = false;
+            let ties = includes_ties(self.limit_boundary, &filter, cap);
+            let q_filter = query_from_filter(&filter, ties);
             if q_filter.is_none() {
                 debug!("Failed to generate query!");
                 continue;
@@ -394,8 +424,19 @@ ON CONFLICT (id) DO NOTHING"#,
                     return Ok(());
                 }
 
+                // stop at the relay's cap; an extra row means we truncated.
+                if cap.map_or(false, |c| filter_rows >= c) {
+                    truncated = true;
+                    break;
+                }
+                filter_rows += 1;
                 row_count += 1;
-                let event_json: Vec<u8> = row.unwrap().get(0);
+                let row = row.unwrap();
+                let event_json: Vec<u8> = row.get(0);
+                if filter.uses_sequence() {
+                    let seq: i64 = row.get(2);
+                    cursor = cursor.map(|c| c.max(seq as u64));
+                }
                 loop {
                     if query_tx.capacity() != 0 {
                         // we have capacity to add another item
@@ -429,6 +470,32 @@ ON CONFLICT (id) DO NOTHING"#,
                     .ok();
                 last_successful_send = Instant::now();
             }
+            if truncated {
+                query_tx
+                    .send(QueryResult {
+                        sub_id: sub.get_id(),
+                        event: TRUNCATED_SENTINEL.to_string(),
+                    })
+                    .await
+                    .ok();
+            }
+            if let Some(msg) = slow_query_message(
+                &filter,
+                filter_rows,
+                start.elapsed(),
+                self.slow_query_threshold_ms,
+            ) {
+                warn!("{} (cid: {}, sub: {:?})", msg, client_id, sub.id);
+            }
+        }
+        if let Some(seq) = cursor {
+            query_tx
+                .send(QueryResult {
+                    sub_id: sub.get_id(),
+                    event: cursor_sentinel(seq),
+                })
+                .await
+                .ok();
         }
         query_tx
             .send(QueryResult {
@@ -716,16 +783,214 @@ LIMIT 1;
             None => Ok(None),
         }
     }
+
+    /// Ban a pubkey
+    async fn ban_pubkey(&self, pubkey: &str) -> Result<()> {
+        sqlx::query("INSERT INTO pubkey_ban (pubkey) VALUES ($1) ON CONFLICT (pubkey) DO NOTHING")
+            .bind(pubkey)
+            .execute(&self.conn_write)
+            .await?;
+        Ok(())
+    }
+
+    /// Get all banned pubkeys
+    async fn get_banned_pubkeys(&self) -> Result<Vec<String>> {
+        let pubkeys = sqlx::query_scalar::<_, String>("SELECT pubkey FROM pubkey_ban")
+            .fetch_all(&self.conn)
+            .await?;
+        Ok(pubkeys)
+    }
+
+    /// Delete all events from an author
+    async fn delete_author_events(&self, pubkey: &str) -> Result<u64> {
+        let result = sqlx::query("DELETE FROM \"event\" WHERE pub_key = $1")
+            .bind(hex::decode(pubkey)?)
+            .execute(&self.conn_write)
+            .await?;
+        Ok(result.rows_affected())
+    }
+
+    /// Find (and optionally delete) tags of events that do not exist
+    async fn orphaned_tags(&self, remove: bool) -> Result<u64> {
+        let orphans =
+            "FROM tag t WHERE NOT EXISTS (SELECT 1 FROM \"event\" e WHERE e.id = t.event_id)";
+        if remove {
+            let result = sqlx::query(&format!("DELETE {orphans}"))
+                .execute(&self.conn_write)
+                .await?;
+            Ok(result.rows_affected())
+        } else {
+            let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) {orphans}"))
+                .fetch_one(&self.conn)
+                .await?;
+            Ok(count as u64)
+        }
+    }
+
+    /// Find events referencing a tag value
+    async fn events_with_tag_value(&self, value: &str, limit: u64) -> Result<Vec<Event>> {
+        let value_hex = if is_lower_hex(value) && (value.len() % 2 == 0) {
+            hex::decode(value).ok()
+        } else {
+            None
+        };
+        let rows = sqlx::query_scalar::<_, Vec<u8>>(
+            "SELECT e.\"content\" FROM \"event\" e WHERE e.hidden != 1::bit(1) \
+             AND e.id IN (SELECT t.event_id FROM tag t WHERE t.value = $1 OR t.value_hex = $2) \
+             ORDER BY e.created_at DESC LIMIT $3",
+        )
+        .bind(value.as_bytes())
+        .bind(value_hex)
+        .bind(limit as i64)
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(rows
+            .iter()
+            .filter_map(|c| serde_json::from_slice::<Event>(c).ok())
+            .collect())
+    }
+
+    /// Find the newest event timestamp for each author
+    async fn latest_created_at_for(&self, pubkeys: &[String]) -> Result<HashMap<String, u64>> {
+        let authors: Vec<Vec<u8>> = pubkeys
+            .iter()
+            .filter(|p| is_lower_hex(p) && p.len() == 64)
+            .filter_map(|p| hex::decode(p).ok())
+            .collect();
+        if authors.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let rows = sqlx::query_as::<_, (Vec<u8>, DateTime<Utc>)>(
+            "SELECT e.pub_key, MAX(e.created_at) FROM \"event\" e \
+             WHERE e.pub_key = ANY($1) AND e.hidden != 1::bit(1) GROUP BY e.pub_key",
+        )
+        .bind(authors)
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(pubkey, created_at)| (hex::encode(pubkey), created_at.timestamp() as u64))
+            .collect())
+    }
+
+    /// Find deleted (hidden) events, and the deletion that hid them
+    async fn tombstones_for(&self, ids: &[String]) -> Result<HashMap<String, String>> {
+        let ids: Vec<Vec<u8>> = ids
+            .iter()
+            .filter(|i| is_lower_hex(i) && i.len() == 64)
+            .filter_map(|i| hex::decode(i).ok())
+            .collect();
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let rows = sqlx::query_as::<_, (Vec<u8>, Vec<u8>)>(
+            "SELECT DISTINCT ON (e.id) e.id, d.id FROM \"event\" e \
+             INNER JOIN tag t ON t.\"name\" = 'e' AND t.value_hex = e.id \
+             INNER JOIN \"event\" d ON d.id = t.event_id AND d.pub_key = e.pub_key \
+             WHERE e.id = ANY($1) AND e.hidden = 1::bit(1) AND d.kind = 5",
+        )
+        .bind(ids)
+        .fetch_all(&self.conn)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(id, deletion)| (hex::encode(id), hex::encode(deletion)))
+            .collect())
+    }
+
+    /// Find the current replaceable events of an author
+    async fn current_replaceable_for(&self, pubkey: &str) -> Result<Vec<Event>> {
+        let author = match hex::decode(pubkey) {
+            Ok(a) if is_lower_hex(pubkey) && a.len() == 32 => a,
+            _ => return Ok(vec![]),
+        };
+        let query = format!(
+            "SELECT e.\"content\" FROM \"event\" e WHERE e.pub_key = $1 AND {REPLACEABLE_KINDS_SQL} \
+             AND e.hidden != 1::bit(1) AND (e.expires_at IS NULL OR e.expires_at > $2)"
+        );
+        let rows = sqlx::query_scalar::<_, Vec<u8>>(&query)
+            .bind(author)
+            .bind(Utc.timestamp_opt(utils::unix_time() as i64, 0).unwrap())
+            .fetch_all(&self.conn)
+            .await?;
+        Ok(current_versions(
+            rows.iter()
+                .filter_map(|c| serde_json::from_slice::<Event>(c).ok())
+                .collect(),
+        ))
+    }
+
+    /// Summarize the serialized size of stored events
+    async fn storage_stats(&self, author: Option<&str>, top: u64) -> Result<StorageStats> {
+        let author: Option<Vec<u8>> = author.and_then(|a| hex::decode(a).ok());
+        let (events, total_bytes) = sqlx::query_as::<_, (i64, i64)>(
+            "SELECT COUNT(*), COALESCE(SUM(octet_length(e.\"content\")), 0)::bigint \
+             FROM \"event\" e WHERE ($1::bytea IS NULL OR e.pub_key = $1)",
+        )
+        .bind(&author)
+        .fetch_one(&self.conn)
+        .await?;
+        let rows = sqlx::query_as::<_, (Vec<u8>, i32)>(
+            "SELECT e.id, octet_length(e.\"content\") AS size FROM \"event\" e \
+             WHERE ($1::bytea IS NULL OR e.pub_key = $1) ORDER BY size DESC, e.id ASC LIMIT $2",
+        )
+        .bind(&author)
+        .bind(top as i64)
+        .fetch_all(&self.conn)
+        .await?;
+        let largest = rows
+            .into_iter()
+            .map(|(id, size)| EventSize {
+                id: hex::encode(id),
+                bytes: size as u64,
+            })
+            .collect();
+        Ok(StorageStats::new(
+            events as u64,
+            total_bytes as u64,
+            largest,
+        ))
+    }
+
+    /// Count the (capped) results of each filter, stopping once over budget
+    async fn count_projected_results(&self, sub: &Subscription, budget: u64) -> Result<u64> {
+        let mut total: u64 = 0;
+        for filter in sub.filters.iter() {
+            let (filter, cap) = cap_filter(filter, Some(self.max_limit));
+            let ties = includes_ties(self.limit_boundary, &filter, cap);
+            if let Some(mut q) = query_from_filter(&filter, ties) {
+                let remaining = budget.saturating_sub(total).saturating_add(1);
+                let take = remaining.min(cap.unwrap_or(u64::MAX));
+                let mut rows = q.build().fetch(&self.conn).take(take as usize);
+                while let Some(row) = rows.next().await {
+                    row?;
+                    total += 1;
+                }
+                if total > budget {
+                    break;
+                }
+            }
+        }
+        Ok(total)
+    }
 }
 
 /// Create a dynamic SQL query and params from a subscription filter.
-fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
-    // if the filter is malformed, don't return anything.
-    if f.force_no_match {
+///
+/// With `ties`, the query also returns the remaining events with the
+/// timestamp of the last event within the limit.
+fn query_from_filter(f: &ReqFilter, ties: bool) -> Option<QueryBuilder<Postgres>> {
+    // if the filter is malformed, don't return anything.  Content
+    // search needs the full-text index only SQLite maintains.
+    if f.force_no_match || f.search.is_some() {
         return None;
     }
 
-    let mut query = QueryBuilder::new("SELECT e.\"content\", e.created_at FROM \"event\" e WHERE ");
+    let mut query = if ties {
+        QueryBuilder::new("SELECT \"content\", created_at, seq FROM (SELECT e.\"content\", e.created_at, e.seq, e.id FROM \"event\" e WHERE ")
+    } else {
+        QueryBuilder::new("SELECT e.\"content\", e.created_at, e.seq FROM \"event\" e WHERE ")
+    };
 
     // This tracks whether we need to push a prefix AND before adding another clause
     let mut push_and = false;
@@ -897,6 +1162,43 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
         }
     }
 
+    // Query for tags which must contain every value
+    if let Some(map) = &f.and_tags {
+        for (key, val) in map.iter() {
+            for v in val.iter() {
+                if push_and {
+                    query.push(" AND ");
+                }
+                push_and = true;
+                query
+                    .push("e.id IN (SELECT t.event_id FROM tag t WHERE t.\"name\" = ")
+                    .push_bind(key.to_string());
+                // hex values are stored separately from plain values
+                if is_lower_hex(v) && (v.len() % 2 == 0) {
+                    query.push(" AND t.value_hex = ");
+                    query.push_bind(hex::decode(v).ok());
+                } else {
+                    query.push(" AND t.value = ");
+                    query.push_bind(v.as_bytes());
+                }
+                query.push(")");
+            }
+        }
+    }
+
+    // Query for delegated (or directly signed) events
+    if let Some(delegated) = f.delegated {
+        if push_and {
+            query.push(" AND ");
+        }
+        push_and = true;
+        if delegated {
+            query.push("e.delegated_by IS NOT NULL");
+        } else {
+            query.push("e.delegated_by IS NULL");
+        }
+    }
+
     // Query for timestamp
     if f.since.is_some() {
         if push_and {
@@ -919,6 +1221,15 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
             .push_bind(Utc.timestamp_opt(f.until.unwrap() as i64, 0).unwrap());
     }
 
+    // Query for sequence number
+    if let Some(after_seq) = f.after_seq {
+        if push_and {
+            query.push(" AND ");
+        }
+        push_and = true;
+        query.push("e.seq > ").push_bind(after_seq as i64);
+    }
+
     // never display hidden events
     if push_and {
         query.push(" AND e.hidden != 1::bit(1)");
@@ -933,13 +1244,25 @@ fn query_from_filter(f: &ReqFilter) -> Option<QueryBuilder<Postgres>> {
 
     // Apply per-filter limit to this query.
     // The use of a LIMIT implies a DESC order, to capture only the most recent events.
-    if let Some(lim) = f.limit {
-        query.push(" ORDER BY e.created_at DESC LIMIT ");
-        query.push(lim.min(1000));
+    // Filters are capped by the caller, so there is always a limit,
+    // and filters without one are also served most recent first.
+    // Events with the same timestamp are always ordered by id (lowest
+    // first), so results are deterministic.
+    // Sequence pages are always in insertion order, oldest first.
+    let lim = f.limit.unwrap_or(DEFAULT_MAX_LIMIT);
+    if ties {
+        query
+            .push(" ORDER BY e.created_at DESC FETCH FIRST ")
+            .push(lim)
+            .push(" ROWS WITH TIES) t ORDER BY created_at DESC, id ASC");
+        return Some(query);
+    }
+    if f.uses_sequence() {
+        query.push(" ORDER BY e.seq ASC LIMIT ");
     } else {
-        query.push(" ORDER BY e.created_at ASC LIMIT ");
-        query.push(1000);
+        query.push(" ORDER BY e.created_at DESC, e.id ASC LIMIT ");
     }
+    query.push(lim);
     Some(query)
 }
 
@@ -964,3 +1287,38 @@ impl FromRow<'_, PgRow> for VerificationRecord {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn and_tag_values_match_storage_column() {
+        let sql_for = |filter: String| {
+            let filter: ReqFilter = serde_json::from_str(&filter).unwrap();
+            query_from_filter(&filter, false).unwrap().sql().to_owned()
+        };
+        let hex_sql = sql_for(format!("{{\"&p\":[\"{}\"]}}", "ab".repeat(32)));
+        assert!(hex_sql.contains("t.\"name\" = $1 AND t.value_hex = $2"));
+        let plain_sql = sql_for("{\"&t\":[\"nostr\"]}".to_owned());
+        assert!(plain_sql.contains("t.\"name\" = $1 AND t.value = $2"));
+    }
+
+    #[test]
+    fn unlimited_filters_served_newest_first() {
+        let (filter, _) = cap_filter(&ReqFilter::default(), Some(DEFAULT_MAX_LIMIT));
+        let q = query_from_filter(&filter, false).unwrap();
+        assert!(q
+            .sql()
+            .ends_with("ORDER BY e.created_at DESC, e.id ASC LIMIT 1001"));
+    }
+
+    #[test]
+    fn limit_ties_fetched_together() {
+        let filter: ReqFilter = serde_json::from_str(r#"{"kinds":[1],"limit":5}"#).unwrap();
+        let q = query_from_filter(&filter, true).unwrap();
+        assert!(q.sql().ends_with(
+            "ORDER BY e.created_at DESC FETCH FIRST 5 ROWS WITH TIES) t ORDER BY created_at DESC, id ASC"
+        ));
+    }
+}
@@ -37,6 +37,9 @@ pub async fn run_migrations(db: &PostgresPool) -> crate::error::Result<usize> {
     run_migration(m003::migration(), db).await;
     run_migration(m004::migration(), db).await;
     run_migration(m005::migration(), db).await;
+    run_migration(m006::migration(), db).await;
+    run_migration(m007::migration(), db).await;
+    run_migration(m008::migration(), db).await;
     Ok(current_version(db).await as usize)
 }
 
@@ -318,3 +321,68 @@ CREATE TABLE "invoice" (
         }
     }
 }
+
+mod m006 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 6;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Create banned pubkeys table
+CREATE TABLE "pubkey_ban" (
+    pubkey varchar NOT NULL,
+    banned_at timestamp with time zone NOT NULL DEFAULT now(),
+    CONSTRAINT pubkey_ban_pkey PRIMARY KEY (pubkey)
+);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m007 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 7;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Index for finding the newest event by an author
+CREATE INDEX event_pub_key_created_at_idx ON "event" (pub_key, created_at);
+        "#,
+            ],
+        }
+    }
+}
+
+mod m008 {
+    use crate::repo::postgres_migration::{Migration, SimpleSqlMigration};
+
+    pub const VERSION: i64 = 8;
+
+    pub fn migration() -> impl Migration {
+        SimpleSqlMigration {
+            serial_number: VERSION,
+            sql: vec![
+                r#"
+-- Insertion order of events, for strict pagination
+ALTER TABLE "event" ADD COLUMN seq bigserial;
+CREATE UNIQUE INDEX event_seq_idx ON "event" (seq);
+-- Sequence numbers are taken from a single row, which stays locked
+-- until the inserting transaction commits.  Unlike a sequence, this
+-- hands out numbers in commit order, so a reader never sees a seq
+-- before a smaller one is committed.
+CREATE TABLE event_sequence (last bigint NOT NULL);
+INSERT INTO event_sequence SELECT COALESCE(max(seq), 0) FROM "event";
+        "#,
+            ],
+        }
+    }
+}
@@ -1,17 +1,17 @@
 //! Event persistence and querying
 //use crate::config::SETTINGS;
-use crate::config::Settings;
+use crate::config::{LimitBoundary, Settings};
 use crate::db::QueryResult;
 use crate::error::{Error::SqlError, Result};
-use crate::event::{single_char_tagname, Event};
+use crate::event::Event;
 use crate::hexrange::hex_range;
 use crate::hexrange::HexSearch;
 use crate::nip05::{Nip05Name, VerificationRecord};
 use crate::payment::{InvoiceInfo, InvoiceStatus};
 use crate::repo::sqlite_migration::{upgrade_db, STARTUP_SQL};
 use crate::server::NostrMetrics;
-use crate::subscription::{ReqFilter, Subscription};
-use crate::utils::{is_hex, unix_time};
+use crate::subscription::{search_terms, ReqFilter, Subscription};
+use crate::utils::{is_hex, is_lower_hex, unix_time};
 use async_trait::async_trait;
 use hex;
 use r2d2;
@@ -19,6 +19,8 @@ use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::params;
 use rusqlite::types::ToSql;
 use rusqlite::OpenFlags;
+use rusqlite::OptionalExtension;
+use std::collections::{BTreeSet, HashMap};
 use std::fmt::Write as _;
 use std::path::Path;
 use std::sync::Arc;
@@ -29,13 +31,21 @@ use tokio::sync::{Mutex, MutexGuard, Semaphore};
 use tokio::task;
 use tracing::{debug, info, trace, warn};
 
-use crate::repo::{now_jitter, NostrRepo};
+use crate::repo::{
+    cap_filter, chunk_filter, current_versions, cursor_sentinel, includes_ties, index_tag,
+    is_chunkable, now_jitter, slow_query_message, stored_tags, EventSize, NostrRepo, StorageStats,
+    REPLACEABLE_KINDS_SQL, TRUNCATED_SENTINEL,
+};
 use nostr::key::Keys;
 
 pub type SqlitePool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
 pub type PooledConnection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
 pub const DB_FILE: &str = "nostr.db";
 
+/// Largest number of authors (or kinds) a limited filter is split
+/// into separate newest-first scans for.
+const MAX_MERGED_SCANS: usize = 256;
+
 #[derive(Clone)]
 pub struct SqliteRepo {
     /// Metrics
@@ -52,6 +62,16 @@ pub struct SqliteRepo {
     write_in_progress: Arc<Mutex<u64>>,
     /// Semaphore for readers to acquire blocking threads
     reader_threads_ready: Arc<Semaphore>,
+    /// Maximum number of results returned for a single filter
+    max_limit: Option<u64>,
+    /// Which events are returned at a filter's limit
+    limit_boundary: LimitBoundary,
+    /// Log queries slower than this (milliseconds)
+    slow_query_threshold_ms: Option<u64>,
+    /// Read unlimited filters in `created_at` windows this wide
+    query_chunk_seconds: Option<u64>,
+    /// Kinds whose tags are not indexed
+    unindexed_kinds: Vec<u64>,
 }
 
 impl SqliteRepo {
@@ -100,11 +120,20 @@ impl SqliteRepo {
             checkpoint_in_progress,
             write_in_progress,
             reader_threads_ready,
+            max_limit: settings.limits.max_limit,
+            limit_boundary: settings.limits.limit_boundary,
+            slow_query_threshold_ms: settings.database.slow_query_threshold_ms,
+            query_chunk_seconds: settings.database.query_chunk_seconds,
+            unindexed_kinds: settings.options.unindexed_kinds.clone(),
         }
     }
 
     /// Persist an event to the database, returning rows added.
-    pub fn persist_event(conn: &mut PooledConnection, e: &Event) -> Result<u64> {
+    pub fn persist_event(
+        conn: &mut PooledConnection,
+        e: &Event,
+        unindexed_kinds: &[u64],
+    ) -> Result<u64> {
         // enable auto vacuum
         conn.execute_batch("pragma auto_vacuum = FULL")?;
 
@@ -117,10 +146,11 @@ impl SqliteRepo {
             e.delegated_by.as_ref().and_then(|d| hex::decode(d).ok());
         let event_str = serde_json::to_string(&e).ok();
         // check for replaceable events that would hide this one; we won't even attempt to insert these.
+        // of two events with the same timestamp, the lowest id wins.
         if e.is_replaceable() {
             let repl_count = tx.query_row(
-                "SELECT e.id FROM event e INDEXED BY author_index WHERE e.author=? AND e.kind=? AND e.created_at >= ? LIMIT 1;",
-                params![pubkey_blob, e.kind, e.created_at], |row| row.get::<usize, usize>(0));
+                "SELECT e.id FROM event e INDEXED BY author_index WHERE e.author=?1 AND e.kind=?2 AND (e.created_at > ?3 OR (e.created_at = ?3 AND e.event_hash <= ?4)) LIMIT 1;",
+                params![pubkey_blob, e.kind, e.created_at, id_blob], |row| row.get::<usize, usize>(0));
             if repl_count.ok().is_some() {
                 return Ok(0);
             }
@@ -128,8 +158,8 @@ impl SqliteRepo {
         // check for parameterized replaceable events that would be hidden; don't insert these either.
         if let Some(d_tag) = e.distinct_param() {
             let repl_count = tx.query_row(
-                "SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.author=? AND e.kind=? AND t.name='d' AND t.value=? AND e.created_at >= ? LIMIT 1;",
-                params![pubkey_blob, e.kind, d_tag, e.created_at],|row| row.get::<usize, usize>(0));
+                "SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.author=?1 AND e.kind=?2 AND t.name='d' AND t.value=?3 AND t.id=(SELECT MIN(t2.id) FROM tag t2 WHERE t2.event_id=e.id AND t2.name='d') AND (e.created_at > ?4 OR (e.created_at = ?4 AND e.event_hash <= ?5)) LIMIT 1;",
+                params![pubkey_blob, e.kind, d_tag, e.created_at, id_blob],|row| row.get::<usize, usize>(0));
             // if any rows were returned, then some newer event with
             // the same author/kind/tag value exist, and we can ignore
             // this event.
@@ -137,9 +167,12 @@ impl SqliteRepo {
                 return Ok(0);
             }
         }
+        // assign the next sequence number; writes are serialized, so
+        // this is never handed out twice.
+        tx.execute("UPDATE event_sequence SET last=last+1;", [])?;
         // ignore if the event hash is a duplicate.
         let mut ins_count = tx.execute(
-            "INSERT OR IGNORE INTO event (event_hash, created_at, expires_at, kind, author, delegated_by, content, first_seen, hidden) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, strftime('%s','now'), FALSE);",
+            "INSERT OR IGNORE INTO event (event_hash, created_at, expires_at, kind, author, delegated_by, content, first_seen, hidden, seq) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, strftime('%s','now'), FALSE, (SELECT last FROM event_sequence));",
             params![id_blob, e.created_at, e.expiration(), e.kind, pubkey_blob, delegator_blob, event_str]
         )? as u64;
         if ins_count == 0 {
@@ -151,22 +184,13 @@ impl SqliteRepo {
         // remember primary key of the event most recently inserted.
         let ev_id = tx.last_insert_rowid();
         // add all tags to the tag table
-        for tag in &e.tags {
-            // ensure we have 2 values.
-            if tag.len() >= 2 {
-                let tagname = &tag[0];
-                let tagval = &tag[1];
-                // only single-char tags are searchable
-                let tagchar_opt = single_char_tagname(tagname);
-                match &tagchar_opt {
-                    Some(_) => {
-                        tx.execute(
-                            "INSERT OR IGNORE INTO tag (event_id, name, value, kind, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
-                            params![ev_id, &tagname, &tagval, e.kind, e.created_at],
-                        )?;
-                    }
-                    None => {}
-                }
+        for (tagname, tagval) in stored_tags(e) {
+            // only single-char tags are searchable
+            if index_tag(e, tagname, unindexed_kinds) {
+                tx.execute(
+                    "INSERT OR IGNORE INTO tag (event_id, name, value, kind, created_at) VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![ev_id, &tagname, &tagval, e.kind, e.created_at],
+                )?;
             }
         }
         // if this event is replaceable update, remove other replaceable
@@ -176,7 +200,7 @@ impl SqliteRepo {
             let author = hex::decode(&e.pubkey).ok();
             // this is a backwards check - hide any events that were older.
             let update_count = tx.execute(
-                "DELETE FROM event WHERE kind=? and author=? and id NOT IN (SELECT id FROM event INDEXED BY author_kind_index WHERE kind=? AND author=? ORDER BY created_at DESC LIMIT 1)",
+                "DELETE FROM event WHERE kind=? and author=? and id NOT IN (SELECT id FROM event INDEXED BY author_kind_index WHERE kind=? AND author=? ORDER BY created_at DESC, event_hash ASC LIMIT 1)",
                 params![e.kind, author, e.kind, author],
             )?;
             if update_count > 0 {
@@ -191,7 +215,7 @@ impl SqliteRepo {
         // if this event is parameterized replaceable, remove other events.
         if let Some(d_tag) = e.distinct_param() {
             let update_count = tx.execute(
-                "DELETE FROM event WHERE kind=? AND author=? AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=? AND e.author=? AND t.name='d' AND t.value=? ORDER BY t.created_at DESC LIMIT -1 OFFSET 1);",
+                "DELETE FROM event WHERE kind=? AND author=? AND id IN (SELECT e.id FROM event e LEFT JOIN tag t ON e.id=t.event_id WHERE e.kind=? AND e.author=? AND t.name='d' AND t.value=? AND t.id=(SELECT MIN(t2.id) FROM tag t2 WHERE t2.event_id=e.id AND t2.name='d') ORDER BY e.created_at DESC, e.event_hash ASC LIMIT -1 OFFSET 1);",
                 params![e.kind, pubkey_blob, e.kind, pubkey_blob, d_tag])?;
             if update_count > 0 {
                 info!(
@@ -284,13 +308,14 @@ impl NostrRepo for SqliteRepo {
         //let mut conn = self.write_pool.get()?;
         let pool = self.write_pool.clone();
         let e = e.clone();
+        let unindexed_kinds = self.unindexed_kinds.clone();
         let event_count = task::spawn_blocking(move || {
             let mut conn = pool.get()?;
             // this could fail because the database was busy; try
             // multiple times before giving up.
             loop {
                 attempts += 1;
-                let wr = SqliteRepo::persist_event(&mut conn, &e);
+                let wr = SqliteRepo::persist_event(&mut conn, &e, &unindexed_kinds);
                 match wr {
                     Err(SqlError(rusqlite::Error::SqliteFailure(e, _))) => {
                         // this basically means that NIP-05 or another
@@ -380,6 +405,8 @@ impl NostrRepo for SqliteRepo {
             // cutoff for displaying slow queries
             let slow_cutoff = Duration::from_millis(250);
             let mut filter_count = 0;
+            // highest sequence number delivered, for filters using after_seq
+            let mut cursor: Option<u64> = None;
             // remove duplicates from the filter list.
             if let Ok(mut conn) = self.read_pool.get() {
                 {
@@ -389,49 +416,120 @@ impl NostrRepo for SqliteRepo {
                         .set((pool_state.connections - pool_state.idle_connections).into());
                 }
                 for filter in sub.filters.iter() {
+                    if let Some(after_seq) = filter.after_seq {
+                        cursor = Some(cursor.map_or(after_seq, |c: u64| c.max(after_seq)));
+                    }
                     let filter_start = Instant::now();
                     filter_count += 1;
                     let sql_gen_elapsed = filter_start.elapsed();
-                    let (q, p, idx) = query_from_filter(filter);
+                    let (filter, cap) = cap_filter(filter, self.max_limit);
+                    let mut filter_rows: u64 = 0;
+                    let mut truncated = false;
+                    let ties = includes_ties(self.limit_boundary, &filter, cap);
+                    // large backfills are read in windows of
+                    // created_at, each in a read transaction of its own,
+                    // so checkpoints can proceed between them.
+                    let bounds = if self.query_chunk_seconds.is_some() && is_chunkable(&filter) {
+                        stored_time_bounds(&conn)?
+                    } else {
+                        None
+                    };
                     if sql_gen_elapsed > Duration::from_millis(10) {
                         debug!("SQL (slow) generated in {:?}", filter_start.elapsed());
                     }
-                    // any client that doesn't cause us to generate new rows in 2
-                    // seconds gets dropped.
-                    let abort_cutoff = Duration::from_secs(2);
-                    let mut slow_first_event;
-                    let mut last_successful_send = Instant::now();
-                    // execute the query.
-                    // make the actual SQL query (with parameters inserted) available
-                    conn.trace(Some(|x| trace!("SQL trace: {:?}", x)));
-                    let mut stmt = conn.prepare_cached(&q)?;
-                    let mut event_rows = stmt.query(rusqlite::params_from_iter(p))?;
-
                     let mut first_result = true;
-                    while let Some(row) = event_rows.next()? {
-                        let first_event_elapsed = filter_start.elapsed();
-                        slow_first_event = first_event_elapsed >= slow_cutoff;
-                        if first_result {
-                            debug!(
+                    for part in chunk_filter(&filter, self.query_chunk_seconds, bounds) {
+                        let (q, p, idx) = query_from_filter(&part, ties);
+                        // any client that doesn't cause us to generate new rows in 2
+                        // seconds gets dropped.
+                        let abort_cutoff = Duration::from_secs(2);
+                        let mut slow_first_event;
+                        let mut last_successful_send = Instant::now();
+                        // execute the query.
+                        // make the actual SQL query (with parameters inserted) available
+                        conn.trace(Some(|x| trace!("SQL trace: {:?}", x)));
+                        let mut stmt = conn.prepare_cached(&q)?;
+                        let mut event_rows = stmt.query(rusqlite::params_from_iter(p))?;
+
+                        while let Some(row) = event_rows.next()? {
+                            let first_event_elapsed = filter_start.elapsed();
+                            slow_first_event = first_event_elapsed >= slow_cutoff;
+                            if first_result {
+                                debug!(
                                 "first result in {:?} (cid: {}, sub: {:?}, filter: {}) [used index: {:?}]",
                                 first_event_elapsed, client_id, sub.id, filter_count, idx
                             );
-                            // logging for slow queries; show filter and SQL.
-                            // to reduce logging; only show 1/16th of clients (leading 0)
-                            if slow_first_event && client_id.starts_with('0') {
-                                debug!(
+                                // logging for slow queries; show filter and SQL.
+                                // to reduce logging; only show 1/16th of clients (leading 0)
+                                if slow_first_event && client_id.starts_with('0') {
+                                    debug!(
                                     "filter first result in {:?} (slow): {} (cid: {}, sub: {:?})",
                                     first_event_elapsed,
                                     serde_json::to_string(&filter)?,
                                     client_id,
                                     sub.id
                                 );
+                                }
+                                first_result = false;
                             }
-                            first_result = false;
-                        }
-                        // check if a checkpoint is trying to run, and abort
-                        if row_count % 100 == 0 {
-                            {
+                            // check if a checkpoint is trying to run, and abort
+                            if row_count % 100 == 0 {
+                                {
+                                    if self.checkpoint_in_progress.try_lock().is_err() {
+                                        // lock was held, abort this query
+                                        debug!(
+                                            "query aborted due to checkpoint (cid: {}, sub: {:?})",
+                                            client_id, sub.id
+                                        );
+                                        metrics
+                                            .query_aborts
+                                            .with_label_values(&["checkpoint"])
+                                            .inc();
+                                        return Ok(());
+                                    }
+                                }
+                            }
+
+                            // check if this is still active; every 100 rows
+                            if row_count % 100 == 0 && abandon_query_rx.try_recv().is_ok() {
+                                debug!(
+                                    "query cancelled by client (cid: {}, sub: {:?})",
+                                    client_id, sub.id
+                                );
+                                return Ok(());
+                            }
+                            // stop at the relay's cap; an extra row means we truncated.
+                            if cap.map_or(false, |c| filter_rows >= c) {
+                                truncated = true;
+                                break;
+                            }
+                            filter_rows += 1;
+                            row_count += 1;
+                            let event_json = row.get(0)?;
+                            if filter.uses_sequence() {
+                                if let Some(seq) = row.get::<usize, Option<u64>>(1)? {
+                                    cursor = cursor.map(|c| c.max(seq));
+                                }
+                            }
+                            loop {
+                                if query_tx.capacity() != 0 {
+                                    // we have capacity to add another item
+                                    break;
+                                }
+                                // the queue is full
+                                trace!("db reader thread is stalled");
+                                if last_successful_send + abort_cutoff < Instant::now() {
+                                    // the queue has been full for too long, abort
+                                    info!("aborting database query due to slow client (cid: {}, sub: {:?})",
+                                      client_id, sub.id);
+                                    metrics
+                                        .query_aborts
+                                        .with_label_values(&["slowclient"])
+                                        .inc();
+                                    let ok: Result<()> = Ok(());
+                                    return ok;
+                                }
+                                // check if a checkpoint is trying to run, and abort
                                 if self.checkpoint_in_progress.try_lock().is_err() {
                                     // lock was held, abort this query
                                     debug!(
@@ -444,71 +542,44 @@ impl NostrRepo for SqliteRepo {
                                         .inc();
                                     return Ok(());
                                 }
-                            }
-                        }
-
-                        // check if this is still active; every 100 rows
-                        if row_count % 100 == 0 && abandon_query_rx.try_recv().is_ok() {
-                            debug!(
-                                "query cancelled by client (cid: {}, sub: {:?})",
-                                client_id, sub.id
-                            );
-                            return Ok(());
-                        }
-                        row_count += 1;
-                        let event_json = row.get(0)?;
-                        loop {
-                            if query_tx.capacity() != 0 {
-                                // we have capacity to add another item
-                                break;
-                            }
-                            // the queue is full
-                            trace!("db reader thread is stalled");
-                            if last_successful_send + abort_cutoff < Instant::now() {
-                                // the queue has been full for too long, abort
-                                info!("aborting database query due to slow client (cid: {}, sub: {:?})",
-                                      client_id, sub.id);
-                                metrics
-                                    .query_aborts
-                                    .with_label_values(&["slowclient"])
-                                    .inc();
-                                let ok: Result<()> = Ok(());
-                                return ok;
-                            }
-                            // check if a checkpoint is trying to run, and abort
-                            if self.checkpoint_in_progress.try_lock().is_err() {
-                                // lock was held, abort this query
+                                // give the queue a chance to clear before trying again
                                 debug!(
-                                    "query aborted due to checkpoint (cid: {}, sub: {:?})",
-                                    client_id, sub.id
-                                );
-                                metrics
-                                    .query_aborts
-                                    .with_label_values(&["checkpoint"])
-                                    .inc();
-                                return Ok(());
-                            }
-                            // give the queue a chance to clear before trying again
-                            debug!(
                                 "query thread sleeping due to full query_tx (cid: {}, sub: {:?})",
                                 client_id, sub.id
                             );
-                            thread::sleep(Duration::from_millis(500));
+                                thread::sleep(Duration::from_millis(500));
+                            }
+                            // TODO: we could use try_send, but we'd have to juggle
+                            // getting the query result back as part of the error
+                            // result.
+                            query_tx
+                                .blocking_send(QueryResult {
+                                    sub_id: sub.get_id(),
+                                    event: event_json,
+                                })
+                                .ok();
+                            last_successful_send = Instant::now();
                         }
-                        // TODO: we could use try_send, but we'd have to juggle
-                        // getting the query result back as part of the error
-                        // result.
+                    }
+                    if truncated {
                         query_tx
                             .blocking_send(QueryResult {
                                 sub_id: sub.get_id(),
-                                event: event_json,
+                                event: TRUNCATED_SENTINEL.to_string(),
                             })
                             .ok();
-                        last_successful_send = Instant::now();
                     }
                     metrics
                         .query_db
                         .observe(filter_start.elapsed().as_secs_f64());
+                    if let Some(msg) = slow_query_message(
+                        &filter,
+                        filter_rows,
+                        filter_start.elapsed(),
+                        self.slow_query_threshold_ms,
+                    ) {
+                        warn!("{} (cid: {}, sub: {:?})", msg, client_id, sub.id);
+                    }
                     // if the filter took too much db_time, print out the JSON.
                     if filter_start.elapsed() > slow_cutoff && client_id.starts_with('0') {
                         debug!(
@@ -524,6 +595,14 @@ impl NostrRepo for SqliteRepo {
                 warn!("Could not get a database connection for querying");
             }
             drop(sem); // new query can begin
+            if let Some(seq) = cursor {
+                query_tx
+                    .blocking_send(QueryResult {
+                        sub_id: sub.get_id(),
+                        event: cursor_sentinel(seq),
+                    })
+                    .ok();
+            }
             debug!(
                 "query completed in {:?} (cid: {}, sub: {:?}, db_time: {:?}, rows: {})",
                 pre_spawn_start.elapsed(),
@@ -927,6 +1006,254 @@ LIMIT 1;
             confirmed_at: None,
         }))
     }
+
+    /// Ban a pubkey
+    async fn ban_pubkey(&self, pubkey: &str) -> Result<()> {
+        let mut conn = self.write_pool.get()?;
+        let pubkey = pubkey.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            {
+                let query = "INSERT OR IGNORE INTO pubkey_ban (pubkey, banned_at) VALUES (?1, strftime('%s','now'));";
+                let mut stmt = tx.prepare(query)?;
+                stmt.execute(params![pubkey])?;
+            }
+            tx.commit()?;
+            let ok: Result<()> = Ok(());
+            ok
+        })
+        .await?
+    }
+
+    /// Get all banned pubkeys
+    async fn get_banned_pubkeys(&self) -> Result<Vec<String>> {
+        let pool = self.read_pool.clone();
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get()?;
+            let mut stmt = conn.prepare_cached("SELECT pubkey FROM pubkey_ban;")?;
+            let pubkeys = stmt
+                .query_map([], |r| r.get::<usize, String>(0))?
+                .collect::<rusqlite::Result<Vec<String>>>()?;
+            Ok(pubkeys)
+        })
+        .await?
+    }
+
+    /// Delete all events from an author
+    async fn delete_author_events(&self, pubkey: &str) -> Result<u64> {
+        let _write_guard = self.write_in_progress.lock().await;
+        let mut conn = self.write_pool.get()?;
+        let author = hex::decode(pubkey)?;
+        tokio::task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            let del_count = tx.execute("DELETE FROM event WHERE author=?;", params![author])?;
+            tx.commit()?;
+            Ok(del_count as u64)
+        })
+        .await?
+    }
+
+    /// Find (and optionally delete) tags of events that do not exist
+    async fn orphaned_tags(&self, remove: bool) -> Result<u64> {
+        let _write_guard = self.write_in_progress.lock().await;
+        let mut conn = self.write_pool.get()?;
+        tokio::task::spawn_blocking(move || {
+            let tx = conn.transaction()?;
+            let orphans =
+                "FROM tag WHERE NOT EXISTS (SELECT 1 FROM event e WHERE e.id=tag.event_id)";
+            let count = if remove {
+                tx.execute(&format!("DELETE {orphans};"), [])? as u64
+            } else {
+                tx.query_row(&format!("SELECT COUNT(*) {orphans};"), [], |r| r.get(0))?
+            };
+            tx.commit()?;
+            Ok(count)
+        })
+        .await?
+    }
+
+    /// Find events referencing a tag value
+    async fn events_with_tag_value(&self, value: &str, limit: u64) -> Result<Vec<Event>> {
+        let pool = self.read_pool.clone();
+        let value = value.to_owned();
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get()?;
+            let query = "SELECT e.content FROM event e WHERE e.hidden!=TRUE AND e.id IN (SELECT t.event_id FROM tag t INDEXED BY tag_val_index WHERE t.value=?) ORDER BY e.created_at DESC LIMIT ?;";
+            let mut stmt = conn.prepare_cached(query)?;
+            let events = stmt
+                .query_map(params![value, limit], |r| r.get::<usize, String>(0))?
+                .filter_map(|r| r.ok())
+                .filter_map(|j| serde_json::from_str::<Event>(&j).ok())
+                .collect();
+            Ok(events)
+        })
+        .await?
+    }
+
+    /// Find the newest event timestamp for each author
+    async fn latest_created_at_for(&self, pubkeys: &[String]) -> Result<HashMap<String, u64>> {
+        let pool = self.read_pool.clone();
+        let authors: Vec<Vec<u8>> = pubkeys
+            .iter()
+            .filter(|p| is_lower_hex(p) && p.len() == 64)
+            .filter_map(|p| hex::decode(p).ok())
+            .collect();
+        if authors.is_empty() {
+            return Ok(HashMap::new());
+        }
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get()?;
+            let placeholders = repeat_vars(authors.len());
+            let query = format!(
+                "SELECT author, MAX(created_at) FROM event INDEXED BY author_created_at_index WHERE author IN ({}) AND hidden!=TRUE GROUP BY author;",
+                placeholders
+            );
+            let mut stmt = conn.prepare(&query)?;
+            let latest = stmt
+                .query_map(rusqlite::params_from_iter(authors), |r| {
+                    Ok((hex::encode(r.get::<usize, Vec<u8>>(0)?), r.get::<usize, u64>(1)?))
+                })?
+                .filter_map(|r| r.ok())
+                .collect();
+            Ok(latest)
+        })
+        .await?
+    }
+
+    /// Find deleted (hidden) events, and the deletion that hid them
+    async fn tombstones_for(&self, ids: &[String]) -> Result<HashMap<String, String>> {
+        let pool = self.read_pool.clone();
+        let ids: Vec<String> = ids
+            .iter()
+            .filter(|i| is_lower_hex(i) && i.len() == 64)
+            .cloned()
+            .collect();
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get()?;
+            let mut stmt = conn.prepare_cached(
+                "SELECT d.event_hash FROM event e INNER JOIN tag t ON t.name='e' AND t.kind=5 AND t.value=?1 INNER JOIN event d ON d.id=t.event_id AND d.author=e.author WHERE e.event_hash=?2 AND e.hidden=TRUE AND d.hidden!=TRUE LIMIT 1;",
+            )?;
+            let mut tombstones = HashMap::new();
+            for id in ids {
+                let hash = hex::decode(&id).ok();
+                let deletion = stmt
+                    .query_row(params![id, hash], |r| r.get::<usize, Vec<u8>>(0))
+                    .optional()?;
+                if let Some(d) = deletion {
+                    tombstones.insert(id, hex::encode(d));
+                }
+            }
+            Ok(tombstones)
+        })
+        .await?
+    }
+
+    /// Find the current replaceable events of an author
+    async fn current_replaceable_for(&self, pubkey: &str) -> Result<Vec<Event>> {
+        let pool = self.read_pool.clone();
+        let author = match hex::decode(pubkey) {
+            Ok(a) if is_lower_hex(pubkey) && a.len() == 32 => a,
+            _ => return Ok(vec![]),
+        };
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get()?;
+            let query = format!(
+                "SELECT content FROM event WHERE author=? AND {REPLACEABLE_KINDS_SQL} AND hidden!=TRUE AND (expires_at IS NULL OR expires_at > ?);"
+            );
+            let mut stmt = conn.prepare_cached(&query)?;
+            let events = stmt
+                .query_map(params![author, unix_time()], |r| r.get::<usize, String>(0))?
+                .filter_map(|r| r.ok())
+                .filter_map(|j| serde_json::from_str::<Event>(&j).ok())
+                .collect();
+            Ok(current_versions(events))
+        })
+        .await?
+    }
+
+    /// Summarize the serialized size of stored events
+    async fn storage_stats(&self, author: Option<&str>, top: u64) -> Result<StorageStats> {
+        let pool = self.read_pool.clone();
+        let author: Option<Vec<u8>> = author.and_then(|a| hex::decode(a).ok());
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get()?;
+            let (events, total_bytes) = conn.query_row(
+                "SELECT COUNT(*), IFNULL(SUM(LENGTH(CAST(content AS BLOB))), 0) FROM event WHERE (?1 IS NULL OR author=?1);",
+                params![author],
+                |r| Ok((r.get::<usize, u64>(0)?, r.get::<usize, u64>(1)?)),
+            )?;
+            let mut stmt = conn.prepare_cached(
+                "SELECT event_hash, LENGTH(CAST(content AS BLOB)) AS size FROM event WHERE (?1 IS NULL OR author=?1) ORDER BY size DESC, event_hash ASC LIMIT ?2;",
+            )?;
+            let largest = stmt
+                .query_map(params![author, top], |r| {
+                    Ok(EventSize {
+                        id: hex::encode(r.get::<usize, Vec<u8>>(0)?),
+                        bytes: r.get(1)?,
+                    })
+                })?
+                .collect::<rusqlite::Result<Vec<EventSize>>>()?;
+            Ok(StorageStats::new(events, total_bytes, largest))
+        })
+        .await?
+    }
+
+    /// Count the (capped) results of each filter, stopping once over budget
+    async fn count_projected_results(&self, sub: &Subscription, budget: u64) -> Result<u64> {
+        let pool = self.read_pool.clone();
+        let filters: Vec<(ReqFilter, Option<u64>, bool)> = sub
+            .filters
+            .iter()
+            .map(|f| {
+                let (filter, cap) = cap_filter(f, self.max_limit);
+                let ties = includes_ties(self.limit_boundary, &filter, cap);
+                (filter, cap, ties)
+            })
+            .collect();
+        // counts share the reader threads with queries
+        let _sem = self
+            .reader_threads_ready
+            .clone()
+            .acquire_owned()
+            .await
+            .unwrap();
+        tokio::task::spawn_blocking(move || {
+            let conn = pool.get()?;
+            let mut total: u64 = 0;
+            for (filter, cap, ties) in &filters {
+                let (q, p, _) = query_from_filter(filter, *ties);
+                // never count more rows than it takes to exceed the budget
+                let take = budget
+                    .saturating_sub(total)
+                    .saturating_add(1)
+                    .min(cap.unwrap_or(u64::MAX))
+                    .min(i64::MAX as u64);
+                let count_q = format!("SELECT COUNT(*) FROM (SELECT 1 FROM ({q}) LIMIT {take})");
+                let mut stmt = conn.prepare(&count_q)?;
+                let count: u64 = stmt.query_row(rusqlite::params_from_iter(p), |r| r.get(0))?;
+                total += count;
+                if total > budget {
+                    break;
+                }
+            }
+            Ok(total)
+        })
+        .await?
+    }
+}
+
+/// The oldest and newest `created_at` of stored events, if there are
+/// any.
+fn stored_time_bounds(conn: &PooledConnection) -> Result<Option<(u64, u64)>> {
+    let bounds: (Option<u64>, Option<u64>) = conn.query_row(
+        "SELECT MIN(created_at), MAX(created_at) FROM event",
+        [],
+        |r| Ok((r.get(0)?, r.get(1)?)),
+    )?;
+    Ok(bounds.0.zip(bounds.1))
 }
 
 /// Decide if there is an index that should be used explicitly
@@ -934,6 +1261,22 @@ fn override_index(f: &ReqFilter) -> Option<String> {
     if f.ids.is_some() {
         return Some("event_hash_index".into());
     }
+    // sequence pages without an author are read in sequence order.
+    if f.after_seq.is_some() && f.authors.is_none() {
+        return Some("event_seq_index".into());
+    }
+    // limited queries with nothing more selective are read newest
+    // first straight from the created_at index, stopping at the limit.
+    if f.limit.is_some()
+        && f.ids.is_none()
+        && f.authors.is_none()
+        && f.kinds.is_none()
+        && f.tags.is_none()
+        && f.and_tags.is_none()
+        && f.search.is_none()
+    {
+        return Some("created_at_index".into());
+    }
     // queries for multiple kinds default to kind_index, which is
     // significantly slower than kind_created_at_index.
     if let Some(ks) = &f.kinds {
@@ -942,6 +1285,7 @@ fn override_index(f: &ReqFilter) -> Option<String> {
             && f.since.is_none()
             && f.until.is_none()
             && f.tags.is_none()
+            && f.and_tags.is_none()
             && f.authors.is_none()
         {
             return Some("kind_created_at_index".into());
@@ -963,8 +1307,102 @@ fn override_index(f: &ReqFilter) -> Option<String> {
     None
 }
 
+/// Split a limited filter over several exact authors (or several
+/// kinds) into one filter per value.  Each part is read newest first
+/// from an index, so merging them touches at most `limit` rows per
+/// part instead of every matching event.
+fn split_limited_filter(f: &ReqFilter) -> Option<Vec<ReqFilter>> {
+    if f.limit.is_none() || f.ids.is_some() || f.uses_sequence() || f.force_no_match {
+        return None;
+    }
+    if let Some(authvec) = &f.authors {
+        // prefixes may overlap, which would duplicate results
+        let exact = authvec
+            .iter()
+            .all(|a| matches!(hex_range(a), Some(HexSearch::Exact(_))));
+        let authors: BTreeSet<&String> = authvec.iter().collect();
+        if !exact || authors.len() < 2 || authors.len() > MAX_MERGED_SCANS {
+            return None;
+        }
+        return Some(
+            authors
+                .into_iter()
+                .map(|a| ReqFilter {
+                    authors: Some(vec![a.clone()]),
+                    ..f.clone()
+                })
+                .collect(),
+        );
+    }
+    let kinds: BTreeSet<u64> = f.kinds.as_ref()?.iter().copied().collect();
+    if kinds.len() < 2 || kinds.len() > MAX_MERGED_SCANS {
+        return None;
+    }
+    Some(
+        kinds
+            .into_iter()
+            .map(|k| ReqFilter {
+                kinds: Some(vec![k]),
+                ..f.clone()
+            })
+            .collect(),
+    )
+}
+
 /// Create a dynamic SQL subquery and params from a subscription filter (and optional explicit index used)
-fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<String>) {
+///
+/// With `ties`, a limited filter also returns the remaining events
+/// with the timestamp of the last event within its limit.
+fn query_from_filter(f: &ReqFilter, ties: bool) -> (String, Vec<Box<dyn ToSql>>, Option<String>) {
+    if !ties || f.limit.is_none() {
+        return limited_query_from_filter(f);
+    }
+    // every result at least as new as the oldest within the limit.
+    let unlimited = ReqFilter {
+        limit: None,
+        ..f.clone()
+    };
+    let (all_q, mut params, idx_name) =
+        single_query_from_filter(&unlimited, "e.content, e.seq, e.created_at, e.event_hash");
+    let (limited_q, mut limited_p, _) = limited_query_from_filter(f);
+    params.append(&mut limited_p);
+    let query = format!(
+        "SELECT content, seq, created_at FROM ({all_q}) WHERE created_at >= (SELECT MIN(created_at) FROM ({limited_q})) ORDER BY created_at DESC, event_hash ASC"
+    );
+    (query, params, idx_name)
+}
+
+/// Create the SQL for a filter, stopping exactly at its limit.
+fn limited_query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<String>) {
+    let parts = match split_limited_filter(f) {
+        Some(parts) => parts,
+        None => return single_query_from_filter(f, "e.content, e.seq, e.created_at"),
+    };
+    // merge the newest results of every part, preserving the
+    // ordering of a single limited query.
+    let mut subqueries: Vec<String> = vec![];
+    let mut params: Vec<Box<dyn ToSql>> = vec![];
+    let mut idx_name = None;
+    for part in &parts {
+        let (q, mut p, idx) =
+            single_query_from_filter(part, "e.content, e.seq, e.created_at, e.event_hash");
+        subqueries.push(format!("SELECT * FROM ({q})"));
+        params.append(&mut p);
+        idx_name = idx_name.or(idx);
+    }
+    let query = format!(
+        "SELECT content, seq, created_at FROM ({}) ORDER BY created_at DESC, event_hash ASC LIMIT {}",
+        subqueries.join(" UNION ALL "),
+        f.limit.unwrap_or_default()
+    );
+    (query, params, idx_name)
+}
+
+/// Create the SQL for a single filter, selecting the given columns.
+fn single_query_from_filter(
+    f: &ReqFilter,
+    columns: &str,
+) -> (String, Vec<Box<dyn ToSql>>, Option<String>) {
     // build a dynamic SQL query.  all user-input is either an integer
     // (sqli-safe), or a string that is filtered to only contain
     // hexadecimal characters.  Strings that require escaping (tag
@@ -972,7 +1410,7 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
 
     // if the filter is malformed, don't return anything.
     if f.force_no_match {
-        let empty_query = "SELECT e.content FROM event e WHERE 1=0".to_owned();
+        let empty_query = format!("SELECT {columns} FROM event e WHERE 1=0");
         // query parameters for SQLite
         let empty_params: Vec<Box<dyn ToSql>> = vec![];
         return (empty_query, empty_params, None);
@@ -983,7 +1421,7 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
     let idx_stmt = idx_name
         .as_ref()
         .map_or_else(|| "".to_owned(), |i| format!("INDEXED BY {i}"));
-    let mut query = format!("SELECT e.content FROM event e {idx_stmt}");
+    let mut query = format!("SELECT {columns} FROM event e {idx_stmt}");
     // query parameters for SQLite
     let mut params: Vec<Box<dyn ToSql>> = vec![];
 
@@ -1105,6 +1543,35 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
             filter_components.push(tag_clause);
         }
     }
+    // Query for tags which must contain every value
+    if let Some(map) = &f.and_tags {
+        for (key, val) in map.iter() {
+            for v in val {
+                filter_components.push(
+                    "e.id IN (SELECT t.event_id FROM tag t WHERE name=? AND value=?)".to_owned(),
+                );
+                params.push(Box::new(key.to_string()));
+                params.push(Box::new(v.clone()));
+            }
+        }
+    }
+    // Query for words in text notes, using the full-text index.
+    // Each word is quoted, so it is never read as query syntax.
+    if let Some(search) = &f.search {
+        let words: Vec<String> = search_terms(search)
+            .iter()
+            .map(|w| format!("\"{w}\""))
+            .collect();
+        filter_components
+            .push("e.id IN (SELECT rowid FROM event_fts WHERE event_fts MATCH ?)".to_owned());
+        params.push(Box::new(words.join(" ")));
+    }
+    // Query for delegated (or directly signed) events
+    match f.delegated {
+        Some(true) => filter_components.push("delegated_by IS NOT NULL".to_owned()),
+        Some(false) => filter_components.push("delegated_by IS NULL".to_owned()),
+        None => {}
+    }
     // Query for timestamp
     if f.since.is_some() {
         let created_clause = format!("created_at >= {}", f.since.unwrap());
@@ -1115,6 +1582,11 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
         let until_clause = format!("created_at <= {}", f.until.unwrap());
         filter_components.push(until_clause);
     }
+    // Query for sequence number
+    if let Some(after_seq) = f.after_seq {
+        let seq_clause = format!("seq > {after_seq}");
+        filter_components.push(seq_clause);
+    }
     // never display hidden events
     query.push_str(" WHERE hidden!=TRUE");
     // never display hidden events
@@ -1127,10 +1599,21 @@ fn query_from_filter(f: &ReqFilter) -> (String, Vec<Box<dyn ToSql>>, Option<Stri
     }
     // Apply per-filter limit to this subquery.
     // The use of a LIMIT implies a DESC order, to capture only the most recent events.
-    if let Some(lim) = f.limit {
-        let _ = write!(query, " ORDER BY e.created_at DESC LIMIT {lim}");
+    // Events with the same timestamp are always ordered by id (lowest
+    // first), so results are deterministic.
+    // Sequence pages are always in insertion order, oldest first.
+    if f.uses_sequence() {
+        query.push_str(" ORDER BY e.seq ASC");
+        if let Some(lim) = f.limit {
+            let _ = write!(query, " LIMIT {lim}");
+        }
+    } else if let Some(lim) = f.limit {
+        let _ = write!(
+            query,
+            " ORDER BY e.created_at DESC, e.event_hash ASC LIMIT {lim}"
+        );
     } else {
-        query.push_str(" ORDER BY e.created_at ASC");
+        query.push_str(" ORDER BY e.created_at ASC, e.event_hash ASC");
     }
     (query, params, idx_name)
 }
@@ -1145,7 +1628,7 @@ fn _query_from_sub(sub: &Subscription) -> (String, Vec<Box<dyn ToSql>>, Vec<Stri
     let mut params: Vec<Box<dyn ToSql>> = vec![];
     // for every filter in the subscription, generate a subquery
     for f in &sub.filters {
-        let (f_subquery, mut f_params, index) = query_from_filter(f);
+        let (f_subquery, mut f_params, index) = query_from_filter(f, false);
         if let Some(i) = index {
             indexes.push(i);
         }
@@ -1387,3 +1870,524 @@ fn _pool_at_capacity(pool: &SqlitePool) -> bool {
     let state: r2d2::State = pool.state();
     state.idle_connections == 0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::create_metrics;
+
+    async fn memory_repo() -> SqliteRepo {
+        let mut settings = Settings::default();
+        settings.database.in_memory = true;
+        let (_, metrics) = create_metrics();
+        let repo = SqliteRepo::new(&settings, metrics);
+        repo.migrate_up().await.unwrap();
+        repo
+    }
+
+    fn tagged_event(id: &str, created_at: u64, tags: Vec<Vec<String>>) -> Event {
+        let mut event = Event::simple_event();
+        event.id = id.to_owned();
+        event.pubkey = "a".repeat(64);
+        event.created_at = created_at;
+        event.kind = 1;
+        event.tags = tags;
+        event
+    }
+
+    fn tag(name: &str, value: &str) -> Vec<String> {
+        vec![name.to_owned(), value.to_owned()]
+    }
+
+    #[tokio::test]
+    async fn tag_value_any_tag_name() -> Result<()> {
+        let repo = memory_repo().await;
+        let value = "f1".repeat(32);
+        let e_ref = tagged_event(&"01".repeat(32), 10, vec![tag("e", &value)]);
+        let p_ref = tagged_event(&"02".repeat(32), 20, vec![tag("p", &value)]);
+        let custom_ref = tagged_event(&"03".repeat(32), 30, vec![tag("q", &value)]);
+        let unrelated = tagged_event(&"04".repeat(32), 40, vec![tag("e", &"f2".repeat(32))]);
+        for e in [&e_ref, &p_ref, &custom_ref, &unrelated] {
+            repo.write_event(e).await?;
+        }
+        let found = repo.events_with_tag_value(&value, 10).await?;
+        let ids: Vec<&str> = found.iter().map(|e| e.id.as_str()).collect();
+        // most recent first
+        assert_eq!(
+            ids,
+            vec![custom_ref.id.as_str(), p_ref.id.as_str(), e_ref.id.as_str()]
+        );
+        // limit is respected
+        assert_eq!(repo.events_with_tag_value(&value, 2).await?.len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn latest_created_at_per_author() -> Result<()> {
+        let repo = memory_repo().await;
+        let authored = |id: &str, pubkey: &str, created_at: u64| {
+            let mut e = tagged_event(id, created_at, vec![]);
+            e.pubkey = pubkey.to_owned();
+            e
+        };
+        let (b, c, absent) = ("b".repeat(64), "c".repeat(64), "d".repeat(64));
+        for e in [
+            authored(&"11".repeat(32), &b, 100),
+            authored(&"12".repeat(32), &b, 300),
+            authored(&"13".repeat(32), &b, 200),
+            authored(&"14".repeat(32), &c, 50),
+        ] {
+            repo.write_event(&e).await?;
+        }
+        let latest = repo
+            .latest_created_at_for(&[b.clone(), c.clone(), absent.clone()])
+            .await?;
+        assert_eq!(latest.len(), 2);
+        assert_eq!(latest.get(&b), Some(&300));
+        assert_eq!(latest.get(&c), Some(&50));
+        assert!(!latest.contains_key(&absent));
+        assert!(repo.latest_created_at_for(&[]).await?.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn projected_count_stops_past_budget() -> Result<()> {
+        let repo = memory_repo().await;
+        let author = "f".repeat(64);
+        for i in 0..5u8 {
+            let mut e = tagged_event(&format!("{:02x}", 0x20 + i).repeat(32), 10, vec![]);
+            e.pubkey = author.clone();
+            repo.write_event(&e).await?;
+        }
+        let sub = |filter: &str| -> Subscription {
+            serde_json::from_str(&format!("[\"REQ\",\"s\",{filter}]")).unwrap()
+        };
+        let all = sub(&format!("{{\"authors\":[\"{author}\"]}}"));
+        assert_eq!(repo.count_projected_results(&all, 10).await?, 5);
+        // counting stops one past the budget
+        assert_eq!(repo.count_projected_results(&all, 2).await?, 3);
+        // and respects filter limits
+        let limited = sub(&format!("{{\"authors\":[\"{author}\"],\"limit\":2}}"));
+        assert_eq!(repo.count_projected_results(&limited, 10).await?, 2);
+        Ok(())
+    }
+
+    /// Run the query for a filter, returning the matching event ids
+    /// and the number of virtual machine steps SQLite took.
+    fn scan_filter(repo: &SqliteRepo, filter: &str) -> (Vec<String>, i32) {
+        let filter: ReqFilter = serde_json::from_str(filter).unwrap();
+        let (q, p, _) = query_from_filter(&filter, false);
+        let conn = repo.read_pool.get().unwrap();
+        let mut stmt = conn.prepare(&q).unwrap();
+        let mut rows = stmt.query(rusqlite::params_from_iter(p)).unwrap();
+        let mut ids = vec![];
+        while let Some(row) = rows.next().unwrap() {
+            let json: String = row.get(0).unwrap();
+            ids.push(serde_json::from_str::<Event>(&json).unwrap().id);
+        }
+        drop(rows);
+        (ids, stmt.get_status(rusqlite::StatementStatus::VmStep))
+    }
+
+    #[tokio::test]
+    async fn limited_queries_stop_at_newest() -> Result<()> {
+        let repo = memory_repo().await;
+        let (a, b) = ("a".repeat(64), "b".repeat(64));
+        let mut seq = 0u64;
+        let mut event = |pubkey: &str, kind: u64, created_at: u64| {
+            seq += 1;
+            let mut e = tagged_event(&format!("{seq:064x}"), created_at, vec![tag("t", "x")]);
+            e.pubkey = pubkey.to_owned();
+            e.kind = kind;
+            e
+        };
+        // the newest events; a and b alternate timestamps
+        let mut newest = vec![];
+        for i in 0..15 {
+            newest.push(event(&a, 1, 1000 + 2 * i));
+            newest.push(event(&b, 7, 1001 + 2 * i));
+        }
+        for e in &newest {
+            repo.write_event(e).await?;
+        }
+        newest.sort_by(|x, y| y.created_at.cmp(&x.created_at));
+        let expect = |f: &dyn Fn(&Event) -> bool| -> Vec<String> {
+            newest
+                .iter()
+                .filter(|e| f(e))
+                .take(10)
+                .map(|e| e.id.clone())
+                .collect()
+        };
+        let streamed = [
+            (
+                format!("{{\"authors\":[\"{a}\"],\"limit\":10}}"),
+                expect(&|e| e.pubkey == a),
+            ),
+            (
+                format!("{{\"authors\":[\"{a}\",\"{b}\"],\"limit\":10}}"),
+                expect(&|_| true),
+            ),
+            (
+                "{\"kinds\":[1,7],\"limit\":10}".to_owned(),
+                expect(&|_| true),
+            ),
+            ("{\"limit\":10}".to_owned(), expect(&|_| true)),
+        ];
+        let tagged = [
+            (
+                format!("{{\"authors\":[\"{a}\",\"{b}\"],\"#t\":[\"x\"],\"limit\":10}}"),
+                expect(&|_| true),
+            ),
+            (
+                "{\"kinds\":[7],\"#t\":[\"x\"],\"limit\":10}".to_owned(),
+                expect(&|e| e.kind == 7),
+            ),
+        ];
+        let mut steps = vec![];
+        for (filter, ids) in streamed.iter().chain(tagged.iter()) {
+            let (found, s) = scan_filter(&repo, filter);
+            assert_eq!(&found, ids, "filter {filter}");
+            steps.push(s);
+        }
+        // many older events match as well
+        for i in 0..300 {
+            repo.write_event(&event(&a, 1, i)).await?;
+            repo.write_event(&event(&b, 7, i)).await?;
+        }
+        for (i, (filter, ids)) in streamed.iter().chain(tagged.iter()).enumerate() {
+            let (found, s) = scan_filter(&repo, filter);
+            assert_eq!(&found, ids, "filter {filter}");
+            // newest-first scans do the same work regardless of how
+            // many older events match
+            if i < streamed.len() {
+                assert_eq!(s, steps[i], "filter {filter}");
+            }
+        }
+        Ok(())
+    }
+
+    /// Log output captured from every thread.
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for LogBuffer {
+        type Writer = LogBuffer;
+
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_query_logged() -> Result<()> {
+        // queries run on blocking threads, so capture logs globally
+        let logs = LogBuffer::default();
+        let subscriber = tracing_subscriber::fmt()
+            .with_writer(logs.clone())
+            .with_max_level(tracing::Level::WARN)
+            .with_ansi(false)
+            .finish();
+        tracing::subscriber::set_global_default(subscriber).unwrap();
+        let mut settings = Settings::default();
+        settings.database.in_memory = true;
+        // every query is slower than this
+        settings.database.slow_query_threshold_ms = Some(0);
+        let (_, metrics) = create_metrics();
+        let repo = SqliteRepo::new(&settings, metrics);
+        repo.migrate_up().await?;
+        let author = "5".repeat(64);
+        let mut e = tagged_event(&"51".repeat(32), 10, vec![]);
+        e.pubkey = author.clone();
+        repo.write_event(&e).await?;
+        let sub: Subscription = serde_json::from_str(&format!(
+            "[\"REQ\",\"slow\",{{\"authors\":[\"{author}\"]}}]"
+        ))?;
+        let (query_tx, mut query_rx) = tokio::sync::mpsc::channel(16);
+        let (_abandon_tx, abandon_rx) = tokio::sync::oneshot::channel();
+        repo.query_subscription(sub, "cid".to_owned(), query_tx, abandon_rx)
+            .await?;
+        while let Some(r) = query_rx.recv().await {
+            if r.event == "EOSE" {
+                break;
+            }
+        }
+        let output = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line = output
+            .lines()
+            .find(|l| l.contains("slow query") && l.contains(&author))
+            .expect("slow query was not logged");
+        assert!(line.contains("WARN"));
+        assert!(line.contains("rows: 1"));
+        assert!(line.contains("sub: \"slow\""));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tombstone_for_deleted_event() -> Result<()> {
+        let repo = memory_repo().await;
+        let author = "e".repeat(64);
+        let authored = |id: &str, kind: u64, tags: Vec<Vec<String>>| {
+            let mut e = tagged_event(id, 500, tags);
+            e.pubkey = author.clone();
+            e.kind = kind;
+            e
+        };
+        let (deleted, kept, deletion) = ("21".repeat(32), "22".repeat(32), "23".repeat(32));
+        for e in [
+            authored(&deleted, 1, vec![]),
+            authored(&kept, 1, vec![]),
+            authored(&deletion, 5, vec![tag("e", &deleted)]),
+        ] {
+            repo.write_event(&e).await?;
+        }
+        let tombstones = repo
+            .tombstones_for(&[deleted.clone(), kept.clone(), "24".repeat(32)])
+            .await?;
+        assert_eq!(tombstones.len(), 1);
+        assert_eq!(tombstones.get(&deleted), Some(&deletion));
+        Ok(())
+    }
+
+    /// Ids of the stored events of a kind
+    fn stored_ids(repo: &SqliteRepo, kind: u64) -> Vec<String> {
+        let conn = repo.read_pool.get().unwrap();
+        let mut stmt = conn
+            .prepare("SELECT event_hash FROM event WHERE kind=? ORDER BY event_hash;")
+            .unwrap();
+        stmt.query_map(params![kind], |r| r.get::<usize, Vec<u8>>(0))
+            .unwrap()
+            .filter_map(|r| r.ok())
+            .map(hex::encode)
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn replaceable_keeps_newest() -> Result<()> {
+        let (older, newer) = (
+            tagged_event(&"61".repeat(32), 10, vec![]),
+            tagged_event(&"60".repeat(32), 20, vec![]),
+        );
+        // the newer event survives, whichever arrives first
+        for order in [[&older, &newer], [&newer, &older]] {
+            let repo = memory_repo().await;
+            for e in order {
+                let mut e = e.clone();
+                e.kind = 0;
+                repo.write_event(&e).await?;
+            }
+            assert_eq!(stored_ids(&repo, 0), vec![newer.id.clone()]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn replaceable_tie_keeps_lowest_id() -> Result<()> {
+        let (low, high) = ("62".repeat(32), "63".repeat(32));
+        for (kind, tags) in [(10_002, vec![]), (30_023, vec![tag("d", "x")])] {
+            for order in [[&low, &high], [&high, &low]] {
+                let repo = memory_repo().await;
+                for id in order {
+                    let mut e = tagged_event(id, 30, tags.clone());
+                    e.kind = kind;
+                    repo.write_event(&e).await?;
+                }
+                assert_eq!(stored_ids(&repo, kind), vec![low.clone()]);
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn param_replaceable_by_first_d_tag() -> Result<()> {
+        let repo = memory_repo().await;
+        let event = |n: u8, created_at: u64, tags: Vec<Vec<String>>| {
+            let mut e = tagged_event(&format!("{n:02x}").repeat(32), created_at, tags);
+            e.kind = 30_000;
+            e
+        };
+        // events sharing a d tag replace each other
+        repo.write_event(&event(0x71, 10, vec![tag("d", "a")]))
+            .await?;
+        repo.write_event(&event(0x72, 20, vec![tag("d", "a")]))
+            .await?;
+        // a missing d tag is the same as an empty one
+        repo.write_event(&event(0x73, 10, vec![])).await?;
+        repo.write_event(&event(0x74, 20, vec![tag("d", "")]))
+            .await?;
+        // only the first d tag is the identifier
+        repo.write_event(&event(0x75, 10, vec![tag("d", "b"), tag("d", "c")]))
+            .await?;
+        repo.write_event(&event(0x76, 20, vec![tag("d", "c")]))
+            .await?;
+        let expected: Vec<String> = ["72", "74", "75", "76"]
+            .iter()
+            .map(|n| n.repeat(32))
+            .collect();
+        assert_eq!(stored_ids(&repo, 30_000), expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn current_replaceable_versions() -> Result<()> {
+        let repo = memory_repo().await;
+        let author = "c".repeat(64);
+        let authored = |n: u8, kind: u64, created_at: u64, tags: Vec<Vec<String>>| {
+            let mut e = tagged_event(&format!("{n:02x}").repeat(32), created_at, tags);
+            e.pubkey = author.clone();
+            e.kind = kind;
+            e
+        };
+        let events = [
+            authored(0x41, 0, 10, vec![]),
+            authored(0x42, 0, 30, vec![]),
+            authored(0x43, 0, 20, vec![]),
+            authored(0x44, 3, 10, vec![]),
+            authored(0x45, 3, 11, vec![]),
+            authored(0x46, 10_002, 10, vec![]),
+            authored(0x47, 30_023, 10, vec![tag("d", "a")]),
+            authored(0x48, 30_023, 20, vec![tag("d", "a")]),
+            authored(0x49, 30_023, 15, vec![tag("d", "b")]),
+            authored(0x4a, 1, 40, vec![]),
+        ];
+        for e in &events {
+            repo.write_event(e).await?;
+        }
+        let mut other = events[0].clone();
+        other.id = "4b".repeat(32);
+        other.pubkey = "d".repeat(64);
+        repo.write_event(&other).await?;
+        let current = repo.current_replaceable_for(&author).await?;
+        let ids: Vec<&str> = current.iter().map(|e| &e.id[..2]).collect();
+        assert_eq!(ids, vec!["42", "45", "46", "48", "49"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn storage_stats_for_author() -> Result<()> {
+        let repo = memory_repo().await;
+        let author = "f".repeat(64);
+        let sized = |id: &str, content_len: usize| {
+            let mut e = tagged_event(id, 600, vec![]);
+            e.pubkey = author.clone();
+            e.content = "x".repeat(content_len);
+            e
+        };
+        let events = [
+            sized(&"31".repeat(32), 10),
+            sized(&"32".repeat(32), 1000),
+            sized(&"33".repeat(32), 100),
+        ];
+        let sizes: Vec<u64> = events
+            .iter()
+            .map(|e| serde_json::to_string(e).unwrap().len() as u64)
+            .collect();
+        for e in &events {
+            repo.write_event(e).await?;
+        }
+        let stats = repo.storage_stats(Some(&author), 2).await?;
+        let total: u64 = sizes.iter().sum();
+        assert_eq!(stats.events, 3);
+        assert_eq!(stats.total_bytes, total);
+        assert_eq!(stats.average_bytes, total / 3);
+        assert_eq!(
+            stats.largest,
+            vec![
+                EventSize {
+                    id: events[1].id.clone(),
+                    bytes: sizes[1]
+                },
+                EventSize {
+                    id: events[2].id.clone(),
+                    bytes: sizes[2]
+                },
+            ]
+        );
+        // events of other authors are counted without a filter
+        assert!(repo.storage_stats(None, 1).await?.events >= 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn orphaned_tags_cleaned() -> Result<()> {
+        let repo = memory_repo().await;
+        let value = "f3".repeat(32);
+        let kept = tagged_event(&"71".repeat(32), 10, vec![tag("e", &value)]);
+        repo.write_event(&kept).await?;
+        {
+            // orphan some index rows, as a crash or manual edit might
+            let conn = repo.write_pool.get()?;
+            conn.execute_batch("PRAGMA foreign_keys = OFF;")?;
+            for event_id in [9_001, 9_002] {
+                conn.execute(
+                    "INSERT INTO tag (event_id, name, value, created_at, kind) VALUES (?, 'e', ?, 10, 1);",
+                    params![event_id, value],
+                )?;
+            }
+            conn.execute_batch("PRAGMA foreign_keys = ON;")?;
+        }
+        assert_eq!(repo.orphaned_tags(false).await?, 2);
+        assert_eq!(repo.orphaned_tags(true).await?, 2);
+        assert_eq!(repo.orphaned_tags(false).await?, 0);
+        // tags of stored events are untouched
+        let found = repo.events_with_tag_value(&value, 10).await?;
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id, kept.id);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn limit_boundary_ties() -> Result<()> {
+        let repo = memory_repo().await;
+        let newest = tagged_event(&"81".repeat(32), 30, vec![]);
+        let tied: Vec<Event> = ["84", "82", "83"]
+            .iter()
+            .map(|id| tagged_event(&id.repeat(32), 20, vec![]))
+            .collect();
+        let oldest = tagged_event(&"85".repeat(32), 10, vec![]);
+        for e in tied.iter().chain([&newest, &oldest]) {
+            repo.write_event(e).await?;
+        }
+        let ids = |filter: &str, ties: bool| -> Vec<String> {
+            let filter: ReqFilter = serde_json::from_str(filter).unwrap();
+            let (q, p, _) = query_from_filter(&filter, ties);
+            let conn = repo.read_pool.get().unwrap();
+            let mut stmt = conn.prepare(&q).unwrap();
+            let rows = stmt
+                .query_map(rusqlite::params_from_iter(p), |r| r.get::<usize, String>(0))
+                .unwrap();
+            rows.map(|r| serde_json::from_str::<Event>(&r.unwrap()).unwrap().id)
+                .collect()
+        };
+        let (n, t) = (newest.id.clone(), |id: &str| id.repeat(32));
+        // cut by id, lowest first
+        assert_eq!(
+            ids(r#"{"kinds":[1],"limit":2}"#, false),
+            vec![n.clone(), t("82")]
+        );
+        // or the whole group, with or without a split query
+        let group = vec![n.clone(), t("82"), t("83"), t("84")];
+        assert_eq!(ids(r#"{"kinds":[1],"limit":2}"#, true), group);
+        assert_eq!(ids(r#"{"kinds":[1,7],"limit":2}"#, true), group);
+        // a limit ending on a unique timestamp is not extended
+        assert_eq!(ids(r#"{"kinds":[1],"limit":1}"#, true), vec![n]);
+        // so the next page starts just before the group
+        assert_eq!(
+            ids(r#"{"kinds":[1],"until":19,"limit":2}"#, true),
+            vec![oldest.id.clone()]
+        );
+        assert!(ids(r#"{"kinds":[1],"until":9,"limit":2}"#, true).is_empty());
+        Ok(())
+    }
+}
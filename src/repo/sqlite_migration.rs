@@ -23,7 +23,7 @@ pragma mmap_size = 0; -- disable mmap (default)
 "##;
 
 /// Latest database version
-pub const DB_VERSION: usize = 18;
+pub const DB_VERSION: usize = 21;
 
 /// Schema definition
 const INIT_SQL: &str = formatcp!(
@@ -48,9 +48,16 @@ author BLOB NOT NULL, -- author pubkey
 delegated_by BLOB, -- delegator pubkey (NIP-26)
 kind INTEGER NOT NULL, -- event kind
 hidden INTEGER, -- relevant for queries
+seq INTEGER, -- insertion order, assigned from event_sequence
 content TEXT NOT NULL -- serialized json of event object
 );
 
+-- Last sequence number assigned to an event
+CREATE TABLE IF NOT EXISTS event_sequence (
+last INTEGER NOT NULL
+);
+INSERT INTO event_sequence (last) SELECT 0 WHERE NOT EXISTS (SELECT 1 FROM event_sequence);
+
 -- Event Indexes
 CREATE UNIQUE INDEX IF NOT EXISTS event_hash_index ON event(event_hash);
 CREATE INDEX IF NOT EXISTS author_index ON event(author);
@@ -63,6 +70,7 @@ CREATE INDEX IF NOT EXISTS kind_created_at_index ON event(kind,created_at);
 CREATE INDEX IF NOT EXISTS author_created_at_index ON event(author,created_at);
 CREATE INDEX IF NOT EXISTS author_kind_index ON event(author,kind);
 CREATE INDEX IF NOT EXISTS event_expiration ON event(expires_at);
+CREATE UNIQUE INDEX IF NOT EXISTS event_seq_index ON event(seq);
 
 -- Tag Table
 -- Tag values are stored as either a BLOB (if they come in as a
@@ -84,6 +92,17 @@ CREATE INDEX IF NOT EXISTS tag_composite_index ON tag(event_id,name,value);
 CREATE INDEX IF NOT EXISTS tag_name_eid_index ON tag(name,event_id,value);
 CREATE INDEX IF NOT EXISTS tag_covering_index ON tag(name,kind,value,created_at,event_id);
 
+-- Full-text index of text note content (NIP-50 search)
+-- Rows share the rowid of their event, and follow it in and out of
+-- the event table.
+CREATE VIRTUAL TABLE IF NOT EXISTS event_fts USING fts5(content);
+CREATE TRIGGER IF NOT EXISTS event_fts_insert AFTER INSERT ON event WHEN new.kind=1 BEGIN
+INSERT INTO event_fts (rowid, content) VALUES (new.id, json_extract(new.content, '$.content'));
+END;
+CREATE TRIGGER IF NOT EXISTS event_fts_delete AFTER DELETE ON event WHEN old.kind=1 BEGIN
+DELETE FROM event_fts WHERE rowid=old.id;
+END;
+
 -- NIP-05 User Validation
 CREATE TABLE IF NOT EXISTS user_verification (
 id INTEGER PRIMARY KEY,
@@ -124,6 +143,11 @@ CONSTRAINT invoice_pubkey_fkey FOREIGN KEY (pubkey) REFERENCES account (pubkey)
 -- Create invoice index
 CREATE INDEX IF NOT EXISTS invoice_pubkey_index ON invoice(pubkey);
 
+-- Banned pubkeys
+CREATE TABLE IF NOT EXISTS pubkey_ban (
+pubkey TEXT PRIMARY KEY, -- hex-encoded author pubkey
+banned_at INTEGER NOT NULL -- when the ban was issued (seconds since 1970)
+);
 
 "##,
     DB_VERSION
@@ -245,6 +269,15 @@ pub fn upgrade_db(conn: &mut PooledConnection) -> Result<usize> {
             if curr_version == 17 {
                 curr_version = mig_17_to_18(conn)?;
             }
+            if curr_version == 18 {
+                curr_version = mig_18_to_19(conn)?;
+            }
+            if curr_version == 19 {
+                curr_version = mig_19_to_20(conn)?;
+            }
+            if curr_version == 20 {
+                curr_version = mig_20_to_21(conn)?;
+            }
 
             if curr_version == DB_VERSION {
                 info!(
@@ -839,3 +872,76 @@ PRAGMA user_version = 18;
     }
     Ok(18)
 }
+
+fn mig_18_to_19(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 18->19");
+    let upgrade_sql = r##"
+-- Create banned pubkeys table
+CREATE TABLE IF NOT EXISTS pubkey_ban (
+pubkey TEXT PRIMARY KEY,
+banned_at INTEGER NOT NULL
+);
+PRAGMA user_version = 19;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v18 -> v19");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(19)
+}
+
+fn mig_19_to_20(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 19->20");
+    let upgrade_sql = r##"
+-- Number existing events in insertion order
+ALTER TABLE event ADD seq INTEGER;
+UPDATE event SET seq=id;
+CREATE UNIQUE INDEX IF NOT EXISTS event_seq_index ON event(seq);
+CREATE TABLE IF NOT EXISTS event_sequence (
+last INTEGER NOT NULL
+);
+INSERT INTO event_sequence (last) SELECT IFNULL(MAX(seq), 0) FROM event;
+PRAGMA user_version = 20;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v19 -> v20");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(20)
+}
+
+fn mig_20_to_21(conn: &mut PooledConnection) -> Result<usize> {
+    info!("database schema needs update from 20->21");
+    let upgrade_sql = r##"
+-- Full-text index of text note content, kept in step with the event table
+CREATE VIRTUAL TABLE IF NOT EXISTS event_fts USING fts5(content);
+CREATE TRIGGER IF NOT EXISTS event_fts_insert AFTER INSERT ON event WHEN new.kind=1 BEGIN
+INSERT INTO event_fts (rowid, content) VALUES (new.id, json_extract(new.content, '$.content'));
+END;
+CREATE TRIGGER IF NOT EXISTS event_fts_delete AFTER DELETE ON event WHEN old.kind=1 BEGIN
+DELETE FROM event_fts WHERE rowid=old.id;
+END;
+INSERT INTO event_fts (rowid, content) SELECT id, json_extract(content, '$.content') FROM event WHERE kind=1;
+PRAGMA user_version = 21;
+"##;
+    match conn.execute_batch(upgrade_sql) {
+        Ok(()) => {
+            info!("database schema upgraded v20 -> v21");
+        }
+        Err(err) => {
+            error!("update failed: {}", err);
+            panic!("database could not be upgraded");
+        }
+    }
+    Ok(21)
+}
@@ -0,0 +1,52 @@
+//! Stored subscription cursors for resuming after a reconnect
+//!
+//! For authenticated clients, the last sequence number delivered to
+//! each subscription is remembered (for the life of the relay
+//! process), so a client reconnecting with the same subscription id
+//! receives only events stored since.
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+/// Shared map of (pubkey, subscription id) to the last delivered
+/// sequence number.
+#[derive(Debug, Clone, Default)]
+pub struct ResumeCursors {
+    cursors: Arc<RwLock<HashMap<(String, String), u64>>>,
+}
+
+impl ResumeCursors {
+    /// The stored cursor for a pubkey's subscription, if any.
+    #[must_use]
+    pub fn get(&self, pubkey: &str, sub_id: &str) -> Option<u64> {
+        self.cursors
+            .read()
+            .ok()
+            .and_then(|map| map.get(&(pubkey.to_owned(), sub_id.to_owned())).copied())
+    }
+
+    /// Record a delivered cursor.  Cursors never move backwards.
+    pub fn record(&self, pubkey: &str, sub_id: &str, seq: u64) {
+        if let Ok(mut map) = self.cursors.write() {
+            let cursor = map
+                .entry((pubkey.to_owned(), sub_id.to_owned()))
+                .or_insert(seq);
+            *cursor = (*cursor).max(seq);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cursors_keyed_by_pubkey_and_sub() {
+        let cursors = ResumeCursors::default();
+        assert_eq!(cursors.get("abcd", "feed"), None);
+        cursors.record("abcd", "feed", 10);
+        cursors.record("abcd", "feed", 7);
+        assert_eq!(cursors.get("abcd", "feed"), Some(10));
+        assert_eq!(cursors.get("abcd", "other"), None);
+        assert_eq!(cursors.get("ef01", "feed"), None);
+    }
+}
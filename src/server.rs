@@ -1,26 +1,46 @@
 //! Server process
+use crate::admission::{reject_client_event, reject_unverified_event};
+use crate::announce;
+use crate::audit::{AuditLog, ConnectionAudit, Submission};
+use crate::blocklist::Blocklist;
 use crate::close::Close;
 use crate::close::CloseCmd;
-use crate::config::{Settings, VerifiedUsersMode};
+use crate::config::{Limits, Settings, VerifiedUsersMode};
 use crate::conn;
+use crate::conn::IpConnections;
 use crate::db;
 use crate::db::SubmittedEvent;
+use crate::diskspace::{self, DiskGuard, StatvfsProbe};
 use crate::error::{Error, Result};
 use crate::event::Event;
+use crate::event::EventBatchCmd;
 use crate::event::EventCmd;
 use crate::event::EventWrapper;
+use crate::extensions::{self, ExtensionsCmd};
+use crate::forward;
+use crate::import;
 use crate::info::RelayInfo;
+use crate::kinds::KindPolicies;
+use crate::localization;
 use crate::nip05;
 use crate::notice::Notice;
 use crate::payment;
 use crate::payment::InvoiceInfo;
 use crate::payment::PaymentMessage;
-use crate::repo::NostrRepo;
+use crate::quarantine::Quarantine;
+use crate::reload::{self, LiveSettings};
+use crate::repo::{parse_cursor_sentinel, NostrRepo, TRUNCATED_SENTINEL};
+use crate::resume::ResumeCursors;
 use crate::server::Error::CommandUnknownError;
 use crate::server::EventWrapper::{WrappedAuth, WrappedEvent};
-use crate::subscription::Subscription;
+use crate::subscription::{ReqFilter, Subscription};
+use crate::utils::{constant_time_eq, is_lower_hex, unix_time};
+use crate::verify::{latency_buckets, Secp256k1Verifier, TimedVerifier, Verifier};
 use futures::SinkExt;
 use futures::StreamExt;
+use governor::clock::{Clock, DefaultClock};
+use governor::middleware::NoOpMiddleware;
+use governor::state::{InMemoryState, NotKeyed};
 use governor::{Jitter, Quota, RateLimiter};
 use http::header::HeaderMap;
 use hyper::body::to_bytes;
@@ -28,7 +48,7 @@ use hyper::header::ACCEPT;
 use hyper::service::{make_service_fn, service_fn};
 use hyper::upgrade::Upgraded;
 use hyper::{
-    header, server::conn::AddrStream, upgrade, Body, Request, Response, Server, StatusCode,
+    header, server::conn::AddrStream, upgrade, Body, Method, Request, Response, Server, StatusCode,
 };
 use prometheus::IntCounterVec;
 use prometheus::IntGauge;
@@ -53,11 +73,15 @@ use tokio::runtime::Builder;
 use tokio::sync::broadcast::{self, Receiver, Sender};
 use tokio::sync::mpsc;
 use tokio::sync::oneshot;
+use tokio::sync::Semaphore;
+use tokio::sync::watch;
 use tokio_tungstenite::WebSocketStream;
 use tracing::{debug, error, info, trace, warn};
 use tungstenite::error::CapacityError::MessageTooLong;
 use tungstenite::error::Error as WsError;
 use tungstenite::handshake;
+use tungstenite::protocol::frame::coding::CloseCode;
+use tungstenite::protocol::frame::CloseFrame;
 use tungstenite::protocol::Message;
 use tungstenite::protocol::WebSocketConfig;
 use nostr::key::FromPkStr;
@@ -69,14 +93,24 @@ async fn handle_web_request(
     mut request: Request<Body>,
     repo: Arc<dyn NostrRepo>,
     settings: Settings,
+    reloads: watch::Receiver<Arc<Settings>>,
     remote_addr: SocketAddr,
     broadcast: Sender<Event>,
     event_tx: tokio::sync::mpsc::Sender<SubmittedEvent>,
     payment_tx: tokio::sync::broadcast::Sender<PaymentMessage>,
+    blocklist: Blocklist,
+    quarantine: Quarantine,
+    connection_slots: Option<Arc<Semaphore>>,
+    ip_connections: Option<IpConnections>,
+    subscription_limiter: Option<Arc<SubscriptionLimiter>>,
     shutdown: Receiver<()>,
     favicon: Option<Vec<u8>>,
     registry: Registry,
     metrics: NostrMetrics,
+    verifier: Arc<dyn Verifier>,
+    cursors: ResumeCursors,
+    disk_guard: DiskGuard,
+    audit: Option<AuditLog>,
 ) -> Result<Response<Body>, Infallible> {
     match (
         request.uri().path(),
@@ -105,7 +139,7 @@ async fn handle_web_request(
                                     ..Default::default()
                                 };
                                 //create a websocket stream from the upgraded object
-                                let ws_stream = WebSocketStream::from_raw_socket(
+                                let mut ws_stream = WebSocketStream::from_raw_socket(
                                     //pass the upgraded object
                                     //as the base layer stream of the Websocket
                                     upgraded,
@@ -113,6 +147,29 @@ async fn handle_web_request(
                                     Some(config),
                                 )
                                 .await;
+                                // reserve a slot if the number of connections is limited;
+                                // the permit is held until the connection ends.
+                                let permit = match connection_slots {
+                                    Some(slots) => match slots.try_acquire_owned() {
+                                        Ok(p) => Some(p),
+                                        Err(_) => {
+                                            info!("refusing connection from {}, max connections reached", remote_addr);
+                                            let notice = Notice::message(
+                                                "overloaded: relay has reached its maximum connections, try again later".into(),
+                                            );
+                                            ws_stream.send(make_notice_message(&notice)).await.ok();
+                                            ws_stream
+                                                .send(Message::Close(Some(CloseFrame {
+                                                    code: CloseCode::Again,
+                                                    reason: "max connections reached".into(),
+                                                })))
+                                                .await
+                                                .ok();
+                                            return;
+                                        }
+                                    },
+                                    None => None,
+                                };
                                 let origin = get_header_string("origin", request.headers());
                                 let user_agent = get_header_string("user-agent", request.headers());
                                 // determine the remote IP from headers if the exist
@@ -124,22 +181,80 @@ async fn handle_web_request(
                                 // use the socket addr as a backup
                                 let remote_ip =
                                     header_ip.unwrap_or_else(|| remote_addr.ip().to_string());
+                                // count the connection against its address, if limited;
+                                // the slot is held until the connection ends.
+                                let ip_slot = match ip_connections {
+                                    Some(ips) => match ips.try_acquire(connection_ip(&remote_ip)) {
+                                        Some(slot) => Some(slot),
+                                        None => {
+                                            info!("refusing connection from {}, max connections per address reached", remote_ip);
+                                            let notice = Notice::message(
+                                                "rate-limited: too many connections from your address".into(),
+                                            );
+                                            ws_stream.send(make_notice_message(&notice)).await.ok();
+                                            ws_stream
+                                                .send(Message::Close(Some(CloseFrame {
+                                                    code: CloseCode::Policy,
+                                                    reason: "too many connections from this address".into(),
+                                                })))
+                                                .await
+                                                .ok();
+                                            return;
+                                        }
+                                    },
+                                    None => None,
+                                };
+                                // a TLS-terminating proxy tells us if the client connection is secure
+                                let secure = settings
+                                    .network
+                                    .forwarded_proto_header
+                                    .as_ref()
+                                    .and_then(|x| get_header_string(x, request.headers()))
+                                    .map_or(false, |proto| {
+                                        proto.eq_ignore_ascii_case("https")
+                                            || proto.eq_ignore_ascii_case("wss")
+                                    });
+                                // the language for messages, from a "lang" query parameter or the Accept-Language header
+                                let requested_lang = request.uri().query().and_then(|query| {
+                                    url::form_urlencoded::parse(query.as_bytes())
+                                        .find(|(k, _)| k == "lang")
+                                        .map(|(_, v)| v.into_owned())
+                                });
+                                let language = localization::select_language(
+                                    &settings.localization,
+                                    requested_lang.as_deref(),
+                                    get_header_string("accept-language", request.headers()).as_deref(),
+                                );
                                 let client_info = ClientInfo {
                                     remote_ip,
                                     user_agent,
                                     origin,
+                                    secure,
+                                    language,
                                 };
                                 // spawn a nostr server with our websocket
-                                tokio::spawn(nostr_server(
-                                    repo,
-                                    client_info,
-                                    settings,
-                                    ws_stream,
-                                    broadcast,
-                                    event_tx,
-                                    shutdown,
-                                    metrics,
-                                ));
+                                tokio::spawn(async move {
+                                    nostr_server(
+                                        repo,
+                                        client_info,
+                                        settings,
+                                        reloads,
+                                        ws_stream,
+                                        broadcast,
+                                        event_tx,
+                                        shutdown,
+                                        metrics,
+                                        verifier,
+                                        cursors,
+                                        disk_guard,
+                                        subscription_limiter,
+                                        audit,
+                                    )
+                                    .await;
+                                    // release the connection slots
+                                    drop(permit);
+                                    drop(ip_slot);
+                                });
                             }
                             // todo: trace, don't print...
                             Err(e) => println!(
@@ -200,18 +315,7 @@ async fn handle_web_request(
                 .body(Body::from("Please use a Nostr client to connect."))
                 .unwrap())
         }
-        ("/metrics", false) => {
-            let mut buffer = vec![];
-            let encoder = TextEncoder::new();
-            let metric_families = registry.gather();
-            encoder.encode(&metric_families, &mut buffer).unwrap();
-
-            Ok(Response::builder()
-                .status(StatusCode::OK)
-                .header("Content-Type", "text/plain")
-                .body(Body::from(buffer))
-                .unwrap())
-        }
+        ("/metrics", false) => Ok(metrics_response(&registry)),
         ("/favicon.ico", false) => {
             if let Some(favicon_bytes) = favicon {
                 info!("returning favicon");
@@ -229,6 +333,46 @@ async fn handle_web_request(
                     .unwrap())
             }
         }
+        // Recent stored events, as a JSON array
+        ("/recent", false) => {
+            let kinds: Option<Vec<u64>> = match get_query_param(&request, "kinds") {
+                Some(ks) => match ks.split(',').map(str::parse).collect() {
+                    Ok(ks) => Some(ks),
+                    Err(_) => {
+                        return Ok(Response::builder()
+                            .status(StatusCode::BAD_REQUEST)
+                            .header("Content-Type", "text/plain")
+                            .body(Body::from("kinds must be a comma-separated list of integers"))
+                            .unwrap());
+                    }
+                },
+                None => None,
+            };
+            let limit = get_query_param(&request, "limit")
+                .and_then(|l| l.parse::<u64>().ok())
+                .unwrap_or(20)
+                .min(settings.limits.max_limit.unwrap_or(1000));
+            let filter = ReqFilter {
+                kinds,
+                limit: Some(limit),
+                ..Default::default()
+            };
+            let conn = conn::ClientConn::new(remote_addr.ip().to_string());
+            let events = recent_events(repo, filter, &conn, &settings).await;
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .header("Access-Control-Allow-Origin", "*")
+                .body(Body::from(format!("[{}]", events.join(","))))
+                .unwrap())
+        }
+        // Per-kind publishing policies, as JSON
+        ("/kinds", false) => Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header("Content-Type", "application/json")
+            .header("Access-Control-Allow-Origin", "*")
+            .body(Body::from(json!(KindPolicies::from(&settings)).to_string()))
+            .unwrap()),
         // LN bits callback endpoint for paid invoices
         ("/lnbits", false) => {
             let callback: payment::lnbits::LNBitsCallback =
@@ -248,6 +392,280 @@ async fn handle_web_request(
                 .body(Body::from("ok"))
                 .unwrap())
         }
+        // Admin endpoint to ban a pubkey
+        ("/admin/ban", false) => {
+            if request.method() != Method::POST {
+                return Ok(Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .body(Body::from("Use POST"))
+                    .unwrap());
+            }
+            if !is_admin_request(request.headers(), &settings) {
+                return Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from("Admin authorization required"))
+                    .unwrap());
+            }
+            let ban_req: Option<AdminBanRequest> = to_bytes(request.into_body())
+                .await
+                .ok()
+                .and_then(|b| serde_json::from_slice(&b).ok());
+            let ban_req = match ban_req {
+                Some(b) if b.pubkey.len() == 64 && is_lower_hex(&b.pubkey) => b,
+                _ => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .header("Content-Type", "text/plain")
+                        .body(Body::from("Expected a JSON body with a hex pubkey"))
+                        .unwrap());
+                }
+            };
+            if let Err(e) = repo.ban_pubkey(&ban_req.pubkey).await {
+                warn!("could not persist pubkey ban: {}", e);
+                return Ok(Response::builder()
+                    .status(StatusCode::INTERNAL_SERVER_ERROR)
+                    .body(Body::from("Error saving ban"))
+                    .unwrap());
+            }
+            blocklist.insert(&ban_req.pubkey);
+            info!("banned pubkey: {:?}", &ban_req.pubkey);
+            let mut purged = 0;
+            if ban_req.purge {
+                match repo.delete_author_events(&ban_req.pubkey).await {
+                    Ok(count) => {
+                        info!("purged {} events from banned pubkey", count);
+                        purged = count;
+                    }
+                    Err(e) => {
+                        warn!("could not purge events from banned pubkey: {}", e);
+                        return Ok(Response::builder()
+                            .status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(Body::from("Pubkey banned, but events could not be purged"))
+                            .unwrap());
+                    }
+                }
+            }
+            let body = json!({"pubkey": ban_req.pubkey, "banned": true, "purged": purged});
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap())
+        }
+        // Admin endpoint to find events referencing a tag value
+        ("/admin/references", false) => {
+            if !is_admin_request(request.headers(), &settings) {
+                return Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from("Admin authorization required"))
+                    .unwrap());
+            }
+            let value = match get_query_param(&request, "value") {
+                Some(v) => v,
+                None => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .header("Content-Type", "text/plain")
+                        .body(Body::from("Missing value parameter"))
+                        .unwrap());
+                }
+            };
+            let limit = get_query_param(&request, "limit")
+                .and_then(|l| l.parse::<u64>().ok())
+                .unwrap_or(100)
+                .min(settings.limits.max_limit.unwrap_or(1000));
+            match repo.events_with_tag_value(&value, limit).await {
+                Ok(events) => Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(json!(events).to_string()))
+                    .unwrap()),
+                Err(e) => {
+                    warn!("could not query tag references: {}", e);
+                    Ok(Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::from("Error querying references"))
+                        .unwrap())
+                }
+            }
+        }
+        // Admin endpoint to summarize event storage
+        ("/admin/storage", false) => {
+            if !is_admin_request(request.headers(), &settings) {
+                return Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from("Admin authorization required"))
+                    .unwrap());
+            }
+            let author = get_query_param(&request, "author");
+            if let Some(a) = &author {
+                if a.len() != 64 || !is_lower_hex(a) {
+                    return Ok(Response::builder()
+                        .status(StatusCode::BAD_REQUEST)
+                        .header("Content-Type", "text/plain")
+                        .body(Body::from("author must be a hex pubkey"))
+                        .unwrap());
+                }
+            }
+            let top = get_query_param(&request, "top")
+                .and_then(|l| l.parse::<u64>().ok())
+                .unwrap_or(10)
+                .min(settings.limits.max_limit.unwrap_or(1000));
+            match repo.storage_stats(author.as_deref(), top).await {
+                Ok(stats) => Ok(Response::builder()
+                    .status(StatusCode::OK)
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(json!(stats).to_string()))
+                    .unwrap()),
+                Err(e) => {
+                    warn!("could not query storage stats: {}", e);
+                    Ok(Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::from("Error querying storage"))
+                        .unwrap())
+                }
+            }
+        }
+        // Admin endpoint to find (GET) or remove (POST) tag index rows
+        // left behind by deleted events
+        ("/admin/cleanup", false) => {
+            if !is_admin_request(request.headers(), &settings) {
+                return Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from("Admin authorization required"))
+                    .unwrap());
+            }
+            let remove = match *request.method() {
+                Method::GET => false,
+                Method::POST => true,
+                _ => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::METHOD_NOT_ALLOWED)
+                        .body(Body::from("Use GET or POST"))
+                        .unwrap());
+                }
+            };
+            match repo.orphaned_tags(remove).await {
+                Ok(count) => {
+                    if remove {
+                        info!("removed {} orphaned tag index rows", count);
+                    }
+                    Ok(Response::builder()
+                        .status(StatusCode::OK)
+                        .header("Content-Type", "application/json")
+                        .body(Body::from(
+                            json!({"orphaned_tags": count, "removed": remove}).to_string(),
+                        ))
+                        .unwrap())
+                }
+                Err(e) => {
+                    warn!("could not clean up orphaned tags: {}", e);
+                    Ok(Response::builder()
+                        .status(StatusCode::INTERNAL_SERVER_ERROR)
+                        .body(Body::from("Error cleaning up tags"))
+                        .unwrap())
+                }
+            }
+        }
+        // Admin endpoint to list quarantined events
+        ("/admin/quarantine", false) => {
+            if !is_admin_request(request.headers(), &settings) {
+                return Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from("Admin authorization required"))
+                    .unwrap());
+            }
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!(quarantine.list()).to_string()))
+                .unwrap())
+        }
+        // Admin endpoints to release a quarantined event
+        ("/admin/quarantine/approve" | "/admin/quarantine/discard", false) => {
+            if request.method() != Method::POST {
+                return Ok(Response::builder()
+                    .status(StatusCode::METHOD_NOT_ALLOWED)
+                    .body(Body::from("Use POST"))
+                    .unwrap());
+            }
+            if !is_admin_request(request.headers(), &settings) {
+                return Ok(Response::builder()
+                    .status(StatusCode::UNAUTHORIZED)
+                    .header("Content-Type", "text/plain")
+                    .body(Body::from("Admin authorization required"))
+                    .unwrap());
+            }
+            let approve = request.uri().path() == "/admin/quarantine/approve";
+            let release_req: Option<AdminQuarantineRequest> = to_bytes(request.into_body())
+                .await
+                .ok()
+                .and_then(|b| serde_json::from_slice(&b).ok());
+            let event = match release_req.and_then(|r| quarantine.release(&r.id)) {
+                Some(e) => e,
+                None => {
+                    return Ok(Response::builder()
+                        .status(StatusCode::NOT_FOUND)
+                        .header("Content-Type", "text/plain")
+                        .body(Body::from("Expected a JSON body with a quarantined event id"))
+                        .unwrap());
+                }
+            };
+            if approve {
+                // approved events take the same write path as client
+                // events, skipping only the quarantine itself.
+                let (notice_tx, mut notice_rx) = mpsc::channel::<Notice>(1);
+                let submit_event = SubmittedEvent {
+                    event: event.clone(),
+                    notice_tx,
+                    source_ip: remote_addr.ip().to_string(),
+                    origin: None,
+                    user_agent: None,
+                    auth_pubkey: None,
+                    reviewed: true,
+                    imported: false,
+                };
+                let result = match event_tx.send(submit_event).await {
+                    Ok(()) => notice_rx.recv().await,
+                    Err(_) => None,
+                };
+                match result {
+                    Some(Notice::EventResult(r)) if r.status.to_bool() => {
+                        info!("approved quarantined event: {:?}", event.get_event_id_prefix());
+                    }
+                    Some(Notice::EventResult(r)) => {
+                        warn!("approved event was not stored: {}", r.msg);
+                        quarantine.hold(event);
+                        return Ok(Response::builder()
+                            .status(StatusCode::CONFLICT)
+                            .header("Content-Type", "text/plain")
+                            .body(Body::from(r.msg))
+                            .unwrap());
+                    }
+                    _ => {
+                        warn!("could not store approved event");
+                        quarantine.hold(event);
+                        return Ok(Response::builder()
+                            .status(StatusCode::INTERNAL_SERVER_ERROR)
+                            .body(Body::from("Error storing approved event"))
+                            .unwrap());
+                    }
+                }
+            } else {
+                info!("discarded quarantined event: {:?}", event.get_event_id_prefix());
+            }
+            let body = json!({"id": event.id, "approved": approve});
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header("Content-Type", "application/json")
+                .body(Body::from(body.to_string()))
+                .unwrap())
+        }
         // Endpoint for relays terms
         ("/terms", false) => Ok(Response::builder()
             .status(200)
@@ -629,18 +1047,78 @@ async fn handle_web_request(
 
 // Get pubkey from request query string
 fn get_pubkey(request: Request<Body>) -> Option<String> {
-    let query = request.uri().query().unwrap_or("").to_string();
-
-    // Gets the pubkey value from query string
-    query.split('&').fold(None, |acc, pair| {
-        let mut parts = pair.splitn(2, '=');
-        let key = parts.next();
-        let value = parts.next();
-        if key == Some("pubkey") {
-            return value.map(|s| s.to_owned());
+    get_query_param(&request, "pubkey")
+}
+
+// Get the (last) decoded value of a query string parameter
+fn get_query_param(request: &Request<Body>, name: &str) -> Option<String> {
+    let query = request.uri().query().unwrap_or("");
+    url::form_urlencoded::parse(query.as_bytes())
+        .filter(|(k, _)| k == name)
+        .last()
+        .map(|(_, v)| v.into_owned())
+}
+
+/// Run a single filter through the subscription query path, and
+/// collect the serialized events that may be sent to this client.
+async fn recent_events(
+    repo: Arc<dyn NostrRepo>,
+    filter: ReqFilter,
+    conn: &conn::ClientConn,
+    settings: &Settings,
+) -> Vec<String> {
+    let sub = Subscription {
+        id: "recent".to_owned(),
+        filters: vec![filter],
+    };
+    let (query_tx, mut query_rx) = mpsc::channel::<db::QueryResult>(1000);
+    let (_abandon_query_tx, abandon_query_rx) = oneshot::channel::<()>();
+    let cid = conn.get_client_prefix();
+    tokio::spawn(async move {
+        repo.query_subscription(sub, cid, query_tx, abandon_query_rx)
+            .await
+            .ok();
+    });
+    let mut events = vec![];
+    // results end with EOSE, or the channel closing if the query was shed
+    while let Some(r) = query_rx.recv().await {
+        if r.event == "EOSE" {
+            break;
+        } else if r.event != TRUNCATED_SENTINEL
+            && parse_cursor_sentinel(&r.event).is_none()
+            && allowed_to_send(&r.event, conn, settings)
+        {
+            events.push(r.event);
         }
-        acc
-    })
+    }
+    events
+}
+
+/// Body of an admin ban request
+#[derive(Deserialize, Debug)]
+struct AdminBanRequest {
+    pubkey: String,
+    #[serde(default)]
+    purge: bool,
+}
+
+/// Body of an admin request to approve or discard a quarantined event
+#[derive(Deserialize, Debug)]
+struct AdminQuarantineRequest {
+    id: String,
+}
+
+// Check that the request carries the configured admin bearer token
+fn is_admin_request(headers: &HeaderMap, settings: &Settings) -> bool {
+    match &settings.admin.api_token {
+        Some(token) if !token.is_empty() => get_header_string("authorization", headers)
+            .and_then(|h| {
+                h.strip_prefix("Bearer ")
+                    .map(|t| constant_time_eq(t.as_bytes(), token.as_bytes()))
+            })
+            .unwrap_or(false),
+        _ => false,
+    }
 }
 
 fn get_header_string(header: &str, headers: &HeaderMap) -> Option<String> {
@@ -672,7 +1150,10 @@ async fn ctrl_c_or_signal(mut shutdown_signal: Receiver<()>) {
     }
 }
 
-fn create_metrics() -> (Registry, NostrMetrics) {
+/// Create the metrics recorded by the relay and its database, and
+/// the registry they are exported from.
+#[must_use]
+pub fn create_metrics() -> (Registry, NostrMetrics) {
     // setup prometheus registry
     let registry = Registry::new();
 
@@ -691,6 +1172,19 @@ fn create_metrics() -> (Registry, NostrMetrics) {
         "Event writing response times",
     ))
     .unwrap();
+    let verify_sig = Histogram::with_opts(
+        HistogramOpts::new(
+            "nostr_signature_verify_seconds",
+            "Event signature verification times",
+        )
+        .buckets(latency_buckets()),
+    )
+    .unwrap();
+    let validate_event = Histogram::with_opts(
+        HistogramOpts::new("nostr_event_validation_seconds", "Event validation times")
+            .buckets(latency_buckets()),
+    )
+    .unwrap();
     let sent_events = IntCounterVec::new(
         Opts::new("nostr_events_sent_total", "Events sent to clients"),
         vec!["source"].as_slice(),
@@ -720,9 +1214,26 @@ fn create_metrics() -> (Registry, NostrMetrics) {
         vec!["reason"].as_slice(),
     )
     .unwrap();
+    let events_received = IntCounter::with_opts(Opts::new(
+        "nostr_events_received_total",
+        "Events submitted by clients",
+    ))
+    .unwrap();
+    let events_rejected = IntCounterVec::new(
+        Opts::new("nostr_events_rejected_total", "Events refused by the relay"),
+        vec!["reason"].as_slice(),
+    )
+    .unwrap();
+    let subscriptions = IntGauge::with_opts(Opts::new(
+        "nostr_subscriptions_active",
+        "Open client subscriptions",
+    ))
+    .unwrap();
     registry.register(Box::new(query_sub.clone())).unwrap();
     registry.register(Box::new(query_db.clone())).unwrap();
     registry.register(Box::new(write_events.clone())).unwrap();
+    registry.register(Box::new(verify_sig.clone())).unwrap();
+    registry.register(Box::new(validate_event.clone())).unwrap();
     registry.register(Box::new(sent_events.clone())).unwrap();
     registry.register(Box::new(connections.clone())).unwrap();
     registry.register(Box::new(db_connections.clone())).unwrap();
@@ -732,10 +1243,15 @@ fn create_metrics() -> (Registry, NostrMetrics) {
     registry.register(Box::new(cmd_close.clone())).unwrap();
     registry.register(Box::new(cmd_auth.clone())).unwrap();
     registry.register(Box::new(disconnects.clone())).unwrap();
+    registry.register(Box::new(events_received.clone())).unwrap();
+    registry.register(Box::new(events_rejected.clone())).unwrap();
+    registry.register(Box::new(subscriptions.clone())).unwrap();
     let metrics = NostrMetrics {
         query_sub,
         query_db,
         write_events,
+        verify_sig,
+        validate_event,
         sent_events,
         connections,
         db_connections,
@@ -745,10 +1261,48 @@ fn create_metrics() -> (Registry, NostrMetrics) {
         cmd_event,
         cmd_close,
         cmd_auth,
+        events_received,
+        events_rejected,
+        subscriptions,
     };
     (registry, metrics)
 }
 
+/// Current values of all metrics, in the Prometheus text format.
+fn metrics_response(registry: &Registry) -> Response<Body> {
+    let mut buffer = vec![];
+    let encoder = TextEncoder::new();
+    let metric_families = registry.gather();
+    encoder.encode(&metric_families, &mut buffer).unwrap();
+
+    Response::builder()
+        .status(StatusCode::OK)
+        .header("Content-Type", "text/plain")
+        .body(Body::from(buffer))
+        .unwrap()
+}
+
+/// Serve metrics on a listener of their own, until shutdown.  Every
+/// path returns the metrics.
+async fn serve_metrics(addr: SocketAddr, registry: Registry, shutdown: Receiver<()>) {
+    let make_svc = make_service_fn(move |_: &AddrStream| {
+        let registry = registry.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |_: Request<Body>| {
+                let response = metrics_response(&registry);
+                async move { Ok::<_, Infallible>(response) }
+            }))
+        }
+    });
+    info!("serving metrics on: {}", addr);
+    let server = Server::bind(&addr)
+        .serve(make_svc)
+        .with_graceful_shutdown(ctrl_c_or_signal(shutdown));
+    if let Err(e) = server.await {
+        error!("metrics server error: {e}");
+    }
+}
+
 fn file_bytes(path: &str) -> Result<Vec<u8>> {
     let f = File::open(path)?;
     let mut reader = BufReader::new(f);
@@ -760,6 +1314,16 @@ fn file_bytes(path: &str) -> Result<Vec<u8>> {
 
 /// Start running a Nostr relay server.
 pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Result<(), Error> {
+    start_server_with_verifier(settings, shutdown_rx, Arc::new(Secp256k1Verifier))
+}
+
+/// Start running a Nostr relay server, checking event signatures
+/// with the given verifier.
+pub fn start_server_with_verifier(
+    settings: &Settings,
+    shutdown_rx: MpscReceiver<()>,
+    verifier: Arc<dyn Verifier>,
+) -> Result<(), Error> {
     trace!("Config: {:?}", settings);
     // do some config validation.
     if !Path::new(&settings.database.data_directory).is_dir() {
@@ -772,6 +1336,11 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
         settings.network.port
     );
     let socket_addr = addr.parse().expect("listening address not valid");
+    let metrics_addr: Option<SocketAddr> = settings.network.metrics_address.as_ref().map(|a| {
+        a.trim()
+            .parse()
+            .expect("metrics listening address not valid")
+    });
     // address whitelisting settings
     if let Some(addr_whitelist) = &settings.authorization.pubkey_whitelist {
         info!(
@@ -779,6 +1348,12 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
             addr_whitelist.len()
         );
     }
+    if let Some(addr_blacklist) = &settings.authorization.pubkey_blacklist {
+        info!(
+            "Event publishing refused for {} pubkey(s)",
+            addr_blacklist.len()
+        );
+    }
     // check if NIP-05 enforced user verification is on
     if settings.verified_users.is_active() {
         info!(
@@ -830,12 +1405,21 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
         // to accommodate slower readers (messages are dropped if
         // clients can not keep up).
         let (bcast_tx, _) = broadcast::channel::<Event>(broadcast_buffer_limit);
+        // events accepted by the database writer, other than those
+        // imported from upstream, are forwarded on this channel.
+        let (forward_tx, _) = broadcast::channel::<Event>(broadcast_buffer_limit);
         // validated events that need to be persisted are sent to the
         // database on via this channel.
         let (event_tx, event_rx) = mpsc::channel::<SubmittedEvent>(persist_buffer_limit);
         // establish a channel for letting all threads now about a
         // requested server shutdown.
         let (invoke_shutdown, shutdown_listen) = broadcast::channel::<()>(1);
+        // settings can be reloaded while running, on SIGHUP
+        let live_settings = LiveSettings::new(settings.clone());
+        tokio::task::spawn(reload::reload_on_hangup(
+            live_settings.clone(),
+            invoke_shutdown.subscribe(),
+        ));
         // create a channel for sending any new metadata event.  These
         // will get processed relatively slowly (a potentially
         // multi-second blocking HTTP call) on a single thread, so we
@@ -849,23 +1433,98 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
         let (payment_tx, payment_rx) = broadcast::channel::<PaymentMessage>(4096);
 
         let (registry, metrics) = create_metrics();
+        // metrics can also be scraped from an address of their own
+        if let Some(addr) = metrics_addr {
+            tokio::spawn(serve_metrics(addr, registry.clone(), invoke_shutdown.subscribe()));
+        }
+        // time signature checks, whichever backend makes them
+        let verifier: Arc<dyn Verifier> =
+            Arc::new(TimedVerifier::new(verifier, metrics.verify_sig.clone()));
 
         // build a repository for events
         let repo = db::build_repo(&settings, metrics.clone()).await;
+        // slots for a global connection limit, if one is configured
+        let connection_slots = settings
+            .limits
+            .max_connections
+            .map(|n| Arc::new(Semaphore::new(n)));
+        // open connections by client address, if they are limited
+        let ip_connections = settings
+            .limits
+            .max_connections_per_ip
+            .map(IpConnections::new);
+        // rate of new subscriptions across all connections, if limited
+        let subscription_limiter = settings
+            .limits
+            .subscription_creation_rate
+            .and_then(core::num::NonZeroU32::new)
+            .map(|rate| {
+                let burst = settings
+                    .limits
+                    .subscription_creation_burst
+                    .and_then(core::num::NonZeroU32::new)
+                    .unwrap_or(rate);
+                Arc::new(RateLimiter::direct(Quota::per_second(rate).allow_burst(burst)))
+            });
+        // subscription cursors for authenticated clients to resume from
+        let resume_cursors = ResumeCursors::default();
+        // load banned pubkeys into memory
+        let blocklist = Blocklist::default();
+        match repo.get_banned_pubkeys().await {
+            Ok(pubkeys) => blocklist.extend(pubkeys),
+            Err(e) => warn!("could not load banned pubkeys: {}", e),
+        }
+        // remove anything published with compromised keys
+        if settings.authorization.purge_revoked {
+            for pubkey in settings.authorization.revoked_pubkeys.iter().flatten() {
+                match repo.delete_author_events(pubkey).await {
+                    Ok(count) => info!("purged {} events from revoked pubkey {}", count, pubkey),
+                    Err(e) => warn!("could not purge events from revoked pubkey {}: {}", pubkey, e),
+                }
+            }
+        }
+        // events held for admin review
+        let quarantine = Quarantine::new(&settings.quarantine);
+        // refuse events while the database volume is low on space
+        let disk_guard = DiskGuard::default();
+        // audit log of event acceptance decisions, if configured
+        let audit = AuditLog::from_settings(&settings.logging);
+        diskspace::start_disk_monitor(
+            &settings,
+            &disk_guard,
+            Arc::new(StatvfsProbe),
+            &invoke_shutdown,
+        );
         // start the database writer task.  Give it a channel for
         // writing events, and for publishing events that have been
         // written (to all connected clients).
         tokio::task::spawn(db::db_writer(
             repo.clone(),
             settings.clone(),
+            live_settings.subscribe(),
             event_rx,
             bcast_tx.clone(),
+            forward_tx.clone(),
             metadata_tx.clone(),
             payment_tx.clone(),
+            blocklist.clone(),
+            quarantine.clone(),
+            disk_guard.clone(),
             shutdown_listen,
         ));
         info!("db writer created");
 
+        // forward accepted events to any upstream relays.
+        forward::start_forwarders(
+            &settings.federation.forward_to_relays,
+            &forward_tx,
+            &invoke_shutdown,
+        );
+        // import events from any upstream relays.
+        import::start_importers(&settings, &event_tx, &verifier, &invoke_shutdown);
+        // periodically announce the relay, if it has a key.
+        announce::start_announcer(&settings, &event_tx, &invoke_shutdown);
+
         // create a nip-05 verifier thread; if enabled.
         if settings.verified_users.mode != VerifiedUsersMode::Disabled {
             let verifier_opt = nip05::Verifier::new(
@@ -943,26 +1602,48 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
             let bcast = bcast_tx.clone();
             let event = event_tx.clone();
             let payment_tx = payment_tx.clone();
+            let blocklist = blocklist.clone();
+            let quarantine = quarantine.clone();
+            let connection_slots = connection_slots.clone();
+            let ip_connections = ip_connections.clone();
+            let subscription_limiter = subscription_limiter.clone();
             let stop = invoke_shutdown.clone();
-            let settings = settings.clone();
+            let live_settings = live_settings.clone();
             let favicon = favicon.clone();
             let registry = registry.clone();
             let metrics = metrics.clone();
+            let verifier = verifier.clone();
+            let cursors = resume_cursors.clone();
+            let disk_guard = disk_guard.clone();
+            let audit = audit.clone();
             async move {
                 // service_fn converts our function into a `Service`
                 Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                    // each request sees the settings in effect when it arrives
+                    let reloads = live_settings.subscribe();
+                    let settings = reloads.borrow().as_ref().clone();
                     handle_web_request(
                         request,
                         repo.clone(),
-                        settings.clone(),
+                        settings,
+                        reloads,
                         remote_addr,
                         bcast.clone(),
                         event.clone(),
                         payment_tx.clone(),
+                        blocklist.clone(),
+                        quarantine.clone(),
+                        connection_slots.clone(),
+                        ip_connections.clone(),
+                        subscription_limiter.clone(),
                         stop.subscribe(),
                         favicon.clone(),
                         registry.clone(),
                         metrics.clone(),
+                        verifier.clone(),
+                        cursors.clone(),
+                        disk_guard.clone(),
+                        audit.clone(),
                     )
                 }))
             }
@@ -984,6 +1665,10 @@ pub fn start_server(settings: &Settings, shutdown_rx: MpscReceiver<()>) -> Resul
 pub enum NostrMessage {
     /// `EVENT` and  `AUTH` messages
     EventMsg(EventCmd),
+    /// An `EXTENSIONS` request
+    ExtensionsMsg(ExtensionsCmd),
+    /// A batch of events, `["EVENT", [event, ...]]`
+    EventBatchMsg(EventBatchCmd),
     /// A `REQ` message
     SubMsg(Subscription),
     /// A `CLOSE` message
@@ -1000,7 +1685,7 @@ fn convert_to_msg(msg: &str, max_bytes: Option<usize>) -> Result<NostrMessage> {
                 // note; this only prints the first 16k of a REQ and then truncates.
                 trace!("REQ: {:?}", msg);
             };
-            if let NostrMessage::EventMsg(_) = m {
+            if let NostrMessage::EventMsg(_) | NostrMessage::EventBatchMsg(_) = m {
                 if let Some(max_size) = max_bytes {
                     // check length, ensure that some max size is set.
                     if msg.len() > max_size && max_size > 0 {
@@ -1029,6 +1714,32 @@ fn make_notice_message(notice: &Notice) -> Message {
     Message::text(json.to_string())
 }
 
+fn make_closed_message(sub_id: &str, msg: &str) -> Message {
+    Message::text(json!(["CLOSED", sub_id, msg]).to_string())
+}
+
+/// Wrap an accepted client event for the database writer.
+fn client_submission(
+    event: Event,
+    conn: &conn::ClientConn,
+    client_info: &ClientInfo,
+    notice_tx: &mpsc::Sender<Notice>,
+) -> SubmittedEvent {
+    let auth_pubkey = conn
+        .auth_pubkey()
+        .and_then(|pubkey| hex::decode(pubkey).ok());
+    SubmittedEvent {
+        event,
+        notice_tx: notice_tx.clone(),
+        source_ip: conn.ip().to_string(),
+        origin: client_info.origin.clone(),
+        user_agent: client_info.user_agent.clone(),
+        auth_pubkey,
+        reviewed: false,
+        imported: false,
+    }
+}
+
 fn allowed_to_send(event_str: &String, conn: &conn::ClientConn, settings: &Settings) -> bool {
     // TODO: pass in kind so that we can avoid deserialization for most events
     if settings.authorization.nip42_dms {
@@ -1058,6 +1769,41 @@ struct ClientInfo {
     remote_ip: String,
     user_agent: Option<String>,
     origin: Option<String>,
+    /// Whether the client connected over TLS
+    secure: bool,
+    /// Configured language to send messages in, if not English
+    language: Option<String>,
+}
+
+/// The client address a connection is counted against.  Proxies
+/// append the address they saw to a header such as `X-Forwarded-For`,
+/// so the last entry is the one a client cannot forge.
+fn connection_ip(remote_ip: &str) -> &str {
+    remote_ip.rsplit(',').next().unwrap_or(remote_ip).trim()
+}
+
+/// Limiter for new subscriptions across all connections
+type SubscriptionLimiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock>;
+
+/// Build the per-connection limiter on EVENT submissions, if one is
+/// configured, reading the time from `clock`.
+fn event_limiter<C: Clock>(
+    limits: &Limits,
+    clock: &C,
+) -> Option<RateLimiter<NotKeyed, InMemoryState, C, NoOpMiddleware<C::Instant>>> {
+    let rate = limits.event_rate_limit_per_min.and_then(core::num::NonZeroU32::new)?;
+    let burst = limits.event_burst.and_then(core::num::NonZeroU32::new).unwrap_or(rate);
+    trace!("Rate limits for EVENT commands ({}/min, burst {})", rate, burst);
+    Some(RateLimiter::direct_with_clock(Quota::per_minute(rate).allow_burst(burst), clock))
+}
+
+/// Notice telling clients whether the relay is accepting events.
+fn read_only_notice(read_only: bool) -> Notice {
+    if read_only {
+        Notice::message(diskspace::READ_ONLY_MESSAGE.to_owned())
+    } else {
+        Notice::message("relay is accepting events again".to_owned())
+    }
 }
 
 /// Handle new client connections.  This runs through an event loop
@@ -1066,19 +1812,30 @@ struct ClientInfo {
 async fn nostr_server(
     repo: Arc<dyn NostrRepo>,
     client_info: ClientInfo,
-    settings: Settings,
+    mut settings: Settings,
+    mut reloads: watch::Receiver<Arc<Settings>>,
     mut ws_stream: WebSocketStream<Upgraded>,
     broadcast: Sender<Event>,
     event_tx: mpsc::Sender<SubmittedEvent>,
     mut shutdown: Receiver<()>,
     metrics: NostrMetrics,
+    verifier: Arc<dyn Verifier>,
+    cursors: ResumeCursors,
+    disk_guard: DiskGuard,
+    subscription_limiter: Option<Arc<SubscriptionLimiter>>,
+    audit: Option<AuditLog>,
 ) {
     // the time this websocket nostr server started
     let orig_start = Instant::now();
     // get a broadcast channel for clients to communicate on
     let mut bcast_rx = broadcast.subscribe();
+    // learn when the relay stops or resumes accepting events
+    let mut read_only_rx = disk_guard.subscribe();
     // Track internal client state
-    let mut conn = conn::ClientConn::new(client_info.remote_ip);
+    let mut conn = conn::ClientConn::new(client_info.remote_ip.clone());
+    conn.set_max_subscription_id_len(settings.limits.max_subscription_id_length);
+    conn.set_max_subscriptions(settings.limits.max_subscriptions_per_connection);
+    conn.set_max_authors(settings.limits.max_authors_per_connection);
     // subscription creation rate limiting
     let mut sub_lim_opt = None;
     // 100ms jitter when the rate limiter returns
@@ -1092,6 +1849,22 @@ async fn nostr_server(
             sub_lim_opt = Some(RateLimiter::direct(quota));
         }
     }
+    // REQ command rate limiting
+    let mut req_lim_opt = None;
+    if let Some(rate) = settings.limits.req_rate_per_second.and_then(core::num::NonZeroU32::new) {
+        let burst = settings.limits.req_burst.and_then(core::num::NonZeroU32::new).unwrap_or(rate);
+        trace!("Rate limits for REQ commands ({}/sec, burst {})", rate, burst);
+        req_lim_opt = Some(RateLimiter::direct(Quota::per_second(rate).allow_burst(burst)));
+    }
+    // CLOSE command rate limiting
+    let mut close_lim_opt = None;
+    if let Some(rate) = settings.limits.close_rate_per_second.and_then(core::num::NonZeroU32::new) {
+        let burst = settings.limits.close_burst.and_then(core::num::NonZeroU32::new).unwrap_or(rate);
+        trace!("Rate limits for CLOSE commands ({}/sec, burst {})", rate, burst);
+        close_lim_opt = Some(RateLimiter::direct(Quota::per_second(rate).allow_burst(burst)));
+    }
+    // EVENT submission rate limiting, checked before signatures
+    let event_lim_opt = event_limiter(&settings.limits, &DefaultClock::default());
     // Use the remote IP as the client identifier
     let cid = conn.get_client_prefix();
     // Create a channel for receiving query results from the database.
@@ -1101,6 +1874,25 @@ async fn nostr_server(
     let (query_tx, mut query_rx) = mpsc::channel::<db::QueryResult>(20_000);
     // Create channel for receiving NOTICEs
     let (notice_tx, mut notice_rx) = mpsc::channel::<Notice>(128);
+    // messages are translated into the client's language, if configured
+    let lang = client_info.language.as_deref();
+    let translations = settings.localization.clone();
+    // results sent for submitted events are audited, if configured
+    let conn_audit = audit.map(ConnectionAudit::new);
+    let notice_message = |notice: &Notice| {
+        if let Notice::EventResult(result) = notice {
+            if !result.status.to_bool() {
+                metrics.events_rejected.with_label_values(&[result.status.prefix()]).inc();
+            }
+            if let Some(audit) = &conn_audit {
+                audit.decided(result);
+            }
+        }
+        make_notice_message(&notice.localized(&translations, lang))
+    };
+    let closed_message = |sub_id: &str, msg: &str| {
+        make_closed_message(sub_id, &localization::localize(&translations, lang, msg))
+    };
 
     // last time this client sent data (message, ping, etc.)
     let mut last_message_time = Instant::now();
@@ -1118,6 +1910,8 @@ async fn nostr_server(
     // when these subscriptions are cancelled, make a message
     // available to the executing query so it knows to stop.
     let mut running_queries: HashMap<String, oneshot::Sender<()>> = HashMap::new();
+    // stored events sent so far for each subscription, until its EOSE.
+    let mut historical_sent: HashMap<String, usize> = HashMap::new();
     // for stats, keep track of how many events the client published,
     // and how many it received from queries.
     let mut client_published_event_count: usize = 0;
@@ -1139,7 +1933,7 @@ async fn nostr_server(
         conn.generate_auth_challenge();
         if let Some(challenge) = conn.auth_challenge() {
             ws_stream
-                .send(make_notice_message(&Notice::AuthChallenge(
+                .send(notice_message(&Notice::AuthChallenge(
                     challenge.to_string(),
                 )))
                 .await
@@ -1147,7 +1941,24 @@ async fn nostr_server(
         }
     }
 
+    if let Some(welcome) = &settings.info.welcome_message {
+        ws_stream.send(notice_message(&Notice::message(welcome.clone()))).await.ok();
+    }
+
+    if disk_guard.is_read_only() {
+        ws_stream.send(notice_message(&read_only_notice(true))).await.ok();
+    }
+
     loop {
+        // settings reloaded since the last message apply from now on;
+        // rate limiters and translations keep their values from when
+        // the connection opened
+        if reloads.has_changed().unwrap_or(false) {
+            settings = reloads.borrow_and_update().as_ref().clone();
+            conn.set_max_subscription_id_len(settings.limits.max_subscription_id_length);
+            conn.set_max_subscriptions(settings.limits.max_subscriptions_per_connection);
+            conn.set_max_authors(settings.limits.max_authors_per_connection);
+        }
         tokio::select! {
             _ = shutdown.recv() => {
         metrics.disconnects.with_label_values(&["shutdown"]).inc();
@@ -1167,15 +1978,43 @@ async fn nostr_server(
                 ws_stream.send(Message::Ping(Vec::new())).await.ok();
             },
             Some(notice_msg) = notice_rx.recv() => {
-                ws_stream.send(make_notice_message(&notice_msg)).await.ok();
+                ws_stream.send(notice_message(&notice_msg)).await.ok();
+            },
+            Ok(()) = read_only_rx.changed() => {
+                let read_only = *read_only_rx.borrow();
+                ws_stream.send(notice_message(&read_only_notice(read_only))).await.ok();
             },
             Some(query_result) = query_rx.recv() => {
                 // database informed us of a query result we asked for
                 let subesc = query_result.sub_id.replace('"', "");
                 if query_result.event == "EOSE" {
+                    historical_sent.remove(&query_result.sub_id);
                     let send_str = format!("[\"EOSE\",\"{subesc}\"]");
                     ws_stream.send(Message::Text(send_str)).await.ok();
+                } else if query_result.event == TRUNCATED_SENTINEL {
+                    if settings.limits.notify_truncated_results {
+                        let msg = format!("results for subscription {subesc} were truncated by the relay; use since/until to paginate");
+                        ws_stream.send(notice_message(&Notice::message(msg))).await.ok();
+                    }
+                } else if let Some(seq) = parse_cursor_sentinel(&query_result.event) {
+                    if settings.options.resume_subscriptions {
+                        if let Some(pubkey) = conn.auth_pubkey() {
+                            cursors.record(pubkey, &query_result.sub_id, seq);
+                        }
+                    }
+                    let send_str = format!("[\"CURSOR\",\"{subesc}\",{seq}]");
+                    ws_stream.send(Message::Text(send_str)).await.ok();
                 } else if allowed_to_send(&query_result.event, &conn, &settings) {
+                    // enforce the relay's ceiling on stored events per subscription
+                    let sent = historical_sent.entry(query_result.sub_id.clone()).or_insert(0);
+                    *sent += 1;
+                    if let Some(max) = settings.limits.hard_max_results_per_subscription.filter(|max| *sent > *max) {
+                        if *sent == max + 1 && settings.limits.notify_truncated_results {
+                            let msg = format!("results for subscription {subesc} were truncated by the relay; use since/until to paginate");
+                            ws_stream.send(notice_message(&Notice::message(msg))).await.ok();
+                        }
+                        continue;
+                    }
                     metrics.sent_events.with_label_values(&["db"]).inc();
                     client_received_event_count += 1;
                     // send a result
@@ -1183,8 +2022,23 @@ async fn nostr_server(
                     ws_stream.send(Message::Text(send_str)).await.ok();
                 }
             },
-            // TODO: consider logging the LaggedRecv error
-            Ok(global_event) = bcast_rx.recv() => {
+            bcast_result = bcast_rx.recv() => {
+                let global_event = match bcast_result {
+                    Ok(e) => e,
+                    Err(broadcast::error::RecvError::Lagged(dropped)) => {
+                        info!("client could not keep up, dropped {} events (cid: {})", dropped, cid);
+                        if settings.limits.notify_dropped_events {
+                            let msg = format!("dropped {dropped} events because the connection could not keep up; resubscribe to resync");
+                            ws_stream.send(notice_message(&Notice::message(msg))).await.ok();
+                        }
+                        continue;
+                    },
+                    Err(broadcast::error::RecvError::Closed) => {
+                        // the relay is shutting down; nothing more will arrive
+                        info!("broadcast channel closed, ending connection (cid: {})", cid);
+                        break;
+                    },
+                };
                 // an event has been broadcast to all clients
                 // first check if there is a subscription for this event.
                 for (s, sub) in conn.subscriptions() {
@@ -1218,7 +2072,7 @@ async fn nostr_server(
                     },
                     Some(Ok(Message::Binary(_))) => {
                         ws_stream.send(
-                            make_notice_message(&Notice::message("binary messages are not accepted".into()))).await.ok();
+                            notice_message(&Notice::message("binary messages are not accepted".into()))).await.ok();
                         continue;
                     },
                     Some(Ok(Message::Ping(_) | Message::Pong(_))) => {
@@ -1228,7 +2082,7 @@ async fn nostr_server(
                     },
                     Some(Err(WsError::Capacity(MessageTooLong{size, max_size}))) => {
                         ws_stream.send(
-                            make_notice_message(&Notice::message(format!("message too large ({size} > {max_size})")))).await.ok();
+                            notice_message(&Notice::message(format!("message too large ({size} > {max_size})")))).await.ok();
                         continue;
                     },
                     None |
@@ -1262,37 +2116,38 @@ async fn nostr_server(
                         // An EventCmd needs to be validated to be converted into an Event
                         // handle each type of message
                         let evid = ec.event_id().to_owned();
-                        let parsed : Result<EventWrapper> = Result::<EventWrapper>::from(ec);
+                        metrics.events_received.inc();
+                        if let Some(ref audit) = conn_audit {
+                            audit.submitted(&evid, Submission { pubkey: ec.event().pubkey.clone(), kind: ec.event().kind, ip: client_info.remote_ip.clone() });
+                        }
+                        // refuse events beyond the per-connection rate, before checking signatures
+                        if event_lim_opt.as_ref().map_or(false, |lim| lim.check().is_err()) {
+                            info!("EVENT rate limit reached (cid: {})", cid);
+                            ws_stream.send(notice_message(&Notice::rate_limited(evid, "too many events from this connection; slow down"))).await.ok();
+                            continue;
+                        }
+                        if let Some(notice) = reject_unverified_event(ec.event(), &settings, &cid) {
+                            ws_stream.send(notice_message(&notice)).await.ok();
+                            continue;
+                        }
+                        let validation_timer = metrics.validate_event.start_timer();
+                        let parsed : Result<EventWrapper> = ec.into_wrapper(verifier.as_ref());
+                        validation_timer.observe_duration();
                         metrics.cmd_event.inc();
                         match parsed {
                             Ok(WrappedEvent(e)) => {
                                 metrics.cmd_event.inc();
                                 let id_prefix:String = e.id.chars().take(8).collect();
                                 debug!("successfully parsed/validated event: {:?} (cid: {}, kind: {})", id_prefix, cid, e.kind);
-                                // check if event is expired
-                                if e.is_expired() {
-                                    let notice = Notice::invalid(e.id, "The event has already expired");
-                                    ws_stream.send(make_notice_message(&notice)).await.ok();
-                                    // check if the event is too far in the future.
-                                } else if e.is_valid_timestamp(settings.options.reject_future_seconds) {
+                                if let Some(notice) = reject_client_event(&e, &settings, &cid) {
+                                    ws_stream.send(notice_message(&notice)).await.ok();
+                                } else if !conn.admit_author(&e.pubkey) {
+                                    info!("too many distinct authors on connection (cid: {})", cid);
+                                    ws_stream.send(notice_message(&Notice::rate_limited(e.id, "too many distinct authors on this connection"))).await.ok();
+                                } else {
                                     // Write this to the database.
-                                    let auth_pubkey = conn.auth_pubkey().and_then(|pubkey| hex::decode(pubkey).ok());
-                                    let submit_event = SubmittedEvent {
-                                        event: e.clone(),
-                                        notice_tx: notice_tx.clone(),
-                                        source_ip: conn.ip().to_string(),
-                                        origin: client_info.origin.clone(),
-                                        user_agent: client_info.user_agent.clone(),
-                                        auth_pubkey };
-                                    event_tx.send(submit_event).await.ok();
+                                    event_tx.send(client_submission(e, &conn, &client_info, &notice_tx)).await.ok();
                                     client_published_event_count += 1;
-                                } else {
-                                    info!("client: {} sent a far future-dated event", cid);
-                                    if let Some(fut_sec) = settings.options.reject_future_seconds {
-                                        let msg = format!("The event created_at field is out of the acceptable range (+{fut_sec}sec) for this relay.");
-                                        let notice = Notice::invalid(e.id, &msg);
-                                        ws_stream.send(make_notice_message(&notice)).await.ok();
-                                    }
                                 }
                             },
                             Ok(WrappedAuth(event)) => {
@@ -1301,6 +2156,10 @@ async fn nostr_server(
                                     let id_prefix:String = event.id.chars().take(8).collect();
                                     debug!("successfully parsed auth: {:?} (cid: {})", id_prefix, cid);
                                     match &settings.info.relay_url {
+                                        _ if settings.authorization.nip42_require_secure && !client_info.secure => {
+                                            info!("refusing AUTH on an insecure connection (cid: {})", cid);
+                                            ws_stream.send(notice_message(&Notice::restricted(event.id, "authentication requires a secure (wss://) connection"))).await.ok();
+                                        },
                                         None => {
                                             error!("AUTH command received, but relay_url is not set in the config file (cid: {})", cid);
                                         },
@@ -1315,7 +2174,7 @@ async fn nostr_server(
                                                 },
                                                 Err(e) => {
                                                     info!("authentication error: {} (cid: {})", e, cid);
-                                                    ws_stream.send(make_notice_message(&Notice::restricted(event.id, format!("authentication error: {e}").as_str()))).await.ok();
+                                                    ws_stream.send(notice_message(&Notice::restricted(event.id, format!("authentication error: {e}").as_str()))).await.ok();
                                                 },
                                             }
                                         }
@@ -1323,18 +2182,105 @@ async fn nostr_server(
                                 } else {
                                     let e = CommandUnknownError;
                                     info!("client sent an invalid event (cid: {})", cid);
-                                    ws_stream.send(make_notice_message(&Notice::invalid(evid, &format!("{e}")))).await.ok();
+                                    ws_stream.send(notice_message(&Notice::invalid(evid, &format!("{e}")))).await.ok();
                                 }
                             },
                             Err(e) => {
                                 metrics.cmd_event.inc();
-                                info!("client sent an invalid event (cid: {})", cid);
-                                ws_stream.send(make_notice_message(&Notice::invalid(evid, &format!("{e}")))).await.ok();
+                                info!("client sent an invalid event: {} (cid: {})", e, cid);
+                                ws_stream.send(notice_message(&Notice::invalid(evid, &format!("{e}")))).await.ok();
                             }
                         }
                     },
-                    Ok(NostrMessage::SubMsg(s)) => {
+                    Ok(NostrMessage::ExtensionsMsg(ext)) => {
+                        let accepted = extensions::negotiate(&ext.requested, &settings);
+                        debug!("extensions negotiated (cid: {}, accepted: {:?})", cid, accepted);
+                        ws_stream.send(Message::text(json!(["EXTENSIONS", accepted]).to_string())).await.ok();
+                    },
+                    Ok(NostrMessage::EventBatchMsg(batch)) => {
+                        // each event in a batch is handled as if it
+                        // had been sent alone, with its own OK result.
+                        debug!("event batch received (cid: {}, events: {})", cid, batch.len());
+                        if !settings.options.batch_events {
+                            info!("client sent an event batch, but batches are disabled (cid: {})", cid);
+                            ws_stream.send(notice_message(&Notice::message("event batches are not supported by this relay".into()))).await.ok();
+                            continue;
+                        }
+                        let cmds = match batch.into_cmds() {
+                            Ok(c) => c,
+                            Err(e) => {
+                                info!("client sent an invalid event batch (cid: {})", cid);
+                                ws_stream.send(notice_message(&Notice::message(format!("{e}")))).await.ok();
+                                continue;
+                            }
+                        };
+                        for cmd in cmds {
+                            metrics.cmd_event.inc();
+                            metrics.events_received.inc();
+                            let ec = match cmd {
+                                Ok(ec) => ec,
+                                Err(Some(evid)) => {
+                                    ws_stream.send(notice_message(&Notice::invalid(evid, "could not parse event"))).await.ok();
+                                    continue;
+                                }
+                                Err(None) => {
+                                    ws_stream.send(notice_message(&Notice::message("could not parse event in batch".into()))).await.ok();
+                                    continue;
+                                }
+                            };
+                            let evid = ec.event_id().to_owned();
+                            if let Some(ref audit) = conn_audit {
+                                audit.submitted(&evid, Submission { pubkey: ec.event().pubkey.clone(), kind: ec.event().kind, ip: client_info.remote_ip.clone() });
+                            }
+                            if event_lim_opt.as_ref().map_or(false, |lim| lim.check().is_err()) {
+                                info!("EVENT rate limit reached (cid: {})", cid);
+                                ws_stream.send(notice_message(&Notice::rate_limited(evid, "too many events from this connection; slow down"))).await.ok();
+                                continue;
+                            }
+                            if let Some(notice) = reject_unverified_event(ec.event(), &settings, &cid) {
+                                ws_stream.send(notice_message(&notice)).await.ok();
+                                continue;
+                            }
+                            let validation_timer = metrics.validate_event.start_timer();
+                            let parsed = ec.into_wrapper(verifier.as_ref());
+                            validation_timer.observe_duration();
+                            match parsed {
+                                Ok(WrappedEvent(e)) => {
+                                    if let Some(notice) = reject_client_event(&e, &settings, &cid) {
+                                        ws_stream.send(notice_message(&notice)).await.ok();
+                                    } else if !conn.admit_author(&e.pubkey) {
+                                        info!("too many distinct authors on connection (cid: {})", cid);
+                                        ws_stream.send(notice_message(&Notice::rate_limited(e.id, "too many distinct authors on this connection"))).await.ok();
+                                    } else {
+                                        event_tx.send(client_submission(e, &conn, &client_info, &notice_tx)).await.ok();
+                                        client_published_event_count += 1;
+                                    }
+                                },
+                                Ok(WrappedAuth(_)) => {
+                                    // authentication uses an AUTH message, not a batch
+                                    ws_stream.send(notice_message(&Notice::invalid(evid, "auth events cannot be published in a batch"))).await.ok();
+                                },
+                                Err(e) => {
+                                    info!("client sent an invalid event: {} (cid: {})", e, cid);
+                                    ws_stream.send(notice_message(&Notice::invalid(evid, &format!("{e}")))).await.ok();
+                                }
+                            }
+                        }
+                    },
+                    Ok(NostrMessage::SubMsg(mut s)) => {
                         debug!("subscription requested (cid: {}, sub: {:?})", cid, s.id);
+                        // refuse REQs beyond the per-connection rate
+                        if req_lim_opt.as_ref().map_or(false, |lim| lim.check().is_err()) {
+                            info!("REQ rate limit reached (cid: {}, sub: {:?})", cid, s.id);
+                            ws_stream.send(closed_message(&s.id, "rate-limited: too many subscription requests")).await.ok();
+                            continue;
+                        }
+                        // and beyond the rate for all connections
+                        if subscription_limiter.as_ref().map_or(false, |lim| lim.check().is_err()) {
+                            info!("global subscription rate limit reached (cid: {}, sub: {:?})", cid, s.id);
+                            ws_stream.send(closed_message(&s.id, "rate-limited: relay is receiving too many new subscriptions")).await.ok();
+                            continue;
+                        }
                         // subscription handling consists of:
                         // * check for rate limits
                         // * registering the subscription so future events can be matched
@@ -1348,21 +2294,102 @@ async fn nostr_server(
                             if let Some(ref lim) = sub_lim_opt {
                                 lim.until_ready_with_jitter(jitter).await;
                             }
+                            // refuse subscriptions during maintenance, if configured
+                            let now = unix_time();
+                            if let Some(w) = settings.maintenance.active_window(now).filter(|w| w.reject_reads) {
+                                info!("refusing subscription during maintenance (cid: {}, sub: {:?})", cid, s.id);
+                                let msg = format!("error: {}", w.message(now));
+                                ws_stream.send(closed_message(&s.id, &msg)).await.ok();
+                                continue;
+                            }
+                            // refuse filters using extensions that are not enabled
+                            if !settings.options.tag_and_filters && s.filters.iter().any(ReqFilter::uses_and_tags) {
+                                info!("refusing subscription with tag AND filters (cid: {}, sub: {:?})", cid, s.id);
+                                ws_stream.send(closed_message(&s.id, "unsupported: \"&\" tag filters are not enabled on this relay")).await.ok();
+                                continue;
+                            }
+                            // authenticated clients resume single-filter subscriptions where they left off
+                            if settings.options.resume_subscriptions && settings.options.sequence_cursors {
+                                if let (Some(pubkey), [filter]) = (conn.auth_pubkey(), s.filters.as_mut_slice()) {
+                                    if filter.after_seq.is_none() {
+                                        filter.after_seq = Some(cursors.get(pubkey, &s.id).unwrap_or(0));
+                                        debug!("resuming subscription after {:?} (cid: {}, sub: {:?})", filter.after_seq, cid, s.id);
+                                    }
+                                }
+                            }
+                            if !settings.options.sequence_cursors && s.filters.iter().any(ReqFilter::uses_sequence) {
+                                info!("refusing subscription with sequence filters (cid: {}, sub: {:?})", cid, s.id);
+                                ws_stream.send(closed_message(&s.id, "unsupported: \"after_seq\" filters are not enabled on this relay")).await.ok();
+                                continue;
+                            }
+                            // a subscription reports a single cursor, so it can only page one filter
+                            if s.filters.len() > 1 && s.filters.iter().any(ReqFilter::uses_sequence) {
+                                info!("refusing subscription with several filters and a sequence filter (cid: {}, sub: {:?})", cid, s.id);
+                                ws_stream.send(closed_message(&s.id, "invalid: \"after_seq\" can only be used in a subscription with one filter")).await.ok();
+                                continue;
+                            }
+                            if settings.options.resolve_address_filters {
+                                s.resolve_addresses();
+                            }
+                            // refuse subscriptions that would return too many stored events
+                            if let Some(max_projected) = settings.limits.max_projected_results {
+                                if s.needs_historical_events() {
+                                    let projected = match repo.count_projected_results(&s, max_projected).await {
+                                        Ok(n) => n,
+                                        Err(e) => {
+                                            warn!("could not estimate results for subscription (cid: {}, sub: {:?}): {:?}", cid, s.id, e);
+                                            ws_stream.send(closed_message(&s.id, "error: could not estimate the size of this subscription")).await.ok();
+                                            continue;
+                                        }
+                                    };
+                                    if projected > max_projected {
+                                        info!("refusing subscription projected to exceed {} results (cid: {}, sub: {:?})", max_projected, cid, s.id);
+                                        let msg = format!("too-large: subscription would return more than {max_projected} events; narrow the filters or add a limit");
+                                        ws_stream.send(closed_message(&s.id, &msg)).await.ok();
+                                        continue;
+                                    }
+                                }
+                            }
                             let (abandon_query_tx, abandon_query_rx) = oneshot::channel::<()>();
+                            let open_subs = conn.subscriptions().len();
                             match conn.subscribe(s.clone()) {
                                 Ok(()) => {
+                                    // a replaced subscription is still just one
+                                    if conn.subscriptions().len() > open_subs {
+                                        metrics.subscriptions.inc();
+                                    }
                                     // when we insert, if there was a previous query running with the same name, cancel it.
                                     if let Some(previous_query) = running_queries.insert(s.id.clone(), abandon_query_tx) {
                                         previous_query.send(()).ok();
                                     }
+                                    historical_sent.remove(&s.id);
+                                    // report any requested events that were deleted
+                                    if settings.options.serve_tombstones {
+                                        let ids: Vec<String> = s.filters.iter().filter_map(|f| f.ids.clone()).flatten().collect();
+                                        if !ids.is_empty() {
+                                            let tombstones = repo.tombstones_for(&ids).await.unwrap_or_default();
+                                            for (id, deletion_id) in tombstones {
+                                                ws_stream.send(Message::text(json!(["DELETED", s.id, id, deletion_id]).to_string())).await.ok();
+                                            }
+                                        }
+                                    }
                                     if s.needs_historical_events() {
                                         // start a database query.  this spawns a blocking database query on a worker thread.
                                         repo.query_subscription(s, cid.clone(), query_tx.clone(), abandon_query_rx).await.ok();
+                                    } else {
+                                        // no stored events were asked for, but clients still wait for EOSE
+                                        query_tx.send(db::QueryResult { sub_id: s.get_id(), event: "EOSE".to_owned() }).await.ok();
                                     }
                                 },
+                                Err(Error::SubMaxExceededError) => {
+                                    info!("too many subscriptions on connection (cid: {}, sub: {:?})", cid, s.id);
+                                    let max_subs = settings.limits.max_subscriptions_per_connection;
+                                    let msg = format!("Subscription error: this relay allows at most {max_subs} subscriptions per connection; close one before opening another");
+                                    ws_stream.send(notice_message(&Notice::message(msg))).await.ok();
+                                },
                                 Err(e) => {
                                     info!("Subscription error: {} (cid: {}, sub: {:?})", e, cid, s.id);
-                                    ws_stream.send(make_notice_message(&Notice::message(format!("Subscription error: {e}")))).await.ok();
+                                    ws_stream.send(notice_message(&Notice::message(format!("Subscription error: {e}")))).await.ok();
                                 }
                             }
                         }
@@ -1372,18 +2399,30 @@ async fn nostr_server(
                         let parsed : Result<Close> = Result::<Close>::from(cc);
                         if let Ok(c) = parsed {
                 metrics.cmd_close.inc();
+                            // ignore CLOSEs beyond the per-connection rate;
+                            // the subscription stays open.
+                            if close_lim_opt.as_ref().map_or(false, |lim| lim.check().is_err()) {
+                                info!("CLOSE rate limit reached (cid: {}, sub: {:?})", cid, c.id);
+                                ws_stream.send(notice_message(&Notice::message("rate-limited: too many CLOSE commands; the subscription is still open".into()))).await.ok();
+                                continue;
+                            }
                             // check if a query is currently
                             // running, and remove it if so.
                             let stop_tx = running_queries.remove(&c.id);
+                            historical_sent.remove(&c.id);
                             if let Some(tx) = stop_tx {
                                 tx.send(()).ok();
                             }
                             // stop checking new events against
                             // the subscription
+                            let open_subs = conn.subscriptions().len();
                             conn.unsubscribe(&c);
+                            if conn.subscriptions().len() < open_subs {
+                                metrics.subscriptions.dec();
+                            }
                         } else {
                             info!("invalid command ignored");
-                            ws_stream.send(make_notice_message(&Notice::message("could not parse command".into()))).await.ok();
+                            ws_stream.send(notice_message(&Notice::message("could not parse command".into()))).await.ok();
                         }
                     },
                     Err(Error::ConnError) => {
@@ -1392,11 +2431,11 @@ async fn nostr_server(
                     }
                     Err(Error::EventMaxLengthError(s)) => {
                         info!("client sent command larger ({} bytes) than max size (cid: {})", s, cid);
-                        ws_stream.send(make_notice_message(&Notice::message("event exceeded max size".into()))).await.ok();
+                        ws_stream.send(notice_message(&Notice::message("event exceeded max size".into()))).await.ok();
                     },
                     Err(Error::ProtoParseError) => {
                         info!("client sent command that could not be parsed (cid: {})", cid);
-                        ws_stream.send(make_notice_message(&Notice::message("could not parse command".into()))).await.ok();
+                        ws_stream.send(notice_message(&Notice::message("could not parse command".into()))).await.ok();
                     },
                     Err(e) => {
                         info!("got non-fatal error from client (cid: {}, error: {:?}", cid, e);
@@ -1409,6 +2448,7 @@ async fn nostr_server(
     for (_, stop_tx) in running_queries {
         stop_tx.send(()).ok();
     }
+    metrics.subscriptions.sub(conn.subscriptions().len() as i64);
     info!(
         "stopping client connection (cid: {}, ip: {:?}, sent: {} events, recv: {} events, connected: {:?})",
         cid,
@@ -1425,6 +2465,8 @@ pub struct NostrMetrics {
     pub query_db: Histogram,         // individual database query execution time
     pub db_connections: IntGauge,    // database connections in use
     pub write_events: Histogram,     // response time of event writes
+    pub verify_sig: Histogram,       // time to check an event signature
+    pub validate_event: Histogram,   // time to validate a client event, signature included
     pub sent_events: IntCounterVec,  // count of events sent to clients
     pub connections: IntCounter,     // count of websocket connections
     pub disconnects: IntCounterVec,  // client disconnects
@@ -1433,4 +2475,37 @@ pub struct NostrMetrics {
     pub cmd_event: IntCounter,       // count of EVENT commands received
     pub cmd_close: IntCounter,       // count of CLOSE commands received
     pub cmd_auth: IntCounter,        // count of AUTH commands received
+    pub events_received: IntCounter, // count of events submitted by clients
+    pub events_rejected: IntCounterVec, // count of events refused, by result status
+    pub subscriptions: IntGauge,     // open subscriptions across all connections
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use governor::clock::FakeRelativeClock;
+
+    #[test]
+    fn event_limiter_burst_then_refill() {
+        let mut limits = Settings::default().limits;
+        assert!(event_limiter(&limits, &FakeRelativeClock::default()).is_none());
+        limits.event_rate_limit_per_min = Some(60);
+        limits.event_burst = Some(3);
+        let clock = FakeRelativeClock::default();
+        let lim = event_limiter(&limits, &clock).unwrap();
+        // the burst is allowed at once, and then events are throttled
+        for _ in 0..3 {
+            assert!(lim.check().is_ok());
+        }
+        assert!(lim.check().is_err());
+        // one event is allowed for each second that passes
+        clock.advance(Duration::from_secs(1));
+        assert!(lim.check().is_ok());
+        assert!(lim.check().is_err());
+        // and a quiet client gets its burst back, but is throttled
+        // again after it (governor can allow one cell more after idling)
+        clock.advance(Duration::from_secs(600));
+        let allowed = (0..10).filter(|_| lim.check().is_ok()).count();
+        assert!((3..=4).contains(&allowed), "{allowed}");
+    }
 }
@@ -1,6 +1,7 @@
 //! Subscription and filter parsing
 use crate::error::Result;
 use crate::event::Event;
+use crate::utils::is_lower_hex;
 use serde::de::Unexpected;
 use serde::ser::SerializeMap;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
@@ -20,7 +21,7 @@ pub struct Subscription {
 /// Corresponds to client-provided subscription request elements.  Any
 /// element can be present if it should be used in filtering, or
 /// absent ([`None`]) if it should be ignored.
-#[derive(PartialEq, Eq, Debug, Clone)]
+#[derive(PartialEq, Eq, Debug, Clone, Default)]
 pub struct ReqFilter {
     /// Event hashes
     pub ids: Option<Vec<String>>,
@@ -36,6 +37,18 @@ pub struct ReqFilter {
     pub limit: Option<u64>,
     /// Set of tags
     pub tags: Option<HashMap<char, HashSet<String>>>,
+    /// Set of tags, all of whose values must be present (`&t`
+    /// extension); unlike `tags`, which matches any value
+    pub and_tags: Option<HashMap<char, HashSet<String>>>,
+    /// Only events published (or not published) under NIP-26
+    /// delegation (`delegated` extension)
+    pub delegated: Option<bool>,
+    /// Only events stored after this sequence number, in insertion
+    /// order (`seq-cursor` extension)
+    pub after_seq: Option<u64>,
+    /// Words that must all appear in the content of a text note
+    /// (NIP-50)
+    pub search: Option<String>,
     /// Force no matches due to malformed data
     // we can't represent it in the req filter, so we don't want to
     // erroneously match.  This basically indicates the req tried to
@@ -74,6 +87,21 @@ impl Serialize for ReqFilter {
                 map.serialize_entry(&format!("#{k}"), &vals)?;
             }
         }
+        if let Some(tags) = &self.and_tags {
+            for (k, v) in tags {
+                let vals: Vec<&String> = v.iter().collect();
+                map.serialize_entry(&format!("&{k}"), &vals)?;
+            }
+        }
+        if let Some(delegated) = &self.delegated {
+            map.serialize_entry("delegated", delegated)?;
+        }
+        if let Some(after_seq) = &self.after_seq {
+            map.serialize_entry("after_seq", after_seq)?;
+        }
+        if let Some(search) = &self.search {
+            map.serialize_entry("search", search)?;
+        }
         map.end()
     }
 }
@@ -98,10 +126,15 @@ impl<'de> Deserialize<'de> for ReqFilter {
             authors: None,
             limit: None,
             tags: None,
+            and_tags: None,
+            delegated: None,
+            after_seq: None,
+            search: None,
             force_no_match: false,
         };
         let empty_string = "".into();
         let mut ts = None;
+        let mut and_ts: Option<HashMap<char, HashSet<String>>> = None;
         // iterate through each key, and assign values that exist
         for (key, val) in filter {
             // ids
@@ -124,6 +157,16 @@ impl<'de> Deserialize<'de> for ReqFilter {
                 rf.until = Deserialize::deserialize(val).ok();
             } else if key == "limit" {
                 rf.limit = Deserialize::deserialize(val).ok();
+            } else if key == "delegated" {
+                rf.delegated = Deserialize::deserialize(val).ok();
+            } else if key == "after_seq" {
+                rf.after_seq = Deserialize::deserialize(val).ok();
+            } else if key == "search" {
+                // a search without any words does not constrain anything
+                rf.search = val
+                    .as_str()
+                    .filter(|s| !search_terms(s).is_empty())
+                    .map(ToOwned::to_owned);
             } else if key == "authors" {
                 let raw_authors: Option<Vec<String>> = Deserialize::deserialize(val).ok();
                 if let Some(a) = raw_authors.as_ref() {
@@ -153,13 +196,38 @@ impl<'de> Deserialize<'de> for ReqFilter {
                     rf.force_no_match = true;
                     continue;
                 }
+            } else if key.starts_with('&') && key.len() > 1 && val.is_array() {
+                if let Some(tag_search) = tag_search_char_from_filter(key) {
+                    let tag_vals: Option<Vec<String>> = Deserialize::deserialize(val).ok();
+                    if let Some(v) = tag_vals {
+                        and_ts
+                            .get_or_insert_with(HashMap::new)
+                            .insert(tag_search, v.into_iter().collect());
+                    }
+                } else {
+                    rf.force_no_match = true;
+                    continue;
+                }
             }
         }
         rf.tags = ts;
+        rf.and_tags = and_ts;
         Ok(rf)
     }
 }
 
+/// The words of a search (NIP-50), lowercased.  Words are runs of
+/// letters and digits, as the full-text index splits them; anything
+/// else, including search syntax, only separates words.
+#[must_use]
+pub fn search_terms(search: &str) -> Vec<String> {
+    search
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|w| !w.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
 /// Attempt to form a single-char identifier from a tag search filter
 fn tag_search_char_from_filter(tagname: &str) -> Option<char> {
     let tagname_nohash = &tagname[1..];
@@ -247,6 +315,22 @@ impl Subscription {
         self.filters.iter().any(|f| f.limit != Some(0))
     }
 
+    /// Also select the parameterized replaceable events addressed by
+    /// `#a` filter values (`address-filter` extension), in addition to
+    /// the events referencing them.
+    pub fn resolve_addresses(&mut self) {
+        let resolved: Vec<ReqFilter> = self
+            .filters
+            .iter()
+            .flat_map(ReqFilter::address_filters)
+            .collect();
+        for f in resolved {
+            if !self.filters.contains(&f) {
+                self.filters.push(f);
+            }
+        }
+    }
+
     /// Determine if this subscription matches a given [`Event`].  Any
     /// individual filter match is sufficient.
     #[must_use]
@@ -260,6 +344,19 @@ impl Subscription {
     }
 }
 
+/// The kind, author and `d` tag of a parameterized replaceable event
+/// address, `<kind>:<pubkey>:<d tag>`.
+fn parse_address(address: &str) -> Option<(u64, &str, &str)> {
+    let mut parts = address.splitn(3, ':');
+    let kind: u64 = parts.next()?.parse().ok()?;
+    let pubkey = parts.next()?;
+    let d_tag = parts.next()?;
+    if !(30000..40000).contains(&kind) || pubkey.len() != 64 || !is_lower_hex(pubkey) {
+        return None;
+    }
+    Some((kind, pubkey, d_tag))
+}
+
 fn prefix_match(prefixes: &[String], target: &str) -> bool {
     for prefix in prefixes {
         if target.starts_with(prefix) {
@@ -309,6 +406,81 @@ impl ReqFilter {
         true
     }
 
+    fn and_tag_match(&self, event: &Event) -> bool {
+        // every value of every tag must be present.
+        self.and_tags.as_ref().map_or(true, |map| {
+            map.iter()
+                .all(|(key, vals)| event.generic_tag_val_superset(*key, vals))
+        })
+    }
+
+    /// Does this filter use the `&` (tag AND) extension?
+    #[must_use]
+    pub fn uses_and_tags(&self) -> bool {
+        self.and_tags.is_some()
+    }
+
+    /// Only text notes are searchable; every word must appear in the
+    /// content.
+    fn search_match(&self, event: &Event) -> bool {
+        self.search.as_ref().map_or(true, |s| {
+            let words: HashSet<String> = search_terms(&event.content).into_iter().collect();
+            event.kind == 1 && search_terms(s).iter().all(|t| words.contains(t))
+        })
+    }
+
+    fn delegated_match(&self, event: &Event) -> bool {
+        self.delegated
+            .map_or(true, |d| d == event.delegated_by.is_some())
+    }
+
+    /// Filters selecting the events addressed by this filter's `#a`
+    /// values, of the form `<kind>:<pubkey>:<d tag>` (NIP-33).  The
+    /// addressed events must still meet the filter's other conditions.
+    /// Sequence pages are not resolved, since they must be the only
+    /// filter of their subscription.
+    #[must_use]
+    pub fn address_filters(&self) -> Vec<ReqFilter> {
+        let tags = match &self.tags {
+            Some(tags) if !self.uses_sequence() => tags,
+            _ => return vec![],
+        };
+        let mut addresses: Vec<&String> = tags.get(&'a').into_iter().flatten().collect();
+        addresses.sort();
+        let mut filters = vec![];
+        for (kind, pubkey, d_tag) in addresses.into_iter().filter_map(|a| parse_address(a)) {
+            if !self.kind_match(kind)
+                || !self
+                    .authors
+                    .as_ref()
+                    .map_or(true, |a| prefix_match(a, pubkey))
+                || !tags.get(&'d').map_or(true, |ds| ds.contains(d_tag))
+            {
+                continue;
+            }
+            let mut tags = tags.clone();
+            tags.remove(&'a');
+            tags.insert('d', HashSet::from([d_tag.to_owned()]));
+            filters.push(ReqFilter {
+                kinds: Some(vec![kind]),
+                authors: Some(vec![pubkey.to_owned()]),
+                tags: Some(tags),
+                ..self.clone()
+            });
+        }
+        filters
+    }
+
+    /// Does this filter page by sequence number (`after_seq`)?
+    ///
+    /// Sequence numbers are not tracked for broadcast events, which
+    /// are always newer than any stored event, so they do not affect
+    /// matching.
+    #[must_use]
+    pub fn uses_sequence(&self) -> bool {
+        self.after_seq.is_some()
+    }
+
     /// Check if this filter either matches, or does not care about the kind.
     fn kind_match(&self, kind: u64) -> bool {
         self.kinds.as_ref().map_or(true, |ks| ks.contains(&kind))
@@ -324,6 +496,9 @@ impl ReqFilter {
             && self.kind_match(event.kind)
             && (self.authors_match(event) || self.delegated_authors_match(event))
             && self.tag_match(event)
+            && self.and_tag_match(event)
+            && self.delegated_match(event)
+            && self.search_match(event)
             && !self.force_no_match
     }
 }
@@ -647,4 +822,136 @@ mod tests {
         }
         Ok(())
     }
+
+    fn t_tagged(values: &[&str]) -> Event {
+        let mut e = Event::simple_event();
+        e.tags = values
+            .iter()
+            .map(|v| vec!["t".to_owned(), (*v).to_owned()])
+            .collect();
+        e.build_index();
+        e
+    }
+
+    #[test]
+    fn tag_values_or_by_default() -> Result<()> {
+        let s: Subscription = serde_json::from_str(r##"["REQ","xyz",{"#t": ["a", "b"]}]"##)?;
+        assert!(!s.filters[0].uses_and_tags());
+        assert!(s.interested_in_event(&t_tagged(&["a"])));
+        assert!(s.interested_in_event(&t_tagged(&["b", "c"])));
+        assert!(s.interested_in_event(&t_tagged(&["a", "b"])));
+        assert!(!s.interested_in_event(&t_tagged(&["c"])));
+        Ok(())
+    }
+
+    #[test]
+    fn tag_values_and_opt_in() -> Result<()> {
+        let s: Subscription = serde_json::from_str(r##"["REQ","xyz",{"&t": ["a", "b"]}]"##)?;
+        assert!(s.filters[0].uses_and_tags());
+        assert!(s.interested_in_event(&t_tagged(&["a", "b"])));
+        assert!(s.interested_in_event(&t_tagged(&["c", "b", "a"])));
+        assert!(!s.interested_in_event(&t_tagged(&["a"])));
+        assert!(!s.interested_in_event(&t_tagged(&["b", "c"])));
+        assert!(!s.interested_in_event(&t_tagged(&[])));
+        // AND and OR conditions combine
+        let s: Subscription =
+            serde_json::from_str(r##"["REQ","xyz",{"&t": ["a", "b"], "#t": ["c", "d"]}]"##)?;
+        assert!(s.interested_in_event(&t_tagged(&["a", "b", "d"])));
+        assert!(!s.interested_in_event(&t_tagged(&["a", "b"])));
+        // and survive serialization
+        let serialized = serde_json::to_string(&s.filters[0])?;
+        assert!(serialized.contains(r#""&t""#));
+        Ok(())
+    }
+
+    #[test]
+    fn delegated_filter() -> Result<()> {
+        let direct = Event::simple_event();
+        let mut delegated = Event::simple_event();
+        delegated.delegated_by = Some("b".repeat(64));
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"delegated": true}]"#)?;
+        assert!(s.interested_in_event(&delegated));
+        assert!(!s.interested_in_event(&direct));
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"delegated": false}]"#)?;
+        assert!(!s.interested_in_event(&delegated));
+        assert!(s.interested_in_event(&direct));
+        let serialized = serde_json::to_string(&s.filters[0])?;
+        assert_eq!(serialized, r#"{"delegated":false}"#);
+        // without the field, both match
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{}]"#)?;
+        assert!(s.interested_in_event(&delegated) && s.interested_in_event(&direct));
+        Ok(())
+    }
+
+    #[test]
+    fn sequence_filter() -> Result<()> {
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"after_seq": 42}]"#)?;
+        assert!(s.filters[0].uses_sequence());
+        assert_eq!(s.filters[0].after_seq, Some(42));
+        // live events are not filtered by sequence
+        assert!(s.interested_in_event(&Event::simple_event()));
+        let serialized = serde_json::to_string(&s.filters[0])?;
+        assert_eq!(serialized, r#"{"after_seq":42}"#);
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{}]"#)?;
+        assert!(!s.filters[0].uses_sequence());
+        Ok(())
+    }
+
+    #[test]
+    fn address_filters_resolved() -> Result<()> {
+        let pk = "a".repeat(64);
+        let article = |d: &str| {
+            let mut e = Event::simple_event();
+            e.kind = 30023;
+            e.pubkey = pk.clone();
+            e.tags = vec![vec!["d".to_owned(), d.to_owned()]];
+            e.build_index();
+            e
+        };
+        let mut comment = Event::simple_event();
+        comment.tags = vec![vec!["a".to_owned(), format!("30023:{pk}:slug")]];
+        comment.build_index();
+        // only parameterized replaceable addresses with a hex pubkey
+        let req = format!(
+            r##"["REQ","xyz",{{"#a":["30023:{pk}:slug","1:{pk}:x","30023:{}:slug","30023:notapubkey:y"]}}]"##,
+            "b".repeat(64)
+        );
+        let mut s: Subscription = serde_json::from_str(&req)?;
+        assert!(!s.interested_in_event(&article("slug")));
+        s.resolve_addresses();
+        assert_eq!(s.filters.len(), 3);
+        assert!(s.interested_in_event(&article("slug")));
+        assert!(s.interested_in_event(&comment));
+        assert!(!s.interested_in_event(&article("other")));
+        // the filter's other conditions still apply
+        let req = format!(r##"["REQ","xyz",{{"kinds":[1],"#a":["30023:{pk}:slug"]}}]"##);
+        let mut s: Subscription = serde_json::from_str(&req)?;
+        s.resolve_addresses();
+        assert_eq!(s.filters.len(), 1);
+        assert!(!s.interested_in_event(&article("slug")));
+        Ok(())
+    }
+
+    #[test]
+    fn search_matches_note_words() -> Result<()> {
+        let mut note = Event::simple_event();
+        note.kind = 1;
+        note.content = "Pizza night! Who's bringing the drinks?".to_owned();
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"search":"pizza DRINKS"}]"#)?;
+        assert!(s.interested_in_event(&note));
+        // every word must be present, as a whole word
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"search":"pizza soda"}]"#)?;
+        assert!(!s.interested_in_event(&note));
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"search":"pizz"}]"#)?;
+        assert!(!s.interested_in_event(&note));
+        // only text notes are searched
+        note.kind = 30023;
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"search":"pizza"}]"#)?;
+        assert!(!s.interested_in_event(&note));
+        // and a search without words is ignored
+        let s: Subscription = serde_json::from_str(r#"["REQ","xyz",{"search":" \"* "}]"#)?;
+        assert_eq!(s.filters[0].search, None);
+        assert!(s.interested_in_event(&note));
+        Ok(())
+    }
 }
@@ -37,12 +37,50 @@ pub fn is_lower_hex(s: &str) -> bool {
     })
 }
 
+/// Check if a string is the lower-case hex encoding of exactly
+/// `expected_bytes` bytes.
+#[must_use]
+pub fn is_lower_hex_bytes(s: &str, expected_bytes: usize) -> bool {
+    s.len() == expected_bytes * 2 && is_lower_hex(s)
+}
+
+/// Compare two secrets in time that depends only on their lengths,
+/// not on where they first differ.
+#[must_use]
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
 pub fn host_str(url: &String) -> Option<String> {
     Url::parse(url)
         .ok()
         .and_then(|u| u.host_str().map(|s| s.to_string()))
 }
 
+/// Normalize a relay URL, as found in tag relay hints.  A missing
+/// scheme is taken to be `wss://`, and the host is lowercased with any
+/// trailing slash removed.  Returns `None` for anything that is not a
+/// websocket URL with a plausible host.
+#[must_use]
+pub fn normalize_relay_url(url: &str) -> Option<String> {
+    let url = url.trim();
+    let parsed = if url.contains("://") {
+        Url::parse(url)
+    } else {
+        Url::parse(&format!("wss://{url}"))
+    }
+    .ok()?;
+    if !matches!(parsed.scheme(), "ws" | "wss") {
+        return None;
+    }
+    let host = parsed.host_str()?;
+    // bare words are not hosts we could ever connect to
+    if !host.contains('.') && !host.contains(':') && host != "localhost" {
+        return None;
+    }
+    Some(parsed.as_str().trim_end_matches('/').to_owned())
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -53,6 +91,21 @@ mod tests {
         assert!(is_lower_hex(hexstr));
     }
 
+    #[test]
+    fn lower_hex_bytes() {
+        assert!(is_lower_hex_bytes("abcd0123", 4));
+        assert!(is_lower_hex_bytes(&"f".repeat(64), 32));
+        // wrong length
+        assert!(!is_lower_hex_bytes("abcd0123", 32));
+        assert!(!is_lower_hex_bytes("0", 32));
+        assert!(!is_lower_hex_bytes(&"f".repeat(63), 32));
+        // uppercase
+        assert!(!is_lower_hex_bytes("ABCD0123", 4));
+        // non-hex characters
+        assert!(!is_lower_hex_bytes("abcdzz23", 4));
+        assert!(!is_lower_hex_bytes("abcd012é", 4));
+    }
+
     #[test]
     fn nip19() {
         let hexkey = "3bf0c63fcb93463407af97a5e5ee64fa883d107ef9e558472c4eb9aaaefa459d";
@@ -69,4 +122,43 @@ mod tests {
 
         assert_eq!(expected, got);
     }
+
+    #[test]
+    fn relay_url_normalization() {
+        let n = normalize_relay_url;
+        assert_eq!(
+            n("wss://relay.example.com"),
+            Some("wss://relay.example.com".into())
+        );
+        assert_eq!(
+            n("wss://Relay.Example.com/"),
+            Some("wss://relay.example.com".into())
+        );
+        assert_eq!(
+            n("ws://localhost:8080/"),
+            Some("ws://localhost:8080".into())
+        );
+        assert_eq!(
+            n("wss://relay.example.com/nostr/"),
+            Some("wss://relay.example.com/nostr".into())
+        );
+        // a missing scheme is assumed to be wss
+        assert_eq!(
+            n("relay.example.com/"),
+            Some("wss://relay.example.com".into())
+        );
+        // garbage
+        assert_eq!(n("https://relay.example.com"), None);
+        assert_eq!(n("not a relay"), None);
+        assert_eq!(n("relay"), None);
+        assert_eq!(n("wss://"), None);
+    }
+
+    #[test]
+    fn constant_time_comparison() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secrets"));
+        assert!(constant_time_eq(b"", b""));
+    }
 }
@@ -0,0 +1,420 @@
+//! Pluggable event signature verification
+//!
+//! Signature checks are the most expensive part of event validation,
+//! and different secp256k1 builds make different tradeoffs.  The
+//! [`Verifier`] trait lets an alternative (or batching) backend be
+//! used in place of the default [`Secp256k1Verifier`], by starting
+//! the relay with `server::start_server_with_verifier`.
+//!
+//! Each check names the [`SignatureScheme`] the event is signed with.
+//! Today every event uses BIP-340 schnorr signatures; the scheme is
+//! chosen per event by [`Event::signature_scheme`](crate::event::Event::signature_scheme)
+//! so that a future NIP can introduce another.
+use crate::error::Error::{EventInvalidSignature, EventMalformedPubkey};
+use crate::error::Result;
+use crate::event::SECP;
+use prometheus::Histogram;
+use secp256k1::{schnorr, XOnlyPublicKey};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::debug;
+
+/// Signature schemes events can be signed with
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SignatureScheme {
+    /// BIP-340 schnorr signatures over secp256k1
+    #[default]
+    Schnorr,
+}
+
+impl SignatureScheme {
+    /// Length of a public key in this scheme, in bytes
+    #[must_use]
+    pub fn pubkey_bytes(self) -> usize {
+        match self {
+            Self::Schnorr => 32,
+        }
+    }
+
+    /// Length of a signature in this scheme, in bytes
+    #[must_use]
+    pub fn signature_bytes(self) -> usize {
+        match self {
+            Self::Schnorr => 64,
+        }
+    }
+}
+
+/// A signature to check, over an event id digest.
+pub struct SignatureCheck<'a> {
+    /// scheme the signature was made with
+    pub scheme: SignatureScheme,
+    /// sha256 digest of the canonical event
+    pub digest: &'a [u8],
+    /// hex-encoded signature
+    pub sig: &'a str,
+    /// hex-encoded x-only public key
+    pub pubkey: &'a str,
+}
+
+/// Signature verification backend
+pub trait Verifier: Send + Sync {
+    /// Verify a single signature.
+    fn verify(&self, check: &SignatureCheck) -> Result<()>;
+
+    /// Verify several signatures, returning a result for each.
+    ///
+    /// Backends that can verify in bulk should override this; the
+    /// default checks each signature in turn.
+    fn verify_batch(&self, checks: &[SignatureCheck]) -> Vec<Result<()>> {
+        checks.iter().map(|c| self.verify(c)).collect()
+    }
+}
+
+/// The default verifier, using the shared secp256k1 context.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Secp256k1Verifier;
+
+impl Verifier for Secp256k1Verifier {
+    fn verify(&self, check: &SignatureCheck) -> Result<()> {
+        match check.scheme {
+            SignatureScheme::Schnorr => verify_schnorr(check),
+        }
+    }
+}
+
+/// Histogram buckets for validation latencies, doubling from 10µs
+/// to about 80ms, fine enough to read p50/p95/p99 of a signature
+/// check from.
+#[must_use]
+pub fn latency_buckets() -> Vec<f64> {
+    prometheus::exponential_buckets(0.000_01, 2.0, 14).unwrap()
+}
+
+/// Records the time taken by each signature check of another
+/// verifier.
+pub struct TimedVerifier {
+    inner: Arc<dyn Verifier>,
+    latency: Histogram,
+}
+
+impl TimedVerifier {
+    #[must_use]
+    pub fn new(inner: Arc<dyn Verifier>, latency: Histogram) -> TimedVerifier {
+        TimedVerifier { inner, latency }
+    }
+}
+
+impl Verifier for TimedVerifier {
+    fn verify(&self, check: &SignatureCheck) -> Result<()> {
+        let _timer = self.latency.start_timer();
+        self.inner.verify(check)
+    }
+
+    fn verify_batch(&self, checks: &[SignatureCheck]) -> Vec<Result<()>> {
+        let start = Instant::now();
+        let results = self.inner.verify_batch(checks);
+        // a batch is timed as a whole, so each check gets an equal share
+        if !checks.is_empty() {
+            let each = start.elapsed().as_secs_f64() / checks.len() as f64;
+            for _ in checks {
+                self.latency.observe(each);
+            }
+        }
+        results
+    }
+}
+
+/// Verify a BIP-340 schnorr signature with the shared context.
+fn verify_schnorr(check: &SignatureCheck) -> Result<()> {
+    let pubkey = XOnlyPublicKey::from_str(check.pubkey).map_err(|_| {
+        debug!("client sent malformed pubkey");
+        EventMalformedPubkey
+    })?;
+    let sig = schnorr::Signature::from_str(check.sig).map_err(|_| EventInvalidSignature)?;
+    let msg = secp256k1::Message::from_slice(check.digest).map_err(|_| {
+        debug!("error converting digest to secp256k1 message");
+        EventInvalidSignature
+    })?;
+    SECP.verify_schnorr(&sig, &msg, &pubkey)
+        .map_err(|_| EventInvalidSignature)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::delegation::validate_delegation;
+    use crate::error::Error;
+    use crate::event::Event;
+    use bitcoin_hashes::hex::ToHex;
+    use bitcoin_hashes::{sha256, Hash};
+    use prometheus::core::Metric;
+    use prometheus::HistogramOpts;
+    use secp256k1::{KeyPair, Secp256k1};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Accepts or rejects every signature, and counts calls.
+    struct MockVerifier {
+        accept: bool,
+        calls: AtomicUsize,
+    }
+
+    impl MockVerifier {
+        fn new(accept: bool) -> MockVerifier {
+            MockVerifier {
+                accept,
+                calls: AtomicUsize::new(0),
+            }
+        }
+    }
+
+    impl Verifier for MockVerifier {
+        fn verify(&self, _check: &SignatureCheck) -> Result<()> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            if self.accept {
+                Ok(())
+            } else {
+                Err(EventInvalidSignature)
+            }
+        }
+    }
+
+    /// Panics on every signature.
+    struct PanickingVerifier;
+
+    impl Verifier for PanickingVerifier {
+        fn verify(&self, _check: &SignatureCheck) -> Result<()> {
+            panic!("verifier failure");
+        }
+    }
+
+    fn signed_event(content: &str) -> Event {
+        let secp = Secp256k1::new();
+        let key_pair = KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let mut event = Event::simple_event();
+        event.pubkey = XOnlyPublicKey::from_keypair(&key_pair).to_hex();
+        event.kind = 1;
+        event.created_at = 1_700_000_000;
+        event.content = content.to_owned();
+        let digest = sha256::Hash::hash(event.to_canonical().unwrap().as_bytes());
+        let msg = secp256k1::Message::from_slice(digest.as_ref()).unwrap();
+        event.id = format!("{digest:x}");
+        event.sig = secp.sign_schnorr(&msg, &key_pair).to_hex();
+        event
+    }
+
+    #[test]
+    fn default_verifier_outcomes() {
+        let event = signed_event("hello");
+        assert!(event.validate_with(&Secp256k1Verifier).is_ok());
+        // a signature from a different event does not verify
+        let mut other = signed_event("hello");
+        other.sig = event.sig;
+        assert!(matches!(
+            other.validate_with(&Secp256k1Verifier),
+            Err(Error::EventInvalidSignature)
+        ));
+        // a malformed signature is rejected, not a panic
+        other.sig = "zz".to_owned();
+        assert!(other.validate_with(&Secp256k1Verifier).is_err());
+    }
+
+    #[test]
+    fn schnorr_events_verify_by_scheme() {
+        let event = signed_event("hello");
+        assert_eq!(event.signature_scheme(), SignatureScheme::Schnorr);
+        assert_eq!(SignatureScheme::default(), SignatureScheme::Schnorr);
+        let digest = sha256::Hash::hash(event.to_canonical().unwrap().as_bytes());
+        let check = SignatureCheck {
+            scheme: event.signature_scheme(),
+            digest: digest.as_ref(),
+            sig: &event.sig,
+            pubkey: &event.pubkey,
+        };
+        assert!(Secp256k1Verifier.verify(&check).is_ok());
+        assert!(event.validate().is_ok());
+    }
+
+    #[test]
+    fn shared_context_verifies_events_and_delegations() {
+        // signatures and delegations are both checked with SECP
+        let secp = Secp256k1::new();
+        let delegator = KeyPair::new(&secp, &mut secp256k1::rand::thread_rng());
+        let delegator_pk = XOnlyPublicKey::from_keypair(&delegator).to_hex();
+        let delegatee = "0".repeat(64);
+        let token = format!("nostr:delegation:{delegatee}:kind=1");
+        let digest = sha256::Hash::hash(token.as_bytes());
+        let msg = secp256k1::Message::from_slice(digest.as_ref()).unwrap();
+        let sig = secp.sign_schnorr(&msg, &delegator).to_hex();
+        for i in 0..10 {
+            assert!(signed_event(&format!("event {i}")).validate().is_ok());
+            assert!(validate_delegation(&delegator_pk, &delegatee, "kind=1", &sig).is_some());
+        }
+    }
+
+    #[test]
+    fn validation_defers_to_verifier() {
+        // a correctly signed event fails if the verifier rejects it
+        let good = signed_event("good");
+        let rejecting = MockVerifier::new(false);
+        assert!(matches!(
+            good.validate_with(&rejecting),
+            Err(Error::EventInvalidSignature)
+        ));
+        assert_eq!(rejecting.calls.load(Ordering::SeqCst), 1);
+        // and a bad signature passes if the verifier accepts it
+        let mut bad = signed_event("bad");
+        bad.sig = good.sig;
+        let accepting = MockVerifier::new(true);
+        assert!(bad.validate_with(&accepting).is_ok());
+        assert_eq!(accepting.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn verifier_panic_rejects_event() {
+        let event = signed_event("hello");
+        assert!(matches!(
+            event.validate_with(&PanickingVerifier),
+            Err(Error::EventInvalidSignature)
+        ));
+        // and validation keeps working afterwards
+        assert!(event.validate_with(&Secp256k1Verifier).is_ok());
+    }
+
+    #[test]
+    fn id_checked_before_signature() {
+        let mut event = signed_event("hello");
+        event.content = "tampered".to_owned();
+        let mock = MockVerifier::new(true);
+        assert!(matches!(
+            event.validate_with(&mock),
+            Err(Error::EventInvalidId)
+        ));
+        assert_eq!(mock.calls.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn malformed_hex_rejected_before_hashing() {
+        let good = signed_event("hello");
+        let malformed = |s: &str| {
+            vec![
+                "0".to_owned(),
+                // odd length
+                s[1..].to_owned(),
+                s.to_uppercase(),
+                format!("z{}", &s[1..]),
+            ]
+        };
+        let mut bad = vec![];
+        for id in malformed(&good.id) {
+            bad.push(Event { id, ..good.clone() });
+        }
+        for pubkey in malformed(&good.pubkey) {
+            bad.push(Event {
+                pubkey,
+                ..good.clone()
+            });
+        }
+        for sig in malformed(&good.sig) {
+            bad.push(Event {
+                sig,
+                ..good.clone()
+            });
+        }
+        // none of them reach the verifier
+        let mock = MockVerifier::new(true);
+        for e in &bad {
+            assert!(e.validate_with(&mock).is_err());
+        }
+        assert_eq!(mock.calls.load(Ordering::SeqCst), 0);
+        assert!(matches!(bad[0].validate(), Err(Error::EventInvalidId)));
+        assert!(matches!(
+            bad[6].validate(),
+            Err(Error::EventMalformedPubkey)
+        ));
+        assert!(matches!(
+            bad[9].validate(),
+            Err(Error::EventInvalidSignature)
+        ));
+    }
+
+    #[test]
+    fn batch_matches_individual() {
+        let good = signed_event("good");
+        let mut bad = signed_event("bad");
+        bad.sig = good.sig.clone();
+        let digests: Vec<sha256::Hash> = [&good, &bad]
+            .iter()
+            .map(|e| sha256::Hash::hash(e.to_canonical().unwrap().as_bytes()))
+            .collect();
+        let checks: Vec<SignatureCheck> = [&good, &bad]
+            .iter()
+            .zip(digests.iter())
+            .map(|(e, d)| SignatureCheck {
+                scheme: e.signature_scheme(),
+                digest: d.as_ref(),
+                sig: &e.sig,
+                pubkey: &e.pubkey,
+            })
+            .collect();
+        let results = Secp256k1Verifier.verify_batch(&checks);
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+    }
+
+    fn latency_histogram() -> Histogram {
+        Histogram::with_opts(
+            HistogramOpts::new("test_latency_seconds", "test latencies").buckets(latency_buckets()),
+        )
+        .unwrap()
+    }
+
+    /// Upper bound of the bucket holding the `q` quantile.
+    fn quantile_bucket(h: &Histogram, q: f64) -> f64 {
+        let metric = h.metric();
+        let hist = metric.get_histogram();
+        let rank = (q * hist.get_sample_count() as f64).ceil() as u64;
+        hist.get_bucket()
+            .iter()
+            .find(|b| b.get_cumulative_count() >= rank)
+            .map_or(f64::INFINITY, |b| b.get_upper_bound())
+    }
+
+    #[test]
+    fn latency_percentile_buckets() {
+        let h = latency_histogram();
+        for (secs, n) in [(0.000_015, 101), (0.000_035, 91), (0.000_15, 7), (0.005, 1)] {
+            for _ in 0..n {
+                h.observe(secs);
+            }
+        }
+        for (q, bound) in [
+            (0.50, 0.000_02),
+            (0.95, 0.000_04),
+            (0.99, 0.000_16),
+            (1.0, 0.005_12),
+        ] {
+            assert!((quantile_bucket(&h, q) - bound).abs() < 1e-12, "p{q}");
+        }
+    }
+
+    #[test]
+    fn timed_verifier_observes_each_check() {
+        let h = latency_histogram();
+        let inner = Arc::new(MockVerifier::new(true));
+        let timed = TimedVerifier::new(inner.clone(), h.clone());
+        let check = || SignatureCheck {
+            scheme: SignatureScheme::Schnorr,
+            digest: &[0; 32],
+            sig: "",
+            pubkey: "",
+        };
+        assert!(timed.verify(&check()).is_ok());
+        let checks = [check(), check()];
+        assert!(timed.verify_batch(&checks).iter().all(Result::is_ok));
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 3);
+        assert_eq!(h.get_sample_count(), 3);
+    }
+}
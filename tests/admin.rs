@@ -0,0 +1,327 @@
+use anyhow::Result;
+use hyper::{Body, Client, Method, Request, StatusCode};
+use nostr_rs_relay::config;
+use serde_json::json;
+
+mod common;
+
+const TOKEN: &str = "test-admin-token";
+
+fn admin_relay() -> Result<common::Relay> {
+    let mut settings = config::Settings::default();
+    settings.admin.api_token = Some(TOKEN.to_owned());
+    common::start_relay_with_settings(settings)
+}
+
+#[test]
+fn secrets_redacted_from_debug_output() {
+    let mut settings = config::Settings::default();
+    settings.admin.api_token = Some(TOKEN.to_owned());
+    settings.announcement.secret_key = Some("announce-secret".to_owned());
+    let logged = format!("{:?}", settings);
+    assert!(!logged.contains(TOKEN));
+    assert!(!logged.contains("announce-secret"));
+    assert!(logged.contains("api_token: Some(\"<redacted>\")"));
+}
+
+async fn post_ban(
+    relay: &common::Relay,
+    token: &str,
+    body: serde_json::Value,
+) -> Result<StatusCode> {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!("http://127.0.0.1:{}/admin/ban", relay.port))
+        .header("Authorization", format!("Bearer {token}"))
+        .body(Body::from(body.to_string()))?;
+    let res = Client::new().request(req).await?;
+    Ok(res.status())
+}
+
+#[tokio::test]
+async fn ban_requires_token() -> Result<()> {
+    let relay = admin_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let pubkey = "a".repeat(64);
+    let status = post_ban(&relay, "wrong-token", json!({ "pubkey": pubkey })).await?;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn banned_pubkey_events_rejected() -> Result<()> {
+    let relay = admin_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let mut ws = common::connect(&relay).await?;
+    // events are accepted before the ban
+    let before = common::signed_event(&keys, 1, vec![], "before ban");
+    let ok = common::publish(&mut ws, &before).await?;
+    assert_eq!(ok[2], true);
+    // ban without purging
+    let status = post_ban(&relay, TOKEN, json!({ "pubkey": before.pubkey })).await?;
+    assert_eq!(status, StatusCode::OK);
+    let after = common::signed_event(&keys, 1, vec![], "after ban");
+    let ok = common::publish(&mut ws, &after).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("blocked:"));
+    // existing events remain
+    let events = common::query(&mut ws, "s", json!({ "authors": [before.pubkey] })).await?;
+    assert_eq!(events.len(), 1);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn ban_with_purge_removes_events() -> Result<()> {
+    let relay = admin_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let mut ws = common::connect(&relay).await?;
+    let event = common::signed_event(&keys, 1, vec![], "to be purged");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], true);
+    let status = post_ban(
+        &relay,
+        TOKEN,
+        json!({ "pubkey": event.pubkey, "purge": true }),
+    )
+    .await?;
+    assert_eq!(status, StatusCode::OK);
+    let events = common::query(&mut ws, "s", json!({ "authors": [event.pubkey] })).await?;
+    assert!(events.is_empty());
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn references_across_tag_names() -> Result<()> {
+    let relay = admin_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let mut ws = common::connect(&relay).await?;
+    let value = common::signed_event(&keys, 1, vec![], "referenced").id;
+    let mut ids = vec![];
+    for name in ["e", "p", "x"] {
+        let tags = vec![vec![name.to_owned(), value.clone()]];
+        let event = common::signed_event(&keys, 1, tags, name);
+        let ok = common::publish(&mut ws, &event).await?;
+        assert_eq!(ok[2], true);
+        ids.push(event.id);
+    }
+    let req = Request::builder()
+        .uri(format!(
+            "http://127.0.0.1:{}/admin/references?value={}",
+            relay.port, value
+        ))
+        .header("Authorization", format!("Bearer {TOKEN}"))
+        .body(Body::empty())?;
+    let res = Client::new().request(req).await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    let events: Vec<serde_json::Value> = serde_json::from_slice(&body)?;
+    let mut found: Vec<String> = events
+        .iter()
+        .map(|e| e["id"].as_str().unwrap().to_owned())
+        .collect();
+    found.sort();
+    ids.sort();
+    assert_eq!(found, ids);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+fn quarantine_relay(quarantine: config::Quarantine) -> Result<common::Relay> {
+    let mut settings = config::Settings::default();
+    settings.admin.api_token = Some(TOKEN.to_owned());
+    settings.quarantine = quarantine;
+    common::start_relay_with_settings(settings)
+}
+
+async fn post_quarantine(
+    relay: &common::Relay,
+    action: &str,
+    body: serde_json::Value,
+) -> Result<StatusCode> {
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri(format!(
+            "http://127.0.0.1:{}/admin/quarantine/{}",
+            relay.port, action
+        ))
+        .header("Authorization", format!("Bearer {TOKEN}"))
+        .body(Body::from(body.to_string()))?;
+    let res = Client::new().request(req).await?;
+    Ok(res.status())
+}
+
+#[tokio::test]
+async fn quarantined_event_served_after_approval() -> Result<()> {
+    let relay = quarantine_relay(config::Quarantine {
+        content_patterns: vec!["(?i)suspicious".to_owned()],
+        new_authors: false,
+        max_held: 10,
+    })?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let mut ws = common::connect(&relay).await?;
+    let event = common::signed_event(&keys, 1, vec![], "a Suspicious offer");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], true);
+    assert!(ok[3].as_str().unwrap().starts_with("quarantined:"));
+    // held events are not served
+    let filter = json!({ "authors": [event.pubkey] });
+    let mut reader = common::connect(&relay).await?;
+    let events = common::query(&mut reader, "q1", filter.clone()).await?;
+    assert!(events.is_empty());
+    // but are listed for review
+    let req = Request::builder()
+        .uri(format!("http://127.0.0.1:{}/admin/quarantine", relay.port))
+        .header("Authorization", format!("Bearer {TOKEN}"))
+        .body(Body::empty())?;
+    let res = Client::new().request(req).await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    let held: Vec<serde_json::Value> = serde_json::from_slice(&body)?;
+    assert!(held.iter().any(|e| e["id"] == event.id.as_str()));
+    // events that match no rule are stored as usual
+    let plain = common::signed_event(&keys, 1, vec![], "hello");
+    let ok = common::publish(&mut ws, &plain).await?;
+    assert_eq!(ok[3], "");
+    // approval stores the event
+    let status = post_quarantine(&relay, "approve", json!({ "id": event.id })).await?;
+    assert_eq!(status, StatusCode::OK);
+    let mut reader = common::connect(&relay).await?;
+    let events = common::query(&mut reader, "q2", filter).await?;
+    let mut ids: Vec<&str> = events.iter().map(|e| e.id.as_str()).collect();
+    ids.sort_unstable();
+    let mut expected = vec![event.id.as_str(), plain.id.as_str()];
+    expected.sort_unstable();
+    assert_eq!(ids, expected);
+    // and removes it from quarantine
+    let status = post_quarantine(&relay, "approve", json!({ "id": event.id })).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn discarded_new_author_event_never_served() -> Result<()> {
+    let relay = quarantine_relay(config::Quarantine {
+        content_patterns: vec![],
+        new_authors: true,
+        max_held: 10,
+    })?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let mut ws = common::connect(&relay).await?;
+    let event = common::signed_event(&keys, 1, vec![], "first post");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert!(ok[3].as_str().unwrap().starts_with("quarantined:"));
+    let status = post_quarantine(&relay, "discard", json!({ "id": event.id })).await?;
+    assert_eq!(status, StatusCode::OK);
+    let status = post_quarantine(&relay, "approve", json!({ "id": event.id })).await?;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let events = common::query(&mut ws, "q", json!({ "authors": [event.pubkey] })).await?;
+    assert!(events.is_empty());
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn full_quarantine_refuses_events() -> Result<()> {
+    let relay = quarantine_relay(config::Quarantine {
+        content_patterns: vec!["(?i)hold me".to_owned()],
+        new_authors: false,
+        max_held: 1,
+    })?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let mut ws = common::connect(&relay).await?;
+    let first = common::signed_event(&keys, 1, vec![], "hold me, first");
+    let ok = common::publish(&mut ws, &first).await?;
+    assert!(ok[3].as_str().unwrap().starts_with("quarantined:"));
+    let second = common::signed_event(&keys, 1, vec![], "hold me, second");
+    let ok = common::publish(&mut ws, &second).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("rate-limited:"));
+    // releasing the held event makes room again
+    let status = post_quarantine(&relay, "approve", json!({ "id": first.id })).await?;
+    assert_eq!(status, StatusCode::OK);
+    let ok = common::publish(&mut ws, &second).await?;
+    assert!(ok[3].as_str().unwrap().starts_with("quarantined:"));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn storage_stats_for_author() -> Result<()> {
+    let relay = admin_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let mut ws = common::connect(&relay).await?;
+    let mut sizes = vec![];
+    for len in [10, 500, 50] {
+        let event = common::signed_event(&keys, 1, vec![], &"x".repeat(len));
+        assert_eq!(common::publish(&mut ws, &event).await?[2], true);
+        sizes.push((serde_json::to_string(&event)?.len() as u64, event.id));
+    }
+    let author = common::signed_event(&keys, 1, vec![], "").pubkey;
+    let req = Request::builder()
+        .uri(format!(
+            "http://127.0.0.1:{}/admin/storage?author={}&top=1",
+            relay.port, author
+        ))
+        .header("Authorization", format!("Bearer {TOKEN}"))
+        .body(Body::empty())?;
+    let res = Client::new().request(req).await?;
+    assert_eq!(res.status(), StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    let stats: serde_json::Value = serde_json::from_slice(&body)?;
+    let total: u64 = sizes.iter().map(|(s, _)| s).sum();
+    assert_eq!(stats["events"], 3);
+    assert_eq!(stats["total_bytes"], total);
+    assert_eq!(stats["average_bytes"], total / 3);
+    assert_eq!(
+        stats["largest"],
+        json!([{ "id": sizes[1].1, "bytes": sizes[1].0 }])
+    );
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+async fn cleanup(
+    relay: &common::Relay,
+    method: Method,
+    token: &str,
+) -> Result<hyper::Response<Body>> {
+    let req = Request::builder()
+        .method(method)
+        .uri(format!("http://127.0.0.1:{}/admin/cleanup", relay.port))
+        .header("Authorization", format!("Bearer {token}"))
+        .body(Body::empty())?;
+    Ok(Client::new().request(req).await?)
+}
+
+#[tokio::test]
+async fn cleanup_reports_orphaned_tags() -> Result<()> {
+    let relay = admin_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let res = cleanup(&relay, Method::POST, "wrong-token").await?;
+    assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+    let keys = common::new_keypair();
+    let mut ws = common::connect(&relay).await?;
+    let event = common::signed_event(&keys, 1, vec![vec!["t".into(), "x".into()]], "tagged");
+    assert_eq!(common::publish(&mut ws, &event).await?[2], true);
+    // tags of stored events are not orphaned
+    for (method, removed) in [(Method::GET, false), (Method::POST, true)] {
+        let res = cleanup(&relay, method, TOKEN).await?;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let report: serde_json::Value = serde_json::from_slice(&body)?;
+        assert_eq!(report, json!({ "orphaned_tags": 0, "removed": removed }));
+    }
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
@@ -1,16 +1,33 @@
+#![allow(dead_code)]
 use anyhow::{anyhow, Result};
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::sha256;
+use bitcoin_hashes::Hash;
+use futures::{SinkExt, StreamExt};
 use nostr_rs_relay::config;
-use nostr_rs_relay::server::start_server;
+use nostr_rs_relay::event::Event;
+use nostr_rs_relay::server::start_server_with_verifier;
+use nostr_rs_relay::utils::unix_time;
+use nostr_rs_relay::verify::{Secp256k1Verifier, Verifier};
 //use http::{Request, Response};
 use hyper::{Client, StatusCode, Uri};
+use secp256k1::{KeyPair, Secp256k1, XOnlyPublicKey};
+use serde_json::Value;
 use std::net::TcpListener;
 use std::sync::atomic::{AtomicU16, Ordering};
 use std::sync::mpsc as syncmpsc;
 use std::sync::mpsc::{Receiver as MpscReceiver, Sender as MpscSender};
+use std::sync::Arc;
 use std::thread;
 use std::thread::JoinHandle;
 use std::time::Duration;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
 use tracing::{debug, info};
+use tungstenite::client::IntoClientRequest;
+use tungstenite::protocol::Message;
+
+pub type WsStream = WebSocketStream<MaybeTlsStream<TcpStream>>;
 
 pub struct Relay {
     pub port: u16,
@@ -19,11 +36,24 @@ pub struct Relay {
 }
 
 pub fn start_relay() -> Result<Relay> {
+    // replace default settings
+    start_relay_with_settings(config::Settings::default())
+}
+
+/// Start a relay, using the given settings for anything other than
+/// the network and database.
+pub fn start_relay_with_settings(settings: config::Settings) -> Result<Relay> {
+    start_relay_with_verifier(settings, Arc::new(Secp256k1Verifier))
+}
+
+/// Start a relay that checks signatures with the given verifier.
+pub fn start_relay_with_verifier(
+    mut settings: config::Settings,
+    verifier: Arc<dyn Verifier>,
+) -> Result<Relay> {
     // setup tracing
     let _trace_sub = tracing_subscriber::fmt::try_init();
     info!("Starting a new relay");
-    // replace default settings
-    let mut settings = config::Settings::default();
     // identify open port
     info!("Checking for address...");
     let port = get_available_port().unwrap();
@@ -38,7 +68,7 @@ pub fn start_relay() -> Result<Relay> {
     let (shutdown_tx, shutdown_rx): (MpscSender<()>, MpscReceiver<()>) = syncmpsc::channel();
     let handle = thread::spawn(move || {
         // server will block the thread it is run on.
-        let _ = start_server(&settings, shutdown_rx);
+        let _ = start_server_with_verifier(&settings, shutdown_rx, verifier);
     });
     // how do we know the relay has finished starting up?
     Ok(Relay {
@@ -108,3 +138,127 @@ pub fn port_is_available(port: u16) -> bool {
         Err(_) => false,
     }
 }
+
+/// Open a websocket connection to the relay
+pub async fn connect(relay: &Relay) -> Result<WsStream> {
+    let (ws, _) = connect_async(format!("ws://127.0.0.1:{}/", relay.port)).await?;
+    Ok(ws)
+}
+
+/// Open a websocket connection to the relay, with a URL query string
+pub async fn connect_with_query(relay: &Relay, query: &str) -> Result<WsStream> {
+    let (ws, _) = connect_async(format!("ws://127.0.0.1:{}/?{}", relay.port, query)).await?;
+    Ok(ws)
+}
+
+/// Open a websocket connection to the relay, sending extra HTTP headers
+pub async fn connect_with_headers(
+    relay: &Relay,
+    headers: &[(&'static str, &str)],
+) -> Result<WsStream> {
+    let mut req = format!("ws://127.0.0.1:{}/", relay.port).into_client_request()?;
+    for (name, value) in headers {
+        req.headers_mut().insert(*name, value.parse()?);
+    }
+    let (ws, _) = connect_async(req).await?;
+    Ok(ws)
+}
+
+/// Send a JSON message over the websocket
+pub async fn send_json(ws: &mut WsStream, msg: &Value) -> Result<()> {
+    ws.send(Message::Text(msg.to_string())).await?;
+    Ok(())
+}
+
+/// Wait (briefly) for the next text message from the relay
+pub async fn next_json(ws: &mut WsStream) -> Result<Value> {
+    loop {
+        let next = tokio::time::timeout(Duration::from_secs(5), ws.next())
+            .await
+            .map_err(|_| anyhow!("timed out waiting for relay message"))?;
+        match next {
+            Some(Ok(Message::Text(t))) => return Ok(serde_json::from_str(&t)?),
+            Some(Ok(_)) => continue,
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(anyhow!("connection closed")),
+        }
+    }
+}
+
+/// Publish an event, and return the relay's OK response
+pub async fn publish(ws: &mut WsStream, event: &Event) -> Result<Value> {
+    send_json(ws, &serde_json::json!(["EVENT", event])).await?;
+    next_json(ws).await
+}
+
+/// Run a REQ and collect the events returned before EOSE
+pub async fn query(ws: &mut WsStream, sub_id: &str, filter: Value) -> Result<Vec<Event>> {
+    send_json(ws, &serde_json::json!(["REQ", sub_id, filter])).await?;
+    let mut events = vec![];
+    loop {
+        let msg = next_json(ws).await?;
+        match msg[0].as_str() {
+            Some("EVENT") => events.push(serde_json::from_value(msg[2].clone())?),
+            Some("EOSE") => break,
+            _ => return Err(anyhow!("unexpected message: {}", msg)),
+        }
+    }
+    Ok(events)
+}
+
+/// Generate a new random keypair
+pub fn new_keypair() -> KeyPair {
+    let secp = Secp256k1::new();
+    KeyPair::new(&secp, &mut secp256k1::rand::thread_rng())
+}
+
+/// Create an event signed by the given keypair
+pub fn signed_event(key_pair: &KeyPair, kind: u64, tags: Vec<Vec<String>>, content: &str) -> Event {
+    signed_event_at(key_pair, kind, tags, content, unix_time())
+}
+
+/// Create an event signed by the given keypair, with a specific timestamp
+pub fn signed_event_at(
+    key_pair: &KeyPair,
+    kind: u64,
+    tags: Vec<Vec<String>>,
+    content: &str,
+    created_at: u64,
+) -> Event {
+    let secp = Secp256k1::new();
+    let public_key = XOnlyPublicKey::from_keypair(key_pair);
+    let mut event = Event {
+        id: "0".to_owned(),
+        pubkey: public_key.to_hex(),
+        delegated_by: None,
+        created_at,
+        kind,
+        tags,
+        content: content.to_owned(),
+        sig: "0".to_owned(),
+        tagidx: None,
+    };
+    let c = event.to_canonical().unwrap();
+    let digest: sha256::Hash = sha256::Hash::hash(c.as_bytes());
+    let msg = secp256k1::Message::from_slice(digest.as_ref()).unwrap();
+    let sig = secp.sign_schnorr(&msg, key_pair);
+    event.id = format!("{digest:x}");
+    event.sig = sig.to_hex();
+    event
+}
+
+/// Create a NIP-26 delegation tag, allowing `delegatee` to publish
+/// events meeting `conditions` on behalf of `delegator`
+pub fn delegation_tag(delegator: &KeyPair, delegatee: &KeyPair, conditions: &str) -> Vec<String> {
+    let secp = Secp256k1::new();
+    let delegatee_pubkey = XOnlyPublicKey::from_keypair(delegatee).to_hex();
+    let token = format!("nostr:delegation:{delegatee_pubkey}:{conditions}");
+    let digest: sha256::Hash = sha256::Hash::hash(token.as_bytes());
+    let msg = secp256k1::Message::from_slice(digest.as_ref()).unwrap();
+    vec![
+        "delegation".to_owned(),
+        XOnlyPublicKey::from_keypair(delegator).to_hex(),
+        conditions.to_owned(),
+        secp.sign_schnorr(&msg, delegator).to_hex(),
+    ]
+}
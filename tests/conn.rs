@@ -6,9 +6,10 @@ mod tests {
     use secp256k1::rand;
     use secp256k1::{KeyPair, Secp256k1, XOnlyPublicKey};
 
-    use nostr_rs_relay::conn::ClientConn;
+    use nostr_rs_relay::conn::{ClientConn, IpConnections};
     use nostr_rs_relay::error::Error;
     use nostr_rs_relay::event::Event;
+    use nostr_rs_relay::subscription::Subscription;
     use nostr_rs_relay::utils::unix_time;
 
     const RELAY: &str = "wss://nostr.example.com/";
@@ -280,6 +281,85 @@ mod tests {
         assert!(matches!(result, Err(Error::AuthFailure)));
     }
 
+    #[test]
+    fn test_subscribe_with_normal_id() {
+        let mut client_conn = ClientConn::new("127.0.0.1".into());
+
+        let result = client_conn.subscribe(subscription("feed-1 (home)"));
+
+        assert!(result.is_ok());
+        assert_eq!(client_conn.subscriptions().len(), 1);
+    }
+
+    #[test]
+    fn test_fail_to_subscribe_with_control_characters() {
+        let mut client_conn = ClientConn::new("127.0.0.1".into());
+
+        let result = client_conn.subscribe(subscription("feed\u{1b}[2J"));
+
+        assert!(matches!(result, Err(Error::SubIdInvalidError)));
+        assert!(client_conn.subscriptions().is_empty());
+    }
+
+    #[test]
+    fn test_fail_to_subscribe_with_long_id() {
+        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        client_conn.set_max_subscription_id_len(8);
+
+        assert!(client_conn.subscribe(subscription("12345678")).is_ok());
+        let result = client_conn.subscribe(subscription("123456789"));
+
+        assert!(matches!(result, Err(Error::SubIdMaxLengthError)));
+    }
+
+    #[test]
+    fn test_fail_to_subscribe_past_limit() {
+        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        client_conn.set_max_subscriptions(2);
+
+        assert!(client_conn.subscribe(subscription("a")).is_ok());
+        assert!(client_conn.subscribe(subscription("b")).is_ok());
+        // reusing an id replaces the subscription
+        assert!(client_conn.subscribe(subscription("a")).is_ok());
+        let result = client_conn.subscribe(subscription("c"));
+
+        assert!(matches!(result, Err(Error::SubMaxExceededError)));
+        assert_eq!(client_conn.subscriptions().len(), 2);
+    }
+
+    #[test]
+    fn test_throttle_new_authors_past_limit() {
+        let mut client_conn = ClientConn::new("127.0.0.1".into());
+        client_conn.set_max_authors(Some(2));
+        assert!(client_conn.admit_author("alice"));
+        assert!(client_conn.admit_author("bob"));
+        assert!(!client_conn.admit_author("carol"));
+        // established authors continue
+        assert!(client_conn.admit_author("alice"));
+        assert!(client_conn.admit_author("bob"));
+        assert!(!client_conn.admit_author("carol"));
+    }
+
+    #[test]
+    fn test_connections_per_ip() {
+        let ips = IpConnections::new(2);
+        let first = ips.try_acquire("10.0.0.1");
+        let second = ips.try_acquire("10.0.0.1");
+        assert!(first.is_some() && second.is_some());
+        assert!(ips.try_acquire("10.0.0.1").is_none());
+        // other addresses have their own count
+        assert!(ips.try_acquire("10.0.0.2").is_some());
+        // a connection ending frees its slot
+        drop(first);
+        assert_eq!(ips.open_connections("10.0.0.1"), 1);
+        assert!(ips.try_acquire("10.0.0.1").is_some());
+    }
+
+    fn subscription(id: &str) -> Subscription {
+        let req = serde_json::json!(["REQ", id, {}]).to_string();
+        serde_json::from_str(&req).unwrap()
+    }
+
     fn auth_event(challenge: &String) -> Event {
         create_auth_event(Some(challenge), Some(&RELAY.into()), 22242, unix_time())
     }
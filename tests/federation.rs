@@ -0,0 +1,202 @@
+use anyhow::Result;
+use futures::{SinkExt, StreamExt};
+use nostr_rs_relay::config;
+use serde_json::{json, Value};
+use std::time::Duration;
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+use tokio_tungstenite::accept_async;
+use tungstenite::protocol::Message;
+
+mod common;
+
+/// A mock upstream relay, which reports the id of every EVENT it
+/// receives.
+async fn mock_upstream() -> Result<(String, mpsc::UnboundedReceiver<String>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("ws://{}/", listener.local_addr()?);
+    let (id_tx, id_rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let id_tx = id_tx.clone();
+            tokio::spawn(async move {
+                let mut ws = accept_async(stream).await.unwrap();
+                while let Some(Ok(Message::Text(t))) = ws.next().await {
+                    let msg: Value = serde_json::from_str(&t).unwrap();
+                    if msg[0] == "EVENT" {
+                        let id = msg[1]["id"].as_str().unwrap().to_owned();
+                        let ok = json!(["OK", id, true, ""]).to_string();
+                        ws.send(Message::Text(ok)).await.ok();
+                        id_tx.send(id).ok();
+                    }
+                }
+            });
+        }
+    });
+    Ok((url, id_rx))
+}
+
+#[tokio::test]
+async fn accepted_event_forwarded_once_per_upstream() -> Result<()> {
+    let (url_a, mut rx_a) = mock_upstream().await?;
+    let (url_b, mut rx_b) = mock_upstream().await?;
+    let mut settings = config::Settings::default();
+    settings.federation.forward_to_relays = vec![url_a, url_b];
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let kp = common::new_keypair();
+    let event = common::signed_event(&kp, 1, vec![], "forward me");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], true);
+    // publishing a duplicate must not forward it again
+    common::publish(&mut ws, &event).await?;
+    for rx in [&mut rx_a, &mut rx_b] {
+        let id = tokio::time::timeout(Duration::from_secs(5), rx.recv()).await?;
+        assert_eq!(id, Some(event.id.clone()));
+        let again = tokio::time::timeout(Duration::from_millis(500), rx.recv()).await;
+        assert!(again.is_err(), "event was forwarded more than once");
+    }
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+/// A mock upstream relay, which answers every REQ with the given
+/// events followed by EOSE, and reports each REQ it has answered.
+async fn mock_source(events: Vec<Value>) -> Result<(String, mpsc::UnboundedReceiver<()>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("ws://{}/", listener.local_addr()?);
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let events = events.clone();
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                let mut ws = accept_async(stream).await.unwrap();
+                while let Some(Ok(Message::Text(t))) = ws.next().await {
+                    let msg: Value = serde_json::from_str(&t).unwrap();
+                    if msg[0] == "REQ" {
+                        let sub_id = msg[1].clone();
+                        for e in &events {
+                            let m = json!(["EVENT", sub_id, e]).to_string();
+                            ws.send(Message::Text(m)).await.ok();
+                        }
+                        let eose = json!(["EOSE", sub_id]).to_string();
+                        ws.send(Message::Text(eose)).await.ok();
+                        tx.send(()).ok();
+                    }
+                }
+            });
+        }
+    });
+    Ok((url, rx))
+}
+
+#[tokio::test]
+async fn imported_events_validated_and_deduplicated() -> Result<()> {
+    let kp = common::new_keypair();
+    let first = common::signed_event_at(&kp, 1, vec![], "first", 1_000);
+    let second = common::signed_event_at(&kp, 1, vec![], "second", 2_000);
+    let mut forged = common::signed_event_at(&kp, 1, vec![], "forged", 3_000);
+    forged.content = "tampered".to_owned();
+    let author = first.pubkey.clone();
+    let (url, _served) = mock_source(vec![
+        json!(first),
+        json!(first),
+        json!(forged),
+        json!(second),
+    ])
+    .await?;
+    let mut settings = config::Settings::default();
+    settings.federation.import_from_relays = vec![config::ImportRelay {
+        url,
+        filter: json!({"authors": [author]}).to_string(),
+    }];
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    // wait for the import to complete
+    let mut events = vec![];
+    for _ in 0..50 {
+        let mut ws = common::connect(&relay).await?;
+        events = common::query(&mut ws, "q", json!({ "authors": [author] })).await?;
+        if events.len() >= 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    // each valid event is stored once; the forgery is dropped
+    let ids: Vec<&str> = events.iter().map(|e| e.id.as_str()).collect();
+    assert_eq!(ids, vec![first.id.as_str(), second.id.as_str()]);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn imports_follow_client_admission_policy() -> Result<()> {
+    let kp = common::new_keypair();
+    let event = common::signed_event(&kp, 1, vec![], "after hours");
+    let author = event.pubkey.clone();
+    let (url, mut served) = mock_source(vec![json!(event)]).await?;
+    let mut settings = config::Settings::default();
+    settings.federation.import_from_relays = vec![config::ImportRelay {
+        url,
+        filter: json!({"authors": [author]}).to_string(),
+    }];
+    // posting hours that have not started yet
+    let at = |offset: i64| {
+        (chrono::Utc::now() + chrono::Duration::minutes(offset))
+            .format("%H:%M")
+            .to_string()
+    };
+    settings.posting_hours = config::PostingHours {
+        start: Some(at(60)),
+        end: Some(at(120)),
+    };
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    served.recv().await;
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let mut ws = common::connect(&relay).await?;
+    let events = common::query(&mut ws, "q", json!({ "authors": [author] })).await?;
+    assert!(events.is_empty());
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn imported_events_not_forwarded() -> Result<()> {
+    let kp = common::new_keypair();
+    let imported = common::signed_event(&kp, 1, vec![], "from upstream");
+    let author = imported.pubkey.clone();
+    let (source, mut served) = mock_source(vec![json!(imported)]).await?;
+    let (upstream, mut forwarded) = mock_upstream().await?;
+    let mut settings = config::Settings::default();
+    settings.federation.import_from_relays = vec![config::ImportRelay {
+        url: source,
+        filter: json!({"authors": [author]}).to_string(),
+    }];
+    settings.federation.forward_to_relays = vec![upstream];
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    served.recv().await;
+    let mut stored = vec![];
+    for _ in 0..50 {
+        let mut ws = common::connect(&relay).await?;
+        stored = common::query(&mut ws, "q", json!({ "ids": [imported.id] })).await?;
+        if !stored.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(!stored.is_empty());
+    // the imported event was stored first, so it would have been
+    // forwarded before this client event.
+    let mut ws = common::connect(&relay).await?;
+    let local = common::signed_event(&kp, 1, vec![], "from a client");
+    assert_eq!(common::publish(&mut ws, &local).await?[2], true);
+    let id = tokio::time::timeout(Duration::from_secs(5), forwarded.recv()).await?;
+    assert_eq!(id, Some(local.id.clone()));
+    assert!(forwarded.try_recv().is_err());
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
@@ -1,5 +1,17 @@
 use anyhow::Result;
+use bitcoin_hashes::hex::ToHex;
+use bitcoin_hashes::{sha256, Hash};
+use futures::StreamExt;
+use nostr_rs_relay::event::Event;
+use nostr_rs_relay::subscription::ReqFilter;
+use nostr_rs_relay::utils::unix_time;
+use nostr_rs_relay::verify::{Secp256k1Verifier, SignatureCheck, Verifier};
+use nostr_rs_relay::{config, db, repo, server};
+use serde_json::json;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::Message;
 
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 
@@ -45,3 +57,2408 @@ async fn relay_home_page() -> Result<()> {
     let _res = relay.shutdown_tx.send(());
     Ok(())
 }
+
+#[tokio::test]
+async fn truncated_query_notice() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.max_limit = Some(3);
+    settings.limits.notify_truncated_results = true;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let mut ws = common::connect(&relay).await?;
+    let mut pubkey = String::new();
+    for i in 0..5 {
+        let event = common::signed_event(&keys, 1, vec![], &format!("note {i}"));
+        pubkey = event.pubkey.clone();
+        let ok = common::publish(&mut ws, &event).await?;
+        assert_eq!(ok[2], true);
+    }
+    common::send_json(&mut ws, &json!(["REQ", "t", {"authors": [pubkey]}])).await?;
+    for _ in 0..3 {
+        let msg = common::next_json(&mut ws).await?;
+        assert_eq!(msg[0], "EVENT");
+    }
+    let notice = common::next_json(&mut ws).await?;
+    assert_eq!(notice[0], "NOTICE");
+    assert!(notice[1].as_str().unwrap().contains("truncated"));
+    let eose = common::next_json(&mut ws).await?;
+    assert_eq!(eose[0], "EOSE");
+    // a query within the cap has no notice
+    let events = common::query(&mut ws, "u", json!({"authors": [pubkey], "limit": 2})).await?;
+    assert_eq!(events.len(), 2);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn limit_returns_newest_events() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.max_limit = Some(3);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let mut ws = common::connect(&relay).await?;
+    let now = unix_time();
+    let mut events = vec![];
+    // published oldest last, so insertion order can't stand in for age
+    for i in (0..5).rev() {
+        let event = common::signed_event_at(&keys, 1, vec![], &format!("note {i}"), now - 10 + i);
+        assert_eq!(common::publish(&mut ws, &event).await?[2], true);
+        events.push(event);
+    }
+    let author = events[0].pubkey.clone();
+    let ids = |v: &[Event]| v.iter().map(|e| e.id.clone()).collect::<Vec<_>>();
+    let mut ws = common::connect(&relay).await?;
+    let newest = common::query(&mut ws, "two", json!({"authors": [author], "limit": 2})).await?;
+    assert_eq!(ids(&newest), ids(&events[..2]));
+    let none = common::query(&mut ws, "zero", json!({"authors": [author], "limit": 0})).await?;
+    assert!(none.is_empty());
+    // without a limit, the server maximum applies
+    let capped = common::query(&mut ws, "all", json!({"authors": [author]})).await?;
+    assert_eq!(ids(&capped), ids(&events[..3]));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn verified_author_accepted() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.verified_users.mode = config::VerifiedUsersMode::Enabled;
+    settings.verified_users.domain_whitelist = Some(vec!["example.com".to_owned()]);
+    let relay = common::start_relay_with_settings(settings.clone())?;
+    common::wait_for_healthy_relay(&relay).await?;
+    // seed the relay's verification table through a shared handle
+    settings.database.in_memory = true;
+    let (_, metrics) = server::create_metrics();
+    let handle = db::build_repo(&settings, metrics).await;
+    let verified = common::new_keypair();
+    let unverified = common::new_keypair();
+    for (keys, name) in [
+        (&verified, "alice@example.com"),
+        (&unverified, "bob@other.com"),
+    ] {
+        let content = json!({ "nip05": name }).to_string();
+        let metadata = common::signed_event(keys, 0, vec![], &content);
+        handle.write_event(&metadata).await?;
+        handle
+            .create_verification_record(&metadata.id, name)
+            .await?;
+    }
+    let mut ws = common::connect(&relay).await?;
+    let event = common::signed_event(&verified, 1, vec![], "verified");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], true);
+    // verified, but for a domain outside the whitelist
+    let event = common::signed_event(&unverified, 1, vec![], "wrong domain");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("blocked:"));
+    // no verification record at all
+    let event = common::signed_event(&common::new_keypair(), 1, vec![], "unknown");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3]
+        .as_str()
+        .unwrap()
+        .contains("NIP-05 verification needed"));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn unverified_author_rejected() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.verified_users.mode = config::VerifiedUsersMode::Enabled;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let mut ws = common::connect(&relay).await?;
+    let event = common::signed_event(&keys, 1, vec![], "not verified");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3]
+        .as_str()
+        .unwrap()
+        .contains("NIP-05 verification needed"));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn global_connection_limit() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.max_connections = Some(1);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    // the first connection takes the only slot
+    let mut first = common::connect(&relay).await?;
+    common::query(&mut first, "s", json!({"ids": ["ff".repeat(32)]})).await?;
+    // the next connection is refused
+    let mut second = common::connect(&relay).await?;
+    let notice = common::next_json(&mut second).await?;
+    assert_eq!(notice[0], "NOTICE");
+    assert!(notice[1].as_str().unwrap().starts_with("overloaded"));
+    // disconnecting frees the slot
+    first.close(None).await?;
+    let mut admitted = false;
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let mut third = common::connect(&relay).await?;
+        if common::query(&mut third, "s", json!({"ids": ["ff".repeat(32)]}))
+            .await
+            .is_ok()
+        {
+            admitted = true;
+            break;
+        }
+    }
+    assert!(admitted);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn connections_per_ip_limited() -> Result<()> {
+    const XFF: &str = "x-forwarded-for";
+    let mut settings = config::Settings::default();
+    settings.limits.max_connections_per_ip = Some(1);
+    settings.network.remote_ip_header = Some(XFF.to_owned());
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let alive = json!({"ids": ["ff".repeat(32)]});
+    let mut first = common::connect_with_headers(&relay, &[(XFF, "10.0.0.1")]).await?;
+    common::query(&mut first, "s", alive.clone()).await?;
+    // the proxy-added (last) address is the one counted
+    let mut second = common::connect_with_headers(&relay, &[(XFF, "192.0.2.7, 10.0.0.1")]).await?;
+    let notice = common::next_json(&mut second).await?;
+    assert_eq!(notice[0], "NOTICE");
+    assert!(notice[1].as_str().unwrap().starts_with("rate-limited:"));
+    match second.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.code, CloseCode::Policy),
+        other => panic!("expected a close frame, got {other:?}"),
+    }
+    // other addresses are unaffected
+    let mut other = common::connect_with_headers(&relay, &[(XFF, "10.0.0.2")]).await?;
+    common::query(&mut other, "s", alive.clone()).await?;
+    // and disconnecting frees the address's slot
+    first.close(None).await?;
+    let mut admitted = false;
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let mut again = common::connect_with_headers(&relay, &[(XFF, "10.0.0.1")]).await?;
+        if common::query(&mut again, "s", alive.clone()).await.is_ok() {
+            admitted = true;
+            break;
+        }
+    }
+    assert!(admitted);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn revoked_pubkey_rejected() -> Result<()> {
+    let revoked = common::new_keypair();
+    let trusted = common::new_keypair();
+    let revoked_event = common::signed_event(&revoked, 1, vec![], "leaked key");
+    let mut settings = config::Settings::default();
+    settings.authorization.revoked_pubkeys = Some(vec![revoked_event.pubkey.clone()]);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    // a valid event from a revoked key is refused, with a distinct reason
+    let ok = common::publish(&mut ws, &revoked_event).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().contains("revoked"));
+    // other keys are unaffected
+    let ok = common::publish(&mut ws, &common::signed_event(&trusted, 1, vec![], "hi")).await?;
+    assert_eq!(ok[2], true);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn pubkey_whitelist_and_blacklist() -> Result<()> {
+    let pubkey = |keys| common::signed_event(keys, 1, vec![], "").pubkey;
+    let (allowed, delegator, denied, stranger) = (
+        common::new_keypair(),
+        common::new_keypair(),
+        common::new_keypair(),
+        common::new_keypair(),
+    );
+    let mut settings = config::Settings::default();
+    settings.authorization.pubkey_whitelist =
+        Some(vec![pubkey(&allowed), pubkey(&delegator), pubkey(&denied)]);
+    settings.authorization.pubkey_blacklist = Some(vec![pubkey(&denied)]);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let tag = common::delegation_tag(&delegator, &stranger, "kind=1");
+    for (event, accepted) in [
+        // whitelisted authors and delegators may publish
+        (common::signed_event(&allowed, 1, vec![], "listed"), true),
+        (
+            common::signed_event(&stranger, 1, vec![tag], "delegated"),
+            true,
+        ),
+        // others may not
+        (
+            common::signed_event(&stranger, 1, vec![], "unlisted"),
+            false,
+        ),
+        // and the blacklist wins over the whitelist
+        (
+            common::signed_event(&denied, 1, vec![], "blacklisted"),
+            false,
+        ),
+    ] {
+        let ok = common::publish(&mut ws, &event).await?;
+        assert_eq!(ok[2], accepted, "{}", event.content);
+        if !accepted {
+            assert_eq!(
+                ok[3],
+                "blocked: pubkey is not allowed to publish to this relay"
+            );
+        }
+    }
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn over_projected_subscription_refused() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.max_projected_results = Some(3);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let mut author = String::new();
+    for i in 0..5 {
+        let event = common::signed_event(&keys, 1, vec![], &format!("note {i}"));
+        author = event.pubkey.clone();
+        common::publish(&mut ws, &event).await?;
+    }
+    // a small REQ proceeds
+    let events = common::query(&mut ws, "small", json!({"authors": [author], "limit": 2})).await?;
+    assert_eq!(events.len(), 2);
+    // an over-projected REQ is refused before any results are sent
+    common::send_json(&mut ws, &json!(["REQ", "big", {"authors": [author]}])).await?;
+    let msg = common::next_json(&mut ws).await?;
+    assert_eq!(msg[0], "CLOSED");
+    assert_eq!(msg[1], "big");
+    assert!(msg[2].as_str().unwrap().starts_with("too-large:"));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+/// A maintenance window from `start` to `end` seconds relative to now.
+fn maintenance_window(start: i64, end: i64, reject_reads: bool) -> config::MaintenanceWindow {
+    let at = |offset: i64| (chrono::Utc::now() + chrono::Duration::seconds(offset)).to_rfc3339();
+    config::MaintenanceWindow {
+        start: at(start),
+        end: at(end),
+        reject_reads,
+    }
+}
+
+#[tokio::test]
+async fn writes_rejected_during_maintenance() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.maintenance.windows = vec![maintenance_window(-60, 3600, false)];
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let event = common::signed_event(&keys, 1, vec![], "during maintenance");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], false);
+    let msg = ok[3].as_str().unwrap();
+    assert!(msg.starts_with("error:"));
+    assert!(msg.contains("maintenance") && msg.contains("retry after"));
+    // reads are still served
+    let events = common::query(&mut ws, "m1", json!({"authors": [event.pubkey]})).await?;
+    assert!(events.is_empty());
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn reads_rejected_during_maintenance() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.maintenance.windows = vec![maintenance_window(-60, 3600, true)];
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    common::send_json(&mut ws, &json!(["REQ", "m2", {"kinds": [1]}])).await?;
+    let msg = common::next_json(&mut ws).await?;
+    assert_eq!(msg[0], "CLOSED");
+    assert_eq!(msg[1], "m2");
+    assert!(msg[2].as_str().unwrap().contains("maintenance"));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn writes_accepted_outside_maintenance() -> Result<()> {
+    let mut settings = config::Settings::default();
+    // one window has ended, the other has not started
+    settings.maintenance.windows = vec![
+        maintenance_window(-3600, -60, true),
+        maintenance_window(3600, 7200, true),
+    ];
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let event = common::signed_event(&keys, 1, vec![], "after maintenance");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], true);
+    let events = common::query(&mut ws, "m3", json!({"authors": [event.pubkey]})).await?;
+    assert_eq!(events.len(), 1);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn writes_rejected_when_disk_low() -> Result<()> {
+    let mut settings = config::Settings::default();
+    // no volume has this much free space
+    settings.database.min_free_disk_mb = Some(u64::MAX);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    // clients are told on connecting
+    let notice = common::next_json(&mut ws).await?;
+    assert_eq!(notice[0], "NOTICE");
+    assert!(notice[1].as_str().unwrap().contains("read-only"));
+    let keys = common::new_keypair();
+    let event = common::signed_event(&keys, 1, vec![], "disk full");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], false);
+    let msg = ok[3].as_str().unwrap();
+    assert!(msg.starts_with("error:") && msg.contains("low on space"));
+    // reads are still served
+    let events = common::query(&mut ws, "d1", json!({"authors": [event.pubkey]})).await?;
+    assert!(events.is_empty());
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn expired_events_rejected_and_not_served() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let now = unix_time();
+    let expiring = |at: u64, content: &str| {
+        common::signed_event(
+            &keys,
+            1,
+            vec![vec!["expiration".to_owned(), at.to_string()]],
+            content,
+        )
+    };
+    // already expired
+    let ok = common::publish(&mut ws, &expiring(now - 10, "stale")).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+    // a malformed expiration never expires
+    let malformed = common::signed_event(
+        &keys,
+        1,
+        vec![vec!["expiration".to_owned(), "soon".to_owned()]],
+        "malformed",
+    );
+    assert_eq!(common::publish(&mut ws, &malformed).await?[2], true);
+    // expires shortly after it is stored
+    let brief = expiring(now + 2, "brief");
+    assert_eq!(common::publish(&mut ws, &brief).await?[2], true);
+    let filter = json!({"authors": [brief.pubkey]});
+    let events = common::query(&mut ws, "x1", filter.clone()).await?;
+    assert_eq!(events.len(), 2);
+    tokio::time::sleep(Duration::from_secs(3)).await;
+    let events = common::query(&mut ws, "x2", filter).await?;
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].id, malformed.id);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn relay_announcement_stored_and_broadcast() -> Result<()> {
+    let keys = common::new_keypair();
+    let relay_pubkey = secp256k1::XOnlyPublicKey::from_keypair(&keys).to_hex();
+    let mut settings = config::Settings::default();
+    settings.info.relay_url = Some("wss://relay.example.com".to_owned());
+    settings.announcement.secret_key = Some(keys.display_secret().to_string());
+    settings.announcement.interval_seconds = 1;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let filter = json!({"authors": [relay_pubkey], "kinds": [10002]});
+    // the first announcement is stored
+    let mut stored = vec![];
+    for _ in 0..50 {
+        let mut ws = common::connect(&relay).await?;
+        stored = common::query(&mut ws, "a1", filter.clone()).await?;
+        if !stored.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert_eq!(stored.len(), 1);
+    assert!(stored[0].validate().is_ok());
+    assert_eq!(stored[0].tags, vec![vec!["r", "wss://relay.example.com"]]);
+    // later announcements reach live subscribers
+    let mut ws = common::connect(&relay).await?;
+    common::query(&mut ws, "a2", filter).await?;
+    let msg = tokio::time::timeout(Duration::from_secs(5), common::next_json(&mut ws)).await??;
+    assert_eq!(msg[0], "EVENT");
+    assert_eq!(msg[1], "a2");
+    assert_eq!(msg[2]["pubkey"], relay_pubkey);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn param_replaceable_without_d_tag() -> Result<()> {
+    let keys = common::new_keypair();
+    let untagged = common::signed_event(&keys, 30000, vec![], "no d tag");
+    let tagged = common::signed_event(&keys, 30001, vec![vec!["d".into(), "x".into()]], "d tag");
+    for required in [false, true] {
+        let mut settings = config::Settings::default();
+        settings.options.require_d_tag_for_parameterized = required;
+        let relay = common::start_relay_with_settings(settings)?;
+        common::wait_for_healthy_relay(&relay).await?;
+        let mut ws = common::connect(&relay).await?;
+        let ok = common::publish(&mut ws, &untagged).await?;
+        assert_eq!(ok[2], !required);
+        if required {
+            assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+        }
+        let ok = common::publish(&mut ws, &tagged).await?;
+        assert_eq!(ok[2], true);
+        let _res = relay.shutdown_tx.send(());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn address_filters_return_current_event() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.options.resolve_address_filters = true;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let d_tag = vec![vec!["d".to_owned(), "slug".to_owned()]];
+    let old = common::signed_event_at(&keys, 30023, d_tag.clone(), "draft", 1_000);
+    let current = common::signed_event_at(&keys, 30023, d_tag, "final", 2_000);
+    let address = format!("30023:{}:slug", current.pubkey);
+    let atag = vec![vec!["a".to_owned(), address.clone()]];
+    let comment = common::signed_event_at(&keys, 1, atag, "nice article", 3_000);
+    for e in [&old, &current, &comment] {
+        assert_eq!(common::publish(&mut ws, e).await?[2], true);
+    }
+    let mut ws = common::connect(&relay).await?;
+    let ids = |events: Vec<Event>| events.into_iter().map(|e| e.id).collect::<Vec<_>>();
+    // the events referencing the address, and the addressed event
+    let found = common::query(&mut ws, "a", json!({ "#a": [address] })).await?;
+    assert_eq!(ids(found), vec![comment.id.clone(), current.id.clone()]);
+    let filter = json!({ "kinds": [30023], "#a": [address] });
+    let found = common::query(&mut ws, "article", filter).await?;
+    assert_eq!(ids(found), vec![current.id.clone()]);
+    // addresses with no event return nothing
+    let missing = format!("30023:{}:missing", current.pubkey);
+    let found = common::query(&mut ws, "missing", json!({ "#a": [missing] })).await?;
+    assert!(found.is_empty());
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn unindexed_kinds_stored_without_tags() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.options.unindexed_kinds = vec![1_707];
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let target = "1b".repeat(32);
+    let etag = vec![vec!["e".to_owned(), target.clone()]];
+    let reaction = common::signed_event(&keys, 1_707, etag.clone(), "+");
+    assert_eq!(common::publish(&mut ws, &reaction).await?[2], true);
+    let note = common::signed_event(&keys, 1_708, etag, "reply");
+    assert_eq!(common::publish(&mut ws, &note).await?[2], true);
+    let ids = |events: Vec<Event>| events.into_iter().map(|e| e.id).collect::<Vec<_>>();
+    // retrievable by id and by author
+    let by_id = common::query(&mut ws, "id", json!({"ids": [reaction.id]})).await?;
+    assert_eq!(ids(by_id), vec![reaction.id.clone()]);
+    let filter = json!({"authors": [reaction.pubkey], "kinds": [1_707]});
+    let by_author = common::query(&mut ws, "author", filter).await?;
+    assert_eq!(ids(by_author), vec![reaction.id.clone()]);
+    // but not by tag
+    let by_tag = common::query(&mut ws, "tag", json!({"#e": [target]})).await?;
+    assert_eq!(ids(by_tag), vec![note.id.clone()]);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn welcome_notice_on_connect() -> Result<()> {
+    const WELCOME: &str = "Welcome! Please read our terms of service.";
+    for nip42_auth in [false, true] {
+        let mut settings = config::Settings::default();
+        settings.info.welcome_message = Some(WELCOME.to_owned());
+        settings.authorization.nip42_auth = nip42_auth;
+        let relay = common::start_relay_with_settings(settings)?;
+        common::wait_for_healthy_relay(&relay).await?;
+        let mut ws = common::connect(&relay).await?;
+        // the AUTH challenge, if any, still comes first
+        if nip42_auth {
+            assert_eq!(common::next_json(&mut ws).await?[0], "AUTH");
+        }
+        assert_eq!(
+            common::next_json(&mut ws).await?,
+            json!(["NOTICE", WELCOME])
+        );
+        let _res = relay.shutdown_tx.send(());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn eose_sent_without_matches() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let subs = [
+        ("none", json!({"authors": ["a".repeat(64)]})),
+        // a subscription that asks for no stored events at all
+        ("zero", json!({"kinds": [1], "limit": 0})),
+        ("last", json!({"ids": ["b".repeat(64)]})),
+    ];
+    // exactly one EOSE each, so the next message is for the next REQ
+    for (id, filter) in subs {
+        common::send_json(&mut ws, &json!(["REQ", id, filter])).await?;
+        assert_eq!(common::next_json(&mut ws).await?, json!(["EOSE", id]));
+    }
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+/// Answer the relay's AUTH challenge for the relay at wss://relay.example.com
+async fn authenticate(ws: &mut common::WsStream, keys: &secp256k1::KeyPair) -> Result<()> {
+    let challenge = common::next_json(ws).await?;
+    assert_eq!(challenge[0], "AUTH");
+    let tags = vec![
+        vec![
+            "challenge".to_owned(),
+            challenge[1].as_str().unwrap().to_owned(),
+        ],
+        vec!["relay".to_owned(), "wss://relay.example.com".to_owned()],
+    ];
+    let auth = common::signed_event(keys, 22242, tags, "");
+    common::send_json(ws, &json!(["AUTH", auth])).await
+}
+
+#[tokio::test]
+async fn auth_requires_secure_connection() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.info.relay_url = Some("wss://relay.example.com".to_owned());
+    settings.network.forwarded_proto_header = Some("x-forwarded-proto".to_owned());
+    settings.authorization.nip42_auth = true;
+    settings.authorization.nip42_dms = true;
+    settings.authorization.nip42_require_secure = true;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let pubkey = common::signed_event(&keys, 1, vec![], "").pubkey;
+    // a DM only an authenticated recipient may read
+    let mut ws = common::connect(&relay).await?;
+    assert_eq!(common::next_json(&mut ws).await?[0], "AUTH");
+    let ptag = vec![vec!["p".to_owned(), pubkey.clone()]];
+    let dm = common::signed_event(&common::new_keypair(), 4, ptag, "secret");
+    assert_eq!(common::publish(&mut ws, &dm).await?[2], true);
+    let filter = json!({"kinds": [4], "#p": [pubkey]});
+    // plain ws:// connections are refused
+    let mut insecure = common::connect(&relay).await?;
+    authenticate(&mut insecure, &keys).await?;
+    let ok = common::next_json(&mut insecure).await?;
+    assert_eq!(ok[0], "OK");
+    assert_eq!(ok[2], false);
+    assert_eq!(
+        ok[3],
+        "restricted: authentication requires a secure (wss://) connection"
+    );
+    assert!(common::query(&mut insecure, "dm", filter.clone())
+        .await?
+        .is_empty());
+    // connections the proxy reports as secure authenticate
+    let mut secure =
+        common::connect_with_headers(&relay, &[("x-forwarded-proto", "https")]).await?;
+    authenticate(&mut secure, &keys).await?;
+    let dms = common::query(&mut secure, "dm", filter).await?;
+    assert_eq!(dms.len(), 1);
+    assert_eq!(dms[0].id, dm.id);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn authenticated_subscriptions_resume_after_reconnect() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.info.relay_url = Some("wss://relay.example.com".to_owned());
+    settings.authorization.nip42_auth = true;
+    settings.options.sequence_cursors = true;
+    settings.options.resume_subscriptions = true;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let author = common::new_keypair();
+    let kind = 1_801;
+    let publish = |content: &'static str| {
+        let relay = &relay;
+        let author = &author;
+        async move {
+            let mut ws = common::connect(relay).await?;
+            assert_eq!(common::next_json(&mut ws).await?[0], "AUTH");
+            let e = common::signed_event(author, kind, vec![], content);
+            assert_eq!(common::publish(&mut ws, &e).await?[2], true);
+            anyhow::Ok(e.id)
+        }
+    };
+    let first = vec![publish("one").await?, publish("two").await?];
+    let feed = json!(["REQ", "feed", {"kinds": [kind]}]);
+    // the first session gets everything stored
+    let mut ws = common::connect(&relay).await?;
+    authenticate(&mut ws, &keys).await?;
+    common::send_json(&mut ws, &feed).await?;
+    assert_eq!(seq_page(&mut ws, 0).await?.0, first);
+    drop(ws);
+    let later = publish("three").await?;
+    // after reconnecting, only what was stored since
+    let mut ws = common::connect(&relay).await?;
+    authenticate(&mut ws, &keys).await?;
+    common::send_json(&mut ws, &feed).await?;
+    assert_eq!(seq_page(&mut ws, 0).await?.0, vec![later]);
+    // other subscription ids start from the beginning
+    common::send_json(&mut ws, &json!(["REQ", "other", {"kinds": [kind]}])).await?;
+    assert_eq!(seq_page(&mut ws, 0).await?.0.len(), 3);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn duplicate_d_tags_rejected() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.options.reject_duplicate_d_tags = true;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let d = |v: &str| vec!["d".to_owned(), v.to_owned()];
+    let single = common::signed_event(&keys, 30_001, vec![d("one")], "");
+    assert_eq!(common::publish(&mut ws, &single).await?[2], true);
+    let multiple = common::signed_event(&keys, 30_001, vec![d("one"), d("two")], "");
+    let ok = common::publish(&mut ws, &multiple).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn empty_tags_rejected() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.options.reject_empty_tags = true;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let tags = vec![vec!["t".to_owned(), "nostr".to_owned()]];
+    let normal = common::signed_event(&keys, 1, tags.clone(), "tagged");
+    assert_eq!(common::publish(&mut ws, &normal).await?[2], true);
+    let mut with_empty = tags;
+    with_empty.push(vec![]);
+    let empty = common::signed_event(&keys, 1, with_empty, "empty tag");
+    let ok = common::publish(&mut ws, &empty).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn ephemeral_events_delivered_not_stored() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let ephemeral = common::signed_event(&keys, 20_001, vec![], "typing...");
+    let filter = json!({"authors": [ephemeral.pubkey], "kinds": [20_001]});
+    // a live subscriber receives the event
+    let mut subscriber = common::connect(&relay).await?;
+    assert!(common::query(&mut subscriber, "live", filter.clone())
+        .await?
+        .is_empty());
+    let mut ws = common::connect(&relay).await?;
+    assert_eq!(common::publish(&mut ws, &ephemeral).await?[2], true);
+    let msg = common::next_json(&mut subscriber).await?;
+    assert_eq!(msg[0], "EVENT");
+    assert_eq!(msg[1], "live");
+    assert_eq!(msg[2]["id"], ephemeral.id);
+    // but it is not stored for later queries
+    let mut later = common::connect(&relay).await?;
+    assert!(common::query(&mut later, "stored", filter)
+        .await?
+        .is_empty());
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn subscriptions_per_connection_limited() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.max_subscriptions_per_connection = 3;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    for id in ["a", "b", "c"] {
+        common::query(&mut ws, id, json!({"kinds": [1]})).await?;
+    }
+    // reusing an id replaces that subscription
+    common::query(&mut ws, "a", json!({"kinds": [7]})).await?;
+    common::send_json(&mut ws, &json!(["REQ", "d", {"kinds": [1]}])).await?;
+    let notice = common::next_json(&mut ws).await?;
+    assert_eq!(notice[0], "NOTICE");
+    assert!(notice[1]
+        .as_str()
+        .unwrap()
+        .contains("at most 3 subscriptions"));
+    // closing one makes room
+    common::send_json(&mut ws, &json!(["CLOSE", "b"])).await?;
+    common::query(&mut ws, "d", json!({"kinds": [1]})).await?;
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn events_rate_limited_per_connection() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.event_rate_limit_per_min = Some(1);
+    settings.limits.event_burst = Some(2);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let mut ws = common::connect(&relay).await?;
+    for i in 0..2 {
+        let event = common::signed_event(&keys, 1, vec![], &format!("note {i}"));
+        assert_eq!(common::publish(&mut ws, &event).await?[2], true);
+    }
+    // even a badly signed event is refused by the limit, not its signature
+    let mut flood = common::signed_event(&keys, 1, vec![], "flood");
+    flood.sig = "00".repeat(64);
+    let ok = common::publish(&mut ws, &flood).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("rate-limited:"));
+    // other connections have their own allowance
+    let mut other = common::connect(&relay).await?;
+    let event = common::signed_event(&keys, 1, vec![], "elsewhere");
+    assert_eq!(common::publish(&mut other, &event).await?[2], true);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn event_decisions_audited() -> Result<()> {
+    let dir = std::env::temp_dir().join(format!("relay-audit-{}", std::process::id()));
+    let mut settings = config::Settings::default();
+    settings.logging.audit_folder_path = Some(dir.to_string_lossy().into_owned());
+    settings.logging.audit_file_prefix = Some("audit".to_owned());
+    settings.limits.event_kind_blacklist = Some(vec![4]);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let accepted = common::signed_event(&keys, 1, vec![], "hello");
+    let rejected = common::signed_event(&keys, 4, vec![], "secret");
+    let mut ws = common::connect(&relay).await?;
+    assert_eq!(common::publish(&mut ws, &accepted).await?[2], true);
+    assert_eq!(common::publish(&mut ws, &rejected).await?[2], false);
+    let _res = relay.shutdown_tx.send(());
+    // one record per line, in the day's file
+    let mut records = vec![];
+    for file in std::fs::read_dir(&dir)? {
+        let text = std::fs::read_to_string(file?.path())?;
+        for line in text.lines() {
+            records.push(serde_json::from_str::<serde_json::Value>(line)?);
+        }
+    }
+    std::fs::remove_dir_all(&dir).ok();
+    assert_eq!(records.len(), 2);
+    for (record, event, decision, status) in [
+        (&records[0], &accepted, "accepted", "saved"),
+        (&records[1], &rejected, "rejected", "blocked"),
+    ] {
+        assert_eq!(record["id"], event.id);
+        assert_eq!(record["pubkey"], event.pubkey);
+        assert_eq!(record["kind"], event.kind);
+        assert_eq!(record["ip"], "127.0.0.1");
+        assert_eq!(record["decision"], decision);
+        assert_eq!(record["status"], status);
+    }
+    assert!(records[1]["reason"]
+        .as_str()
+        .unwrap()
+        .starts_with("blocked:"));
+    Ok(())
+}
+
+#[tokio::test]
+async fn subscription_creation_limited_globally() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.subscription_creation_rate = Some(1);
+    settings.limits.subscription_creation_burst = Some(2);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut first = common::connect(&relay).await?;
+    let mut second = common::connect(&relay).await?;
+    // the burst is shared between connections
+    common::query(&mut first, "a", json!({"kinds": [1]})).await?;
+    common::query(&mut second, "b", json!({"kinds": [1]})).await?;
+    for (ws, id) in [(&mut first, "c"), (&mut second, "d")] {
+        common::send_json(ws, &json!(["REQ", id, {"kinds": [1]}])).await?;
+        let msg = common::next_json(ws).await?;
+        assert_eq!(msg[0], "CLOSED");
+        assert_eq!(msg[1], id);
+        assert!(msg[2].as_str().unwrap().starts_with("rate-limited:"));
+    }
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn backdated_events_rejected() -> Result<()> {
+    let keys = common::new_keypair();
+    let year_ago = unix_time() - 365 * 24 * 60 * 60;
+    let backdated = common::signed_event_at(&keys, 1, vec![], "last year", year_ago);
+    for limit in [None, Some(3600)] {
+        let mut settings = config::Settings::default();
+        settings.options.reject_past_seconds = limit;
+        let relay = common::start_relay_with_settings(settings)?;
+        common::wait_for_healthy_relay(&relay).await?;
+        let mut ws = common::connect(&relay).await?;
+        let ok = common::publish(&mut ws, &backdated).await?;
+        if limit.is_some() {
+            assert_eq!(ok[2], false);
+            let msg = ok[3].as_str().unwrap();
+            assert!(msg.starts_with("invalid:"));
+            assert!(msg.contains("-3600sec"));
+            // recent events are still accepted
+            let recent = common::signed_event(&keys, 1, vec![], "now");
+            assert_eq!(common::publish(&mut ws, &recent).await?[2], true);
+        } else {
+            // without a limit, any age is accepted
+            assert_eq!(ok[2], true);
+        }
+        let _res = relay.shutdown_tx.send(());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn increasing_timestamps_required() -> Result<()> {
+    let keys = common::new_keypair();
+    let now = unix_time();
+    for required in [false, true] {
+        let mut settings = config::Settings::default();
+        settings.options.require_increasing_created_at = required;
+        let relay = common::start_relay_with_settings(settings)?;
+        common::wait_for_healthy_relay(&relay).await?;
+        let mut ws = common::connect(&relay).await?;
+        let at = |created_at: u64| common::signed_event_at(&keys, 1, vec![], "note", created_at);
+        assert_eq!(common::publish(&mut ws, &at(now - 20)).await?[2], true);
+        assert_eq!(common::publish(&mut ws, &at(now - 10)).await?[2], true);
+        // backdated, and same-second, events
+        for created_at in [now - 15, now - 10] {
+            let event = common::signed_event_at(&keys, 1, vec![], "again", created_at);
+            let ok = common::publish(&mut ws, &event).await?;
+            assert_eq!(ok[2], !required);
+            if required {
+                assert!(ok[3].as_str().unwrap().starts_with("invalid: created_at"));
+            }
+        }
+        // other authors have their own latest timestamp
+        let other = common::signed_event_at(&common::new_keypair(), 1, vec![], "hi", now - 30);
+        assert_eq!(common::publish(&mut ws, &other).await?[2], true);
+        let _res = relay.shutdown_tx.send(());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn placeholder_timestamps_rejected() -> Result<()> {
+    let keys = common::new_keypair();
+    let placeholders = [
+        common::signed_event_at(&keys, 1, vec![], "epoch", 0),
+        common::signed_event_at(&keys, 1, vec![], "one", 1),
+    ];
+    let normal = [
+        common::signed_event_at(&keys, 1, vec![], "two", 2),
+        common::signed_event(&keys, 1, vec![], "now"),
+    ];
+    for enabled in [false, true] {
+        let mut settings = config::Settings::default();
+        if enabled {
+            settings.options.reject_created_at = vec![0, 1];
+        }
+        let relay = common::start_relay_with_settings(settings)?;
+        common::wait_for_healthy_relay(&relay).await?;
+        let mut ws = common::connect(&relay).await?;
+        for e in &placeholders {
+            let ok = common::publish(&mut ws, e).await?;
+            assert_eq!(ok[2], !enabled);
+            if enabled {
+                assert_eq!(
+                    ok[3],
+                    "invalid: created_at is a placeholder, not a real timestamp"
+                );
+            }
+        }
+        for e in &normal {
+            assert_eq!(common::publish(&mut ws, e).await?[2], true);
+        }
+        let _res = relay.shutdown_tx.send(());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn tag_count_limited_before_signature_check() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.max_tag_count = Some(1);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let tags: Vec<Vec<String>> = (0..100)
+        .map(|i| vec!["t".to_owned(), format!("topic{i}")])
+        .collect();
+    let mut many = common::signed_event(&keys, 1, tags, "many tags");
+    // the tag count is refused even though the signature is bad
+    many.sig = "0".repeat(128);
+    let ok = common::publish(&mut ws, &many).await?;
+    assert_eq!(ok[2], false);
+    assert_eq!(
+        ok[3],
+        "invalid: Events may not have more than 1 tags on this relay."
+    );
+    let one = vec![vec!["t".to_owned(), "topic".to_owned()]];
+    let single = common::signed_event(&keys, 1, one, "one tag");
+    assert_eq!(common::publish(&mut ws, &single).await?[2], true);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn required_tags_per_kind() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.options.kind_required_tags = vec![config::KindRequiredTags {
+        kind: 1,
+        tags: vec!["client".to_owned()],
+    }];
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let untagged = common::signed_event(&keys, 1, vec![], "no client");
+    let ok = common::publish(&mut ws, &untagged).await?;
+    assert_eq!(ok[2], false);
+    assert_eq!(
+        ok[3],
+        "invalid: events of this kind must have a \"client\" tag"
+    );
+    let client = vec!["client".to_owned(), "test-suite".to_owned()];
+    let tagged = common::signed_event(&keys, 1, vec![client], "with client");
+    assert_eq!(common::publish(&mut ws, &tagged).await?[2], true);
+    // kinds without requirements are unaffected
+    let reaction = common::signed_event(&keys, 7, vec![], "+");
+    assert_eq!(common::publish(&mut ws, &reaction).await?[2], true);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn rejections_sent_in_requested_language() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.options.reject_empty_tags = true;
+    settings.localization.translations = vec![config::Translation {
+        lang: "es".to_owned(),
+        message: "tags must have at least one element".to_owned(),
+        text: "las etiquetas deben tener al menos un elemento".to_owned(),
+    }];
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let mut by_header =
+        common::connect_with_headers(&relay, &[("accept-language", "es-MX,es;q=0.9")]).await?;
+    let mut by_query = common::connect_with_query(&relay, "lang=es").await?;
+    let mut english = common::connect(&relay).await?;
+    for (ws, expected) in [
+        (
+            &mut by_header,
+            "invalid: las etiquetas deben tener al menos un elemento",
+        ),
+        (
+            &mut by_query,
+            "invalid: las etiquetas deben tener al menos un elemento",
+        ),
+        (&mut english, "invalid: tags must have at least one element"),
+    ] {
+        let empty = common::signed_event(&keys, 1, vec![vec![]], "empty tag");
+        let ok = common::publish(ws, &empty).await?;
+        assert_eq!(ok[2], false);
+        assert_eq!(ok[3], expected);
+    }
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn event_batch_per_id_results() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let first = common::signed_event(&keys, 1, vec![], "batch one");
+    let second = common::signed_event(&keys, 1, vec![], "batch two");
+    let mut forged = common::signed_event(&keys, 1, vec![], "batch forged");
+    forged.content = "tampered".to_owned();
+    let batch = json!(["EVENT", [first, forged, second]]);
+    common::send_json(&mut ws, &batch).await?;
+    let mut results = std::collections::HashMap::new();
+    for _ in 0..3 {
+        let msg = common::next_json(&mut ws).await?;
+        assert_eq!(msg[0], "OK");
+        results.insert(
+            msg[1].as_str().unwrap().to_owned(),
+            msg[2].as_bool().unwrap(),
+        );
+    }
+    assert_eq!(results.get(&first.id), Some(&true));
+    assert_eq!(results.get(&second.id), Some(&true));
+    assert_eq!(results.get(&forged.id), Some(&false));
+    // only the valid events are stored
+    let mut ws = common::connect(&relay).await?;
+    let events = common::query(&mut ws, "b1", json!({"authors": [first.pubkey]})).await?;
+    let mut ids: Vec<String> = events.into_iter().map(|e| e.id).collect();
+    let mut expected = vec![first.id, second.id];
+    ids.sort();
+    expected.sort();
+    assert_eq!(ids, expected);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn extension_negotiation_reflects_settings() -> Result<()> {
+    let request = json!(["EXTENSIONS", ["batch-events", "relative-since"]]);
+    for enabled in [true, false] {
+        let mut settings = config::Settings::default();
+        settings.options.batch_events = enabled;
+        let relay = common::start_relay_with_settings(settings)?;
+        common::wait_for_healthy_relay(&relay).await?;
+        let mut ws = common::connect(&relay).await?;
+        common::send_json(&mut ws, &request).await?;
+        let msg = common::next_json(&mut ws).await?;
+        assert_eq!(msg[0], "EXTENSIONS");
+        if enabled {
+            assert_eq!(msg[1], json!(["batch-events"]));
+        } else {
+            assert_eq!(msg[1], json!([]));
+        }
+        let _res = relay.shutdown_tx.send(());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn deleted_event_tombstone() -> Result<()> {
+    let keys = common::new_keypair();
+    let note = common::signed_event(&keys, 1, vec![], "regrettable");
+    let deletion = common::signed_event(&keys, 5, vec![vec!["e".into(), note.id.clone()]], "");
+    for serve in [false, true] {
+        let mut settings = config::Settings::default();
+        settings.options.serve_tombstones = serve;
+        let relay = common::start_relay_with_settings(settings)?;
+        common::wait_for_healthy_relay(&relay).await?;
+        let mut ws = common::connect(&relay).await?;
+        common::publish(&mut ws, &note).await?;
+        common::publish(&mut ws, &deletion).await?;
+        let mut ws = common::connect(&relay).await?;
+        common::send_json(&mut ws, &json!(["REQ", "t1", {"ids": [note.id]}])).await?;
+        let msg = common::next_json(&mut ws).await?;
+        if serve {
+            assert_eq!(msg, json!(["DELETED", "t1", note.id, deletion.id]));
+            let msg = common::next_json(&mut ws).await?;
+            assert_eq!(msg[0], "EOSE");
+        } else {
+            // the deleted event is simply omitted
+            assert_eq!(msg[0], "EOSE");
+        }
+        let _res = relay.shutdown_tx.send(());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn replies_to_deleted_events() -> Result<()> {
+    use nostr_rs_relay::config::DeletedReferences;
+    let keys = common::new_keypair();
+    let replier = common::new_keypair();
+    let note = common::signed_event(&keys, 1, vec![], "regrettable");
+    let deletion = common::signed_event(&keys, 5, vec![vec!["e".into(), note.id.clone()]], "");
+    let kept = common::signed_event(&keys, 1, vec![], "still here");
+    for (policy, prefix) in [
+        (DeletedReferences::Accept, ""),
+        (DeletedReferences::Reject, "blocked:"),
+        (DeletedReferences::Flag, "quarantined:"),
+    ] {
+        let mut settings = config::Settings::default();
+        settings.options.deleted_references = policy;
+        let relay = common::start_relay_with_settings(settings)?;
+        common::wait_for_healthy_relay(&relay).await?;
+        let mut ws = common::connect(&relay).await?;
+        for event in [&note, &deletion, &kept] {
+            assert_eq!(common::publish(&mut ws, event).await?[2], true);
+        }
+        let e_tag = |id: &str| vec![vec!["e".to_owned(), id.to_owned()]];
+        let reply = common::signed_event(&replier, 1, e_tag(&note.id), "what did you say?");
+        let ok = common::publish(&mut ws, &reply).await?;
+        // held events are accepted from the client, just not stored yet
+        assert_eq!(ok[2], policy != DeletedReferences::Reject, "{policy:?}");
+        assert!(ok[3].as_str().unwrap().starts_with(prefix), "{policy:?}");
+        // replies to events that were not deleted are unaffected
+        let other = common::signed_event(&replier, 1, e_tag(&kept.id), "nice");
+        assert_eq!(common::publish(&mut ws, &other).await?[2], true);
+        let mut ws = common::connect(&relay).await?;
+        let stored = common::query(&mut ws, "r", json!({"ids": [reply.id]})).await?;
+        assert_eq!(
+            stored.len(),
+            usize::from(policy == DeletedReferences::Accept)
+        );
+        let _res = relay.shutdown_tx.send(());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn deletion_only_by_author() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let (alice, mallory) = (common::new_keypair(), common::new_keypair());
+    let note = common::signed_event(&alice, 1, vec![], "mine");
+    let e_tag = vec!["e".to_owned(), note.id.clone()];
+    common::publish(&mut ws, &note).await?;
+    // read on a connection that did not see the note broadcast
+    let mut reader = common::connect(&relay).await?;
+    let ids = json!({"ids": [note.id]});
+    // a deletion by someone else leaves the event in place
+    let forged = common::signed_event(&mallory, 5, vec![e_tag.clone()], "");
+    common::publish(&mut ws, &forged).await?;
+    assert_eq!(
+        common::query(&mut reader, "d1", ids.clone()).await?.len(),
+        1
+    );
+    // the author's own deletion removes it, and repeating it is harmless
+    let deletion = common::signed_event(&alice, 5, vec![e_tag.clone()], "");
+    assert_eq!(common::publish(&mut ws, &deletion).await?[2], true);
+    let again = common::signed_event(&alice, 5, vec![e_tag], "again");
+    assert_eq!(common::publish(&mut ws, &again).await?[2], true);
+    assert!(common::query(&mut reader, "d2", ids.clone())
+        .await?
+        .is_empty());
+    // and publishing the event again does not bring it back
+    common::publish(&mut ws, &note).await?;
+    assert!(common::query(&mut reader, "d3", ids).await?.is_empty());
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn tag_and_filter_opt_in() -> Result<()> {
+    let keys = common::new_keypair();
+    let topic = |v: &str| vec!["t".to_owned(), v.to_owned()];
+    let both = common::signed_event(&keys, 1, vec![topic("and-a"), topic("and-b")], "both");
+    let only_a = common::signed_event(&keys, 1, vec![topic("and-a")], "only a");
+    let only_b = common::signed_event(&keys, 1, vec![topic("and-b")], "only b");
+    for enabled in [false, true] {
+        let mut settings = config::Settings::default();
+        settings.options.tag_and_filters = enabled;
+        let relay = common::start_relay_with_settings(settings)?;
+        common::wait_for_healthy_relay(&relay).await?;
+        let mut ws = common::connect(&relay).await?;
+        for e in [&both, &only_a, &only_b] {
+            common::publish(&mut ws, e).await?;
+        }
+        let mut ws = common::connect(&relay).await?;
+        // OR remains the default
+        let events = common::query(&mut ws, "or", json!({"#t": ["and-a", "and-b"]})).await?;
+        assert_eq!(events.len(), 3);
+        common::send_json(&mut ws, &json!(["REQ", "and", {"&t": ["and-a", "and-b"]}])).await?;
+        let msg = common::next_json(&mut ws).await?;
+        if enabled {
+            assert_eq!(msg[0], "EVENT");
+            assert_eq!(msg[2]["id"], both.id);
+            assert_eq!(common::next_json(&mut ws).await?[0], "EOSE");
+        } else {
+            assert_eq!(msg[0], "CLOSED");
+            assert!(msg[2].as_str().unwrap().starts_with("unsupported:"));
+        }
+        let _res = relay.shutdown_tx.send(());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn slow_subscriber_notified_of_dropped_events() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.notify_dropped_events = true;
+    // a single-slot broadcast buffer, and a writer queue that makes
+    // the connection wait on each event in a batch, so broadcasts
+    // pile up while the connection is busy submitting.
+    settings.limits.broadcast_buffer = 1;
+    settings.limits.event_persist_buffer = 1;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let events: Vec<_> = (0..20)
+        .map(|i| common::signed_event(&keys, 1, vec![], &format!("flood {i}")))
+        .collect();
+    let live = json!(["REQ", "live", {"authors": [events[0].pubkey], "limit": 0}]);
+    common::send_json(&mut ws, &live).await?;
+    common::send_json(&mut ws, &json!(["EVENT", events])).await?;
+    let notice = loop {
+        let msg =
+            tokio::time::timeout(Duration::from_secs(5), common::next_json(&mut ws)).await??;
+        if msg[0] == "NOTICE" {
+            break msg[1].as_str().unwrap().to_owned();
+        }
+    };
+    assert!(notice.starts_with("dropped "));
+    let dropped: u64 = notice.split(' ').nth(1).unwrap().parse()?;
+    assert!(dropped > 0);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn insufficient_pow_rejected_with_pow_prefix() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.min_pow_difficulty = Some(24);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let mut event = common::signed_event(&common::new_keypair(), 1, vec![], "low effort");
+    // regenerate in the (unlikely) case the random id meets the target
+    while event.pow_difficulty() >= 24 {
+        event = common::signed_event(&common::new_keypair(), 1, vec![], "low effort");
+    }
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], false);
+    let expected = format!(
+        "pow: difficulty 24 required; got {}",
+        event.pow_difficulty()
+    );
+    assert_eq!(ok[3], expected.as_str());
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn pow_target_commitment_required() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.min_pow_difficulty = Some(6);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    // mine an event with at least 6 leading zero bits
+    let mine = |nonce_tag: Vec<&str>| {
+        (0u64..)
+            .map(|n| {
+                let mut tag: Vec<String> = nonce_tag.iter().map(|s| (*s).to_owned()).collect();
+                tag.insert(1, n.to_string());
+                common::signed_event(&keys, 1, vec![tag], "mined")
+            })
+            .find(|e| e.pow_difficulty() >= 6)
+            .unwrap()
+    };
+    // enough work, but the target is lower than required
+    let ok = common::publish(&mut ws, &mine(vec!["nonce", "2"])).await?;
+    assert_eq!(ok[2], false);
+    assert_eq!(
+        ok[3],
+        "pow: committed target 2 is below required difficulty 6"
+    );
+    // enough work, and no target
+    let ok = common::publish(&mut ws, &mine(vec!["nonce"])).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("pow: nonce tag"));
+    // enough work, committed in advance
+    let ok = common::publish(&mut ws, &mine(vec!["nonce", "6"])).await?;
+    assert_eq!(ok[2], true);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn relay_hints_validated() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.options.validate_relay_hints = true;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let hinted = |hint: &str| {
+        let tags = vec![vec!["p".to_owned(), "0".repeat(64), hint.to_owned()]];
+        common::signed_event(&keys, 1, tags, hint)
+    };
+    let valid = hinted("wss://relay.example.com/");
+    assert_eq!(common::publish(&mut ws, &valid).await?[2], true);
+    let schemeless = hinted("relay.example.com");
+    assert_eq!(common::publish(&mut ws, &schemeless).await?[2], true);
+    let garbage = hinted("}} not a relay {{");
+    let ok = common::publish(&mut ws, &garbage).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+    // stored events keep the hints exactly as signed
+    let pubkey = valid.pubkey.clone();
+    let stored = common::query(&mut ws, "hints", json!({"authors": [pubkey]})).await?;
+    assert!(stored.iter().any(|e| e.tags[0][2] == "relay.example.com"));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn req_churn_rate_limited() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.req_rate_per_second = Some(1);
+    settings.limits.req_burst = Some(3);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let filter = json!({"kinds": [30999], "limit": 1});
+    // normal usage, within the burst, is served
+    for i in 0..3 {
+        let sub = format!("normal-{i}");
+        assert!(common::query(&mut ws, &sub, filter.clone())
+            .await?
+            .is_empty());
+        common::send_json(&mut ws, &json!(["CLOSE", sub])).await?;
+    }
+    // rapid churn beyond it is closed
+    common::send_json(&mut ws, &json!(["REQ", "churn", filter.clone()])).await?;
+    let closed = common::next_json(&mut ws).await?;
+    assert_eq!(closed[0], "CLOSED");
+    assert_eq!(closed[1], "churn");
+    assert!(closed[2].as_str().unwrap().starts_with("rate-limited:"));
+    // subscriptions at the configured pace are served again
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert!(common::query(&mut ws, "paced", filter).await?.is_empty());
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn close_churn_rate_limited() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.close_rate_per_second = Some(1);
+    settings.limits.close_burst = Some(2);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let filter = json!({"kinds": [1]});
+    for sub in ["a", "b", "c"] {
+        assert!(common::query(&mut ws, sub, filter.clone())
+            .await?
+            .is_empty());
+    }
+    // closing within the burst tears subscriptions down quietly, but
+    // a rapid CLOSE beyond it is ignored
+    for sub in ["a", "b", "c"] {
+        common::send_json(&mut ws, &json!(["CLOSE", sub])).await?;
+    }
+    let notice = common::next_json(&mut ws).await?;
+    assert_eq!(notice[0], "NOTICE");
+    assert!(notice[1].as_str().unwrap().starts_with("rate-limited:"));
+    // so only the last subscription still receives events
+    let mut publisher = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    for content in ["one", "two"] {
+        let event = common::signed_event(&keys, 1, vec![], content);
+        assert_eq!(common::publish(&mut publisher, &event).await?[2], true);
+        let msg = common::next_json(&mut ws).await?;
+        assert_eq!(msg, json!(["EVENT", "c", event]));
+    }
+    // and it can be closed at the configured pace
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    common::send_json(&mut ws, &json!(["CLOSE", "c"])).await?;
+    assert!(common::query(&mut ws, "d", json!({"kinds": [30999]}))
+        .await?
+        .is_empty());
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn new_authors_throttled_past_connection_limit() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.max_authors_per_connection = Some(2);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let (alice, bob, carol) = (
+        common::new_keypair(),
+        common::new_keypair(),
+        common::new_keypair(),
+    );
+    for keys in [&alice, &bob] {
+        let event = common::signed_event(keys, 1, vec![], "hello");
+        assert_eq!(common::publish(&mut ws, &event).await?[2], true);
+    }
+    let event = common::signed_event(&carol, 1, vec![], "hello");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("rate-limited:"));
+    // established authors continue
+    let event = common::signed_event(&alice, 1, vec![], "again");
+    assert_eq!(common::publish(&mut ws, &event).await?[2], true);
+    // the limit is per connection
+    let mut other = common::connect(&relay).await?;
+    let event = common::signed_event(&carol, 1, vec![], "hello");
+    assert_eq!(common::publish(&mut other, &event).await?[2], true);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn absurd_kinds_rejected() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.max_kind = Some(u64::from(u16::MAX));
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let normal = common::signed_event(&keys, 1, vec![], "normal");
+    assert_eq!(common::publish(&mut ws, &normal).await?[2], true);
+    let absurd = common::signed_event(&keys, u64::MAX, vec![], "absurd");
+    let ok = common::publish(&mut ws, &absurd).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn mass_mentions_rejected() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.max_distinct_p_tags = Some(5);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let ptag = |i: usize| vec!["p".to_owned(), format!("{i:064}")];
+    let keys = common::new_keypair();
+    let distinct = common::signed_event(&keys, 1, (0..6).map(ptag).collect(), "");
+    let ok = common::publish(&mut ws, &distinct).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+    let repeated = common::signed_event(&keys, 1, (0..60).map(|i| ptag(i % 3)).collect(), "");
+    assert_eq!(common::publish(&mut ws, &repeated).await?[2], true);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn indexed_tag_cap_enforced() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.max_indexed_tags = Some(5);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let tags = |name: &str, n: usize| -> Vec<Vec<String>> {
+        (0..n)
+            .map(|i| vec![name.to_owned(), format!("value{i}")])
+            .collect()
+    };
+    // many indexable tags
+    let indexed = common::signed_event(&common::new_keypair(), 1, tags("t", 6), "");
+    let ok = common::publish(&mut ws, &indexed).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+    // many tags that are not indexed
+    let plain = common::signed_event(&common::new_keypair(), 1, tags("topic", 50), "");
+    assert_eq!(common::publish(&mut ws, &plain).await?[2], true);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn oversized_contact_list_rejected() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.max_contact_list_entries = Some(2);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let follows = |n: usize| -> Vec<Vec<String>> {
+        (0..n)
+            .map(|i| vec!["p".to_owned(), format!("{i:064}")])
+            .collect()
+    };
+    // at the cap
+    let at_cap = common::signed_event(&common::new_keypair(), 3, follows(2), "");
+    assert_eq!(common::publish(&mut ws, &at_cap).await?[2], true);
+    // over the cap
+    let over = common::signed_event(&common::new_keypair(), 3, follows(3), "");
+    let ok = common::publish(&mut ws, &over).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+    // other kinds are unaffected
+    let note = common::signed_event(&common::new_keypair(), 1, follows(3), "");
+    assert_eq!(common::publish(&mut ws, &note).await?[2], true);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_etag_marker_rejected() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.options.validate_etag_markers = true;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let etag = |marker: &str| -> Vec<String> {
+        vec!["e".into(), "ab".repeat(32), "".into(), marker.into()]
+    };
+    let reply = common::signed_event(&keys, 1, vec![etag("root"), etag("reply")], "reply");
+    assert_eq!(common::publish(&mut ws, &reply).await?[2], true);
+    let unmarked = common::signed_event(&keys, 1, vec![vec!["e".into(), "ab".repeat(32)]], "x");
+    assert_eq!(common::publish(&mut ws, &unmarked).await?[2], true);
+    let bad = common::signed_event(&keys, 1, vec![etag("parent")], "bad marker");
+    let ok = common::publish(&mut ws, &bad).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn stored_events_ordered_by_time_then_id() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let mut events = vec![common::signed_event_at(&keys, 1, vec![], "older", 1_000)];
+    for i in 0..5 {
+        let content = format!("same time {i}");
+        events.push(common::signed_event_at(&keys, 1, vec![], &content, 2_000));
+    }
+    events.push(common::signed_event_at(&keys, 1, vec![], "newer", 3_000));
+    for e in &events {
+        assert_eq!(common::publish(&mut ws, e).await?[2], true);
+    }
+    let author = events[0].pubkey.clone();
+    // query on a new connection, so none of the events are also
+    // broadcast to these subscriptions.
+    let mut ws = common::connect(&relay).await?;
+    let key = |e: &Event| (e.created_at, e.id.clone());
+    // without a limit, oldest first; ties broken by lowest id
+    let mut expected: Vec<(u64, String)> = events.iter().map(key).collect();
+    expected.sort();
+    let found = common::query(&mut ws, "all", json!({ "authors": [author] })).await?;
+    assert_eq!(found.iter().map(key).collect::<Vec<_>>(), expected);
+    // with a limit, newest first; ties still broken by lowest id
+    expected.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    expected.truncate(4);
+    let found = common::query(&mut ws, "lim", json!({ "authors": [author], "limit": 4 })).await?;
+    assert_eq!(found.iter().map(key).collect::<Vec<_>>(), expected);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn limit_boundary_ties_paginate() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.limit_boundary = config::LimitBoundary::Ties;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let mut events = vec![common::signed_event_at(&keys, 1, vec![], "newest", 3_000)];
+    for (i, created_at) in [2_000, 2_000, 2_000, 1_000, 1_000].iter().enumerate() {
+        let content = format!("event {i}");
+        events.push(common::signed_event_at(
+            &keys,
+            1,
+            vec![],
+            &content,
+            *created_at,
+        ));
+    }
+    for e in &events {
+        assert_eq!(common::publish(&mut ws, e).await?[2], true);
+    }
+    let author = events[0].pubkey.clone();
+    let mut ws = common::connect(&relay).await?;
+    // page backwards with a limit of 2, which falls within the group
+    // at 2_000; each page ends with a whole group.
+    let key = |e: &Event| (e.created_at, e.id.clone());
+    let (mut pages, mut found) = (vec![], vec![]);
+    let mut until = 4_000;
+    loop {
+        let filter = json!({ "authors": [author], "until": until, "limit": 2 });
+        let page = common::query(&mut ws, "page", filter.clone()).await?;
+        // repeating a page returns the same events
+        assert_eq!(common::query(&mut ws, "again", filter).await?, page);
+        match page.last() {
+            Some(last) => until = last.created_at - 1,
+            None => break,
+        }
+        pages.push(page.len());
+        found.extend(page.iter().map(key));
+    }
+    assert_eq!(pages, vec![4, 2]);
+    // every event exactly once, newest first, ties by lowest id
+    let mut expected: Vec<(u64, String)> = events.iter().map(key).collect();
+    expected.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+    assert_eq!(found, expected);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+/// Read one page of a sequence subscription: the event ids, and the
+/// cursor sent before EOSE.
+async fn seq_page(ws: &mut common::WsStream, after_seq: u64) -> Result<(Vec<String>, u64)> {
+    let mut ids = vec![];
+    loop {
+        let msg = common::next_json(ws).await?;
+        match msg[0].as_str() {
+            Some("EVENT") => ids.push(msg[2]["id"].as_str().unwrap().to_owned()),
+            Some("CURSOR") => {
+                assert_eq!(common::next_json(ws).await?[0], "EOSE");
+                return Ok((ids, msg[2].as_u64().unwrap()));
+            }
+            _ => panic!("unexpected message after {after_seq}: {msg}"),
+        }
+    }
+}
+
+#[tokio::test]
+async fn sequence_cursor_pages_identical_timestamps() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.options.sequence_cursors = true;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let events: Vec<Event> = (0..5)
+        .map(|i| common::signed_event_at(&keys, 1, vec![], &format!("seq {i}"), 2_000))
+        .collect();
+    for e in &events {
+        assert_eq!(common::publish(&mut ws, e).await?[2], true);
+    }
+    let author = events[0].pubkey.clone();
+    // page through two at a time, following the cursor
+    let mut ws = common::connect(&relay).await?;
+    let mut cursor = 0;
+    let mut found = vec![];
+    loop {
+        let filter = json!({"authors": [author], "after_seq": cursor, "limit": 2});
+        common::send_json(&mut ws, &json!(["REQ", format!("page-{cursor}"), filter])).await?;
+        let (ids, next) = seq_page(&mut ws, cursor).await?;
+        assert!(ids.len() <= 2);
+        if ids.is_empty() {
+            // an empty page leaves the cursor where it was
+            assert_eq!(next, cursor);
+            break;
+        }
+        assert!(next > cursor);
+        found.extend(ids);
+        cursor = next;
+    }
+    // events come back exactly once, in the order they were stored
+    let expected: Vec<String> = events.iter().map(|e| e.id.clone()).collect();
+    assert_eq!(found, expected);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn sequence_filters_refused_when_disabled() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    common::send_json(&mut ws, &json!(["REQ", "seq", {"after_seq": 0}])).await?;
+    let msg = common::next_json(&mut ws).await?;
+    assert_eq!(msg[0], "CLOSED");
+    assert!(msg[2].as_str().unwrap().starts_with("unsupported:"));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn sequence_filter_must_be_alone() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.options.sequence_cursors = true;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let req = json!(["REQ", "seq", {"after_seq": 0}, {"kinds": [1]}]);
+    common::send_json(&mut ws, &req).await?;
+    let msg = common::next_json(&mut ws).await?;
+    assert_eq!(msg[0], "CLOSED");
+    assert!(msg[2].as_str().unwrap().starts_with("invalid:"));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+fn posting_hours(start: i64, end: i64) -> config::PostingHours {
+    let at = |offset: i64| {
+        (chrono::Utc::now() + chrono::Duration::minutes(offset))
+            .format("%H:%M")
+            .to_string()
+    };
+    config::PostingHours {
+        start: Some(at(start)),
+        end: Some(at(end)),
+    }
+}
+
+#[tokio::test]
+async fn events_accepted_within_posting_hours() -> Result<()> {
+    let settings = config::Settings {
+        posting_hours: posting_hours(-60, 60),
+        ..Default::default()
+    };
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let event = common::signed_event(&common::new_keypair(), 1, vec![], "open");
+    assert_eq!(common::publish(&mut ws, &event).await?[2], true);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn events_rejected_outside_posting_hours() -> Result<()> {
+    let settings = config::Settings {
+        posting_hours: posting_hours(60, 120),
+        ..Default::default()
+    };
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let event = common::signed_event(&common::new_keypair(), 1, vec![], "closed");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], false);
+    let msg = ok[3].as_str().unwrap();
+    assert!(msg.starts_with("blocked: relay only accepts events between"));
+    // the event was not stored
+    let events = common::query(&mut ws, "s", json!({ "ids": [event.id] })).await?;
+    assert!(events.is_empty());
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn delegated_filter_separates_events() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let (delegator, delegatee) = (common::new_keypair(), common::new_keypair());
+    let tag = common::delegation_tag(&delegator, &delegatee, "kind=1");
+    let delegated = common::signed_event(&delegatee, 1, vec![tag], "on behalf");
+    let direct = common::signed_event(&delegatee, 1, vec![], "for myself");
+    for e in [&delegated, &direct] {
+        assert_eq!(common::publish(&mut ws, e).await?[2], true);
+    }
+    let author = direct.pubkey.clone();
+    for (flag, expected) in [(true, &delegated), (false, &direct)] {
+        let filter = json!({ "authors": [author], "delegated": flag });
+        let events = common::query(&mut ws, &format!("d-{flag}"), filter).await?;
+        let ids: Vec<&str> = events.iter().map(|e| e.id.as_str()).collect();
+        assert_eq!(ids, vec![expected.id.as_str()]);
+    }
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_delegations_rejected() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let (delegator, delegatee) = (common::new_keypair(), common::new_keypair());
+    // subscribe to events delegated by the delegator
+    let author = common::signed_event(&delegator, 1, vec![], "").pubkey;
+    let mut reader = common::connect(&relay).await?;
+    assert!(
+        common::query(&mut reader, "by", json!({ "authors": [author] }))
+            .await?
+            .is_empty()
+    );
+    let mut ws = common::connect(&relay).await?;
+    let tag = common::delegation_tag(&delegator, &delegatee, "kind=1");
+    // the kind is outside the delegated conditions
+    let wrong_kind = common::signed_event(&delegatee, 7, vec![tag.clone()], "+");
+    // the token was not signed by the claimed delegator
+    let mut forged = tag.clone();
+    forged[1] = common::signed_event(&common::new_keypair(), 1, vec![], "").pubkey;
+    let forged = common::signed_event(&delegatee, 1, vec![forged], "forged");
+    for e in [&wrong_kind, &forged] {
+        let ok = common::publish(&mut ws, e).await?;
+        assert_eq!(ok[2], false);
+        assert_eq!(
+            ok[3],
+            "invalid: delegation tag is invalid, or does not allow this event"
+        );
+    }
+    let delegated = common::signed_event(&delegatee, 1, vec![tag], "allowed");
+    assert_eq!(common::publish(&mut ws, &delegated).await?[2], true);
+    // only the valid delegation reaches the delegator's subscribers
+    let msg = common::next_json(&mut reader).await?;
+    assert_eq!(msg[0], "EVENT");
+    assert_eq!(msg[2]["id"], delegated.id);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn unconstrained_delegations_rejected() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.options.require_constrained_delegation = true;
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let (delegator, delegatee) = (common::new_keypair(), common::new_keypair());
+    let mut ws = common::connect(&relay).await?;
+    for conditions in ["", "kind!"] {
+        let tag = common::delegation_tag(&delegator, &delegatee, conditions);
+        let e = common::signed_event(&delegatee, 1, vec![tag], conditions);
+        let ok = common::publish(&mut ws, &e).await?;
+        assert_eq!(ok[2], false, "{conditions:?}");
+        assert_eq!(
+            ok[3],
+            "invalid: delegation conditions must restrict the kind or created_at of events"
+        );
+    }
+    for conditions in ["kind=1", "created_at<4000000000"] {
+        let tag = common::delegation_tag(&delegator, &delegatee, conditions);
+        let e = common::signed_event(&delegatee, 1, vec![tag], conditions);
+        assert_eq!(
+            common::publish(&mut ws, &e).await?[2],
+            true,
+            "{conditions:?}"
+        );
+    }
+    // events without a delegation are unaffected
+    let plain = common::signed_event(&delegatee, 1, vec![], "plain");
+    assert_eq!(common::publish(&mut ws, &plain).await?[2], true);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn json_content_rejected_for_plaintext_kinds() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.options.reject_json_content_kinds = vec![1];
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let plain = common::signed_event(&keys, 1, vec![], "just some text");
+    assert_eq!(common::publish(&mut ws, &plain).await?[2], true);
+    let blob = common::signed_event(&keys, 1, vec![], r#"{"name": "bot"}"#);
+    let ok = common::publish(&mut ws, &blob).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+    // unlisted kinds may carry JSON
+    let metadata = common::signed_event(&keys, 0, vec![], r#"{"name": "bot"}"#);
+    assert_eq!(common::publish(&mut ws, &metadata).await?[2], true);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn off_curve_pubkey_rejected_with_reason() -> Result<()> {
+    let keys = common::new_keypair();
+    let mut event = common::signed_event(&keys, 1, vec![], "off curve");
+    // x = 5 is not the x coordinate of any point on the curve
+    event.pubkey = format!("{:064x}", 5);
+    let digest = sha256::Hash::hash(event.to_canonical().unwrap().as_bytes());
+    event.id = format!("{digest:x}");
+    for (enabled, reason) in [
+        (
+            true,
+            "invalid: pubkey is not a valid BIP-340 x-only public key",
+        ),
+        (false, "invalid: Event malformed pubkey"),
+    ] {
+        let mut settings = config::Settings::default();
+        settings.options.require_valid_pubkeys = enabled;
+        let relay = common::start_relay_with_settings(settings)?;
+        common::wait_for_healthy_relay(&relay).await?;
+        let mut ws = common::connect(&relay).await?;
+        let ok = common::publish(&mut ws, &event).await?;
+        assert_eq!(ok[2], false);
+        assert_eq!(ok[3], reason);
+        // valid keys are unaffected
+        let valid = common::signed_event(&keys, 1, vec![], "on curve");
+        assert_eq!(common::publish(&mut ws, &valid).await?[2], true);
+        let _res = relay.shutdown_tx.send(());
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn kind_created_at_bounds_override_global() -> Result<()> {
+    let kind = 1_503;
+    let mut settings = config::Settings::default();
+    settings.options.reject_future_seconds = Some(60);
+    settings.options.kind_created_at_bounds = vec![config::KindCreatedAtBounds {
+        kind,
+        past_seconds: Some(60),
+        future_seconds: Some(3600),
+    }];
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let now = common::signed_event(&keys, 1, vec![], "").created_at;
+    for (k, offset, accepted) in [
+        // other kinds use the global bounds: no past limit, 60s future
+        (1, 600, false),
+        (1, -600, true),
+        // the listed kind uses only its own bounds
+        (kind, 600, true),
+        (kind, -600, false),
+    ] {
+        let created_at = now.checked_add_signed(offset).unwrap();
+        let e = common::signed_event_at(&keys, k, vec![], &format!("{offset}"), created_at);
+        let ok = common::publish(&mut ws, &e).await?;
+        assert_eq!(ok[2], accepted, "kind {k} at {offset:+}s: {ok}");
+        if !accepted {
+            assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+        }
+    }
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn library_query_matches_req() -> Result<()> {
+    let settings = config::Settings::default();
+    let relay = common::start_relay_with_settings(settings.clone())?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let now = common::signed_event(&keys, 1, vec![], "").created_at;
+    for (kind, age) in [(1, 40), (1_504, 30), (1, 20), (1_504, 10), (7, 0)] {
+        let e = common::signed_event_at(&keys, kind, vec![], "library", now - age);
+        assert_eq!(common::publish(&mut ws, &e).await?[2], true);
+    }
+    let author = common::signed_event(&keys, 1, vec![], "").pubkey;
+    // the relay's in-memory database is shared with this handle
+    let mut settings = settings;
+    settings.database.in_memory = true;
+    let (_, metrics) = server::create_metrics();
+    let handle = db::build_repo(&settings, metrics).await;
+    for filter in [
+        json!({"authors": [author]}),
+        json!({"authors": [author], "kinds": [1, 1504], "limit": 3}),
+        json!({"authors": [author], "kinds": [7], "since": now}),
+    ] {
+        let req = common::query(&mut ws, "lib", filter.clone()).await?;
+        assert!(!req.is_empty());
+        let filters: Vec<ReqFilter> = vec![serde_json::from_value(filter)?];
+        let found: Vec<Event> = repo::query(handle.clone(), &filters).collect().await;
+        let ids = |events: &[Event]| events.iter().map(|e| e.id.clone()).collect::<Vec<_>>();
+        assert_eq!(ids(&found), ids(&req));
+    }
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn recent_events_over_http() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let (kind, other_kind) = (1_501, 1_502);
+    let now = common::signed_event(&keys, 1, vec![], "").created_at;
+    let mut notes = vec![];
+    for age in [30, 20, 10] {
+        let e = common::signed_event_at(&keys, kind, vec![], "recent", now - age);
+        assert_eq!(common::publish(&mut ws, &e).await?[2], true);
+        notes.push(e);
+    }
+    let other = common::signed_event_at(&keys, other_kind, vec![], "other", now);
+    assert_eq!(common::publish(&mut ws, &other).await?[2], true);
+    let recent = |query: String| async move {
+        let uri = format!("http://127.0.0.1:{}/recent?{}", relay.port, query).parse()?;
+        let res = hyper::Client::new().get(uri).await?;
+        assert_eq!(res.status(), hyper::StatusCode::OK);
+        let body = hyper::body::to_bytes(res.into_body()).await?;
+        let events: Vec<Event> = serde_json::from_slice(&body)?;
+        anyhow::Ok(events.into_iter().map(|e| e.id).collect::<Vec<String>>())
+    };
+    // newest first, honoring the kind and limit
+    let ids = recent(format!("kinds={kind}&limit=2")).await?;
+    assert_eq!(ids, vec![notes[2].id.clone(), notes[1].id.clone()]);
+    let ids = recent(format!("kinds={kind},{other_kind}&limit=3")).await?;
+    assert_eq!(
+        ids,
+        vec![other.id.clone(), notes[2].id.clone(), notes[1].id.clone()]
+    );
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn kind_policies_endpoint() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.event_kind_blacklist = Some(vec![4]);
+    settings.limits.max_event_bytes = Some(4096);
+    settings.limits.max_tag_count = Some(100);
+    settings.limits.max_contact_list_entries = Some(500);
+    settings.options.kind_required_tags = vec![config::KindRequiredTags {
+        kind: 30023,
+        tags: vec!["d".into(), "title".into()],
+    }];
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let uri = format!("http://127.0.0.1:{}/kinds", relay.port).parse()?;
+    let res = hyper::Client::new().get(uri).await?;
+    assert_eq!(res.status(), hyper::StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    let doc: serde_json::Value = serde_json::from_slice(&body)?;
+    assert_eq!(doc["blocked"], json!([4]));
+    assert_eq!(
+        doc["defaults"],
+        json!({"max_event_bytes": 4096, "max_tag_count": 100})
+    );
+    assert_eq!(
+        doc["kinds"]["30023"],
+        json!({"max_event_bytes": 4096, "max_tag_count": 100, "required_tags": ["d", "title"]})
+    );
+    assert_eq!(doc["kinds"]["3"]["max_p_tags"], 500);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn subscription_results_capped_at_hard_ceiling() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.limits.hard_max_results_per_subscription = Some(3);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let kind = 1_601;
+    for i in 0..5 {
+        let e = common::signed_event(&keys, kind, vec![], &format!("note {i}"));
+        assert_eq!(common::publish(&mut ws, &e).await?[2], true);
+    }
+    let pubkey = common::signed_event(&keys, kind, vec![], "").pubkey;
+    // no limit in the filter
+    let filter = json!({"authors": [pubkey], "kinds": [kind]});
+    assert_eq!(
+        common::query(&mut ws, "all", filter.clone()).await?.len(),
+        3
+    );
+    // the ceiling applies across filters
+    common::send_json(
+        &mut ws,
+        &json!(["REQ", "two", filter, {"authors": [pubkey]}]),
+    )
+    .await?;
+    let mut events = 0;
+    loop {
+        let msg = common::next_json(&mut ws).await?;
+        match msg[0].as_str() {
+            Some("EVENT") => events += 1,
+            Some("EOSE") => break,
+            _ => panic!("unexpected message: {msg}"),
+        }
+    }
+    assert_eq!(events, 3);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn relay_info_reports_current_time() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let req = hyper::Request::get(format!("http://127.0.0.1:{}/", relay.port))
+        .header("Accept", "application/nostr+json")
+        .body(hyper::Body::empty())?;
+    let res = hyper::Client::new().request(req).await?;
+    assert_eq!(res.status(), hyper::StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    let doc: serde_json::Value = serde_json::from_slice(&body)?;
+    let relay_time = doc["current_time"].as_u64().unwrap();
+    assert!(relay_time.abs_diff(unix_time()) <= 2);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn ok_reasons_for_malformed_and_duplicate_events() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let event = common::signed_event(&keys, 1, vec![], "original");
+    let mut bad_sig = common::signed_event(&keys, 1, vec![], "bad sig");
+    bad_sig.sig = event.sig.clone();
+    let mut bad_id = common::signed_event(&keys, 1, vec![], "bad id");
+    bad_id.content = "changed".to_owned();
+    for (e, reason) in [
+        (&bad_sig, "invalid: Event invalid signature"),
+        (&bad_id, "invalid: Event invalid id"),
+    ] {
+        let ok = common::publish(&mut ws, e).await?;
+        assert_eq!(ok, json!(["OK", e.id, false, reason]));
+    }
+    // a stored event is accepted again, as a duplicate
+    assert_eq!(common::publish(&mut ws, &event).await?[2], true);
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(
+        ok,
+        json!(["OK", event.id, true, "duplicate: already have this event"])
+    );
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+/// Rejects every signature.
+struct RejectingVerifier;
+
+impl Verifier for RejectingVerifier {
+    fn verify(&self, _check: &SignatureCheck) -> nostr_rs_relay::error::Result<()> {
+        Err(nostr_rs_relay::error::Error::EventInvalidSignature)
+    }
+}
+
+#[tokio::test]
+async fn configured_verifier_checks_signatures() -> Result<()> {
+    let settings = config::Settings::default();
+    let relay = common::start_relay_with_verifier(settings, Arc::new(RejectingVerifier))?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let event = common::signed_event(&common::new_keypair(), 1, vec![], "rejected");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+/// Panics on the first signature, then verifies normally.
+#[derive(Default)]
+struct PanicOnceVerifier {
+    panicked: std::sync::atomic::AtomicBool,
+}
+
+impl Verifier for PanicOnceVerifier {
+    fn verify(&self, check: &SignatureCheck) -> nostr_rs_relay::error::Result<()> {
+        if !self
+            .panicked
+            .swap(true, std::sync::atomic::Ordering::SeqCst)
+        {
+            panic!("verifier failure");
+        }
+        Secp256k1Verifier.verify(check)
+    }
+}
+
+#[tokio::test]
+async fn verifier_panic_rejects_event() -> Result<()> {
+    let settings = config::Settings::default();
+    let verifier = Arc::new(PanicOnceVerifier::default());
+    let relay = common::start_relay_with_verifier(settings, verifier)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let mut ws = common::connect(&relay).await?;
+    let keys = common::new_keypair();
+    let event = common::signed_event(&keys, 1, vec![], "panics");
+    let ok = common::publish(&mut ws, &event).await?;
+    assert_eq!(ok[2], false);
+    assert!(ok[3].as_str().unwrap().starts_with("invalid:"));
+    // the connection is still served
+    let event = common::signed_event(&keys, 1, vec![], "verified");
+    assert_eq!(common::publish(&mut ws, &event).await?[2], true);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+/// Value of a sample in a Prometheus text exposition, if present.
+fn metric_value(text: &str, sample: &str) -> Option<f64> {
+    text.lines()
+        .find_map(|l| l.strip_prefix(sample)?.strip_prefix(' '))
+        .and_then(|v| v.trim().parse().ok())
+}
+
+async fn scrape_metrics(addr: &str) -> Result<String> {
+    let res = hyper::Client::new()
+        .get(format!("http://{addr}/metrics").parse()?)
+        .await?;
+    assert_eq!(res.status(), hyper::StatusCode::OK);
+    let body = hyper::body::to_bytes(res.into_body()).await?;
+    Ok(String::from_utf8(body.to_vec())?)
+}
+
+#[tokio::test]
+async fn metrics_served_on_own_address() -> Result<()> {
+    let metrics_addr = std::net::TcpListener::bind("127.0.0.1:0")?
+        .local_addr()?
+        .to_string();
+    let mut settings = config::Settings::default();
+    settings.network.metrics_address = Some(metrics_addr.clone());
+    settings.limits.event_kind_blacklist = Some(vec![4]);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let mut ws = common::connect(&relay).await?;
+    for (kind, content) in [(1, "one"), (1, "two"), (4, "secret")] {
+        let event = common::signed_event(&keys, kind, vec![], content);
+        common::publish(&mut ws, &event).await?;
+    }
+    common::send_json(&mut ws, &json!(["REQ", "sub", {"kinds": [1]}])).await?;
+    while common::next_json(&mut ws).await?[0] != "EOSE" {}
+    let text = scrape_metrics(&metrics_addr).await?;
+    assert_eq!(
+        metric_value(&text, "nostr_events_received_total"),
+        Some(3.0)
+    );
+    assert_eq!(
+        metric_value(&text, "nostr_events_rejected_total{reason=\"blocked\"}"),
+        Some(1.0)
+    );
+    assert_eq!(metric_value(&text, "nostr_subscriptions_active"), Some(1.0));
+    assert!(text.contains("nostr_signature_verify_seconds_count"));
+    // closing the subscription is reflected once the relay handles it
+    common::send_json(&mut ws, &json!(["CLOSE", "sub"])).await?;
+    let mut active = None;
+    for _ in 0..50 {
+        let text = scrape_metrics(&metrics_addr).await?;
+        active = metric_value(&text, "nostr_subscriptions_active");
+        if active == Some(0.0) {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(active, Some(0.0));
+    // the relay's own port still serves them too
+    let port_text = scrape_metrics(&format!("127.0.0.1:{}", relay.port)).await?;
+    assert_eq!(
+        metric_value(&port_text, "nostr_events_received_total"),
+        Some(3.0)
+    );
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn search_text_notes() -> Result<()> {
+    let relay = common::start_relay()?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let (alice, bob) = (common::new_keypair(), common::new_keypair());
+    let now = unix_time();
+    let notes = [
+        (&alice, 1, "Pizza night at my place", now - 30),
+        (&alice, 1, "the best PIZZA in town", now - 20),
+        (&bob, 1, "pizza is overrated", now - 10),
+        (&alice, 1, "pasta for lunch", now - 5),
+        // only text notes are indexed
+        (&alice, 30023, "a long read about pizza", now),
+    ];
+    let mut ws = common::connect(&relay).await?;
+    let mut ids = vec![];
+    for (keys, kind, content, created_at) in notes {
+        let tags = if kind == 30023 {
+            vec![vec!["d".to_owned(), "pizza".to_owned()]]
+        } else {
+            vec![]
+        };
+        let e = common::signed_event_at(keys, kind, tags, content, created_at);
+        assert_eq!(common::publish(&mut ws, &e).await?[2], true);
+        ids.push(e.id);
+    }
+    let found = |events: Vec<Event>| events.into_iter().map(|e| e.id).collect::<Vec<_>>();
+    let results = common::query(&mut ws, "s1", json!({"search": "pizza"})).await?;
+    assert_eq!(
+        found(results),
+        vec![ids[0].clone(), ids[1].clone(), ids[2].clone()]
+    );
+    // other conditions and the limit still apply
+    let author = common::signed_event(&alice, 1, vec![], "").pubkey;
+    let filter = json!({"search": "pizza", "authors": [author], "limit": 1});
+    let results = common::query(&mut ws, "s2", filter).await?;
+    assert_eq!(found(results), vec![ids[1].clone()]);
+    let filter = json!({"search": "pizza", "until": now - 25});
+    let results = common::query(&mut ws, "s3", filter).await?;
+    assert_eq!(found(results), vec![ids[0].clone()]);
+    // every word must match
+    let results = common::query(&mut ws, "s4", json!({"search": "pizza night"})).await?;
+    assert_eq!(found(results), vec![ids[0].clone()]);
+    // deleted notes are no longer found
+    let deletion = common::signed_event(&alice, 5, vec![vec!["e".to_owned(), ids[0].clone()]], "");
+    assert_eq!(common::publish(&mut ws, &deletion).await?[2], true);
+    let results = common::query(&mut ws, "s5", json!({"search": "pizza"})).await?;
+    assert_eq!(found(results), vec![ids[1].clone(), ids[2].clone()]);
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}
+
+#[tokio::test]
+async fn backfill_read_in_chunks() -> Result<()> {
+    let mut settings = config::Settings::default();
+    settings.database.query_chunk_seconds = Some(10);
+    let relay = common::start_relay_with_settings(settings)?;
+    common::wait_for_healthy_relay(&relay).await?;
+    let keys = common::new_keypair();
+    let base = unix_time() - 1000;
+    let mut ws = common::connect(&relay).await?;
+    // events on both sides of window boundaries, several sharing a
+    // timestamp, published out of order
+    let mut events = vec![];
+    for (i, offset) in [0, 9, 10, 10, 19, 20, 21, 45, 99, 100, 100, 101, 5, 55]
+        .into_iter()
+        .enumerate()
+    {
+        let e = common::signed_event_at(&keys, 1, vec![], &format!("{i}"), base + offset);
+        assert_eq!(common::publish(&mut ws, &e).await?[2], true);
+        events.push(e);
+    }
+    events.sort_by(|a, b| (a.created_at, &a.id).cmp(&(b.created_at, &b.id)));
+    let ids = |events: &[Event]| events.iter().map(|e| e.id.clone()).collect::<Vec<_>>();
+    let author = events[0].pubkey.clone();
+    // every event once, in the same order as an unchunked read
+    let results = common::query(&mut ws, "all", json!({ "authors": [author] })).await?;
+    assert_eq!(ids(&results), ids(&events));
+    // with bounds of the filter's own, inside and across windows
+    let filter = json!({"authors": [author], "since": base + 10, "until": base + 55});
+    let results = common::query(&mut ws, "some", filter).await?;
+    let expected: Vec<Event> = events
+        .iter()
+        .filter(|e| (base + 10..=base + 55).contains(&e.created_at))
+        .cloned()
+        .collect();
+    assert_eq!(ids(&results), ids(&expected));
+    let _res = relay.shutdown_tx.send(());
+    Ok(())
+}